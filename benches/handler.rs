@@ -0,0 +1,78 @@
+//! Times a call to the logs handler the way `main` makes it, against the extra async block it used to be wrapped
+//! in, and the `Arc<HandlerState>` clone each call takes, at a high call rate of single-record batches.
+//!
+//! `cargo bench --bench handler`; there's no criterion here, so each is the best of a few runs, taken in turns.
+
+use std::hint::black_box;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use chrono::{TimeZone, Utc};
+use json::JsonValue;
+use lambda_extension::{service_fn, Error, LambdaLog, LambdaLogRecord, Service};
+use log_store_extension::config::Config;
+use log_store_extension::handler::{handler, HandlerState};
+use log_store_extension::stats::Stats;
+use tokio::sync::mpsc::{channel, Receiver};
+
+const CALLS: u32 = 200_000;
+const RUNS: usize = 5;
+
+fn batch() -> Vec<LambdaLog> {
+    let record = LambdaLogRecord::Function(r#"{"level":"info","msg":"order placed","order_id":42}"#.to_string());
+
+    vec![LambdaLog { time: Utc.timestamp_millis_opt(1_712_345_678_000).unwrap(), record }]
+}
+
+/// How long `CALLS` calls to `service` take, emptying the queue after each.
+async fn time_calls<S>(service: &mut S, recver: &mut Receiver<JsonValue>) -> Duration
+    where S: Service<Vec<LambdaLog>, Response = (), Error = Error>
+{
+    let started = Instant::now();
+
+    for _ in 0..CALLS {
+        service.call(batch()).await.unwrap();
+        while recver.try_recv().is_ok() {}
+    }
+
+    started.elapsed()
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    let config = Config::from_vars([("LOG_STORE_ADDRESS", "127.0.0.1:1234")]).unwrap();
+    let (sender, mut recver) = channel(1024);
+    let state = Arc::new(HandlerState::new(&config, sender, Arc::new(Stats::new(None))));
+
+    let direct_state = state.clone();
+    let mut direct = service_fn(move |logs| handler(logs, direct_state.clone()));
+    let wrapped_state = state.clone();
+    let mut wrapped = service_fn(move |logs| {
+        let state_clone = wrapped_state.clone();
+
+        async move {
+            handler(logs, state_clone).await
+        }
+    });
+
+    let mut times = [Duration::MAX; 3];
+
+    // the three take turns, so drift in the machine's speed doesn't favor one
+    for _ in 0..RUNS {
+        times[0] = times[0].min(runtime.block_on(time_calls(&mut direct, &mut recver)));
+        times[1] = times[1].min(runtime.block_on(time_calls(&mut wrapped, &mut recver)));
+
+        let started = Instant::now();
+
+        for _ in 0..CALLS {
+            drop(black_box(state.clone()));
+        }
+
+        times[2] = times[2].min(started.elapsed());
+    }
+
+    let [direct_call, wrapped_call, clone] = times.map(|time| time / CALLS);
+
+    println!("handler, called directly (as main does): {:>9.3?} per call", direct_call);
+    println!("handler, in an extra async block:        {:>9.3?} per call", wrapped_call);
+    println!("Arc<HandlerState> clone and drop:        {:>9.3?} per call", clone);
+}
@@ -0,0 +1,65 @@
+//! Times the logs handler queueing function records pre-serialized, with a JSON object spliced into its line as it
+//! was logged, against the general path, where it's parsed, laid out, and serialized again (`LOG_STORE_SPLICE=0`),
+//! for a small object and a larger, nested one.
+//!
+//! `cargo bench --bench splice`; there's no criterion here, so each is the best of a few runs, taken in turns.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use chrono::{TimeZone, Utc};
+use json::JsonValue;
+use lambda_extension::{LambdaLog, LambdaLogRecord};
+use log_store_extension::config::Config;
+use log_store_extension::handler::{handler, HandlerState};
+use log_store_extension::stats::Stats;
+use tokio::sync::mpsc::{channel, Receiver};
+
+const CALLS: u32 = 100_000;
+const RUNS: usize = 5;
+
+const SMALL: &str = r#"{"level":"info","msg":"order placed","order_id":42}"#;
+const NESTED: &str = r#"{"level":"info","msg":"order placed","order":{"id":42,"customer":{"id":"c-1234","tier":"gold","region":"eu-west-1"},"items":[{"sku":"A-1","qty":2,"price":9.99},{"sku":"B-27","qty":1,"price":24.5},{"sku":"C-300","qty":4,"price":1.25}],"total":49.48,"currency":"EUR"},"http":{"method":"POST","path":"/orders","status":201,"duration_ms":12.7},"tags":["checkout","web","v2"]}"#;
+
+fn batch(line: &str) -> Vec<LambdaLog> {
+    let record = LambdaLogRecord::Function(line.to_string());
+
+    vec![LambdaLog { time: Utc.timestamp_millis_opt(1_712_345_678_000).unwrap(), record }]
+}
+
+/// How long `CALLS` calls to the handler take with `line`, emptying the queue after each.
+async fn time_calls(state: &Arc<HandlerState>, recver: &mut Receiver<JsonValue>, line: &str) -> Duration {
+    let started = Instant::now();
+
+    for _ in 0..CALLS {
+        handler(batch(line), state.clone()).await.unwrap();
+        while recver.try_recv().is_ok() {}
+    }
+
+    started.elapsed()
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    let state = |splice: &str| {
+        let config = Config::from_vars([("LOG_STORE_ADDRESS", "127.0.0.1:1234"), ("LOG_STORE_PRESERIALIZE", "1"), ("LOG_STORE_SPLICE", splice)]).unwrap();
+        let (sender, recver) = channel(1024);
+
+        (Arc::new(HandlerState::new(&config, sender, Arc::new(Stats::new(None)))), recver)
+    };
+    let (mut spliced, mut parsed) = (state("1"), state("0"));
+
+    for (name, line) in [("small", SMALL), ("nested", NESTED)] {
+        let mut times = [Duration::MAX; 2];
+
+        // the two take turns, so drift in the machine's speed doesn't favor one
+        for _ in 0..RUNS {
+            times[0] = times[0].min(runtime.block_on(time_calls(&spliced.0, &mut spliced.1, line)));
+            times[1] = times[1].min(runtime.block_on(time_calls(&parsed.0, &mut parsed.1, line)));
+        }
+
+        let [spliced_call, parsed_call] = times.map(|time| time / CALLS);
+
+        println!("{:<6} object ({:>3} bytes), spliced: {:>9.3?} per call", name, line.len(), spliced_call);
+        println!("{:<6} object ({:>3} bytes), parsed:  {:>9.3?} per call", name, line.len(), parsed_call);
+    }
+}
@@ -0,0 +1,6 @@
+use std::time::Duration;
+
+/// Exponential backoff: `base_ms` doubled for every previous attempt, capped at `max_ms`.
+pub fn delay(attempt: u32, base_ms: u64, max_ms: u64) -> Duration {
+    Duration::from_millis(base_ms.saturating_mul(1 << attempt.min(32)).min(max_ms))
+}
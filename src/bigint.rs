@@ -0,0 +1,99 @@
+use std::borrow::Cow;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+// Number.MAX_SAFE_INTEGER: past it, a consumer reading numbers as doubles (JavaScript, most JSON
+// libraries' defaults) can't tell neighbouring integers apart
+const MAX_SAFE_INTEGER: &str = "9007199254740991";
+
+/// What to do with integers in a function's JSON log line too large to survive being read as a double.
+///
+/// The json crate keeps integers up to `u64::MAX` exactly, but rounds longer ones, and many consumers
+/// downstream read every number as a double anyway, so IDs past 2^53 can be silently corrupted on the way.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BigInt {
+    /// Ship them as numbers, as they were logged
+    #[default]
+    Keep,
+    /// Ship them as strings of their digits, exactly as they were logged
+    String,
+}
+
+/// `line` with every integer past `MAX_SAFE_INTEGER` (either sign) outside a string quoted, going by the tokens
+/// as they were logged, so not even the json crate gets to round them. Numbers with a fraction or an exponent
+/// are left alone. A line that isn't JSON comes back as it was, or at worst as something that's still not JSON.
+pub fn quote_big_integers(line: &str) -> Cow<'_, str> {
+    let bytes = line.as_bytes();
+    let mut out = String::new();
+    let mut copied = 0;
+    let mut in_string = false;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' if in_string => i += 1,
+            b'"' => in_string = !in_string,
+            b'-' | b'0'..=b'9' if !in_string => {
+                let start = i;
+                let digits_start = if bytes[i] == b'-' { i + 1 } else { i };
+                let mut end = digits_start;
+
+                while bytes.get(end).is_some_and(u8::is_ascii_digit) {
+                    end += 1;
+                }
+
+                let digits = &line[digits_start..end];
+                let integer = !matches!(bytes.get(end), Some(b'.' | b'e' | b'E'));
+
+                if integer && is_unsafe(digits) {
+                    out.push_str(&line[copied..start]);
+                    out.push('"');
+                    out.push_str(&line[start..end]);
+                    out.push('"');
+                    copied = end;
+                }
+
+                // what follows a number (a fraction, exponent, or delimiter) has no quotes to throw the scan off
+                i = end.max(start + 1);
+                continue;
+            }
+            _ => (),
+        }
+
+        i += 1;
+    }
+
+    if copied == 0 {
+        return Cow::Borrowed(line);
+    }
+
+    out.push_str(&line[copied..]);
+    Cow::Owned(out)
+}
+
+fn is_unsafe(digits: &str) -> bool {
+    let digits = digits.trim_start_matches('0');
+
+    digits.len() > MAX_SAFE_INTEGER.len() || (digits.len() == MAX_SAFE_INTEGER.len() && digits > MAX_SAFE_INTEGER)
+}
+
+impl FromStr for BigInt {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "keep" => Ok(BigInt::Keep),
+            "string" => Ok(BigInt::String),
+            _ => Err(format!("unknown big integer handling {:?}, expected keep or string", s)),
+        }
+    }
+}
+
+impl Display for BigInt {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BigInt::Keep => write!(f, "keep"),
+            BigInt::String => write!(f, "string"),
+        }
+    }
+}
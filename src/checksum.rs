@@ -0,0 +1,151 @@
+use json::object;
+
+/// The `type` of the line `BatchChecksum` adds after each batch.
+pub const BATCH_TYPE: &str = "_batch";
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+const H0: [u32; 8] = [0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19];
+
+/// SHA-256 (FIPS 180-4), fed a piece at a time. There's no crate for it among the dependencies, and a batch
+/// checksum is all it's for.
+#[derive(Clone, Debug)]
+pub struct Sha256 {
+    state: [u32; 8],
+    block: [u8; 64],
+    block_len: usize,
+    total_len: u64,
+}
+
+impl Sha256 {
+    pub fn new() -> Sha256 {
+        Sha256 { state: H0, block: [0; 64], block_len: 0, total_len: 0 }
+    }
+
+    pub fn update(&mut self, mut bytes: &[u8]) {
+        self.total_len += bytes.len() as u64;
+
+        while !bytes.is_empty() {
+            let n = (64 - self.block_len).min(bytes.len());
+
+            self.block[self.block_len..self.block_len + n].copy_from_slice(&bytes[..n]);
+            self.block_len += n;
+            bytes = &bytes[n..];
+
+            if self.block_len == 64 {
+                self.compress();
+                self.block_len = 0;
+            }
+        }
+    }
+
+    /// The digest of everything fed in, as 64 hex digits.
+    pub fn finish(mut self) -> String {
+        let bits = self.total_len.wrapping_mul(8);
+
+        // a 1 bit, zeros up to 8 bytes short of a block, then the length in bits
+        self.update(&[0x80]);
+
+        while self.block_len != 56 {
+            self.update(&[0]);
+        }
+
+        self.update(&bits.to_be_bytes());
+        self.state.iter().map(|word| format!("{:08x}", word)).collect()
+    }
+
+    fn compress(&mut self) {
+        let mut w = [0u32; 64];
+
+        for (i, word) in self.block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h.wrapping_add(s1).wrapping_add(ch).wrapping_add(K[i]).wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+
+        for (word, add) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *word = word.wrapping_add(add);
+        }
+    }
+}
+
+impl Default for Sha256 {
+    fn default() -> Self {
+        Sha256::new()
+    }
+}
+
+/// With `batch_checksum`, follows the bytes written for each record, and after every `every`, makes the line
+/// `{"type":"_batch","count":<records>,"sha256":"<of exactly those bytes>"}` to write after them.
+pub struct BatchChecksum {
+    every: u64,
+    count: u64,
+    sha256: Sha256,
+}
+
+impl BatchChecksum {
+    pub fn new(every: u64) -> BatchChecksum {
+        BatchChecksum { every: every.max(1), count: 0, sha256: Sha256::new() }
+    }
+
+    /// Adds a record as written; the batch's line, once it's full.
+    pub fn add(&mut self, written: &str) -> Option<String> {
+        self.sha256.update(written.as_bytes());
+        self.count += 1;
+
+        match self.count >= self.every {
+            true => self.take(),
+            false => None,
+        }
+    }
+
+    /// The line for the records added since the last one, if there are any, e.g. once the last has been written.
+    pub fn take(&mut self) -> Option<String> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let line = object! {
+            "type": BATCH_TYPE,
+            "count": self.count,
+            "sha256": std::mem::take(&mut self.sha256).finish(),
+        };
+
+        self.count = 0;
+        Some(format!("{}\n", line.dump()))
+    }
+}
@@ -0,0 +1,76 @@
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// The state of a `CircuitBreaker`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Records go to the sink
+    Closed,
+    /// The sink is failing: records go to the fallback until the cooldown is over
+    Open,
+    /// The cooldown is over: the next record probes the sink
+    HalfOpen,
+}
+
+impl Display for CircuitState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CircuitState::Closed => write!(f, "closed"),
+            CircuitState::Open => write!(f, "open"),
+            CircuitState::HalfOpen => write!(f, "half_open"),
+        }
+    }
+}
+
+/// Stops retrying a sink that keeps failing: after `threshold` consecutive failures the circuit opens
+/// for `cooldown`, then a single record probes the sink, closing the circuit if it gets through.
+/// A `threshold` of 0 disables it.
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    failures: u32,
+    opened_at: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> CircuitBreaker {
+        CircuitBreaker {
+            threshold,
+            cooldown,
+            failures: 0,
+            opened_at: None,
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        match self.opened_at {
+            None => CircuitState::Closed,
+            Some(opened_at) if opened_at.elapsed() < self.cooldown => CircuitState::Open,
+            Some(_) => CircuitState::HalfOpen,
+        }
+    }
+
+    /// True if the next record should be sent to the sink, rather than the fallback.
+    pub fn allow(&self) -> bool {
+        self.state() != CircuitState::Open
+    }
+
+    pub fn success(&mut self) {
+        self.failures = 0;
+        self.opened_at = None;
+    }
+
+    /// Records a failure, returning true if it opened the circuit. A failed probe re-opens it right away.
+    pub fn failure(&mut self) -> bool {
+        self.failures += 1;
+
+        let open = self.threshold > 0 && (self.failures >= self.threshold || self.opened_at.is_some());
+
+        if open {
+            self.opened_at = Some(Instant::now());
+        }
+
+        open
+    }
+}
@@ -0,0 +1,60 @@
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Where the extension gets the time it stamps on records, and measures its uptime by.
+pub trait Clock: Debug + Send + Sync {
+    /// The wall-clock time, for timestamps
+    fn now(&self) -> SystemTime;
+
+    /// A monotonic time, for durations
+    fn instant(&self) -> Instant;
+
+    /// Milliseconds since the epoch
+    fn now_ms(&self) -> u64 {
+        self.now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+    }
+}
+
+/// The system's clock.
+#[derive(Debug)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when told to, for tests.
+#[derive(Debug)]
+pub struct MockClock {
+    start_ms: u64,
+    start: Instant,
+    elapsed_ms: AtomicU64,
+}
+
+impl MockClock {
+    /// A clock reading `now_ms` milliseconds since the epoch.
+    pub fn new(now_ms: u64) -> MockClock {
+        MockClock { start_ms: now_ms, start: Instant::now(), elapsed_ms: AtomicU64::new(0) }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.elapsed_ms.fetch_add(by.as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.start_ms + self.elapsed_ms.load(Ordering::Relaxed))
+    }
+
+    fn instant(&self) -> Instant {
+        self.start + Duration::from_millis(self.elapsed_ms.load(Ordering::Relaxed))
+    }
+}
@@ -0,0 +1,1091 @@
+use std::collections::HashMap;
+use std::env;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use json::{JsonValue, object};
+use lambda_extension::{Error, LogBuffering};
+use tracing::Level;
+
+use crate::bigint::BigInt;
+use crate::dup_keys::DupKeys;
+use crate::encoder::Compression;
+use crate::invocation::BatchBy;
+use crate::layout::{self, Layout, TimeFields};
+use crate::monotonic::MonotonicTime;
+use crate::otel::Format;
+use crate::partition::PartitionKey;
+use crate::precision::TimePrecision;
+use crate::record_time::RecordTime;
+use crate::rename::FieldRenames;
+use crate::schema::{Schema, SchemaOnFail};
+use crate::sequence::SeqScope;
+use crate::severity::{self, SeverityMap, SEVERITIES};
+use crate::framing::Framing;
+use crate::hash::ContentHash;
+use crate::shutdown::ShutdownDump;
+use crate::syslog::SyslogTransport;
+use crate::transform::{EnrichPrecedence, Enricher, KeepFields, NewlineReplacement, TtlMap};
+use crate::utf8::NonUtf8;
+
+pub const ADDRESS_ENV_NAME: &str = "LOG_STORE_ADDRESS";
+pub const ADDRESS_FILE_ENV_NAME: &str = "LOG_STORE_ADDRESS_FILE";
+pub const SUBSCRIBE_RETRIES_ENV_NAME: &str = "LOG_STORE_SUBSCRIBE_RETRIES";
+pub const FILE_MAX_BYTES_ENV_NAME: &str = "LOG_STORE_FILE_MAX_BYTES";
+pub const FILE_KEEP_ENV_NAME: &str = "LOG_STORE_FILE_KEEP";
+pub const BATCH_CHECKSUM_ENV_NAME: &str = "LOG_STORE_BATCH_CHECKSUM";
+pub const BATCH_CHECKSUM_RECORDS_ENV_NAME: &str = "LOG_STORE_BATCH_CHECKSUM_RECORDS";
+pub const SHIP_CONFIG_WARNINGS_ENV_NAME: &str = "LOG_STORE_SHIP_CONFIG_WARNINGS";
+pub const SHIP_INIT_ERRORS_ENV_NAME: &str = "LOG_STORE_SHIP_INIT_ERRORS";
+pub const BUFFER_TIMEOUT_MS_ENV_NAME: &str = "LOG_STORE_BUFFER_TIMEOUT_MS";
+pub const BUFFER_MAX_BYTES_ENV_NAME: &str = "LOG_STORE_BUFFER_MAX_BYTES";
+pub const BUFFER_MAX_ITEMS_ENV_NAME: &str = "LOG_STORE_BUFFER_MAX_ITEMS";
+pub const SEQ_SCOPE_ENV_NAME: &str = "LOG_STORE_SEQ_SCOPE";
+pub const MONOTONIC_TIME_ENV_NAME: &str = "LOG_STORE_MONOTONIC_TIME";
+pub const ACK_CRITICAL_ENV_NAME: &str = "LOG_STORE_ACK_CRITICAL";
+pub const CRITICAL_MATCH_ENV_NAME: &str = "LOG_STORE_CRITICAL_MATCH";
+pub const FLUSH_TYPES_ENV_NAME: &str = "LOG_STORE_FLUSH_TYPES";
+pub const ACK_TIMEOUT_MS_ENV_NAME: &str = "LOG_STORE_ACK_TIMEOUT_MS";
+pub const ACK_RETRIES_ENV_NAME: &str = "LOG_STORE_ACK_RETRIES";
+pub const CONTROL_CHANNEL_ENV_NAME: &str = "LOG_STORE_CONTROL_CHANNEL";
+pub const RECORD_COMPRESS_MIN_BYTES_ENV_NAME: &str = "LOG_STORE_RECORD_COMPRESS_MIN_BYTES";
+pub const RECORD_COMPRESSION_ENV_NAME: &str = "LOG_STORE_RECORD_COMPRESSION";
+pub const SOURCE_ENV_NAME: &str = "LOG_STORE_SOURCE";
+pub const OVERFLOW_POLICY_ENV_NAME: &str = "LOG_STORE_OVERFLOW_POLICY";
+pub const PRESERIALIZE_ENV_NAME: &str = "LOG_STORE_PRESERIALIZE";
+pub const SPLICE_ENV_NAME: &str = "LOG_STORE_SPLICE";
+pub const ENQUEUE_DEADLINE_MS_ENV_NAME: &str = "LOG_STORE_ENQUEUE_DEADLINE_MS";
+pub const SLOW_SINK_MS_ENV_NAME: &str = "LOG_STORE_SLOW_SINK_MS";
+pub const MAX_INFLIGHT_BYTES_ENV_NAME: &str = "LOG_STORE_MAX_INFLIGHT_BYTES";
+pub const MAX_RECORD_AGE_MS_ENV_NAME: &str = "LOG_STORE_MAX_RECORD_AGE_MS";
+pub const MAX_RECORD_AGE_EXEMPT_PLATFORM_ENV_NAME: &str = "LOG_STORE_MAX_RECORD_AGE_EXEMPT_PLATFORM";
+pub const DEDUP_PLATFORM_ENV_NAME: &str = "LOG_STORE_DEDUP_PLATFORM";
+pub const FLUSH_MODE_ENV_NAME: &str = "LOG_STORE_FLUSH_MODE";
+pub const FLUSH_INTERVAL_MS_ENV_NAME: &str = "LOG_STORE_FLUSH_INTERVAL_MS";
+pub const COALESCE_WAIT_MS_ENV_NAME: &str = "LOG_STORE_COALESCE_WAIT_MS";
+pub const RECONNECT_RETRIES_ENV_NAME: &str = "LOG_STORE_RECONNECT_RETRIES";
+pub const INITIAL_CONNECT_RETRIES_ENV_NAME: &str = "LOG_STORE_INITIAL_CONNECT_RETRIES";
+pub const PRECONNECT_ENV_NAME: &str = "LOG_STORE_PRECONNECT";
+pub const PRECONNECT_TIMEOUT_MS_ENV_NAME: &str = "LOG_STORE_PRECONNECT_TIMEOUT_MS";
+pub const INIT_BUFFER_MAX_ENV_NAME: &str = "LOG_STORE_INIT_BUFFER_MAX";
+pub const WAIT_FOR_SINK_SECS_ENV_NAME: &str = "LOG_STORE_WAIT_FOR_SINK_SECS";
+pub const SEVERITY_MAP_ENV_NAME: &str = "LOG_STORE_SEVERITY_MAP";
+pub const PRETTY_ENV_NAME: &str = "LOG_STORE_PRETTY";
+pub const TIME_SOURCE_ENV_NAME: &str = "LOG_STORE_TIME_SOURCE";
+pub const PREFER_RECORD_TIME_ENV_NAME: &str = "LOG_STORE_PREFER_RECORD_TIME";
+pub const TIME_PRECISION_ENV_NAME: &str = "LOG_STORE_TIME_PRECISION";
+pub const LAYOUT_ENV_NAME: &str = "LOG_STORE_LAYOUT";
+pub const TIME_FIELD_ENV_NAME: &str = "LOG_STORE_TIME_FIELD";
+pub const TIME_FIELD_BY_TYPE_ENV_NAME: &str = "LOG_STORE_TIME_FIELD_BY_TYPE";
+pub const RENAME_ENV_NAME: &str = "LOG_STORE_RENAME";
+pub const DROP_EMPTY_ENV_NAME: &str = "LOG_STORE_DROP_EMPTY";
+pub const TAG_PHASE_ENV_NAME: &str = "LOG_STORE_TAG_PHASE";
+pub const NONUTF8_ENV_NAME: &str = "LOG_STORE_NONUTF8";
+pub const DNS_TTL_SECS_ENV_NAME: &str = "LOG_STORE_DNS_TTL_SECS";
+pub const PROXY_ENV_NAME: &str = "LOG_STORE_PROXY";
+pub const PROXY_AUTH_ENV_NAME: &str = "LOG_STORE_PROXY_AUTH";
+pub const BATCH_DEADLINE_MS_ENV_NAME: &str = "LOG_STORE_BATCH_DEADLINE_MS";
+pub const FORMAT_ENV_NAME: &str = "LOG_STORE_FORMAT";
+pub const FRAMING_ENV_NAME: &str = "LOG_STORE_FRAMING";
+pub const CB_FAILURE_THRESHOLD_ENV_NAME: &str = "LOG_STORE_CB_FAILURE_THRESHOLD";
+pub const CB_COOLDOWN_MS_ENV_NAME: &str = "LOG_STORE_CB_COOLDOWN_MS";
+pub const INCLUDE_SESSION_ENV_NAME: &str = "LOG_STORE_INCLUDE_SESSION";
+pub const INCLUDE_SEQ_ENV_NAME: &str = "LOG_STORE_INCLUDE_SEQ";
+pub const SORT_KEYS_ENV_NAME: &str = "LOG_STORE_SORT_KEYS";
+pub const SHUTDOWN_DUMP_ENV_NAME: &str = "LOG_STORE_SHUTDOWN_DUMP";
+pub const BATCH_BY_ENV_NAME: &str = "LOG_STORE_BATCH_BY";
+pub const BATCH_BY_TIMEOUT_MS_ENV_NAME: &str = "LOG_STORE_BATCH_BY_TIMEOUT_MS";
+pub const BATCH_BY_MAX_BYTES_ENV_NAME: &str = "LOG_STORE_BATCH_BY_MAX_BYTES";
+pub const INCLUDE_UPTIME_ENV_NAME: &str = "LOG_STORE_INCLUDE_UPTIME";
+pub const REPORT_DERIVED_ENV_NAME: &str = "LOG_STORE_REPORT_DERIVED";
+pub const INCLUDE_MEM_LIMIT_ENV_NAME: &str = "LOG_STORE_INCLUDE_MEM_LIMIT";
+pub const INCLUDE_IDS_ENV_NAME: &str = "LOG_STORE_INCLUDE_IDS";
+pub const INCLUDE_ID_ENV_NAME: &str = "LOG_STORE_INCLUDE_ID";
+pub const INCLUDE_LATENCY_ENV_NAME: &str = "LOG_STORE_INCLUDE_LATENCY";
+pub const MAX_RECORDS_PER_INVOCATION_ENV_NAME: &str = "LOG_STORE_MAX_RECORDS_PER_INVOCATION";
+pub const IDLE_DISCONNECT_SECS_ENV_NAME: &str = "LOG_STORE_IDLE_DISCONNECT_SECS";
+pub const HEALTH_INTERVAL_SECS_ENV_NAME: &str = "LOG_STORE_HEALTH_INTERVAL_SECS";
+pub const STATS_LOG_SECS_ENV_NAME: &str = "LOG_STORE_STATS_LOG_SECS";
+pub const PROBE_ON_THAW_ENV_NAME: &str = "LOG_STORE_PROBE_ON_THAW";
+pub const MARK_PARSE_FAILURE_ENV_NAME: &str = "LOG_STORE_MARK_PARSE_FAILURE";
+pub const PARSE_FAULT_JSON_ENV_NAME: &str = "LOG_STORE_PARSE_FAULT_JSON";
+pub const SHIP_PLATFORM_EXTENSION_ENV_NAME: &str = "LOG_STORE_SHIP_PLATFORM_EXTENSION";
+pub const KEEP_FIELDS_ENV_NAME: &str = "LOG_STORE_KEEP_FIELDS";
+pub const TTL_MAP_ENV_NAME: &str = "LOG_STORE_TTL_MAP";
+pub const ENRICH_FILE_ENV_NAME: &str = "LOG_STORE_ENRICH_FILE";
+pub const ENRICH_PRECEDENCE_ENV_NAME: &str = "LOG_STORE_ENRICH_PRECEDENCE";
+pub const SCHEMA_FILE_ENV_NAME: &str = "LOG_STORE_SCHEMA_FILE";
+pub const SCHEMA_ON_FAIL_ENV_NAME: &str = "LOG_STORE_SCHEMA_ON_FAIL";
+pub const SPILL_DIR_ENV_NAME: &str = "LOG_STORE_SPILL_DIR";
+pub const SPILL_MAX_BYTES_ENV_NAME: &str = "LOG_STORE_SPILL_MAX_BYTES";
+pub const WARN_RECORD_BYTES_ENV_NAME: &str = "LOG_STORE_WARN_RECORD_BYTES";
+pub const INCLUDE_HASH_ENV_NAME: &str = "LOG_STORE_INCLUDE_HASH";
+pub const HASH_EXCLUDE_ENV_NAME: &str = "LOG_STORE_HASH_EXCLUDE";
+pub const INCLUDE_TRACE_ID_ENV_NAME: &str = "LOG_STORE_INCLUDE_TRACE_ID";
+pub const PARTITION_KEY_ENV_NAME: &str = "LOG_STORE_PARTITION_KEY";
+pub const NORMALIZE_NEWLINES_ENV_NAME: &str = "LOG_STORE_NORMALIZE_NEWLINES";
+pub const STRIP_ANSI_ENV_NAME: &str = "LOG_STORE_STRIP_ANSI";
+pub const NEWLINE_REPLACEMENT_ENV_NAME: &str = "LOG_STORE_NEWLINE_REPLACEMENT";
+pub const MEMORY_BUDGET_BYTES_ENV_NAME: &str = "LOG_STORE_MEMORY_BUDGET_BYTES";
+pub const MIRROR_ADDRESS_ENV_NAME: &str = "LOG_STORE_MIRROR_ADDRESS";
+pub const REASSEMBLE_MIN_BYTES_ENV_NAME: &str = "LOG_STORE_REASSEMBLE_MIN_BYTES";
+pub const DUP_KEYS_ENV_NAME: &str = "LOG_STORE_DUP_KEYS";
+pub const BARE_PRIMITIVES_ENV_NAME: &str = "LOG_STORE_BARE_PRIMITIVES";
+pub const BIGINT_ENV_NAME: &str = "LOG_STORE_BIGINT";
+pub const MAX_FLATTEN_DEPTH_ENV_NAME: &str = "LOG_STORE_MAX_FLATTEN_DEPTH";
+pub const MESSAGE_KEY_ENV_NAME: &str = "LOG_STORE_MESSAGE_KEY";
+pub const EMIT_BOTH_ENV_NAME: &str = "LOG_STORE_EMIT_BOTH";
+pub const EMIT_BOTH_MAX_BYTES_ENV_NAME: &str = "LOG_STORE_EMIT_BOTH_MAX_BYTES";
+pub const WRITER_RESTARTS_ENV_NAME: &str = "LOG_STORE_WRITER_RESTARTS";
+pub const STDOUT_MAX_LINE_BYTES_ENV_NAME: &str = "LOG_STORE_STDOUT_MAX_LINE_BYTES";
+pub const LOG_LEVEL_ENV_NAME: &str = "LOG_STORE_LOG_LEVEL";
+pub const LOG_TARGET_ENV_NAME: &str = "LOG_STORE_LOG_TARGET";
+pub const LOG_TIME_ENV_NAME: &str = "LOG_STORE_LOG_TIME";
+// set by Lambda; the `fn` label of Loki streams
+const FUNCTION_NAME_ENV_NAME: &str = "AWS_LAMBDA_FUNCTION_NAME";
+// set by Lambda; `mem_limit_mb`, with LOG_STORE_INCLUDE_MEM_LIMIT
+const FUNCTION_MEMORY_SIZE_ENV_NAME: &str = "AWS_LAMBDA_FUNCTION_MEMORY_SIZE";
+// honored for the level when LOG_STORE_LOG_LEVEL isn't set, if it's just a level
+const RUST_LOG_ENV_NAME: &str = "RUST_LOG";
+
+const FILE_ADDRESS_PREFIX: &str = "file:";
+const STDOUT_ADDRESS: &str = "stdout";
+const CLOUDWATCH_ADDRESS_PREFIX: &str = "cloudwatch://";
+const SYSLOG_TCP_ADDRESS_PREFIX: &str = "syslog+tcp://";
+const SYSLOG_UDP_ADDRESS_PREFIX: &str = "syslog+udp://";
+
+const DEFAULT_SUBSCRIBE_RETRIES: u32 = 3;
+const DEFAULT_FILE_MAX_BYTES: u64 = 10 * 1024 * 1024;
+const DEFAULT_FILE_KEEP: usize = 3;
+const DEFAULT_BATCH_CHECKSUM_RECORDS: u64 = 1000;
+const DEFAULT_ACK_TIMEOUT_MS: u64 = 1_000;
+const DEFAULT_ACK_RETRIES: u32 = 3;
+const DEFAULT_FLUSH_INTERVAL_MS: u64 = 200;
+// coalescing is for a few milliseconds; anything longer is what `buffered` is for
+const MAX_COALESCE_WAIT_MS: u64 = 50;
+const DEFAULT_RECONNECT_RETRIES: u32 = 5;
+const DEFAULT_WRITER_RESTARTS: u32 = 3;
+// the most CloudWatch takes as one log event
+const DEFAULT_STDOUT_MAX_LINE_BYTES: usize = 256 * 1024;
+const DEFAULT_INITIAL_CONNECT_RETRIES: u32 = 5;
+const DEFAULT_PRECONNECT_TIMEOUT_MS: u64 = 1_000;
+const DEFAULT_DNS_TTL_SECS: u64 = 60;
+const DEFAULT_CB_COOLDOWN_MS: u64 = 30_000;
+const DEFAULT_BATCH_BY_TIMEOUT_MS: u64 = 60_000;
+const DEFAULT_BATCH_BY_MAX_BYTES: u64 = 1024 * 1024;
+const DEFAULT_SPILL_DIR: &str = "/tmp/log-store-spill";
+// a shutdown's spill has to be written within SHUTDOWN_DUMP_MARGIN of the deadline
+const DEFAULT_SPILL_MAX_BYTES: u64 = 4 * 1024 * 1024;
+const DEFAULT_MESSAGE_KEY: &str = "record";
+const DEFAULT_TIME_FIELD: &str = "t";
+const DEFAULT_EMIT_BOTH_MAX_BYTES: u64 = 8 * 1024;
+
+/// Where records are shipped to, parsed from `LOG_STORE_ADDRESS`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SinkAddress {
+    /// `host:port` of a log-store instance
+    Tcp(String),
+    /// `file:<path>`
+    File(String),
+    /// `syslog+tcp://host:port` or `syslog+udp://host:port` of a syslog server
+    Syslog(SyslogTransport, String),
+    /// `stdout`, for local development
+    Stdout,
+}
+
+impl SinkAddress {
+    pub fn parse(address: &str) -> SinkAddress {
+        if address.eq_ignore_ascii_case(STDOUT_ADDRESS) {
+            return SinkAddress::Stdout;
+        }
+
+        if let Some(path) = address.strip_prefix(FILE_ADDRESS_PREFIX) {
+            return SinkAddress::File(path.to_string());
+        }
+
+        match (address.strip_prefix(SYSLOG_TCP_ADDRESS_PREFIX), address.strip_prefix(SYSLOG_UDP_ADDRESS_PREFIX)) {
+            (Some(address), _) => SinkAddress::Syslog(SyslogTransport::Tcp, address.to_string()),
+            (_, Some(address)) => SinkAddress::Syslog(SyslogTransport::Udp, address.to_string()),
+            (None, None) => SinkAddress::Tcp(address.to_string()),
+        }
+    }
+}
+
+/// The fields to merge into every record, from a `LOG_STORE_ENRICH_FILE` holding a JSON object.
+fn read_enrich_file(path: &str, precedence: EnrichPrecedence) -> Result<Enricher, Error> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read {} {:?}: {}", ENRICH_FILE_ENV_NAME, path, e))?;
+    let object = json::parse(contents.as_str())
+        .map_err(|e| format!("Unable to parse {} {:?}: {}", ENRICH_FILE_ENV_NAME, path, e))?;
+
+    Ok(Enricher::new(object, precedence).map_err(|e| format!("Unable to use {} {:?}: {}", ENRICH_FILE_ENV_NAME, path, e))?)
+}
+
+/// The schema records are checked against, from a `LOG_STORE_SCHEMA_FILE` holding a JSON Schema.
+fn read_schema_file(path: &str) -> Result<Schema, Error> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read {} {:?}: {}", SCHEMA_FILE_ENV_NAME, path, e))?;
+    let schema = json::parse(contents.as_str())
+        .map_err(|e| format!("Unable to parse {} {:?}: {}", SCHEMA_FILE_ENV_NAME, path, e))?;
+
+    Ok(Schema::new(schema).map_err(|e| format!("Unable to use {} {:?}: {}", SCHEMA_FILE_ENV_NAME, path, e))?)
+}
+
+/// The address in a `LOG_STORE_ADDRESS_FILE`: its contents, trimmed.
+fn read_address_file(path: &str) -> Result<String, Error> {
+    let address = std::fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read {} {:?}: {}", ADDRESS_FILE_ENV_NAME, path, e))?;
+
+    match address.trim() {
+        "" => Err(format!("{} {:?} is empty", ADDRESS_FILE_ENV_NAME, path).into()),
+        address => Ok(address.to_string()),
+    }
+}
+
+/// Which Lambda API records are received from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    Logs,
+    Telemetry,
+}
+
+impl FromStr for Source {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "logs" => Ok(Source::Logs),
+            "telemetry" => Ok(Source::Telemetry),
+            _ => Err(format!("unknown source {:?}, expected logs or telemetry", s)),
+        }
+    }
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::Logs => write!(f, "logs"),
+            Source::Telemetry => write!(f, "telemetry"),
+        }
+    }
+}
+
+/// What to do with records when the channel to the writer is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Wait for room, applying backpressure to the Logs API
+    Block,
+    /// Drop records that can't be enqueued before the deadline
+    Drop,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "block" => Ok(OverflowPolicy::Block),
+            "drop" => Ok(OverflowPolicy::Drop),
+            _ => Err(format!("unknown overflow policy {:?}, expected block or drop", s)),
+        }
+    }
+}
+
+impl Display for OverflowPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OverflowPolicy::Block => write!(f, "block"),
+            OverflowPolicy::Drop => write!(f, "drop"),
+        }
+    }
+}
+
+/// When the TCP writer explicitly flushes its buffer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlushMode {
+    /// After every record, for the lowest latency
+    Eager,
+    /// When the buffer fills, or `flush_interval_ms` after the first unflushed record
+    Buffered,
+}
+
+impl FromStr for FlushMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "eager" => Ok(FlushMode::Eager),
+            "buffered" => Ok(FlushMode::Buffered),
+            _ => Err(format!("unknown flush mode {:?}, expected eager or buffered", s)),
+        }
+    }
+}
+
+impl Display for FlushMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FlushMode::Eager => write!(f, "eager"),
+            FlushMode::Buffered => write!(f, "buffered"),
+        }
+    }
+}
+
+/// Where a record's `t` comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeSource {
+    /// The time Lambda gave the record
+    Record,
+    /// The time the extension processed it
+    Ingest,
+    /// The record's time in `t`, and the ingest time in `it`
+    Both,
+}
+
+impl FromStr for TimeSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "record" => Ok(TimeSource::Record),
+            "ingest" => Ok(TimeSource::Ingest),
+            "both" => Ok(TimeSource::Both),
+            _ => Err(format!("unknown time source {:?}, expected record, ingest, or both", s)),
+        }
+    }
+}
+
+impl Display for TimeSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TimeSource::Record => write!(f, "record"),
+            TimeSource::Ingest => write!(f, "ingest"),
+            TimeSource::Both => write!(f, "both"),
+        }
+    }
+}
+
+/// How a function's log line that's a bare JSON primitive (`42`, `true`, `"hi"`) goes under `record`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BarePrimitives {
+    /// As the JSON value it is: `42` is the number 42
+    #[default]
+    Native,
+    /// As the line was logged, like any other plain text line: `42` is the string "42"
+    Text,
+}
+
+impl FromStr for BarePrimitives {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "native" => Ok(BarePrimitives::Native),
+            "text" => Ok(BarePrimitives::Text),
+            _ => Err(format!("unknown bare primitive handling {:?}, expected native or text", s)),
+        }
+    }
+}
+
+impl Display for BarePrimitives {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BarePrimitives::Native => write!(f, "native"),
+            BarePrimitives::Text => write!(f, "text"),
+        }
+    }
+}
+
+/// A `key=value` pair matched against a top-level field of a record.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FieldMatch {
+    pub key: String,
+    pub value: String,
+}
+
+impl FieldMatch {
+    /// Compares the field's value in its string form, so `audit=true` matches both `true` and `"true"`.
+    pub fn matches(&self, json: &JsonValue) -> bool {
+        let field = layout::field(json, self.key.as_str());
+
+        match field.as_str() {
+            Some(s) => s == self.value,
+            None => !field.is_null() && field.dump() == self.value,
+        }
+    }
+}
+
+impl FromStr for FieldMatch {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => Ok(FieldMatch {
+                key: key.trim().to_string(),
+                value: value.trim().to_string(),
+            }),
+            _ => Err(format!("expected key=value, got {:?}", s)),
+        }
+    }
+}
+
+impl Display for FieldMatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.key, self.value)
+    }
+}
+
+/// The records that are written (and flushed) as soon as they arrive, rather than held in a batch: those of
+/// the given types, and with `level:<severity>`, those of that severity or above. Parsed from a comma-separated
+/// list, e.g. `platform_report,platform_fault,level:error`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FlushTypes {
+    types: Vec<String>,
+    // an index into `SEVERITIES`
+    min_severity: Option<usize>,
+}
+
+impl FlushTypes {
+    pub fn matches(&self, json: &JsonValue) -> bool {
+        let record_type = layout::field(json, "type").as_str().unwrap_or_default();
+        let severity = layout::field(json, "severity").as_str()
+            .and_then(|severity| SEVERITIES.iter().position(|s| *s == severity));
+
+        self.types.iter().any(|t| t == record_type)
+            || matches!((self.min_severity, severity), (Some(min), Some(severity)) if severity >= min)
+    }
+}
+
+impl FromStr for FlushTypes {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut flush_types = FlushTypes::default();
+
+        for entry in s.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            match entry.strip_prefix("level:") {
+                Some(level) => {
+                    let severity = severity::normalize(level)
+                        .and_then(|severity| SEVERITIES.iter().position(|s| *s == severity))
+                        .ok_or_else(|| format!("unknown level {:?}", level))?;
+
+                    flush_types.min_severity = Some(flush_types.min_severity.map_or(severity, |min| min.min(severity)));
+                }
+                None => flush_types.types.push(entry.to_string()),
+            }
+        }
+
+        Ok(flush_types)
+    }
+}
+
+impl Display for FlushTypes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let level = self.min_severity.map(|i| format!("level:{}", SEVERITIES[i]));
+
+        write!(f, "{}", self.types.iter().cloned().chain(level).collect::<Vec<_>>().join(","))
+    }
+}
+
+/// A value kept out of the logged config, such as a password.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+impl FromStr for Secret {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Secret(s.to_string()))
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Secret(***)")
+    }
+}
+
+/// Where records are shipped to, kept out of the logged config when it came from a `LOG_STORE_ADDRESS_FILE`, as
+/// that's to keep the address, and any token in it, out of sight.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Address {
+    sink: SinkAddress,
+    from_file: bool,
+}
+
+impl Address {
+    pub fn sink(&self) -> &SinkAddress {
+        &self.sink
+    }
+}
+
+impl PartialEq<SinkAddress> for Address {
+    fn eq(&self, other: &SinkAddress) -> bool {
+        self.sink == *other
+    }
+}
+
+impl std::fmt::Debug for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.from_file {
+            true => write!(f, "<from file>"),
+            false => write!(f, "{:?}", self.sink),
+        }
+    }
+}
+
+/// A snapshot of all the configuration, read from the environment exactly once at startup.
+/// Lambda can't change an execution environment's variables, so nothing reads them after this.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub address: Address,
+    /// A second sink every record is also written to, independently of the first
+    pub mirror_address: Option<SinkAddress>,
+    pub source: Source,
+    pub subscribe_retries: u32,
+    pub ship_config_warnings: bool,
+    /// Send an `extension_error` record for each recoverable error during init, and the config warnings
+    pub ship_init_errors: bool,
+    pub seq_scope: Option<SeqScope>,
+    /// Make `t` non-decreasing, by clamping it or adding `mt`, if set
+    pub monotonic_time: Option<MonotonicTime>,
+    pub time_source: TimeSource,
+    /// The field of a function's JSON log whose time, if it can be read, is used in place of Lambda's
+    pub prefer_record_time: Option<RecordTime>,
+    /// How record times are shipped, with the json and logfmt formats
+    pub time_precision: TimePrecision,
+    /// Stamp `up_ms`, the milliseconds since the extension started, on every record
+    pub include_uptime: bool,
+    /// Add `billed_overhead_ms`, `memory_utilization`, and `right_sized` to `platform_report` records
+    pub report_derived: bool,
+    /// Stamp `mem_limit_mb`, the function's configured memory, on every record
+    pub include_mem_limit: bool,
+    /// Stamp `pid`, the extension's process id, and `tid`, the thread that handled it, on every record
+    pub include_ids: bool,
+    /// Stamp `id`, a UUIDv7 made from the record's time, on every record
+    pub include_id: bool,
+    /// Stamp `ship_lag_ms`, the milliseconds from a record's `t` to the TCP writer writing it, on every record
+    pub include_latency: bool,
+    /// Stamp the `phase` (init, invoke, or shutdown) on function and extension records
+    pub tag_phase: bool,
+    pub layout: Layout,
+    /// The field a record's time is shipped under, with the json and logfmt formats
+    pub time_field: String,
+    /// The field the time is shipped under for particular record types, in place of `time_field`
+    pub time_field_by_type: Option<TimeFields>,
+    /// Fields renamed as records are shipped, with the json and logfmt formats
+    pub rename: Option<FieldRenames>,
+    /// Our own record shape, OTel's, or Loki's; `layout` only applies to the first
+    pub format: Format,
+    /// How records are delimited on the connection to the log-store
+    pub framing: Framing,
+    /// The function's name, as Lambda gives it
+    pub function_name: Option<String>,
+    /// The function's configured memory in MB, as Lambda gives it
+    pub function_memory_mb: Option<u64>,
+    /// Drop function records with no content
+    pub drop_empty: bool,
+    /// What to do with function records that had invalid UTF-8
+    pub nonutf8: NonUtf8,
+    /// The only fields of function and extension records shipped, if set
+    pub keep_fields: Option<KeepFields>,
+    /// Days the log-store should keep records for, by type and severity, if set
+    pub ttl_map: Option<TtlMap>,
+    /// Fields merged into every record, from a JSON file read at startup, if set
+    pub enrich: Option<Enricher>,
+    /// The JSON Schema every record is checked against before it's shipped, from a file read at startup, if set
+    pub schema: Option<Schema>,
+    /// What's done with a record that doesn't match it
+    pub schema_on_fail: SchemaOnFail,
+    /// Length of the function log lines that may be the first piece of one Lambda split, to join back together
+    pub reassemble_min_bytes: Option<usize>,
+    /// Add `"parse_failed": true` to function records that look like JSON but don't parse
+    pub mark_parse_failure: bool,
+    /// Flatten `platform_fault` records that are JSON objects, as function records are, rather than send them under `record`
+    pub parse_fault_json: bool,
+    /// Ship the records about extensions registering and subscribing, as `platform_extension`
+    pub ship_platform_extension: bool,
+    /// What to do with a key a function's JSON log line has more than once
+    pub dup_keys: DupKeys,
+    /// Whether a function's log line that's a bare JSON number, boolean, or string is shipped as that value or as text
+    pub bare_primitives: BarePrimitives,
+    /// Whether integers in a function's JSON log line too large for a double are shipped as numbers or strings
+    pub bigint: BigInt,
+    /// How deeply a function's JSON object may nest before what's below is kept as JSON text, if set
+    pub max_flatten_depth: Option<usize>,
+    /// The field a function or extension log line that isn't a JSON object is shipped under
+    pub message_key: String,
+    /// Also ship a function or extension record's JSON object, as it was logged, under `_raw`
+    pub emit_both: bool,
+    /// Objects larger than this (by the in-flight estimate) aren't shipped twice with `emit_both`
+    pub emit_both_max_bytes: u64,
+    /// Replace line breaks in every string of a record
+    pub normalize_newlines: bool,
+    /// Remove ANSI escape sequences (terminal colors) from every string of a record
+    pub strip_ansi: bool,
+    /// What they're replaced with
+    pub newline_replacement: NewlineReplacement,
+    /// Stamp the X-Ray trace id of the invocation on its records
+    pub include_trace_id: bool,
+    /// What the `pk` stamped on every record is taken from, if anything
+    pub partition_key: Option<PartitionKey>,
+    /// Add `"h"`, a hash of each record's content
+    pub include_hash: bool,
+    /// What's left out of that hash
+    pub content_hash: ContentHash,
+    /// Log a warning for each record larger than this, if set
+    pub warn_record_bytes: Option<u64>,
+    /// Function records shipped per invocation, past which the rest are dropped
+    pub max_records_per_invocation: Option<u64>,
+    pub file_max_bytes: u64,
+    pub file_keep: usize,
+    /// After every `batch_checksum_records` records (and the last ones, at shutdown or before the file rotates),
+    /// the `stdout` and `file:` sinks write a `_batch` line with the SHA-256 of exactly the bytes written for them
+    pub batch_checksum: bool,
+    pub batch_checksum_records: u64,
+    pub buffer_timeout_ms: usize,
+    pub buffer_max_bytes: usize,
+    pub buffer_max_items: usize,
+    /// Wait for the log-store to acknowledge records matching `critical_match`
+    pub ack_critical: bool,
+    pub critical_match: FieldMatch,
+    /// Records the TCP writer writes and flushes straight away, even when it batches
+    pub flush_types: FlushTypes,
+    pub ack_timeout_ms: u64,
+    pub ack_retries: u32,
+    /// Act on control messages the log-store sends back, e.g. asking the TCP writer to pause
+    pub control_channel: bool,
+    /// Records that serialize to at least this many bytes are compressed
+    pub record_compress_min_bytes: Option<usize>,
+    pub record_compression: Compression,
+    pub overflow_policy: OverflowPolicy,
+    /// Encode records for the TCP writer in the handler, and queue them as lines, where nothing the writer
+    /// does to a record needs it as JSON
+    pub preserialize: bool,
+    /// With `preserialize`, queue a function's JSON object as it was logged, with our fields spliced in ahead of
+    /// its own, where nothing else needs it parsed
+    pub splice: bool,
+    /// With the `drop` policy, how long a batch may wait for room in the channel before the rest of it is dropped
+    pub enqueue_deadline_ms: u64,
+    /// A write to the log-store still blocked after this long is reported, and counts as the sink being slow
+    pub slow_sink_ms: Option<u64>,
+    /// Cap on the estimated bytes of records between the handlers and the log-store
+    pub max_inflight_bytes: Option<u64>,
+    /// Records older than this when the TCP writer gets to them are dropped, rather than sent
+    pub max_record_age_ms: Option<u64>,
+    /// Send platform records however old they are
+    pub max_record_age_exempt_platform: bool,
+    /// Write each platform record (by `type` and `request_id`) only once, however many times it comes round
+    pub dedup_platform: bool,
+    /// Estimated bytes buffered at which the TCP writer flushes early, and then drops the oldest records
+    pub memory_budget_bytes: Option<u64>,
+    /// How many times a writer that panics is replaced, before records go to stdout instead
+    pub writer_restarts: u32,
+    /// JSON lines written to stdout longer than this are truncated, with a marker; 0 for no limit
+    pub stdout_max_line_bytes: usize,
+    pub flush_mode: FlushMode,
+    pub flush_interval_ms: u64,
+    /// With `eager`, how long the TCP writer waits after a record for more, to write them together
+    pub coalesce_wait_ms: u64,
+    /// With `buffered`, the longest a single flush may take; what's left waits for the next one
+    pub batch_deadline_ms: Option<u64>,
+    /// With `invocation`, the TCP writer holds an invocation's records until its `platform_report`, and writes them as one
+    pub batch_by: BatchBy,
+    /// Bounds on what's held for one invocation; past either, what's held is written as it is
+    pub batch_by_timeout_ms: u64,
+    pub batch_by_max_bytes: u64,
+    /// Times the TCP writer tries to reconnect, with backoff, after losing its connection
+    pub reconnect_retries: u32,
+    /// Seconds without a record after which the TCP writer closes its connection, reconnecting on the next one; 0 never does
+    pub idle_disconnect_secs: u64,
+    /// Check the connection to the log-store is still there when an invocation comes after a long freeze
+    pub probe_on_thaw: bool,
+    /// Seconds between the TCP writer's `health` records; 0 sends none
+    pub health_interval_secs: u64,
+    /// Seconds between the extension's own logs of its counters; 0 logs none
+    pub stats_log_secs: u64,
+    /// Times the TCP writer retries its first connection before falling back to stdout
+    pub initial_connect_retries: u32,
+    /// Connect to the log-store before registering with Lambda, rather than once the writer starts
+    pub preconnect: bool,
+    /// How long registering waits for that connection
+    pub preconnect_timeout_ms: u64,
+    /// With this set, registering doesn't wait for the first connection, and up to this many records that arrive
+    /// before it's made are held by the TCP writer and written once it is
+    pub init_buffer_max: Option<usize>,
+    /// How long the TCP writer keeps trying a log-store on a loopback address once its first retries fail; 0 doesn't
+    pub wait_for_sink_secs: u64,
+    /// How long the log-store's resolved addresses are reused for before being looked up again
+    pub dns_ttl_secs: u64,
+    /// HTTP proxy the TCP writer tunnels through with CONNECT
+    pub proxy: Option<String>,
+    /// `user:password` for the proxy
+    pub proxy_auth: Option<Secret>,
+    /// Stamp the id of the connection's session on every record sent to the log-store
+    pub include_session: bool,
+    /// Number the records sent on each connection to the log-store, 0 up, so it can spot any that went missing
+    pub include_seq: bool,
+    /// Consecutive failures after which the TCP writer's circuit opens; 0 disables the circuit breaker
+    pub cb_failure_threshold: u32,
+    pub cb_cooldown_ms: u64,
+    /// What to do with records still queued when the shutdown deadline is about to pass
+    pub shutdown_dump: ShutdownDump,
+    /// Where records are spilled with `shutdown_dump=spill`, and replayed from on startup
+    pub spill_dir: String,
+    /// The most bytes of records a shutdown spills; the rest are printed to stdout
+    pub spill_max_bytes: u64,
+    /// Severity stamped on non-function records, by type
+    pub severity_map: SeverityMap,
+    /// Indent records written to the stdout and file sinks; ignored for the log-store
+    pub pretty: bool,
+    /// Write every record's keys in sorted order, so the same record always serializes the same way
+    pub sort_keys: bool,
+    /// The extension's own diagnostics, not the records it ships: the most verbose level printed,
+    /// and whether lines include the module and time (CloudWatch adds the ingestion time anyway)
+    pub log_level: Level,
+    pub log_target: bool,
+    pub log_time: bool,
+    /// Values that were clamped or ignored while building the config
+    pub warnings: Vec<ConfigWarning>,
+}
+
+impl Config {
+    pub fn from_env() -> Result<Config, Error> {
+        Config::from_vars(env::vars())
+    }
+
+    /// Builds the config from the given variables instead of the process' environment.
+    pub fn from_vars<I, K, V>(vars: I) -> Result<Config, Error>
+        where I: IntoIterator<Item = (K, V)>, K: Into<String>, V: Into<String>
+    {
+        let mut env = EnvReader::new(vars.into_iter().map(|(k, v)| (k.into(), v.into())).collect());
+
+        // from a mounted secret, to keep it out of the function's configuration
+        let (address, from_file) = match (env.vars.get(ADDRESS_ENV_NAME).cloned(), env.vars.get(ADDRESS_FILE_ENV_NAME).cloned()) {
+            (Some(address), Some(path)) => {
+                env.warn(ADDRESS_FILE_ENV_NAME, path.as_str(), &ADDRESS_ENV_NAME, format!("{} is set as well", ADDRESS_ENV_NAME));
+                (address, false)
+            }
+            (Some(address), None) => (address, false),
+            (None, Some(path)) => (read_address_file(path.as_str())?, true),
+            (None, None) => return Err(format!("Unable to find environment variable: {} (or {})", ADDRESS_ENV_NAME, ADDRESS_FILE_ENV_NAME).into()),
+        };
+
+        // PutLogEvents needs the AWS SDK (or SigV4 over TLS), which this build doesn't have; shipping somewhere else
+        // instead would leave the records out of the log group they were meant for, unnoticed
+        let address_name = if from_file { ADDRESS_FILE_ENV_NAME } else { ADDRESS_ENV_NAME };
+        let mirror = env.vars.get(MIRROR_ADDRESS_ENV_NAME).map(|mirror| (MIRROR_ADDRESS_ENV_NAME, mirror.trim()));
+
+        for (name, address) in [(address_name, address.as_str())].into_iter().chain(mirror) {
+            if address.starts_with(CLOUDWATCH_ADDRESS_PREFIX) {
+                return Err(format!("Unable to use {}: CloudWatch Logs isn't supported as a sink (there's no AWS SDK to call PutLogEvents with)", name).into());
+            }
+        }
+
+        let mut config = Config {
+            address: Address { sink: SinkAddress::parse(address.as_str()), from_file },
+            mirror_address: env.get_opt::<String>(MIRROR_ADDRESS_ENV_NAME).map(|mirror| SinkAddress::parse(mirror.as_str())),
+            source: env.get(SOURCE_ENV_NAME, Source::Logs),
+            subscribe_retries: env.get(SUBSCRIBE_RETRIES_ENV_NAME, DEFAULT_SUBSCRIBE_RETRIES),
+            ship_config_warnings: env.get_bool(SHIP_CONFIG_WARNINGS_ENV_NAME, false),
+            ship_init_errors: env.get_bool(SHIP_INIT_ERRORS_ENV_NAME, false),
+            seq_scope: env.get_opt(SEQ_SCOPE_ENV_NAME),
+            monotonic_time: env.get_opt(MONOTONIC_TIME_ENV_NAME),
+            time_source: env.get(TIME_SOURCE_ENV_NAME, TimeSource::Record),
+            prefer_record_time: env.get_opt(PREFER_RECORD_TIME_ENV_NAME),
+            time_precision: env.get(TIME_PRECISION_ENV_NAME, TimePrecision::Millis),
+            include_uptime: env.get_bool(INCLUDE_UPTIME_ENV_NAME, false),
+            report_derived: env.get_bool(REPORT_DERIVED_ENV_NAME, false),
+            include_mem_limit: env.get_bool(INCLUDE_MEM_LIMIT_ENV_NAME, false),
+            include_ids: env.get_bool(INCLUDE_IDS_ENV_NAME, false),
+            include_id: env.get_bool(INCLUDE_ID_ENV_NAME, false),
+            include_latency: env.get_bool(INCLUDE_LATENCY_ENV_NAME, false),
+            tag_phase: env.get_bool(TAG_PHASE_ENV_NAME, false),
+            layout: env.get(LAYOUT_ENV_NAME, Layout::Flat),
+            time_field: env.get(TIME_FIELD_ENV_NAME, DEFAULT_TIME_FIELD.to_string()),
+            time_field_by_type: env.get_opt(TIME_FIELD_BY_TYPE_ENV_NAME),
+            rename: env.get_opt(RENAME_ENV_NAME),
+            format: env.get(FORMAT_ENV_NAME, Format::Json),
+            framing: env.get(FRAMING_ENV_NAME, Framing::Newline),
+            function_name: env.vars.get(FUNCTION_NAME_ENV_NAME).cloned(),
+            function_memory_mb: env.vars.get(FUNCTION_MEMORY_SIZE_ENV_NAME).and_then(|mb| mb.trim().parse().ok()),
+            drop_empty: env.get_bool(DROP_EMPTY_ENV_NAME, false),
+            nonutf8: env.get(NONUTF8_ENV_NAME, NonUtf8::Replace),
+            keep_fields: env.get_opt(KEEP_FIELDS_ENV_NAME),
+            ttl_map: env.get_opt(TTL_MAP_ENV_NAME),
+            enrich: match (env.get_opt::<String>(ENRICH_FILE_ENV_NAME), env.get(ENRICH_PRECEDENCE_ENV_NAME, EnrichPrecedence::Record)) {
+                (Some(path), precedence) => Some(read_enrich_file(path.as_str(), precedence)?),
+                (None, _) => None,
+            },
+            schema: match env.get_opt::<String>(SCHEMA_FILE_ENV_NAME) {
+                Some(path) => Some(read_schema_file(path.as_str())?),
+                None => None,
+            },
+            schema_on_fail: env.get(SCHEMA_ON_FAIL_ENV_NAME, SchemaOnFail::Mark),
+            reassemble_min_bytes: env.get_opt(REASSEMBLE_MIN_BYTES_ENV_NAME),
+            mark_parse_failure: env.get_bool(MARK_PARSE_FAILURE_ENV_NAME, false),
+            parse_fault_json: env.get_bool(PARSE_FAULT_JSON_ENV_NAME, false),
+            ship_platform_extension: env.get_bool(SHIP_PLATFORM_EXTENSION_ENV_NAME, true),
+            dup_keys: env.get(DUP_KEYS_ENV_NAME, DupKeys::Last),
+            bare_primitives: env.get(BARE_PRIMITIVES_ENV_NAME, BarePrimitives::Native),
+            bigint: env.get(BIGINT_ENV_NAME, BigInt::Keep),
+            max_flatten_depth: env.get_opt(MAX_FLATTEN_DEPTH_ENV_NAME),
+            message_key: env.get(MESSAGE_KEY_ENV_NAME, DEFAULT_MESSAGE_KEY.to_string()),
+            emit_both: env.get_bool(EMIT_BOTH_ENV_NAME, false),
+            emit_both_max_bytes: env.get(EMIT_BOTH_MAX_BYTES_ENV_NAME, DEFAULT_EMIT_BOTH_MAX_BYTES),
+            warn_record_bytes: env.get_opt(WARN_RECORD_BYTES_ENV_NAME),
+            normalize_newlines: env.get_bool(NORMALIZE_NEWLINES_ENV_NAME, false),
+            strip_ansi: env.get_bool(STRIP_ANSI_ENV_NAME, false),
+            newline_replacement: env.get(NEWLINE_REPLACEMENT_ENV_NAME, NewlineReplacement::Text(" ".to_string())),
+            include_trace_id: env.get_bool(INCLUDE_TRACE_ID_ENV_NAME, false),
+            partition_key: env.get_opt(PARTITION_KEY_ENV_NAME),
+            include_hash: env.get_bool(INCLUDE_HASH_ENV_NAME, false),
+            content_hash: env.get(HASH_EXCLUDE_ENV_NAME, ContentHash::default()),
+            max_records_per_invocation: env.get_opt(MAX_RECORDS_PER_INVOCATION_ENV_NAME),
+            file_max_bytes: env.get(FILE_MAX_BYTES_ENV_NAME, DEFAULT_FILE_MAX_BYTES),
+            file_keep: env.get(FILE_KEEP_ENV_NAME, DEFAULT_FILE_KEEP),
+            batch_checksum: env.get_bool(BATCH_CHECKSUM_ENV_NAME, false),
+            batch_checksum_records: env.get(BATCH_CHECKSUM_RECORDS_ENV_NAME, DEFAULT_BATCH_CHECKSUM_RECORDS).max(1),
+            // defaults to the min, to try and speed up logging; clamped to the limits of the Logs API
+            buffer_timeout_ms: env.get_clamped(BUFFER_TIMEOUT_MS_ENV_NAME, 25, 25, 30_000),
+            buffer_max_bytes: env.get_clamped(BUFFER_MAX_BYTES_ENV_NAME, 262_144, 262_144, 1_048_576),
+            buffer_max_items: env.get_clamped(BUFFER_MAX_ITEMS_ENV_NAME, 1_000, 1_000, 10_000),
+            ack_critical: env.get_bool(ACK_CRITICAL_ENV_NAME, false),
+            critical_match: env.get(CRITICAL_MATCH_ENV_NAME, FieldMatch { key: "audit".to_string(), value: "true".to_string() }),
+            flush_types: env.get(FLUSH_TYPES_ENV_NAME, FlushTypes::default()),
+            ack_timeout_ms: env.get(ACK_TIMEOUT_MS_ENV_NAME, DEFAULT_ACK_TIMEOUT_MS),
+            ack_retries: env.get(ACK_RETRIES_ENV_NAME, DEFAULT_ACK_RETRIES),
+            control_channel: env.get_bool(CONTROL_CHANNEL_ENV_NAME, false),
+            record_compress_min_bytes: env.get_opt(RECORD_COMPRESS_MIN_BYTES_ENV_NAME),
+            record_compression: env.get(RECORD_COMPRESSION_ENV_NAME, Compression::Gzip),
+            overflow_policy: env.get(OVERFLOW_POLICY_ENV_NAME, OverflowPolicy::Block),
+            preserialize: env.get_bool(PRESERIALIZE_ENV_NAME, false),
+            splice: env.get_bool(SPLICE_ENV_NAME, true),
+            enqueue_deadline_ms: env.get(ENQUEUE_DEADLINE_MS_ENV_NAME, 0),
+            slow_sink_ms: env.get_opt(SLOW_SINK_MS_ENV_NAME),
+            max_inflight_bytes: env.get_opt(MAX_INFLIGHT_BYTES_ENV_NAME),
+            max_record_age_ms: env.get_opt(MAX_RECORD_AGE_MS_ENV_NAME),
+            max_record_age_exempt_platform: env.get_bool(MAX_RECORD_AGE_EXEMPT_PLATFORM_ENV_NAME, false),
+            dedup_platform: env.get_bool(DEDUP_PLATFORM_ENV_NAME, false),
+            memory_budget_bytes: env.get_opt(MEMORY_BUDGET_BYTES_ENV_NAME),
+            writer_restarts: env.get(WRITER_RESTARTS_ENV_NAME, DEFAULT_WRITER_RESTARTS),
+            stdout_max_line_bytes: env.get(STDOUT_MAX_LINE_BYTES_ENV_NAME, DEFAULT_STDOUT_MAX_LINE_BYTES),
+            flush_mode: env.get(FLUSH_MODE_ENV_NAME, FlushMode::Eager),
+            flush_interval_ms: env.get(FLUSH_INTERVAL_MS_ENV_NAME, DEFAULT_FLUSH_INTERVAL_MS),
+            coalesce_wait_ms: env.get_clamped(COALESCE_WAIT_MS_ENV_NAME, 0, 0, MAX_COALESCE_WAIT_MS),
+            batch_deadline_ms: env.get_opt(BATCH_DEADLINE_MS_ENV_NAME),
+            batch_by: env.get(BATCH_BY_ENV_NAME, BatchBy::Record),
+            batch_by_timeout_ms: env.get(BATCH_BY_TIMEOUT_MS_ENV_NAME, DEFAULT_BATCH_BY_TIMEOUT_MS),
+            batch_by_max_bytes: env.get(BATCH_BY_MAX_BYTES_ENV_NAME, DEFAULT_BATCH_BY_MAX_BYTES),
+            reconnect_retries: env.get(RECONNECT_RETRIES_ENV_NAME, DEFAULT_RECONNECT_RETRIES),
+            idle_disconnect_secs: env.get(IDLE_DISCONNECT_SECS_ENV_NAME, 0),
+            probe_on_thaw: env.get_bool(PROBE_ON_THAW_ENV_NAME, false),
+            health_interval_secs: env.get(HEALTH_INTERVAL_SECS_ENV_NAME, 0),
+            stats_log_secs: env.get(STATS_LOG_SECS_ENV_NAME, 0),
+            initial_connect_retries: env.get(INITIAL_CONNECT_RETRIES_ENV_NAME, DEFAULT_INITIAL_CONNECT_RETRIES),
+            preconnect: env.get_bool(PRECONNECT_ENV_NAME, true),
+            preconnect_timeout_ms: env.get(PRECONNECT_TIMEOUT_MS_ENV_NAME, DEFAULT_PRECONNECT_TIMEOUT_MS),
+            init_buffer_max: env.get_opt(INIT_BUFFER_MAX_ENV_NAME),
+            wait_for_sink_secs: env.get(WAIT_FOR_SINK_SECS_ENV_NAME, 0),
+            dns_ttl_secs: env.get(DNS_TTL_SECS_ENV_NAME, DEFAULT_DNS_TTL_SECS),
+            proxy: env.get_opt(PROXY_ENV_NAME),
+            proxy_auth: env.get_opt(PROXY_AUTH_ENV_NAME),
+            include_session: env.get_bool(INCLUDE_SESSION_ENV_NAME, false),
+            include_seq: env.get_bool(INCLUDE_SEQ_ENV_NAME, false),
+            cb_failure_threshold: env.get(CB_FAILURE_THRESHOLD_ENV_NAME, 0),
+            cb_cooldown_ms: env.get(CB_COOLDOWN_MS_ENV_NAME, DEFAULT_CB_COOLDOWN_MS),
+            shutdown_dump: env.get(SHUTDOWN_DUMP_ENV_NAME, ShutdownDump::Stdout),
+            spill_dir: env.get(SPILL_DIR_ENV_NAME, DEFAULT_SPILL_DIR.to_string()),
+            spill_max_bytes: env.get(SPILL_MAX_BYTES_ENV_NAME, DEFAULT_SPILL_MAX_BYTES),
+            severity_map: env.get(SEVERITY_MAP_ENV_NAME, SeverityMap::default()),
+            pretty: env.get_bool(PRETTY_ENV_NAME, false),
+            sort_keys: env.get_bool(SORT_KEYS_ENV_NAME, false),
+            log_level: env.get(LOG_LEVEL_ENV_NAME, default_log_level(&env)),
+            log_target: env.get_bool(LOG_TARGET_ENV_NAME, false),
+            log_time: env.get_bool(LOG_TIME_ENV_NAME, false),
+            warnings: env.warnings,
+        };
+
+        if let Some(conflict) = config.preserialize.then(|| config.preserialize_conflict()).flatten() {
+            config.preserialize = false;
+            config.warnings.push(ConfigWarning {
+                field: PRESERIALIZE_ENV_NAME.to_string(),
+                given: env.vars.get(PRESERIALIZE_ENV_NAME).cloned().unwrap_or_default(),
+                used: false.to_string(),
+                reason: format!("{} needs records as JSON in the writer", conflict),
+            });
+        }
+
+        // the syslog sink makes its messages from the fields of a record
+        if matches!(config.address.sink(), SinkAddress::Syslog(..)) && config.format != Format::Json {
+            config.warnings.push(ConfigWarning {
+                field: FORMAT_ENV_NAME.to_string(),
+                given: config.format.to_string(),
+                used: Format::Json.to_string(),
+                reason: "a syslog address is sent RFC 5424 messages made from the JSON records".to_string(),
+            });
+            config.format = Format::Json;
+        }
+
+        Ok(config)
+    }
+
+    /// The setting that keeps records from being queued pre-serialized, if there is one: one that has the
+    /// writer look into or add to each record, or a sink other than the log-store.
+    fn preserialize_conflict(&self) -> Option<&'static str> {
+        let tcp = |address: &SinkAddress| matches!(address, SinkAddress::Tcp(_));
+
+        [
+            (!tcp(self.address.sink()), ADDRESS_ENV_NAME),
+            (!self.mirror_address.as_ref().is_none_or(tcp), MIRROR_ADDRESS_ENV_NAME),
+            (self.ack_critical, ACK_CRITICAL_ENV_NAME),
+            (self.flush_types != FlushTypes::default(), FLUSH_TYPES_ENV_NAME),
+            (self.max_record_age_ms.is_some(), MAX_RECORD_AGE_MS_ENV_NAME),
+            (self.dedup_platform, DEDUP_PLATFORM_ENV_NAME),
+            (self.batch_by == BatchBy::Invocation, BATCH_BY_ENV_NAME),
+            (self.include_latency, INCLUDE_LATENCY_ENV_NAME),
+            (self.include_session, INCLUDE_SESSION_ENV_NAME),
+            (self.include_seq, INCLUDE_SEQ_ENV_NAME),
+        ]
+        .into_iter()
+        .find_map(|(conflicts, name)| conflicts.then_some(name))
+    }
+
+    pub fn log_buffering(&self) -> LogBuffering {
+        LogBuffering {
+            timeout_ms: self.buffer_timeout_ms,
+            max_bytes: self.buffer_max_bytes,
+            max_items: self.buffer_max_items,
+        }
+    }
+}
+
+/// `RUST_LOG` if it's a single level (directives like `info,hyper=warn` aren't supported), else `INFO`.
+fn default_log_level(env: &EnvReader) -> Level {
+    env.vars.get(RUST_LOG_ENV_NAME)
+        .and_then(|level| level.trim().parse().ok())
+        .unwrap_or(Level::INFO)
+}
+
+/// A config value that was clamped, or ignored because it couldn't be parsed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigWarning {
+    pub field: String,
+    pub given: String,
+    pub used: String,
+    pub reason: String,
+}
+
+impl ConfigWarning {
+    pub fn to_json(&self) -> JsonValue {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+
+        object! {
+            "t": now.as_millis() as u64,
+            "type": "config_warning",
+            "severity": "warn",
+            "field": self.field.as_str(),
+            "given": self.given.as_str(),
+            "used": self.used.as_str(),
+            "reason": self.reason.as_str(),
+        }
+    }
+}
+
+/// Reads values from a snapshot of the environment, falling back to defaults (and recording a warning)
+/// for values that can't be used as given. Only `Config::from_vars` should use this.
+struct EnvReader {
+    vars: HashMap<String, String>,
+    warnings: Vec<ConfigWarning>,
+}
+
+impl EnvReader {
+    fn new(vars: HashMap<String, String>) -> EnvReader {
+        EnvReader {
+            vars,
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Parses the variable `name`, using `default` if it isn't set or can't be parsed.
+    fn get<T>(&mut self, name: &str, default: T) -> T
+        where T: FromStr + Display, T::Err: Display
+    {
+        let given = match self.vars.get(name) {
+            Some(v) => v.clone(),
+            None => return default,
+        };
+
+        match given.trim().parse::<T>() {
+            Ok(v) => v,
+            Err(e) => {
+                self.warn(name, given.as_str(), &default, e.to_string());
+                default
+            }
+        }
+    }
+
+    /// Parses the variable `name`, returning `None` if it isn't set or can't be parsed.
+    fn get_opt<T>(&mut self, name: &str) -> Option<T>
+        where T: FromStr, T::Err: Display
+    {
+        let given = self.vars.get(name)?.clone();
+
+        match given.trim().parse::<T>() {
+            Ok(v) => Some(v),
+            Err(e) => {
+                self.warn(name, given.as_str(), &"unset", e.to_string());
+                None
+            }
+        }
+    }
+
+    /// Like `get`, but clamps the value into `min..=max`.
+    fn get_clamped<T>(&mut self, name: &str, default: T, min: T, max: T) -> T
+        where T: FromStr + Display + PartialOrd, T::Err: Display
+    {
+        let v = self.get(name, default);
+
+        if v < min {
+            self.warn(name, v.to_string().as_str(), &min, format!("below the minimum of {}", min));
+            min
+        } else if v > max {
+            self.warn(name, v.to_string().as_str(), &max, format!("above the maximum of {}", max));
+            max
+        } else {
+            v
+        }
+    }
+
+    /// Reads a boolean flag; `1`/`true`/`yes`/`on` enable it.
+    fn get_bool(&mut self, name: &str, default: bool) -> bool {
+        let given = match self.vars.get(name) {
+            Some(v) => v.clone(),
+            None => return default,
+        };
+
+        match given.trim().to_ascii_lowercase().as_str() {
+            "1" | "true" | "yes" | "on" => true,
+            "0" | "false" | "no" | "off" | "" => false,
+            _ => {
+                self.warn(name, given.as_str(), &default, "expected a boolean".to_string());
+                default
+            }
+        }
+    }
+
+    // these are logged once the config is built, as the log level itself comes from it
+    fn warn(&mut self, field: &str, given: &str, used: &dyn Display, reason: String) {
+        self.warnings.push(ConfigWarning {
+            field: field.to_string(),
+            given: given.to_string(),
+            used: used.to_string(),
+            reason,
+        });
+    }
+}
@@ -0,0 +1,44 @@
+use std::collections::{HashSet, VecDeque};
+
+// a few platform records per invocation, so this is the last thousand or so invocations'
+const MAX_KEYS: usize = 4096;
+
+/// With `dedup_platform`, the platform records the TCP writer has written, by `type` and `request_id`, so one
+/// that comes round again (replayed from a spill, or redelivered by Lambda after a reconnect) isn't written
+/// twice. There's one of each per invocation, unlike function records, which the log-store can tell apart
+/// itself by id or hash. The oldest are forgotten past `MAX_KEYS`.
+pub struct PlatformDedup {
+    sent: HashSet<(String, String)>,
+    order: VecDeque<(String, String)>,
+}
+
+impl PlatformDedup {
+    pub fn new() -> PlatformDedup {
+        PlatformDedup { sent: HashSet::new(), order: VecDeque::new() }
+    }
+
+    /// Records a platform record as written: false if it had been already.
+    pub fn first(&mut self, record_type: &str, request_id: &str) -> bool {
+        let key = (record_type.to_string(), request_id.to_string());
+
+        if !self.sent.insert(key.clone()) {
+            return false;
+        }
+
+        self.order.push_back(key);
+
+        if self.order.len() > MAX_KEYS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.sent.remove(&oldest);
+            }
+        }
+
+        true
+    }
+}
+
+impl Default for PlatformDedup {
+    fn default() -> Self {
+        PlatformDedup::new()
+    }
+}
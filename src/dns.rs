@@ -0,0 +1,38 @@
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::net::lookup_host;
+
+/// The addresses a `host:port` resolved to, kept for `ttl` so reconnecting doesn't look them up every time,
+/// but still picks up a change of IP before long. A TTL of zero looks them up on every connect.
+pub struct DnsCache {
+    ttl: Duration,
+    cached: Option<(Instant, Vec<SocketAddr>)>,
+}
+
+impl DnsCache {
+    pub fn new(ttl: Duration) -> DnsCache {
+        DnsCache { ttl, cached: None }
+    }
+
+    /// The addresses `address` resolves to, and whether they came from the cache.
+    pub async fn resolve(&mut self, address: &str) -> std::io::Result<(Vec<SocketAddr>, bool)> {
+        if let Some((resolved_at, addrs)) = &self.cached {
+            if resolved_at.elapsed() < self.ttl {
+                return Ok((addrs.clone(), true));
+            }
+        }
+
+        let addrs: Vec<_> = lookup_host(address).await?.collect();
+
+        if !self.ttl.is_zero() {
+            self.cached = Some((Instant::now(), addrs.clone()));
+        }
+
+        Ok((addrs, false))
+    }
+
+    /// Forgets what's cached, e.g. when none of the addresses could be connected to.
+    pub fn invalidate(&mut self) {
+        self.cached = None;
+    }
+}
@@ -0,0 +1,154 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use json::JsonValue;
+
+/// What to do with a key a function's JSON log line has more than once, at the top level (the fields that
+/// end up in the record). The `json` crate keeps the last value, so that's all the default does.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DupKeys {
+    /// Keep the last value
+    Last,
+    /// Keep the first value
+    First,
+    /// Keep every value, in an array
+    Array,
+    /// Keep every value, the second as `key_2`, the third as `key_3`, and so on
+    Suffix,
+}
+
+impl DupKeys {
+    /// `object` as parsed from `line`, with its duplicate keys handled; it's only looked at again if set.
+    pub fn resolve(&self, line: &str, object: JsonValue) -> JsonValue {
+        if *self == DupKeys::Last {
+            return object;
+        }
+
+        let members = match members(line) {
+            Some(members) if members.len() > object.len() => members,
+            _ => return object,
+        };
+        let mut resolved = JsonValue::new_object();
+        let mut collected = Vec::new();
+
+        for (key, raw) in members {
+            let value = match json::parse(raw) {
+                Ok(value) => value,
+                Err(_) => return object,
+            };
+
+            if !resolved.has_key(key.as_str()) {
+                let _ = resolved.insert(key.as_str(), value);
+                continue;
+            }
+
+            match self {
+                DupKeys::Last | DupKeys::First => (),
+                DupKeys::Array => {
+                    if !collected.contains(&key) {
+                        let first = resolved.remove(key.as_str());
+                        let _ = resolved.insert(key.as_str(), JsonValue::Array(vec![first]));
+                        collected.push(key.clone());
+                    }
+
+                    let _ = resolved[key.as_str()].push(value);
+                }
+                DupKeys::Suffix => {
+                    let renamed = (2..).map(|n| format!("{}_{}", key, n))
+                        .find(|renamed| !resolved.has_key(renamed.as_str()))
+                        .unwrap_or_default();
+                    let _ = resolved.insert(renamed.as_str(), value);
+                }
+            }
+        }
+
+        resolved
+    }
+}
+
+/// The top-level members of a JSON object, as written: each key (unescaped) and its value's text.
+/// `line` has already parsed, so it's only scanned for where the members start and end.
+fn members(line: &str) -> Option<Vec<(String, &str)>> {
+    let bytes = line.as_bytes();
+    let mut i = line.find('{')? + 1;
+    let mut members = Vec::new();
+    let skip_ws = |i: &mut usize| while *i < bytes.len() && bytes[*i].is_ascii_whitespace() { *i += 1 };
+
+    loop {
+        skip_ws(&mut i);
+
+        if bytes.get(i)? == &b'}' {
+            return Some(members);
+        }
+
+        let key_end = string_end(bytes, i)?;
+        let key = json::parse(&line[i..key_end]).ok()?.as_str()?.to_string();
+
+        i = key_end;
+        skip_ws(&mut i);
+        i += 1; // the colon
+
+        let start = i;
+        let mut depth = 0;
+
+        while i < bytes.len() {
+            match bytes[i] {
+                b'"' => {
+                    i = string_end(bytes, i)?;
+                    continue;
+                }
+                b'{' | b'[' => depth += 1,
+                b'}' | b']' if depth > 0 => depth -= 1,
+                b',' | b'}' if depth == 0 => break,
+                _ => (),
+            }
+
+            i += 1;
+        }
+
+        members.push((key, line[start..i].trim()));
+
+        if bytes.get(i)? == &b',' {
+            i += 1;
+        }
+    }
+}
+
+/// Where the string starting at `start` (its opening quote) ends, just past its closing quote.
+fn string_end(bytes: &[u8], start: usize) -> Option<usize> {
+    let mut i = start + 1;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            b'"' => return Some(i + 1),
+            _ => i += 1,
+        }
+    }
+
+    None
+}
+
+impl FromStr for DupKeys {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "last" => Ok(DupKeys::Last),
+            "first" => Ok(DupKeys::First),
+            "array" => Ok(DupKeys::Array),
+            "suffix" => Ok(DupKeys::Suffix),
+            _ => Err(format!("unknown duplicate key handling {:?}, expected last, first, array, or suffix", s)),
+        }
+    }
+}
+
+impl Display for DupKeys {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DupKeys::Last => write!(f, "last"),
+            DupKeys::First => write!(f, "first"),
+            DupKeys::Array => write!(f, "array"),
+            DupKeys::Suffix => write!(f, "suffix"),
+        }
+    }
+}
@@ -0,0 +1,267 @@
+use std::borrow::Cow;
+use std::fmt::{Display, Formatter};
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use flate2::Compression as Level;
+use flate2::write::{DeflateEncoder, GzEncoder, ZlibEncoder};
+use json::{JsonValue, object};
+use tracing::warn;
+
+use crate::config::{Config, SinkAddress};
+use crate::layout::{self, TimeFields};
+use crate::logfmt;
+use crate::otel::Format;
+use crate::rename::FieldRenames;
+use crate::writer::ACK_FIELD;
+
+/// Algorithm used to compress large records.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Zlib,
+    Deflate,
+}
+
+impl Compression {
+    fn compress(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Level::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Compression::Zlib => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Level::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Compression::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Level::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "gzip" => Ok(Compression::Gzip),
+            "zlib" => Ok(Compression::Zlib),
+            "deflate" => Ok(Compression::Deflate),
+            _ => Err(format!("unknown compression {:?}, expected gzip, zlib, or deflate", s)),
+        }
+    }
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Compression::Gzip => write!(f, "gzip"),
+            Compression::Zlib => write!(f, "zlib"),
+            Compression::Deflate => write!(f, "deflate"),
+        }
+    }
+}
+
+/// Turns records into the bytes written to a sink.
+pub struct Encoder {
+    compression: Compression,
+    compress_min_bytes: Option<usize>,
+    pretty: bool,
+    sort_keys: bool,
+    logfmt: bool,
+    /// With `time_field` or `time_field_by_type`, what `t` is renamed to
+    time_fields: Option<TimeFields>,
+    /// With `rename`, the fields renamed after that
+    renames: Option<FieldRenames>,
+    /// Set once a rename has been skipped (and warned about)
+    rename_skipped: AtomicBool,
+}
+
+impl Encoder {
+    pub fn new(config: &Config) -> Encoder {
+        Encoder {
+            compression: config.record_compression,
+            compress_min_bytes: config.record_compress_min_bytes,
+            // indenting is only worth it for someone reading the file, not over the network
+            pretty: config.pretty && matches!(config.address.sink(), SinkAddress::File(_)),
+            sort_keys: config.sort_keys,
+            logfmt: config.format == Format::Logfmt,
+            // OTel and Loki have times of their own
+            time_fields: Some(TimeFields::new(config.time_field.as_str(), config.time_field_by_type.as_ref()))
+                .filter(|time_fields| !time_fields.is_default() && matches!(config.format, Format::Json | Format::Logfmt)),
+            renames: config.rename.clone().filter(|_| matches!(config.format, Format::Json | Format::Logfmt)),
+            rename_skipped: AtomicBool::new(false),
+        }
+    }
+
+    /// Frames a record for the wire (or file): a single line of JSON.
+    /// Records larger than `compress_min_bytes` are replaced by
+    /// `{"t":..,"type":..,"_z":"<algorithm>","payload":"<base64 of the compressed record>"}`
+    /// (plus `_ack` for critical records).
+    /// In pretty mode, records are indented over several lines and separated by a blank line.
+    /// With `sort_keys`, every object's keys are in sorted order (before compressing).
+    /// With the logfmt format, records are logfmt lines instead, and never compressed or indented.
+    /// With `time_fields`, `t` is renamed last, so it's in its place in the line (and the compressed envelope);
+    /// the `renames` come after that.
+    pub fn encode(&self, json: &JsonValue) -> String {
+        let json = if self.sort_keys { Cow::Owned(sort_keys(json)) } else { Cow::Borrowed(json) };
+        let shipped = self.rename_fields(&json);
+
+        if self.logfmt {
+            return logfmt(&shipped);
+        }
+
+        let line = shipped.dump();
+        let compressed = match self.compress_min_bytes {
+            Some(min) if line.len() >= min => self.compress(&json, line.as_bytes()),
+            _ => None,
+        };
+
+        match (compressed, self.pretty) {
+            (Some(compressed), true) => pretty(&self.rename_fields(&compressed)),
+            (Some(compressed), false) => format!("{}\n", self.rename_fields(&compressed).dump()),
+            (None, true) => pretty(&shipped),
+            (None, false) => format!("{}\n", line),
+        }
+    }
+
+    /// True if a record's line is its JSON and nothing more: none of it renamed, sorted, compressed, or indented.
+    pub fn is_plain(&self) -> bool {
+        self.compress_min_bytes.is_none() && !self.pretty && !self.sort_keys && !self.logfmt
+            && self.time_fields.is_none() && self.renames.is_none()
+    }
+
+    fn rename_fields<'a>(&self, json: &'a JsonValue) -> Cow<'a, JsonValue> {
+        if self.time_fields.is_none() && self.renames.is_none() {
+            return Cow::Borrowed(json);
+        }
+
+        let mut json = json.clone();
+
+        if let Some(time_fields) = &self.time_fields {
+            time_fields.rename(&mut json);
+        }
+
+        if let Some(renames) = &self.renames {
+            let skipped = renames.rename(&mut json);
+
+            // the same collision is likely in every record of its kind, so it's only logged the once
+            if let Some((from, to)) = skipped.first() {
+                if !self.rename_skipped.swap(true, Ordering::Relaxed) {
+                    warn!("Not renaming {} to {}, which the record has already (further skipped renames aren't logged)", from, to);
+                }
+            }
+        }
+
+        Cow::Owned(json)
+    }
+
+    fn compress(&self, json: &JsonValue, line: &[u8]) -> Option<JsonValue> {
+        let compressed = self.compression.compress(line).ok()?;
+
+        let mut envelope = object! {
+            "t": layout::field(json, "t").clone(),
+            "type": layout::field(json, "type").clone(),
+            "_z": self.compression.to_string(),
+            "payload": BASE64.encode(compressed),
+        };
+
+        // the log-store has to see the ack id without decompressing
+        if json.has_key(ACK_FIELD) {
+            envelope.insert(ACK_FIELD, json[ACK_FIELD].clone()).ok()?;
+        }
+
+        Some(envelope)
+    }
+}
+
+/// A copy of `json` with the keys of every object, however deeply nested, in sorted order.
+pub fn sort_keys(json: &JsonValue) -> JsonValue {
+    match json {
+        JsonValue::Object(obj) => {
+            let mut entries: Vec<_> = obj.iter().collect();
+            let mut sorted = JsonValue::new_object();
+
+            entries.sort_unstable_by_key(|(k, _)| *k);
+
+            for (k, v) in entries {
+                sorted[k] = sort_keys(v);
+            }
+
+            sorted
+        }
+        JsonValue::Array(values) => JsonValue::Array(values.iter().map(sort_keys).collect()),
+        _ => json.clone(),
+    }
+}
+
+/// A record as a line of JSON, without its newline: as it was encoded, if it was queued pre-serialized (with
+/// `preserialize`, a record that's a string is the line the handler encoded it to).
+pub fn json_line(json: &JsonValue) -> Cow<'_, str> {
+    match json.as_str() {
+        Some(line) => Cow::Borrowed(line.trim_end_matches('\n')),
+        None => Cow::Owned(json.dump()),
+    }
+}
+
+/// A line (without its newline) for stdout, where CloudWatch splits anything over its limit into pieces that
+/// don't parse. One longer than `max_bytes` (0 for no limit) is replaced by
+/// `{"t":..,"type":..,"truncated":true,"bytes":<its length>,"record":"<as much of it as fits>"}`.
+pub fn fit_line(line: &str, max_bytes: usize) -> Cow<'_, str> {
+    if max_bytes == 0 || line.len() <= max_bytes {
+        return Cow::Borrowed(line);
+    }
+
+    let parsed = json::parse(line).unwrap_or(JsonValue::Null);
+    let mut fitted = object! {};
+
+    for field in ["t", "type"] {
+        let value = layout::field(&parsed, field);
+
+        if !value.is_null() {
+            let _ = fitted.insert(field, value.clone());
+        }
+    }
+
+    let _ = fitted.insert("truncated", true);
+    let _ = fitted.insert("bytes", line.len());
+
+    // escaping makes what's kept longer, so it's cut back by however much it's over until it fits
+    let mut keep = max_bytes;
+
+    loop {
+        while !line.is_char_boundary(keep) {
+            keep -= 1;
+        }
+
+        let _ = fitted.insert("record", &line[..keep]);
+        let dumped = fitted.dump();
+
+        if dumped.len() <= max_bytes || keep == 0 {
+            return Cow::Owned(dumped);
+        }
+
+        keep = keep.saturating_sub(dumped.len() - max_bytes);
+    }
+}
+
+/// Indented, multi-line JSON followed by a blank line, as newlines no longer separate records.
+pub fn pretty(json: &JsonValue) -> String {
+    format!("{}\n\n", json.pretty(2))
+}
+
+/// A record as a logfmt line, or a frame (an invocation's records) as one line per record.
+pub fn logfmt(json: &JsonValue) -> String {
+    match json {
+        JsonValue::Array(records) => records.iter().map(|record| format!("{}\n", logfmt::line(record))).collect(),
+        record => format!("{}\n", logfmt::line(record)),
+    }
+}
@@ -0,0 +1,136 @@
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use json::JsonValue;
+use tracing::warn;
+
+use crate::encoder::Encoder;
+
+// the only location that's always writable inside of Lambda
+const TMP_DIR: &str = "/tmp";
+
+/// Writes newline-delimited records to a file, rotating it once it reaches `max_bytes`.
+/// Rotated files are named `<path>.1` (newest) through `<path>.<keep>` (oldest).
+pub struct FileSink {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    file: BufWriter<File>,
+    size: u64,
+    /// How records are encoded when it's used as a `Sink`; JSON lines if there's none
+    encoder: Option<Encoder>,
+}
+
+impl FileSink {
+    /// Opens (or creates) the file at `path` for appending. If the path isn't writable,
+    /// a file with the same name is created in `/tmp` instead.
+    pub async fn open(path: &str, max_bytes: u64, keep: usize) -> std::io::Result<FileSink> {
+        let requested = PathBuf::from(path);
+
+        let (path, file) = match open_append(&requested).await {
+            Ok(file) => (requested, file),
+            Err(e) => {
+                let file_name = requested.file_name().ok_or_else(|| std::io::Error::new(ErrorKind::InvalidInput, "file sink path has no file name"))?;
+                let tmp_path = Path::new(TMP_DIR).join(file_name);
+
+                warn!("Unable to open {}: {}; writing to {} instead", requested.display(), e, tmp_path.display());
+
+                let file = open_append(&tmp_path).await?;
+                (tmp_path, file)
+            }
+        };
+
+        let size = file.metadata().await?.len();
+
+        Ok(FileSink {
+            path,
+            max_bytes,
+            keep,
+            file: BufWriter::new(file),
+            size,
+            encoder: None,
+        })
+    }
+
+    /// Encodes the records written to it as a `Sink` with `encoder`, as the `file:` sink does.
+    pub fn with_encoder(mut self, encoder: Encoder) -> FileSink {
+        self.encoder = Some(encoder);
+        self
+    }
+
+    /// A record as it's written to the file by `Sink::write_batch`.
+    pub fn encode(&self, json: &JsonValue) -> String {
+        match &self.encoder {
+            Some(encoder) => encoder.encode(json),
+            None => format!("{}\n", json.dump()),
+        }
+    }
+
+    /// Whether writing `len` more bytes rotates the file first.
+    pub fn rotates_before(&self, len: usize) -> bool {
+        self.size > 0 && self.size + len as u64 > self.max_bytes
+    }
+
+    /// Writes a single, already framed, record; rotating first if it would push the file past `max_bytes`.
+    pub async fn write(&mut self, line: &str) -> std::io::Result<()> {
+        if self.rotates_before(line.len()) {
+            self.rotate().await?;
+        }
+
+        self.append(line).await
+    }
+
+    /// Writes `line` without rotating first, for a line that belongs in the file with the ones before it.
+    pub async fn append(&mut self, line: &str) -> std::io::Result<()> {
+        self.file.write_all(line.as_bytes()).await?;
+        self.file.flush().await?;
+        self.size += line.len() as u64;
+
+        Ok(())
+    }
+
+    pub async fn shutdown(&mut self) -> std::io::Result<()> {
+        self.file.shutdown().await
+    }
+
+    async fn rotate(&mut self) -> std::io::Result<()> {
+        self.file.shutdown().await?;
+
+        if self.keep == 0 {
+            fs::remove_file(&self.path).await?;
+        } else {
+            // shift <path>.N -> <path>.N+1, dropping the oldest
+            let _ = fs::remove_file(self.rotated_path(self.keep)).await;
+
+            for n in (1..self.keep).rev() {
+                let from = self.rotated_path(n);
+
+                if fs::metadata(&from).await.is_ok() {
+                    fs::rename(&from, self.rotated_path(n + 1)).await?;
+                }
+            }
+
+            fs::rename(&self.path, self.rotated_path(1)).await?;
+        }
+
+        self.file = BufWriter::new(open_append(&self.path).await?);
+        self.size = 0;
+
+        Ok(())
+    }
+
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        PathBuf::from(path)
+    }
+}
+
+async fn open_append(path: &Path) -> std::io::Result<File> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+
+    OpenOptions::new().create(true).append(true).open(path).await
+}
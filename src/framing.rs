@@ -0,0 +1,102 @@
+use std::borrow::Cow;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use flate2::Crc;
+
+// the length before a frame's payload, and the CRC after it
+const LENGTH_BYTES: usize = 4;
+const CRC_BYTES: usize = 4;
+
+/// How records are delimited on the connection to the log-store.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Framing {
+    /// Each record as encoded, ending with a newline
+    #[default]
+    Newline,
+    /// Each record (without its newline) as a frame: the payload's length as 4 bytes, big-endian, then the
+    /// payload, then the CRC-32 (IEEE, as in gzip) of the payload as 4 bytes, big-endian
+    Crc,
+}
+
+impl Framing {
+    /// The bytes to write for an encoded record (or invocation frame).
+    pub fn frame<'a>(&self, line: &'a str) -> Cow<'a, [u8]> {
+        match self {
+            Framing::Newline => Cow::Borrowed(line.as_bytes()),
+            Framing::Crc => {
+                let payload = line.trim_end_matches('\n').as_bytes();
+                let mut crc = Crc::new();
+                let mut frame = Vec::with_capacity(LENGTH_BYTES + payload.len() + CRC_BYTES);
+
+                crc.update(payload);
+                frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+                frame.extend_from_slice(payload);
+                frame.extend_from_slice(&crc.sum().to_be_bytes());
+
+                Cow::Owned(frame)
+            }
+        }
+    }
+}
+
+/// Why `decode` rejected a frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameError {
+    /// The CRC doesn't match the payload: the length, payload, or CRC was corrupted
+    Crc { expected: u32, actual: u32 },
+}
+
+impl Display for FrameError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameError::Crc { expected, actual } => write!(f, "frame CRC {:08x} doesn't match payload CRC {:08x}", expected, actual),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// Reads a `crc` frame from the front of `buf`, as a log-store would: the payload and how many bytes the frame
+/// took, or `None` if `buf` doesn't hold all of it yet. A frame that fails its CRC is an error; the reader
+/// can't trust its length either, so it should drop the connection rather than read on. A corrupted length can
+/// also pass for a frame that hasn't all arrived, so a reader should cap how long a frame it waits for.
+pub fn decode(buf: &[u8]) -> Result<Option<(&[u8], usize)>, FrameError> {
+    let length = match buf.get(..LENGTH_BYTES) {
+        Some(length) => u32::from_be_bytes(length.try_into().unwrap_or_default()) as usize,
+        None => return Ok(None),
+    };
+    let end = LENGTH_BYTES + length;
+    let (payload, expected) = match (buf.get(LENGTH_BYTES..end), buf.get(end..end + CRC_BYTES)) {
+        (Some(payload), Some(expected)) => (payload, u32::from_be_bytes(expected.try_into().unwrap_or_default())),
+        _ => return Ok(None),
+    };
+    let mut crc = Crc::new();
+
+    crc.update(payload);
+
+    match crc.sum() {
+        actual if actual == expected => Ok(Some((payload, end + CRC_BYTES))),
+        actual => Err(FrameError::Crc { expected, actual }),
+    }
+}
+
+impl FromStr for Framing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "newline" => Ok(Framing::Newline),
+            "crc" => Ok(Framing::Crc),
+            _ => Err(format!("unknown framing {:?}, expected newline or crc", s)),
+        }
+    }
+}
+
+impl Display for Framing {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Framing::Newline => write!(f, "newline"),
+            Framing::Crc => write!(f, "crc"),
+        }
+    }
+}
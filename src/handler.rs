@@ -0,0 +1,1146 @@
+use std::borrow::Cow;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use json::{JsonValue, object};
+use lambda_extension::{Error, InitPhase, InitType, LambdaLog, LambdaLogRecord, LambdaTelemetry, LambdaTelemetryRecord, Span, Status, TraceContext};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::time::{Instant, timeout_at};
+use tracing::{debug, warn};
+
+use crate::bigint::{self, BigInt};
+use crate::config::{BarePrimitives, Config, OverflowPolicy, TimeSource};
+use crate::dup_keys::DupKeys;
+use crate::encoder::Encoder;
+use crate::hash::ContentHash;
+use crate::invocation;
+use crate::layout::{self, Layout, META_FIELDS};
+use crate::limit::InvocationLimit;
+use crate::loki;
+use crate::monotonic::Monotonic;
+use crate::otel::{self, Format};
+use crate::partition::{PartitionKey, PARTITION_KEY_FIELD};
+use crate::phase::PhaseTracker;
+use crate::precision::{TimePrecision, SUB_MS_FIELD};
+use crate::reassemble::Reassembler;
+use crate::record_id::{RecordIds, ID_FIELD};
+use crate::record_time::RecordTime;
+use crate::schema::{Schema, SchemaOnFail, SCHEMA_INVALID_FIELD};
+use crate::sequence::Sequencer;
+use crate::severity::LEVEL_FIELDS;
+use crate::splice;
+use crate::stats::{estimated_size, Stats};
+use crate::thaw::ThawDetector;
+use crate::trace::{TraceIds, TRACE_ID_FIELD};
+use crate::transform::{AnsiStripper, Leveler, NewlineNormalizer, RecordTransform, LINE_FIELDS};
+use crate::utf8::{self, NonUtf8};
+
+/// The field holding a function's JSON object as it was logged, with `emit_both`.
+pub const RAW_FIELD: &str = "_raw";
+
+// how much of an oversized record is logged
+const OVERSIZED_PREFIX_CHARS: usize = 256;
+
+// the share of its memory a function with `right_sized` uses: less and it has more than it needs, more and it's
+// close to running out
+const RIGHT_SIZED_UTILIZATION: std::ops::RangeInclusive<f64> = 0.5..=0.9;
+
+/// The mirror's writer, with counters of its own.
+struct Mirror {
+    sender: Sender<JsonValue>,
+    stats: Arc<Stats>,
+}
+
+impl Mirror {
+    /// Queues a copy of each record, dropping whatever doesn't fit right away: the primary never waits on the mirror.
+    fn send(&self, records: &[JsonValue]) {
+        let mut dropped = 0;
+
+        for json in records {
+            let size = self.stats.inflight_size(json);
+
+            if !self.stats.try_acquire(size) {
+                dropped += 1;
+                continue
+            }
+
+            match self.sender.try_send(json.clone()) {
+                Ok(()) => (),
+                Err(TrySendError::Full(_)) => {
+                    self.stats.release(size);
+                    dropped += 1;
+                }
+                // the mirror's writer has gone, which is no reason to hold up the primary
+                Err(TrySendError::Closed(_)) => return,
+            }
+        }
+
+        if dropped > 0 {
+            warn!("Dropped {} of {} records for the mirror: its writer is falling behind", dropped, records.len());
+            self.stats.add_dropped(dropped);
+        }
+    }
+}
+
+/// Everything `handler` needs across calls, built once from the `Config`.
+pub struct HandlerState {
+    sender: Sender<JsonValue>,
+    stats: Arc<Stats>,
+    mirror: Option<Mirror>,
+    sequencer: Option<Sequencer>,
+    phase: Option<PhaseTracker>,
+    invocation_limit: Option<InvocationLimit>,
+    trace_ids: Option<TraceIds>,
+    partition_key: Option<PartitionKey>,
+    /// With `partition_key=request_id`, the request id of the invocation in progress
+    request_id: Mutex<Option<String>>,
+    thaw: Option<ThawDetector>,
+    reassembler: Option<Reassembler>,
+    time_source: TimeSource,
+    prefer_record_time: Option<RecordTime>,
+    time_precision: TimePrecision,
+    monotonic: Option<Monotonic>,
+    include_uptime: bool,
+    report_derived: bool,
+    /// With `include_mem_limit`, the function's configured memory, as it was at startup
+    mem_limit_mb: Option<u64>,
+    /// With `include_ids`, the extension's process id, as it was at startup
+    pid: Option<u32>,
+    record_ids: Option<RecordIds>,
+    layout: Layout,
+    format: Format,
+    function_name: Option<String>,
+    transforms: Vec<Box<dyn RecordTransform>>,
+    content_hash: Option<ContentHash>,
+    drop_empty: bool,
+    nonutf8: NonUtf8,
+    mark_parse_failure: bool,
+    parse_fault_json: bool,
+    ship_platform_extension: bool,
+    dup_keys: DupKeys,
+    bare_primitives: BarePrimitives,
+    bigint: BigInt,
+    max_flatten_depth: Option<usize>,
+    /// With `message_key`, the field a log line that isn't a JSON object goes under in place of `record`
+    message_key: Option<String>,
+    /// With `emit_both`, the largest JSON object that's copied under `_raw`
+    emit_both_max_bytes: Option<u64>,
+    warn_record_bytes: Option<u64>,
+    schema: Option<Schema>,
+    schema_on_fail: SchemaOnFail,
+    overflow_policy: OverflowPolicy,
+    enqueue_deadline: Duration,
+    /// With `preserialize`, what encodes records into the lines that are queued in their place
+    preserialize: Option<Encoder>,
+    /// With `splice`, and nothing else that needs them parsed, function log lines that are JSON objects are
+    /// queued as they were logged, after our fields; see `splice`
+    splice: bool,
+}
+
+impl HandlerState {
+    pub fn new(config: &Config, sender: Sender<JsonValue>, stats: Arc<Stats>) -> HandlerState {
+        let preserialize = config.preserialize.then(|| Encoder::new(config));
+
+        HandlerState {
+            sender,
+            stats,
+            mirror: None,
+            sequencer: config.seq_scope.map(Sequencer::new),
+            phase: config.tag_phase.then(PhaseTracker::new),
+            invocation_limit: config.max_records_per_invocation.map(InvocationLimit::new),
+            trace_ids: config.include_trace_id.then(TraceIds::new),
+            partition_key: config.partition_key.clone(),
+            request_id: Mutex::new(None),
+            thaw: config.probe_on_thaw.then(ThawDetector::new),
+            reassembler: config.reassemble_min_bytes.map(Reassembler::new),
+            time_source: config.time_source,
+            prefer_record_time: config.prefer_record_time.clone(),
+            time_precision: config.time_precision,
+            monotonic: config.monotonic_time.map(Monotonic::new),
+            include_uptime: config.include_uptime,
+            report_derived: config.report_derived,
+            mem_limit_mb: config.function_memory_mb.filter(|_| config.include_mem_limit),
+            pid: config.include_ids.then(std::process::id),
+            record_ids: config.include_id.then(RecordIds::new),
+            layout: config.layout,
+            format: config.format,
+            function_name: config.function_name.clone(),
+            transforms: built_in_transforms(config),
+            content_hash: config.include_hash.then(|| config.content_hash.clone()),
+            drop_empty: config.drop_empty,
+            nonutf8: config.nonutf8,
+            mark_parse_failure: config.mark_parse_failure,
+            parse_fault_json: config.parse_fault_json,
+            ship_platform_extension: config.ship_platform_extension,
+            dup_keys: config.dup_keys,
+            bare_primitives: config.bare_primitives,
+            bigint: config.bigint,
+            max_flatten_depth: config.max_flatten_depth,
+            message_key: Some(config.message_key.clone()).filter(|key| !key.is_empty() && key != "record"),
+            emit_both_max_bytes: config.emit_both.then_some(config.emit_both_max_bytes),
+            warn_record_bytes: config.warn_record_bytes,
+            schema: config.schema.clone(),
+            schema_on_fail: config.schema_on_fail,
+            overflow_policy: config.overflow_policy,
+            enqueue_deadline: Duration::from_millis(config.enqueue_deadline_ms),
+            splice: splices(config, preserialize.as_ref()),
+            preserialize,
+        }
+    }
+
+    /// Sends a whole batch of records to the writer. With the `drop` policy, once the deadline
+    /// (measured from the start of the batch) has passed, whatever doesn't fit is dropped; so is
+    /// anything over the in-flight byte limit. With `block`, both wait for the writer to catch up.
+    async fn enqueue(&self, records: Vec<JsonValue>) -> Result<(), Error> {
+        // one string each holds much less than a record's tree of values, and the writer has less to do
+        let records = match &self.preserialize {
+            // a spliced record is its line already
+            Some(encoder) => records.into_iter()
+                .map(|json| if json.is_string() { json } else { JsonValue::String(encoder.encode(&json)) })
+                .collect(),
+            None => records,
+        };
+
+        if let Some(mirror) = &self.mirror {
+            mirror.send(&records);
+        }
+
+        if self.overflow_policy == OverflowPolicy::Block {
+            for json in records {
+                self.stats.acquire(self.stats.inflight_size(&json)).await;
+                self.sender.send(json).await?;
+            }
+
+            return Ok(());
+        }
+
+        // a log-store that isn't keeping up won't make room before the deadline
+        let deadline = match self.stats.sink_slow.load(Ordering::Relaxed) {
+            true => Instant::now(),
+            false => Instant::now() + self.enqueue_deadline,
+        };
+        let total = records.len();
+        let mut dropped = 0;
+        let mut records = records.into_iter()
+            .filter(|json| {
+                let fits = self.stats.try_acquire(self.stats.inflight_size(json));
+
+                dropped += u64::from(!fits);
+                fits
+            })
+            .collect::<Vec<_>>()
+            .into_iter();
+
+        for json in records.by_ref() {
+            let size = self.stats.inflight_size(&json);
+
+            match self.sender.try_send(json) {
+                Ok(()) => continue,
+                Err(TrySendError::Full(json)) => {
+                    match timeout_at(deadline, self.sender.send(json)).await {
+                        Ok(res) => res?,
+                        Err(_) => {
+                            self.stats.release(size);
+                            dropped += 1;
+                            break;
+                        }
+                    }
+                }
+                Err(TrySendError::Closed(_)) => return Err("channel to the writer is closed".into()),
+            }
+        }
+
+        // past the deadline, only take what fits right now
+        for json in records {
+            let size = self.stats.inflight_size(&json);
+
+            match self.sender.try_send(json) {
+                Ok(()) => (),
+                Err(TrySendError::Full(_)) => {
+                    self.stats.release(size);
+                    dropped += 1;
+                }
+                Err(TrySendError::Closed(_)) => return Err("channel to the writer is closed".into()),
+            }
+        }
+
+        if dropped > 0 {
+            warn!("Dropped {} of {} records: the writer is falling behind", dropped, total);
+            self.stats.add_dropped(dropped);
+        }
+
+        Ok(())
+    }
+
+    /// Starts a record with the fields every record has; `t` is `time_ns` (in milliseconds, until it's shipped in
+    /// the `time_precision`) or the ingest time, per `time_source`, and `up_ms` (with `include_uptime`) how long
+    /// the extension had been running when the record was received, `mem_limit_mb` (with `include_mem_limit`)
+    /// the function's configured memory, `pid` and `tid` (with `include_ids`) the process and the thread
+    /// handling the record, and `id` (with `include_id`) a UUIDv7 from `t`.
+    fn new_record(&self, time_ns: i64, starts_invocation: bool) -> Result<JsonValue, Error> {
+        let time_ms = time_ns.div_euclid(1_000_000);
+        let ingest_ms = || self.stats.now_ms() as i64;
+        let mut json = match self.time_source {
+            TimeSource::Record => object! { "t": time_ms },
+            TimeSource::Ingest => object! { "t": ingest_ms() },
+            TimeSource::Both => object! { "t": time_ms, "it": ingest_ms() },
+        };
+
+        if let Some(record_ids) = &self.record_ids {
+            let time_ms = json["t"].as_i64().unwrap_or(time_ms);
+
+            json.insert(ID_FIELD, record_ids.next(time_ms))?;
+        }
+
+        if self.time_precision != TimePrecision::Millis && self.time_source != TimeSource::Ingest {
+            json.insert(SUB_MS_FIELD, time_ns.rem_euclid(1_000_000))?;
+        }
+
+        if let Some(monotonic) = &self.monotonic {
+            monotonic.stamp(&mut json)?;
+        }
+
+        if self.include_uptime {
+            json.insert("up_ms", self.stats.uptime().as_millis() as u64)?;
+        }
+
+        if let Some(mem_limit_mb) = self.mem_limit_mb {
+            json.insert("mem_limit_mb", mem_limit_mb)?;
+        }
+
+        if let Some(pid) = self.pid {
+            json.insert("pid", pid)?;
+
+            if let Some(tid) = thread_id() {
+                json.insert("tid", tid)?;
+            }
+        }
+
+        if let Some(sequencer) = &self.sequencer {
+            sequencer.stamp(starts_invocation, &mut json)?;
+        }
+
+        Ok(json)
+    }
+
+    /// With `prefer_record_time`, the time a function's JSON log carries in that field, if it has one that can be
+    /// read; otherwise `time_ns`, Lambda's.
+    fn event_time(&self, body: Option<&JsonValue>, time_ns: i64) -> i64 {
+        self.prefer_record_time.as_ref().zip(body)
+            .and_then(|(record_time, body)| record_time.time_ns(body))
+            .unwrap_or(time_ns)
+    }
+
+    /// Follows the phase from platform records, and stamps it on function and extension records.
+    fn tag_phase(&self, json: &mut JsonValue) -> Result<(), Error> {
+        if let Some(tracker) = &self.phase {
+            let record_type = json["type"].as_str().unwrap_or_default();
+            let phase = tracker.observe(record_type);
+
+            if record_type == "function" || record_type == "extension" {
+                json.insert("phase", phase.to_string())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Called on an INVOKE event, with the request id and tracing header it carries.
+    pub fn invoked(&self, request_id: &str, tracing: &str) {
+        if let Some(trace_ids) = &self.trace_ids {
+            trace_ids.invoked(request_id, tracing);
+        }
+
+        if self.thaw.as_ref().is_some_and(|thaw| thaw.invoked(self.stats.uptime())) {
+            self.stats.thawed();
+
+            if let Some(mirror) = &self.mirror {
+                mirror.stats.thawed();
+            }
+        }
+    }
+
+    fn start_invocation(&self, request_id: &str) {
+        if let Some(limit) = &self.invocation_limit {
+            limit.start();
+        }
+
+        if let Some(trace_ids) = &self.trace_ids {
+            trace_ids.start(request_id);
+        }
+
+        if self.partition_key == Some(PartitionKey::RequestId) {
+            *self.request_id.lock().unwrap_or_else(|e| e.into_inner()) = Some(request_id.to_string());
+        }
+    }
+
+    /// Stamps the trace id of the invocation in progress, if there is one.
+    fn tag_trace(&self, json: &mut JsonValue) -> Result<(), Error> {
+        if let Some(trace_id) = self.trace_ids.as_ref().and_then(TraceIds::current) {
+            json.insert(TRACE_ID_FIELD, trace_id)?;
+        }
+
+        Ok(())
+    }
+
+    /// False for a function record past `max_records_per_invocation`.
+    fn admit(&self) -> bool {
+        self.invocation_limit.as_ref().is_none_or(InvocationLimit::admit)
+    }
+
+    /// The `truncated_invocation` record for an invocation that just ended, if any of its records were dropped.
+    fn truncation_notice(&self, time_ns: i64, request_id: &str) -> Result<Option<JsonValue>, Error> {
+        let dropped = match self.invocation_limit.as_ref().map(InvocationLimit::end) {
+            Some(dropped) if dropped > 0 => dropped,
+            _ => return Ok(None),
+        };
+
+        warn!("Dropped {} records from invocation {}, over the limit per invocation", dropped, request_id);
+
+        let mut json = self.new_record(time_ns, false)?;
+
+        json.insert("type", "truncated_invocation")?;
+        json.insert("request_id", request_id)?;
+        json.insert("dropped", dropped)?;
+
+        Ok(Some(json))
+    }
+
+    /// A record the extension makes itself, such as a `health` record, from `fields` (its `type` and the rest),
+    /// stamped, transformed, laid out, and formatted as the records from Lambda are; `None` if it's dropped.
+    pub fn own_record(&self, mut fields: JsonValue) -> Result<Option<JsonValue>, Error> {
+        let mut json = self.new_record(self.stats.now_ms() as i64 * 1_000_000, false)?;
+
+        for (k, v) in fields.entries_mut() {
+            json.insert(k, v.take())?;
+        }
+
+        self.finish_record(json, JsonValue::new_object())
+    }
+
+    /// A batch's records with their times, and function log lines that were split joined back together, with
+    /// `reassemble_min_bytes`; see `Reassembler::reassemble`. Each is flagged if it's a piece that couldn't be.
+    fn reassemble<R>(&self, records: Vec<(i64, R)>, line: fn(&mut R) -> Option<String>, function: fn(String) -> R) -> Vec<(i64, R, bool)> {
+        match &self.reassembler {
+            Some(reassembler) => reassembler.reassemble(records.into_iter(), line, function),
+            None => records.into_iter().map(|(time_ns, record)| (time_ns, record, false)).collect(),
+        }
+    }
+
+    /// A function's log line as fields, handling invalid UTF-8 per `nonutf8`; `None` if it's dropped.
+    fn function_body(&self, record: String) -> Option<JsonValue> {
+        if !utf8::is_lossy(record.as_str()) {
+            return Some(function_body(record, self.mark_parse_failure, self.dup_keys, self.bare_primitives, self.bigint, self.max_flatten_depth));
+        }
+
+        self.stats.nonutf8_records.fetch_add(1, Ordering::Relaxed);
+
+        match self.nonutf8 {
+            NonUtf8::Replace => Some(function_body(record, self.mark_parse_failure, self.dup_keys, self.bare_primitives, self.bigint, self.max_flatten_depth)),
+            NonUtf8::Base64 => Some(object! { "_b64": BASE64.encode(record) }),
+            NonUtf8::Drop => None,
+        }
+    }
+
+    /// With `splice`, a function's log line that's a JSON object as the line that's queued, what it was logged as
+    /// copied in after our fields rather than parsed and serialized again: what the general path would ship, but
+    /// for its whitespace and how its numbers and strings are written. Only the fields the `Leveler` reads a level
+    /// from are parsed, as it's the only transform there is. `None` for a line to be handled as any other, not
+    /// being an object `splice::object` can vouch for, having a field of ours, or being split; `Some(None)` for one
+    /// past `max_records_per_invocation`.
+    fn splice(&self, time_ns: i64, line: &str, split: bool) -> Result<Option<Option<JsonValue>>, Error> {
+        let object = match self.splice && !split && !utf8::is_lossy(line) {
+            true => splice::object(line),
+            false => None,
+        };
+        // a field of ours would be in the line twice, and a line that's all `record` (or marks on it) is one
+        let object = match object {
+            Some(object) if object.members.iter().any(|(k, _)| META_FIELDS.contains(k)) => return Ok(None),
+            Some(object) if object.members.iter().all(|(k, _)| LINE_FIELDS.contains(k)) => return Ok(None),
+            Some(object) => object,
+            None => return Ok(None),
+        };
+        // past the limit, it isn't started, so it takes no `seq`, `id`, or `mt`
+        if !self.admit() {
+            return Ok(Some(None));
+        }
+
+        let mut json = self.new_record(time_ns, false)?;
+
+        json.insert("type", "function")?;
+        self.tag_phase(&mut json)?;
+        self.tag_trace(&mut json)?;
+
+        let sub_ms_nanos = take_sub_ms_nanos(&mut json);
+        let mut record = layout::envelope(json, JsonValue::new_object());
+        let mut levels = JsonValue::new_object();
+
+        for (k, v) in object.members.iter().filter(|(k, _)| LEVEL_FIELDS.contains(k) || *k == "record") {
+            levels.insert(k, json::parse(v)?)?;
+        }
+
+        // the leveler sees the fields it needs in the body, in place of ours
+        let ours = std::mem::replace(&mut record["body"], levels);
+
+        for transform in self.transforms.iter() {
+            transform.transform(&mut record);
+        }
+
+        record["body"] = ours;
+
+        // flat, with nothing of ours in the body, is just the meta
+        let mut json = match record["body"].is_empty() {
+            true => record["meta"].take(),
+            false => self.layout.apply(record),
+        };
+
+        self.time_precision.apply(&mut json, sub_ms_nanos);
+
+        // ours always has `t` and `type`, so there's a field before the function's
+        let ours = json.dump();
+
+        Ok(Some(Some(JsonValue::String(format!("{},{}}}\n", &ours[..ours.len() - 1], object.inner)))))
+    }
+
+    /// Sends a copy of every record to a second writer, as well; see `Mirror::send`.
+    pub fn with_mirror(mut self, sender: Sender<JsonValue>, stats: Arc<Stats>) -> HandlerState {
+        self.mirror = Some(Mirror { sender, stats });
+        self
+    }
+
+    /// Registers a transform, run on every record after the built-in ones (and any registered before it).
+    pub fn with_transform(mut self, transform: impl RecordTransform + 'static) -> HandlerState {
+        // it may look at any field, so every record has to be parsed for it
+        self.splice = false;
+        self.transforms.push(Box::new(transform));
+        self
+    }
+
+    /// Runs the transforms over a record once its type-specific fields are in, and lays it out.
+    /// `body` holds a function's own fields; empty for other records. Returns `None` for an empty
+    /// record that's being dropped.
+    fn finish_record(&self, mut json: JsonValue, body: JsonValue) -> Result<Option<JsonValue>, Error> {
+        // from the record as it was logged, before the transforms
+        if let Some(partition_key) = &self.partition_key {
+            let request_id = self.request_id.lock().unwrap_or_else(|e| e.into_inner()).clone();
+
+            if let Some(pk) = partition_key.key(&json, &body, request_id.as_deref(), self.function_name.as_deref()) {
+                json.insert(PARTITION_KEY_FIELD, pk)?;
+            }
+        }
+
+        let sub_ms_nanos = take_sub_ms_nanos(&mut json);
+        let mut record = layout::envelope(json, body);
+
+        if is_empty(&record) {
+            self.stats.empty_records.fetch_add(1, Ordering::Relaxed);
+
+            if self.drop_empty {
+                return Ok(None);
+            }
+        }
+
+        // told apart before the transforms, which go by `record`, may have dropped anything else
+        let line = self.message_key.is_some() && is_line(&record);
+        // the object as it was logged, whatever the transforms make of it
+        let raw = match self.emit_both_max_bytes {
+            Some(max) if is_object(&record) && estimated_size(&record["body"]) <= max => Some(record["body"].clone()),
+            _ => None,
+        };
+
+        for transform in self.transforms.iter() {
+            transform.transform(&mut record);
+        }
+
+        if let Some(raw) = raw {
+            // body is always an object
+            let _ = record["body"].insert(RAW_FIELD, raw);
+        }
+
+        if let Some(message_key) = self.message_key.as_deref().filter(|_| line) {
+            let message = record["body"].remove("record");
+
+            // body is always an object
+            let _ = record["body"].insert(message_key, message);
+        }
+
+        // of the record as it's shipped, whatever the transforms made of it
+        if let Some(content_hash) = &self.content_hash {
+            content_hash.stamp(&mut record);
+        }
+
+        let mut json = match self.format {
+            Format::Json => self.layout.apply(record),
+            Format::Otel => otel::log_record(record),
+            Format::Loki => loki::push(record, self.function_name.as_deref()),
+            Format::Logfmt => Layout::Flat.apply(record),
+        };
+
+        // OTel and Loki have nanosecond timestamps of their own
+        if matches!(self.format, Format::Json | Format::Logfmt) {
+            self.time_precision.apply(&mut json, sub_ms_nanos);
+        }
+
+        if let Some(errors) = self.schema.as_ref().map(|schema| schema.validate(&json)).filter(|errors| !errors.is_empty()) {
+            self.stats.schema_invalid.fetch_add(1, Ordering::Relaxed);
+
+            match self.schema_on_fail {
+                SchemaOnFail::Mark => json.insert(SCHEMA_INVALID_FIELD, errors)?,
+                on_fail => {
+                    warn!("Record doesn't match the schema, {} (type {}, request_id {}): {}",
+                          if on_fail == SchemaOnFail::Drop { "dropped" } else { "shipped anyway" },
+                          invocation::field(&json, "type").as_deref().unwrap_or("-"),
+                          invocation::field(&json, "request_id").as_deref().unwrap_or("-"),
+                          errors.join("; "));
+
+                    if on_fail == SchemaOnFail::Drop {
+                        return Ok(None);
+                    }
+                }
+            }
+        }
+
+        self.warn_if_oversized(&json);
+        Ok(Some(json))
+    }
+
+    /// With `warn_record_bytes`, logs the type, request id, size, and start of a record larger than that.
+    /// Only a record whose estimated size is over the limit is serialized, to be measured.
+    fn warn_if_oversized(&self, json: &JsonValue) {
+        let max = match self.warn_record_bytes {
+            Some(max) if estimated_size(json) > max => max,
+            _ => return,
+        };
+
+        let line = json.dump();
+
+        if line.len() as u64 <= max {
+            return;
+        }
+
+        warn!("Record of {} bytes is over {} (type {}, request_id {}): {}...",
+              line.len(), max,
+              invocation::field(json, "type").as_deref().unwrap_or("-"),
+              invocation::field(json, "request_id").as_deref().unwrap_or("-"),
+              line.chars().take(OVERSIZED_PREFIX_CHARS).collect::<String>());
+    }
+}
+
+/// The transforms every record goes through before any registered with `with_transform`, in order.
+fn built_in_transforms(config: &Config) -> Vec<Box<dyn RecordTransform>> {
+    let mut transforms: Vec<Box<dyn RecordTransform>> = Vec::new();
+
+    // before the leveler, so a colored level is still one
+    if config.strip_ansi {
+        transforms.push(Box::new(AnsiStripper));
+    }
+
+    transforms.push(Box::new(Leveler::new(config.severity_map.clone())));
+
+    // after the leveler, which may need a field that isn't kept
+    if let Some(keep_fields) = &config.keep_fields {
+        transforms.push(Box::new(keep_fields.clone()));
+    }
+
+    // after keep_fields, which would drop them
+    if let Some(enrich) = &config.enrich {
+        transforms.push(Box::new(enrich.clone()));
+    }
+
+    if config.normalize_newlines {
+        transforms.push(Box::new(NewlineNormalizer::new(config.newline_replacement.clone())));
+    }
+
+    // after the leveler, whose severity it goes by
+    if let Some(ttl_map) = &config.ttl_map {
+        transforms.push(Box::new(ttl_map.clone()));
+    }
+
+    transforms
+}
+
+/// True if a function's JSON objects can be queued as they were logged, with `HandlerState::splice`: with
+/// `splice` and `preserialize`, an `Encoder` that adds nothing of its own, and a flat JSON record that nothing
+/// but the `Leveler` looks into or adds to.
+fn splices(config: &Config, encoder: Option<&Encoder>) -> bool {
+    config.splice && encoder.is_some_and(Encoder::is_plain) && config.format == Format::Json && config.layout == Layout::Flat
+        // the transforms `built_in_transforms` adds to the leveler
+        && !config.strip_ansi && config.keep_fields.is_none() && config.enrich.is_none() && !config.normalize_newlines && config.ttl_map.is_none()
+        // and whatever else goes by the function's fields, or how they're parsed
+        && config.prefer_record_time.is_none() && config.partition_key.is_none() && !config.include_hash && !config.emit_both
+        && config.schema.is_none() && config.warn_record_bytes.is_none() && config.max_flatten_depth.is_none() && config.bigint == BigInt::Keep
+}
+
+/// Takes the nanoseconds past `t`'s millisecond that `new_record` stamped, to be added back in the `time_precision`.
+/// A `t` that was clamped isn't the record's own time any more, so it has none.
+fn take_sub_ms_nanos(json: &mut JsonValue) -> i64 {
+    match json.remove(SUB_MS_FIELD).as_i64() {
+        Some(_) if json["t_clamped"].as_bool() == Some(true) => 0,
+        sub_ms_nanos => sub_ms_nanos.unwrap_or_default(),
+    }
+}
+
+/// The number Rust gives the current thread (a tokio worker, in the extension), unique within the process.
+/// There's no stable way to get it as a number, so it's read from `ThreadId`'s `Debug`, e.g. `ThreadId(5)`.
+fn thread_id() -> Option<u64> {
+    let id = format!("{:?}", std::thread::current().id());
+
+    id.trim_start_matches("ThreadId(").trim_end_matches(')').parse().ok()
+}
+
+/// True for a function or extension record that's a log line that wasn't a JSON object, under `record`.
+fn is_line(record: &JsonValue) -> bool {
+    matches!(record["meta"]["type"].as_str(), Some("function") | Some("extension"))
+        && record["body"].has_key("record")
+        && record["body"].entries().all(|(k, _)| LINE_FIELDS.contains(&k))
+}
+
+/// True for a function or extension record that's a JSON object the function logged.
+fn is_object(record: &JsonValue) -> bool {
+    matches!(record["meta"]["type"].as_str(), Some("function") | Some("extension"))
+        && !record["body"].entries().all(|(k, _)| LINE_FIELDS.contains(&k))
+}
+
+/// True for a function or extension record with nothing in it but the fields the extension added.
+/// Platform records are never empty: their own fields are the content.
+fn is_empty(record: &JsonValue) -> bool {
+    let body = &record["body"];
+
+    match record["meta"]["type"].as_str() {
+        Some("function") | Some("extension") => body.is_empty() ||
+            (body.len() == 1 && body["record"].as_str().is_some_and(|r| r.trim().is_empty())),
+        _ => false,
+    }
+}
+
+/// A function's log line as fields: JSON objects as they are, anything else under `record` (a bare primitive
+/// as its JSON value, or with `BarePrimitives::Text` as the line it was). With `mark_parse_failure`, a line that
+/// looks like JSON but isn't gets `"parse_failed": true`. With `BigInt::String`, integers too large for a double
+/// are parsed as strings. With `max_depth`, what's nested deeper in an object is kept as its JSON text.
+fn function_body(record: String, mark_parse_failure: bool, dup_keys: DupKeys, bare_primitives: BarePrimitives, bigint: BigInt,
+                 max_depth: Option<usize>) -> JsonValue {
+    let parsed = {
+        let line = match bigint {
+            BigInt::String => bigint::quote_big_integers(record.as_str()),
+            BigInt::Keep => Cow::Borrowed(record.as_str()),
+        };
+
+        json::parse(&line).map(|json| match json {
+            JsonValue::Object(obj) => dup_keys.resolve(&line, JsonValue::Object(obj)),
+            json => json,
+        })
+    };
+
+    // attempt to parse the record as JSON
+    match parsed {
+        Ok(JsonValue::Object(obj)) => {
+            let mut json = JsonValue::Object(obj);
+
+            if let Some(max_depth) = max_depth {
+                cap_depth(&mut json, max_depth);
+            }
+
+            json
+        }
+        // skip entirely
+        Ok(JsonValue::Null) => JsonValue::new_object(),
+        Ok(JsonValue::Array(values)) => object! { "record": values },
+        Ok(_) if bare_primitives == BarePrimitives::Text => object! { "record": record },
+        Ok(json_value) => object! { "record": json_value },
+        Err(_) if mark_parse_failure && record.trim_start().starts_with(['{', '[']) => {
+            object! { "record": record, "parse_failed": true }
+        }
+        Err(_) => object! { "record": record },
+    }
+}
+
+/// Replaces each object or array `depth` levels down in `json` (its own fields being the first level) with its
+/// JSON text, so no record is nested any deeper than that.
+fn cap_depth(json: &mut JsonValue, depth: usize) {
+    let values: Box<dyn Iterator<Item = &mut JsonValue>> = match json {
+        JsonValue::Object(obj) => Box::new(obj.iter_mut().map(|(_, value)| value)),
+        JsonValue::Array(values) => Box::new(values.iter_mut()),
+        _ => return,
+    };
+
+    for value in values.filter(|value| value.is_object() || value.is_array()) {
+        match depth {
+            0 | 1 => *value = value.dump().into(),
+            _ => cap_depth(value, depth - 1),
+        }
+    }
+}
+
+fn insert_report(json: &mut JsonValue,
+                 duration_ms: f64,
+                 billed_duration_ms: u64,
+                 memory_size_mb: u64,
+                 max_memory_used_mb: u64,
+                 init_duration_ms: Option<f64>) -> Result<(), Error> {
+    json.insert("type", "platform_report")?;
+    json.insert("duration_ms", duration_ms)?;
+    json.insert("billed_duration_ms", billed_duration_ms)?;
+    json.insert("memory_size_mb", memory_size_mb)?;
+    json.insert("max_memory_used_mb", max_memory_used_mb)?;
+    json.insert("init_duration_ms", init_duration_ms)?;
+
+    Ok(())
+}
+
+/// With `report_derived`, what a `platform_report` says about how well the function is sized, from the fields
+/// `insert_report` added: any it can't be worked out from (a memory size of 0, say) is left out.
+fn insert_report_derived(json: &mut JsonValue) -> Result<(), Error> {
+    if let (Some(billed), Some(duration)) = (json["billed_duration_ms"].as_f64(), json["duration_ms"].as_f64()) {
+        json.insert("billed_overhead_ms", round_to_thousandths(billed - duration))?;
+    }
+
+    let utilization = match (json["max_memory_used_mb"].as_f64(), json["memory_size_mb"].as_f64()) {
+        (Some(used), Some(size)) if size > 0.0 => used / size,
+        _ => return Ok(()),
+    };
+
+    json.insert("memory_utilization", round_to_thousandths(utilization))?;
+    json.insert("right_sized", RIGHT_SIZED_UTILIZATION.contains(&utilization))?;
+
+    Ok(())
+}
+
+// clears the noise of the subtraction (100 - 95.123 is 4.877000000000002)
+fn round_to_thousandths(value: f64) -> f64 {
+    (value * 1000.0).round() / 1000.0
+}
+
+/// An extension registering (`kind` is `extension`, with the `events` it registered for) or subscribing to
+/// the Logs or Telemetry API (with the `types` of records it subscribed to).
+fn insert_platform_extension(json: &mut JsonValue, kind: &str, name: String, state: String, list_field: &str, list: Vec<String>) -> Result<(), Error> {
+    json.insert("type", "platform_extension")?;
+    json.insert("kind", kind)?;
+    json.insert("name", name)?;
+    json.insert("state", state)?;
+    json.insert(list_field, list)?;
+
+    Ok(())
+}
+
+/// Records that Lambda itself dropped before they reached us, e.g. because we were too slow to take them.
+fn insert_logs_dropped(json: &mut JsonValue, stats: &Stats, reason: String, dropped_records: u64, dropped_bytes: u64) -> Result<(), Error> {
+    warn!("Lambda dropped {} records ({} bytes): {}", dropped_records, dropped_bytes, reason);
+    stats.platform_dropped.fetch_add(dropped_records, Ordering::Relaxed);
+
+    json.insert("type", "platform_logs_dropped")?;
+    json.insert("reason", reason)?;
+    json.insert("dropped_records", dropped_records)?;
+    json.insert("dropped_bytes", dropped_bytes)?;
+
+    Ok(())
+}
+
+pub async fn handler(logs: Vec<LambdaLog>, state: Arc<HandlerState>) -> Result<(), Error> {
+    debug!("Received a batch of {} logs", logs.len());
+    state.stats.record_batch(logs.len());
+
+    let mut records = Vec::with_capacity(logs.len());
+
+    let logs = state.reassemble(
+        logs.into_iter().map(|log| (log.time.timestamp_nanos(), log.record)).collect(),
+        |record| match record {
+            LambdaLogRecord::Function(line) => Some(std::mem::take(line)),
+            _ => None,
+        },
+        LambdaLogRecord::Function,
+    );
+
+    for (time_ns, mut record, split) in logs {
+        if let LambdaLogRecord::Function(line) = &record {
+            if let Some(spliced) = state.splice(time_ns, line, split)? {
+                records.extend(spliced);
+                continue;
+            }
+        }
+
+        // parsed before the record is started, so with `prefer_record_time` the time it logged can be its `t`, and
+        // one that's dropped (per `nonutf8`, or past the limit) isn't started at all: it takes no `seq`, `id`, or `mt`
+        let parsed = match &mut record {
+            LambdaLogRecord::Function(line) => match state.function_body(std::mem::take(line)) {
+                Some(body) if state.admit() => Some(body),
+                _ => continue,
+            },
+            _ => None,
+        };
+        let time_ns = state.event_time(parsed.as_ref(), time_ns);
+        let mut json = state.new_record(time_ns, matches!(record, LambdaLogRecord::PlatformStart { .. }))?;
+        let mut body = JsonValue::new_object();
+        let mut notice = None;
+
+        match record {
+            LambdaLogRecord::Function(_) => {
+                json.insert("type", "function")?;
+                body = parsed.unwrap_or_else(JsonValue::new_object);
+
+                if split {
+                    body.insert("split", true)?;
+                }
+            },
+            // LambdaLogRecord::Extension(record) => {
+            //     json.insert("type", "extension")?;
+            //     json.insert("record", record)?;
+            // },
+            LambdaLogRecord::PlatformStart {request_id} => {
+                state.start_invocation(request_id.as_str());
+                json.insert("type", "platform_start")?;
+                json.insert("request_id", request_id)?;
+            }
+            LambdaLogRecord::PlatformEnd {request_id} => {
+                notice = state.truncation_notice(time_ns, request_id.as_str())?;
+                json.insert("type", "platform_end")?;
+                json.insert("request_id", request_id)?;
+            }
+            LambdaLogRecord::PlatformFault(record) => {
+                json.insert("type", "platform_fault")?;
+
+                // they're free-form, so they're only parsed if asked to be
+                match state.parse_fault_json {
+                    true => body = function_body(record, false, state.dup_keys, state.bare_primitives, state.bigint, state.max_flatten_depth),
+                    false => json.insert("record", record)?,
+                }
+            }
+            LambdaLogRecord::PlatformReport {request_id, metrics} => {
+                insert_report(&mut json, metrics.duration_ms, metrics.billed_duration_ms, metrics.memory_size_mb,
+                              metrics.max_memory_used_mb, metrics.init_duration_ms)?;
+
+                if state.report_derived {
+                    insert_report_derived(&mut json)?;
+                }
+
+                json.insert("request_id", request_id)?;
+            }
+            LambdaLogRecord::PlatformLogsDropped {reason, dropped_records, dropped_bytes} => {
+                insert_logs_dropped(&mut json, &state.stats, reason, dropped_records, dropped_bytes)?;
+            }
+            LambdaLogRecord::PlatformExtension {name, state: extension_state, events} => {
+                match state.ship_platform_extension {
+                    true => insert_platform_extension(&mut json, "extension", name, extension_state, "events", events)?,
+                    false => continue,
+                }
+            }
+            LambdaLogRecord::PlatformLogsSubscription {name, state: subscription_state, types} => {
+                match state.ship_platform_extension {
+                    true => insert_platform_extension(&mut json, "logs_subscription", name, subscription_state, "types", types)?,
+                    false => continue,
+                }
+            }
+            _ => (),
+        }
+
+        state.tag_phase(&mut json)?;
+        state.tag_trace(&mut json)?;
+        records.extend(state.finish_record(json, body)?);
+
+        if let Some(notice) = notice {
+            records.extend(state.finish_record(notice, JsonValue::new_object())?);
+        }
+    }
+
+    state.enqueue(records).await
+}
+
+/// The Telemetry API equivalent of `handler`. Records common to both APIs are mapped the same way;
+/// the spans attached to init/runtime done/report records are sent as their own `span` records.
+pub async fn telemetry_handler(events: Vec<LambdaTelemetry>, state: Arc<HandlerState>) -> Result<(), Error> {
+    debug!("Received a batch of {} telemetry events", events.len());
+    state.stats.record_batch(events.len());
+
+    let mut records = Vec::with_capacity(events.len());
+
+    let events = state.reassemble(
+        events.into_iter().map(|event| (event.time.timestamp_nanos(), event.record)).collect(),
+        |record| match record {
+            LambdaTelemetryRecord::Function(line) => Some(std::mem::take(line)),
+            _ => None,
+        },
+        LambdaTelemetryRecord::Function,
+    );
+
+    for (time_ns, mut record, split) in events {
+        if let LambdaTelemetryRecord::Function(line) = &record {
+            if let Some(spliced) = state.splice(time_ns, line, split)? {
+                records.extend(spliced);
+                continue;
+            }
+        }
+
+        // parsed before the record is started, so with `prefer_record_time` the time it logged can be its `t`, and
+        // one that's dropped (per `nonutf8`, or past the limit) isn't started at all: it takes no `seq`, `id`, or `mt`
+        let parsed = match &mut record {
+            LambdaTelemetryRecord::Function(line) => match state.function_body(std::mem::take(line)) {
+                Some(body) if state.admit() => Some(body),
+                _ => continue,
+            },
+            _ => None,
+        };
+        let time_ns = state.event_time(parsed.as_ref(), time_ns);
+        let mut json = state.new_record(time_ns, matches!(record, LambdaTelemetryRecord::PlatformStart { .. }))?;
+        let mut body = JsonValue::new_object();
+        let mut spans = Vec::new();
+        let mut span_request_id = None;
+        let mut notice = None;
+
+        match record {
+            LambdaTelemetryRecord::Function(_) => {
+                json.insert("type", "function")?;
+                body = parsed.unwrap_or_else(JsonValue::new_object);
+
+                if split {
+                    body.insert("split", true)?;
+                }
+            }
+            LambdaTelemetryRecord::PlatformInitStart {initialization_type, phase, runtime_version, runtime_version_arn} => {
+                json.insert("type", "platform_init_start")?;
+                json.insert("initialization_type", init_type_str(&initialization_type))?;
+                json.insert("phase", init_phase_str(&phase))?;
+                json.insert("runtime_version", runtime_version)?;
+                json.insert("runtime_version_arn", runtime_version_arn)?;
+            }
+            LambdaTelemetryRecord::PlatformInitRuntimeDone {initialization_type, phase, status, error_type, spans: s} => {
+                json.insert("type", "platform_init_runtime_done")?;
+                json.insert("initialization_type", init_type_str(&initialization_type))?;
+                json.insert("phase", phase.as_ref().map(init_phase_str))?;
+                json.insert("status", status_str(&status))?;
+                json.insert("error_type", error_type)?;
+                spans = s;
+            }
+            LambdaTelemetryRecord::PlatformInitReport {initialization_type, phase, metrics, spans: s} => {
+                json.insert("type", "platform_init_report")?;
+                json.insert("initialization_type", init_type_str(&initialization_type))?;
+                json.insert("phase", init_phase_str(&phase))?;
+                json.insert("duration_ms", metrics.duration_ms)?;
+                spans = s;
+            }
+            LambdaTelemetryRecord::PlatformStart {request_id, version, tracing} => {
+                state.start_invocation(request_id.as_str());
+                json.insert("type", "platform_start")?;
+                json.insert("request_id", request_id)?;
+                json.insert("version", version)?;
+                insert_tracing(&mut json, tracing)?;
+            }
+            LambdaTelemetryRecord::PlatformRuntimeDone {request_id, status, error_type, metrics, spans: s, tracing} => {
+                notice = state.truncation_notice(time_ns, request_id.as_str())?;
+                json.insert("type", "platform_runtime_done")?;
+                json.insert("request_id", request_id.as_str())?;
+                json.insert("status", status_str(&status))?;
+                json.insert("error_type", error_type)?;
+
+                if let Some(metrics) = metrics {
+                    json.insert("duration_ms", metrics.duration_ms)?;
+                    json.insert("produced_bytes", metrics.produced_bytes)?;
+                }
+
+                insert_tracing(&mut json, tracing)?;
+                spans = s;
+                span_request_id = Some(request_id);
+            }
+            LambdaTelemetryRecord::PlatformReport {request_id, status, error_type, metrics, spans: s, tracing} => {
+                insert_report(&mut json, metrics.duration_ms, metrics.billed_duration_ms, metrics.memory_size_mb,
+                              metrics.max_memory_used_mb, metrics.init_duration_ms)?;
+
+                if state.report_derived {
+                    insert_report_derived(&mut json)?;
+                }
+
+                json.insert("request_id", request_id.as_str())?;
+                json.insert("restore_duration_ms", metrics.restore_duration_ms)?;
+                json.insert("status", status_str(&status))?;
+                json.insert("error_type", error_type)?;
+                insert_tracing(&mut json, tracing)?;
+                spans = s;
+                span_request_id = Some(request_id);
+            }
+            LambdaTelemetryRecord::PlatformLogsDropped {reason, dropped_records, dropped_bytes} => {
+                insert_logs_dropped(&mut json, &state.stats, reason, dropped_records, dropped_bytes)?;
+            }
+            LambdaTelemetryRecord::PlatformExtension {name, state: extension_state, events} => {
+                match state.ship_platform_extension {
+                    true => insert_platform_extension(&mut json, "extension", name, extension_state, "events", events)?,
+                    false => continue,
+                }
+            }
+            LambdaTelemetryRecord::PlatformTelemetrySubscription {name, state: subscription_state, types} => {
+                match state.ship_platform_extension {
+                    true => insert_platform_extension(&mut json, "telemetry_subscription", name, subscription_state, "types", types)?,
+                    false => continue,
+                }
+            }
+            _ => (),
+        }
+
+        let parent = json["type"].as_str().unwrap_or_default().to_string();
+
+        state.tag_phase(&mut json)?;
+        state.tag_trace(&mut json)?;
+        records.extend(state.finish_record(json, body)?);
+
+        for span in spans {
+            let mut json = state.new_record(span.start.timestamp_nanos(), false)?;
+
+            insert_span(&mut json, &span, parent.as_str())?;
+            json.insert("request_id", span_request_id.clone())?;
+
+            records.extend(state.finish_record(json, JsonValue::new_object())?);
+        }
+
+        if let Some(notice) = notice {
+            records.extend(state.finish_record(notice, JsonValue::new_object())?);
+        }
+    }
+
+    state.enqueue(records).await
+}
+
+fn insert_span(json: &mut JsonValue, span: &Span, parent: &str) -> Result<(), Error> {
+    let start_ns = span.start.timestamp() * 1_000_000_000 + span.start.timestamp_subsec_nanos() as i64;
+
+    json.insert("type", "span")?;
+    json.insert("name", span.name.as_str())?;
+    json.insert("parent", parent)?;
+    json.insert("start_ns", start_ns)?;
+    json.insert("end_ns", start_ns + (span.duration_ms * 1_000_000.0) as i64)?;
+    json.insert("duration_ms", span.duration_ms)?;
+
+    Ok(())
+}
+
+fn insert_tracing(json: &mut JsonValue, tracing: Option<TraceContext>) -> Result<(), Error> {
+    if let Some(tracing) = tracing {
+        json.insert("trace", tracing.value)?;
+        json.insert("span_id", tracing.span_id)?;
+    }
+
+    Ok(())
+}
+
+fn init_type_str(init_type: &InitType) -> &'static str {
+    match init_type {
+        InitType::OnDemand => "on-demand",
+        InitType::ProvisionedConcurrency => "provisioned-concurrency",
+        InitType::SnapStart => "snap-start",
+    }
+}
+
+fn init_phase_str(phase: &InitPhase) -> &'static str {
+    match phase {
+        InitPhase::Init => "init",
+        InitPhase::Invoke => "invoke",
+    }
+}
+
+fn status_str(status: &Status) -> &'static str {
+    match status {
+        Status::Success => "success",
+        Status::Error => "error",
+        Status::Failure => "failure",
+        Status::Timeout => "timeout",
+    }
+}
@@ -0,0 +1,68 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use json::JsonValue;
+
+use crate::encoder::sort_keys;
+
+/// The field holding a record's content hash, when `include_hash` is set.
+pub const HASH_FIELD: &str = "h";
+
+// what changes between two sends of the same record
+const DEFAULT_EXCLUDE: [&str; 4] = ["t", "it", "up_ms", "seq"];
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// Hashes a record's content, for the log-store to spot duplicates by: the record with its keys sorted and
+/// the `exclude`d fields left out, hashed with 64-bit FNV-1a. Parsed from the comma-separated fields to
+/// leave out; none at all to hash everything.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ContentHash {
+    exclude: Vec<String>,
+}
+
+impl Default for ContentHash {
+    fn default() -> Self {
+        ContentHash { exclude: DEFAULT_EXCLUDE.iter().map(|field| field.to_string()).collect() }
+    }
+}
+
+impl ContentHash {
+    /// Adds `h` to an envelope's `meta` (see `layout::envelope`), as 16 hex digits.
+    pub fn stamp(&self, record: &mut JsonValue) {
+        let mut content = record.clone();
+
+        for part in ["meta", "body"] {
+            for field in self.exclude.iter() {
+                content[part].remove(field);
+            }
+        }
+
+        let hash = fnv1a(sort_keys(&content).dump().as_bytes());
+        let _ = record["meta"].insert(HASH_FIELD, format!("{:016x}", hash));
+    }
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(FNV_OFFSET_BASIS, |hash, b| (hash ^ *b as u64).wrapping_mul(FNV_PRIME))
+}
+
+impl FromStr for ContentHash {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let exclude = s.split(',')
+            .map(str::trim)
+            .filter(|field| !field.is_empty())
+            .map(str::to_string)
+            .collect();
+
+        Ok(ContentHash { exclude })
+    }
+}
+
+impl Display for ContentHash {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.exclude.join(","))
+    }
+}
@@ -0,0 +1,60 @@
+use std::sync::{Arc, Weak};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use json::{JsonValue, object};
+use tracing::warn;
+
+use crate::config::Config;
+use crate::handler::HandlerState;
+use crate::stats::Stats;
+
+/// With `health_interval_secs`, how often the TCP writer sends a `health` record, and the handlers' state that
+/// makes them, so they're stamped, transformed, laid out, and formatted as the records from Lambda are.
+///
+/// The state is held weakly: it holds the sending side of the channel the writer reads from, which would
+/// otherwise never close.
+#[derive(Clone)]
+pub struct Health {
+    state: Weak<HandlerState>,
+    interval: Duration,
+}
+
+impl Health {
+    /// `None` without `health_interval_secs`.
+    pub fn new(config: &Config, state: &Arc<HandlerState>) -> Option<Health> {
+        (config.health_interval_secs > 0).then(|| Health {
+            state: Arc::downgrade(state),
+            interval: Duration::from_secs(config.health_interval_secs),
+        })
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// A `health` record, for telling from the log-store's side whether this function's logging is healthy:
+    /// whether the writer is `connected`, what's waiting on the queue, how many records have been dropped in all,
+    /// reconnects, and how long ago anything was last written (`null` if nothing has been). `None` once the
+    /// handlers' state has gone, or if the record is dropped (as empty records or by the schema are).
+    pub fn record(&self, stats: &Stats, connected: bool) -> Option<JsonValue> {
+        let state = self.state.upgrade()?;
+        let now_ms = stats.now_ms();
+        let last_written_ms = stats.last_written_ms.load(Ordering::Relaxed);
+        let fields = object! {
+            "type": "health",
+            "connected": connected,
+            "channel_depth": stats.queue_depth(),
+            "dropped_total": stats.dropped_total(),
+            "reconnects": stats.reconnects.load(Ordering::Relaxed),
+            "last_write_ago_ms": (last_written_ms > 0).then(|| now_ms.saturating_sub(last_written_ms)),
+        };
+
+        match state.own_record(fields) {
+            Ok(json) => json,
+            Err(e) => {
+                warn!("Error making a health record: {}", e);
+                None
+            }
+        }
+    }
+}
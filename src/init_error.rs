@@ -0,0 +1,28 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use json::{JsonValue, object};
+
+/// A recoverable error the extension hit during init, e.g. a spill directory it can't write to. These are
+/// collected while the sinks are being set up and sent, with `ship_init_errors`, once they are. Errors from
+/// the writers and sinks themselves are only logged: sending them through the sink that's failing would loop.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InitError {
+    pub detail: String,
+}
+
+impl InitError {
+    pub fn new(detail: impl Into<String>) -> InitError {
+        InitError { detail: detail.into() }
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+
+        object! {
+            "t": now.as_millis() as u64,
+            "type": "extension_error",
+            "phase": "init",
+            "severity": "warn",
+            "detail": self.detail.as_str(),
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::Duration;
+use json::JsonValue;
+use tokio::time::Instant;
+
+use crate::layout;
+use crate::stats::estimated_size;
+
+/// What the TCP writer writes as a unit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BatchBy {
+    /// Each record on its own line
+    Record,
+    /// All of an invocation's records in a single JSON array, once its `platform_report` arrives
+    Invocation,
+}
+
+impl FromStr for BatchBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "record" => Ok(BatchBy::Record),
+            "invocation" => Ok(BatchBy::Invocation),
+            _ => Err(format!("unknown batching {:?}, expected record or invocation", s)),
+        }
+    }
+}
+
+impl Display for BatchBy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BatchBy::Record => write!(f, "record"),
+            BatchBy::Invocation => write!(f, "invocation"),
+        }
+    }
+}
+
+/// The records of the invocation in progress.
+struct Held {
+    request_id: String,
+    records: Vec<JsonValue>,
+    bytes: u64,
+    deadline: Instant,
+}
+
+/// Holds the records from a `platform_start` up to the `platform_report` with the same `request_id`, and hands
+/// them back together. Records outside an invocation (e.g. during init) are handed back right away. Once what's
+/// held has been held for `timeout` or has grown past `max_bytes`, it's handed back as far as it got, and the
+/// rest of that invocation goes record by record.
+pub struct InvocationBatcher {
+    timeout: Duration,
+    max_bytes: u64,
+    held: Option<Held>,
+}
+
+impl InvocationBatcher {
+    pub fn new(timeout: Duration, max_bytes: u64) -> InvocationBatcher {
+        InvocationBatcher { timeout, max_bytes, held: None }
+    }
+
+    /// Takes a record, returning the frames that are ready to write: single records are handed back
+    /// as they are, and an invocation's records as an array.
+    pub fn push(&mut self, json: JsonValue) -> Vec<JsonValue> {
+        let mut ready = Vec::new();
+        let record_type = field(&json, "type");
+        // only the records that start and end an invocation are looked at any closer
+        let request_id = match record_type.as_deref() {
+            Some("platform_start") | Some("platform_report") => field(&json, "request_id"),
+            _ => None,
+        };
+
+        match (record_type.as_deref(), request_id) {
+            (Some("platform_start"), Some(request_id)) => {
+                // the previous invocation never reported
+                ready.extend(self.take());
+                self.held = Some(Held {
+                    request_id,
+                    records: vec![],
+                    bytes: 0,
+                    deadline: Instant::now() + self.timeout,
+                });
+                self.hold(json);
+            }
+            (Some("platform_report"), Some(request_id)) if self.is_holding(request_id.as_str()) => {
+                self.hold(json);
+                ready.extend(self.take());
+            }
+            _ if self.held.is_some() => {
+                self.hold(json);
+
+                if self.held.as_ref().is_some_and(|held| held.bytes >= self.max_bytes) {
+                    ready.extend(self.take());
+                }
+            }
+            _ => ready.push(json),
+        }
+
+        ready
+    }
+
+    /// The estimated size of what's held.
+    pub fn bytes(&self) -> u64 {
+        self.held.as_ref().map_or(0, |held| held.bytes)
+    }
+
+    /// When what's held is due to be handed back without its `platform_report`.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.held.as_ref().map(|held| held.deadline)
+    }
+
+    /// Hands back what's held, e.g. on the deadline or at shutdown, and stops holding.
+    pub fn take(&mut self) -> Option<JsonValue> {
+        let held = self.held.take()?;
+
+        (!held.records.is_empty()).then(|| JsonValue::Array(held.records))
+    }
+
+    fn is_holding(&self, request_id: &str) -> bool {
+        self.held.as_ref().is_some_and(|held| held.request_id == request_id)
+    }
+
+    fn hold(&mut self, json: JsonValue) {
+        if let Some(held) = self.held.as_mut() {
+            held.bytes += estimated_size(&json);
+            held.records.push(json);
+        }
+    }
+}
+
+/// A string field of a record in any layout or format: OTel records have ours as attributes, and Loki ones
+/// have the type as a label and everything else in the line.
+pub(crate) fn field(json: &JsonValue, key: &str) -> Option<String> {
+    if json.has_key("streams") {
+        let stream = &json["streams"][0];
+
+        return match key {
+            "type" => stream["stream"]["type"].as_str().map(str::to_string),
+            _ => json::parse(stream["values"][0][1].as_str()?).ok()?[key].as_str().map(str::to_string),
+        };
+    }
+
+    let value = layout::field(json, key);
+
+    if !value.is_null() {
+        return value.as_str().map(str::to_string);
+    }
+
+    json["attributes"].members()
+        .find(|attr| attr["key"] == key)
+        .and_then(|attr| attr["value"]["stringValue"].as_str())
+        .map(str::to_string)
+}
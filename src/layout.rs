@@ -0,0 +1,182 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use json::{JsonValue, object};
+
+/// The fields the extension adds to every record, which go under `meta` in the envelope layout.
+pub const META_FIELDS: [&str; 16] = ["t", "it", "mt", "t_clamped", "id", "up_ms", "mem_limit_mb", "pid", "tid", "type", "seq", "seq_scope", "phase", "trace_id", "pk", "severity"];
+
+/// How a record's fields are arranged.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Layout {
+    /// Everything at the top level; a function's own fields override ours
+    Flat,
+    /// `{"meta":{<META_FIELDS>},"body":{<everything else>}}`
+    Envelope,
+}
+
+/// Builds the envelope from the fields the extension built (`json`) and a function's own fields (`body`).
+/// This is how records are handled until they're enqueued, whatever the layout.
+pub fn envelope(mut json: JsonValue, mut body: JsonValue) -> JsonValue {
+    let mut meta = object! {};
+    let mut rest = object! {};
+
+    // both are always objects, so the inserts can't fail
+    for (k, v) in json.entries_mut() {
+        let fields = if META_FIELDS.contains(&k) { &mut meta } else { &mut rest };
+        let _ = fields.insert(k, v.take());
+    }
+
+    // when there's nothing of ours to add, the function's fields are the body as they are
+    if rest.is_empty() && body.is_object() {
+        return object! { "meta": meta, "body": body };
+    }
+
+    for (k, v) in body.entries_mut() {
+        let _ = rest.insert(k, v.take());
+    }
+
+    object! { "meta": meta, "body": rest }
+}
+
+impl Layout {
+    /// Lays out a record built as an envelope.
+    pub fn apply(&self, mut record: JsonValue) -> JsonValue {
+        match self {
+            Layout::Envelope => record,
+            Layout::Flat => {
+                let severity = record["meta"]["severity"].take();
+                let mut json = object! {};
+
+                // a function's own fields override ours, apart from the severity
+                for part in ["meta", "body"] {
+                    for (k, v) in record[part].entries_mut() {
+                        let _ = json.insert(k, v.take());
+                    }
+                }
+
+                if !severity.is_null() {
+                    let _ = json.insert("severity", severity);
+                }
+
+                json
+            }
+        }
+    }
+
+    /// Lays out a record built flat, e.g. a `config_warning`.
+    pub fn arrange(&self, json: JsonValue) -> JsonValue {
+        self.apply(envelope(json, JsonValue::new_object()))
+    }
+}
+
+/// A field of a record in either layout: at the top level, or under `meta` or `body` for the envelope.
+pub fn field<'a>(json: &'a JsonValue, key: &str) -> &'a JsonValue {
+    if json.has_key(key) {
+        &json[key]
+    } else if json["meta"].has_key(key) {
+        &json["meta"][key]
+    } else {
+        &json["body"][key]
+    }
+}
+
+/// The field a record's `t` is shipped under: `default` (`LOG_STORE_TIME_FIELD`), or for the record types
+/// listed in `LOG_STORE_TIME_FIELD_BY_TYPE` (`platform_report:metric_t,...`), theirs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TimeFields {
+    default: String,
+    by_type: Vec<(String, String)>,
+}
+
+impl TimeFields {
+    pub fn new(default: &str, by_type: Option<&TimeFields>) -> TimeFields {
+        let by_type = by_type.map(|by_type| by_type.by_type.clone()).unwrap_or_default();
+
+        let default = if default.trim().is_empty() { "t" } else { default.trim() };
+
+        TimeFields { default: default.to_string(), by_type }
+    }
+
+    /// True if every record's time is shipped as `t`, as it's built.
+    pub fn is_default(&self) -> bool {
+        self.default == "t" && self.by_type.iter().all(|(_, field)| field == "t")
+    }
+
+    /// Renames `t` in a laid-out record (or each record of an invocation's frame), where it is.
+    pub fn rename(&self, json: &mut JsonValue) {
+        if json.is_array() {
+            return json.members_mut().for_each(|record| self.rename(record));
+        }
+
+        let record_type = field(json, "type").as_str();
+        let name = self.by_type.iter()
+            .find(|(t, _)| Some(t.as_str()) == record_type)
+            .map_or(self.default.as_str(), |(_, name)| name.as_str())
+            .to_string();
+        let fields = if json.has_key("meta") { &mut json["meta"] } else { json };
+
+        if name == "t" || !fields.has_key("t") {
+            return;
+        }
+
+        // in place, so it's still first
+        let mut renamed = object! {};
+
+        for (k, v) in fields.entries_mut() {
+            let _ = renamed.insert(if k == "t" { name.as_str() } else { k }, v.take());
+        }
+
+        *fields = renamed;
+    }
+}
+
+/// Parses `LOG_STORE_TIME_FIELD_BY_TYPE`; the default is filled in by `TimeFields::new`.
+impl FromStr for TimeFields {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let by_type = s.split(',').map(str::trim).filter(|entry| !entry.is_empty())
+            .map(|entry| match entry.split_once(':') {
+                Some((record_type, name)) if !record_type.trim().is_empty() && !name.trim().is_empty() => {
+                    Ok((record_type.trim().to_string(), name.trim().to_string()))
+                }
+                _ => Err(format!("expected type:field, got {:?}", entry)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if by_type.is_empty() {
+            return Err("expected a comma-separated list of type:field".to_string());
+        }
+
+        Ok(TimeFields { default: "t".to_string(), by_type })
+    }
+}
+
+impl Display for TimeFields {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let entries = self.by_type.iter().map(|(record_type, name)| format!("{}:{}", record_type, name)).collect::<Vec<_>>();
+
+        write!(f, "{}", entries.join(","))
+    }
+}
+
+impl FromStr for Layout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "flat" => Ok(Layout::Flat),
+            "envelope" => Ok(Layout::Envelope),
+            _ => Err(format!("unknown layout {:?}, expected flat or envelope", s)),
+        }
+    }
+}
+
+impl Display for Layout {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Layout::Flat => write!(f, "flat"),
+            Layout::Envelope => write!(f, "envelope"),
+        }
+    }
+}
@@ -0,0 +1,46 @@
+pub mod backoff;
+pub mod bigint;
+pub mod checksum;
+pub mod circuit;
+pub mod clock;
+pub mod config;
+pub mod dedup;
+pub mod dns;
+pub mod dup_keys;
+pub mod encoder;
+pub mod file_sink;
+pub mod framing;
+pub mod handler;
+pub mod health;
+pub mod hash;
+pub mod init_error;
+pub mod invocation;
+pub mod layout;
+pub mod limit;
+pub mod logfmt;
+pub mod loki;
+pub mod monotonic;
+pub mod otel;
+pub mod partition;
+pub mod phase;
+pub mod precision;
+pub mod proxy;
+pub mod reassemble;
+pub mod record_id;
+pub mod record_time;
+pub mod rename;
+pub mod schema;
+pub mod sequence;
+pub mod session;
+pub mod severity;
+pub mod shutdown;
+pub mod sink;
+pub mod spill;
+pub mod splice;
+pub mod stats;
+pub mod syslog;
+pub mod thaw;
+pub mod trace;
+pub mod transform;
+pub mod utf8;
+pub mod writer;
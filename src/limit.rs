@@ -0,0 +1,50 @@
+use std::sync::Mutex;
+
+struct LimitState {
+    invoking: bool,
+    shipped: u64,
+    dropped: u64,
+}
+
+/// Caps the function records shipped per invocation, counting those dropped past the cap.
+/// Only records between a `platform_start` and its end are counted; platform records never are.
+pub struct InvocationLimit {
+    max: u64,
+    state: Mutex<LimitState>,
+}
+
+impl InvocationLimit {
+    pub fn new(max: u64) -> InvocationLimit {
+        InvocationLimit {
+            max,
+            state: Mutex::new(LimitState { invoking: false, shipped: 0, dropped: 0 }),
+        }
+    }
+
+    /// Called on `platform_start`: the count starts over.
+    pub fn start(&self) {
+        *self.state.lock().unwrap_or_else(|e| e.into_inner()) = LimitState { invoking: true, shipped: 0, dropped: 0 };
+    }
+
+    /// True if a function record fits within the cap; if not, it's counted as dropped.
+    pub fn admit(&self) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+
+        if !state.invoking || state.shipped < self.max {
+            state.shipped += 1;
+            true
+        } else {
+            state.dropped += 1;
+            false
+        }
+    }
+
+    /// Called at the end of an invocation, returning how many of its records were dropped.
+    pub fn end(&self) -> u64 {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let dropped = state.dropped;
+
+        *state = LimitState { invoking: false, shipped: 0, dropped: 0 };
+        dropped
+    }
+}
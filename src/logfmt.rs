@@ -0,0 +1,42 @@
+use json::JsonValue;
+
+/// A flat record (see `Layout::Flat`) as a logfmt line, without its newline: `key=value` pairs in the
+/// record's order. Strings are quoted (JSON style) when they're empty or hold spaces, `=`, quotes, or
+/// control characters; objects and arrays are written as compact JSON, and quoted the same way.
+pub fn line(json: &JsonValue) -> String {
+    let mut line = String::new();
+
+    for (k, v) in json.entries() {
+        if !line.is_empty() {
+            line.push(' ');
+        }
+
+        line.push_str(key(k).as_str());
+        line.push('=');
+
+        match v {
+            JsonValue::Null => (),
+            JsonValue::Short(_) | JsonValue::String(_) => line.push_str(value(v.as_str().unwrap_or_default()).as_str()),
+            JsonValue::Object(_) | JsonValue::Array(_) => line.push_str(value(v.dump().as_str()).as_str()),
+            _ => line.push_str(v.dump().as_str()),
+        }
+    }
+
+    line
+}
+
+/// A key, with the characters that would end it (or the value) replaced.
+fn key(k: &str) -> String {
+    match k.is_empty() {
+        true => "_".to_string(),
+        false => k.chars().map(|c| if c == '=' || c == '"' || c <= ' ' { '_' } else { c }).collect(),
+    }
+}
+
+/// A value, quoted if it has to be.
+fn value(v: &str) -> String {
+    match v.is_empty() || v.chars().any(|c| c == '=' || c == '"' || c == '\\' || c <= ' ') {
+        true => json::stringify(v),
+        false => v.to_string(),
+    }
+}
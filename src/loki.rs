@@ -0,0 +1,61 @@
+use json::{JsonValue, object};
+
+// fields of a function record used as the line when they're all there is to it
+const LINE_FIELDS: [&str; 3] = ["message", "msg", "record"];
+
+/// Maps an envelope (see `layout::envelope`) to a Loki push request holding just that record: the function name,
+/// type, and severity are the stream's labels, and `t` the timestamp. The line is a function's message when that's
+/// all there is to it, else the rest of its fields as JSON.
+pub fn push(mut record: JsonValue, function_name: Option<&str>) -> JsonValue {
+    let meta = &mut record["meta"];
+    let mut labels = object! {
+        "type": meta["type"].take(),
+        "level": meta["severity"].take(),
+    };
+
+    if let Some(name) = function_name {
+        let _ = labels.insert("fn", name);
+    }
+
+    let nanos = (meta["t"].take().as_i64().unwrap_or_default() as i128 * 1_000_000).to_string();
+    let mut fields = object! {};
+
+    // what's left of ours (e.g. `seq`) goes in the line, with the function's fields
+    for part in ["meta", "body"] {
+        for (k, v) in record[part].entries_mut() {
+            if !v.is_null() {
+                let _ = fields.insert(k, v.take());
+            }
+        }
+    }
+
+    let line = match LINE_FIELDS.iter().find(|f| fields.has_key(f)) {
+        Some(field) if fields.len() == 1 && fields[*field].is_string() => fields[*field].take(),
+        _ => fields.dump().into(),
+    };
+
+    let value = JsonValue::Array(vec![nanos.into(), line]);
+    let stream = object! { "stream": labels, "values": JsonValue::Array(vec![value]) };
+
+    object! { "streams": JsonValue::Array(vec![stream]) }
+}
+
+/// Merges push requests (each as built by `push`) into one, with a stream per set of labels.
+pub fn merge(mut pushes: JsonValue) -> JsonValue {
+    let mut streams: Vec<JsonValue> = Vec::new();
+
+    for push in pushes.members_mut() {
+        for stream in push["streams"].members_mut() {
+            match streams.iter_mut().find(|s| s["stream"] == stream["stream"]) {
+                Some(existing) => {
+                    for value in stream["values"].members_mut() {
+                        let _ = existing["values"].push(value.take());
+                    }
+                }
+                None => streams.push(stream.take()),
+            }
+        }
+    }
+
+    object! { "streams": JsonValue::Array(streams) }
+}
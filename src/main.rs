@@ -1,145 +1,331 @@
-use std::env;
-use json::{JsonValue, object};
-use lambda_extension::{service_fn, Error, Extension, LambdaLog, LambdaLogRecord, SharedService, LogBuffering};
-use tokio::io::{AsyncWriteExt, BufWriter};
-use tokio::net::TcpStream;
-use tokio::sync::mpsc::{Sender, channel};
-use tracing::error;
-
-const ADDRESS_ENV_NAME: &str = "LOG_STORE_ADDRESS";
-
-async fn handler(logs: Vec<LambdaLog>, sender: Sender<JsonValue>) -> Result<(), Error> {
-    for log in logs {
-        let mut json = object! {
-            "t": log.time.timestamp_millis()
-        };
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use json::JsonValue;
+use lambda_extension::{service_fn, Error, Extension, LambdaEvent, NextEvent, SharedService};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::time::Instant;
+use tracing::{info, warn};
 
-        match log.record {
-            LambdaLogRecord::Function(record) => {
-                json.insert("type", "function")?;
-
-                // attempt to parse the record as JSON
-                if let Ok(json_value) = json::parse(record.as_str()) {
-                    match json_value {
-                        JsonValue::Object(obj) => {
-                            for (k,v) in obj.iter() {
-                                json.insert(k, v.to_owned())?;
-                            }
-                        }
-                        JsonValue::Null => {
-                            // skip entirely
-                        }
-                         _ => {
-                             json.insert("record", json_value)?;
-                         }
-                    }
-                } else {
-                    json.insert("record", record)?;
-                }
-            },
-            // LambdaLogRecord::Extension(record) => {
-            //     json.insert("type", "extension")?;
-            //     json.insert("record", record)?;
-            // },
-            LambdaLogRecord::PlatformStart {request_id} => {
-                json.insert("type", "platform_start")?;
-                json.insert("request_id", request_id)?;
-            }
-            LambdaLogRecord::PlatformEnd {request_id} => {
-                json.insert("type", "platform_end")?;
-                json.insert("request_id", request_id)?;
-            }
-            LambdaLogRecord::PlatformFault(record) => {
-                json.insert("type", "platform_fault")?;
-                json.insert("record", record)?;
-            }
-            LambdaLogRecord::PlatformReport {request_id, metrics} => {
-                json.insert("type", "platform_report")?;
-                json.insert("request_id", request_id)?;
-                json.insert("duration_ms", metrics.duration_ms)?;
-                json.insert("billed_duration_ms", metrics.billed_duration_ms)?;
-                json.insert("memory_size_mb", metrics.memory_size_mb)?;
-                json.insert("max_memory_used_mb", metrics.max_memory_used_mb)?;
-                json.insert("init_duration_ms", metrics.init_duration_ms)?;
-            }
-            _ => (),
+use log_store_extension::backoff;
+use log_store_extension::config::{Config, SinkAddress, Source};
+use log_store_extension::handler::{handler, telemetry_handler, HandlerState};
+use log_store_extension::health::Health;
+use log_store_extension::init_error::InitError;
+use log_store_extension::layout::{self, Layout};
+use log_store_extension::loki;
+use log_store_extension::otel::{self, Format};
+use log_store_extension::shutdown::{shutdown_channel, Shutdown, ShutdownDump, ShutdownListener, ShutdownReason};
+use log_store_extension::spill::Spill;
+use log_store_extension::stats::{self, Stats};
+use log_store_extension::syslog::Syslog;
+use log_store_extension::writer::{supervise, write_file, write_sink, write_stdout, StdoutFormat, TcpWriter};
+
+const SUBSCRIBE_BACKOFF_MS: u64 = 100;
+const SUBSCRIBE_MAX_BACKOFF_MS: u64 = 2_000;
+// the default ports lambda-extension listens on for the Logs and Telemetry APIs
+const LOG_PORT: u16 = 9002;
+const TELEMETRY_PORT: u16 = 9003;
+// how long the writer gets to drain when there's no SHUTDOWN deadline, and how far ahead of one it must finish
+const SHUTDOWN_DRAIN_MS: u64 = 1_000;
+const SHUTDOWN_MARGIN_MS: u64 = 100;
+
+/// Returns true if the error returned from registering/subscribing is worth retrying.
+/// Connection level failures, 5xx and 429 responses are transient; everything else is
+/// considered a permanent configuration error.
+fn is_transient(err: &Error) -> bool {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(err.as_ref());
+
+    while let Some(e) = source {
+        if let Some(io_err) = e.downcast_ref::<std::io::Error>() {
+            return matches!(io_err.kind(),
+                ErrorKind::ConnectionRefused | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted |
+                ErrorKind::NotConnected | ErrorKind::BrokenPipe | ErrorKind::TimedOut |
+                ErrorKind::Interrupted | ErrorKind::UnexpectedEof);
         }
 
-        sender.send(json).await?;
+        source = e.source();
+    }
+
+    let msg = err.to_string();
+
+    if msg.contains("connection closed before message completed") {
+        return true;
+    }
+
+    // lambda-extension only surfaces API failures as "unable to ...: <status>"
+    match msg.rsplit_once(": ").and_then(|(_, status)| status.split_whitespace().next()) {
+        Some(code) => code.starts_with('5') || code == "429",
+        None => false,
+    }
+}
+
+/// Waits for the writer to drain and write its summary, until `deadline_ms` (since the epoch, less a margin).
+async fn shutdown(shutdown: &Shutdown, reason: ShutdownReason, deadline_ms: Option<u64>) {
+    let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+    let drain_ms = match deadline_ms {
+        Some(deadline_ms) => deadline_ms.saturating_sub(now_ms).saturating_sub(SHUTDOWN_MARGIN_MS),
+        None => SHUTDOWN_DRAIN_MS,
+    };
+
+    info!("Shutting down ({:?}), draining for up to {}ms", reason, drain_ms);
+
+    if !shutdown.shutdown(reason, Instant::now() + Duration::from_millis(drain_ms)).await {
+        warn!("The writer didn't finish draining before the shutdown deadline");
+    }
+}
+
+/// Sends a record the extension makes itself (not one from Lambda) to every sink, in the configured format.
+async fn ship(config: &Config, senders: &[Sender<JsonValue>], record: JsonValue) -> Result<(), Error> {
+    let mut json = match config.format {
+        Format::Json => config.layout.arrange(record),
+        Format::Otel => otel::log_record(layout::envelope(record, JsonValue::new_object())),
+        Format::Loki => loki::push(layout::envelope(record, JsonValue::new_object()), config.function_name.as_deref()),
+        Format::Logfmt => Layout::Flat.arrange(record),
+    };
+
+    if matches!(config.format, Format::Json | Format::Logfmt) {
+        config.time_precision.apply(&mut json, 0);
+    }
+
+    for sender in senders.iter() {
+        sender.send(json.clone()).await?;
     }
 
     Ok(())
 }
 
+/// Logs a recoverable error during init, returning it to be shipped once the sinks are up.
+fn init_error(detail: String) -> InitError {
+    warn!("{}", detail);
+    InitError::new(detail)
+}
+
+/// Starts the writer for a sink, under `supervise`; a TCP one connects first, with `preconnect`, so that's done
+/// during init.
+async fn spawn_writer(address: SinkAddress, config: Arc<Config>, stats: Arc<Stats>, recver: Receiver<JsonValue>, shutdown_listener: ShutdownListener,
+                      health: Option<Health>) {
+    let supervisor_config = config.clone();
+    let supervisor_stats = stats.clone();
+
+    match address {
+        SinkAddress::File(path) => {
+            tokio::spawn(supervise(recver, shutdown_listener, supervisor_config, supervisor_stats, move |recver, shutdown_listener| {
+                write_file(path.clone(), config.clone(), stats.clone(), recver, shutdown_listener)
+            }));
+        }
+        SinkAddress::Stdout => {
+            let format = StdoutFormat {
+                pretty: config.pretty,
+                sort_keys: config.sort_keys,
+                logfmt: config.format == Format::Logfmt,
+                max_line_bytes: config.stdout_max_line_bytes,
+                checksum_records: config.batch_checksum.then_some(config.batch_checksum_records),
+            };
+
+            tokio::spawn(supervise(recver, shutdown_listener, supervisor_config, supervisor_stats, move |recver, shutdown_listener| {
+                write_stdout(format, stats.clone(), recver, shutdown_listener)
+            }));
+        }
+        SinkAddress::Syslog(transport, address) => {
+            tokio::spawn(supervise(recver, shutdown_listener, supervisor_config, supervisor_stats, move |recver, shutdown_listener| {
+                let syslog = Syslog::new(transport, address.clone(), &config);
+
+                write_sink(Box::new(syslog), config.clone(), stats.clone(), recver, shutdown_listener)
+            }));
+        }
+        SinkAddress::Tcp(address) => {
+            let mut writer = TcpWriter::new(address.clone(), config.clone(), stats.clone()).with_health(health.clone());
+
+            // connected (and introduced, with include_session) during init, before the first records; with
+            // init_buffer_max, they're held while the writer connects instead, so subscribing isn't held up
+            if config.preconnect && config.init_buffer_max.is_none() {
+                writer.preconnect().await;
+            }
+
+            // a restarted writer connects afresh
+            let mut preconnected = Some(writer);
+
+            tokio::spawn(supervise(recver, shutdown_listener, supervisor_config, supervisor_stats, move |recver, shutdown_listener| {
+                let writer = preconnected.take().unwrap_or_else(|| {
+                    TcpWriter::new(address.clone(), config.clone(), stats.clone()).with_health(health.clone())
+                });
+
+                writer.start(recver, shutdown_listener)
+            }));
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::DEBUG)
-        // disable printing the name of the module in every log line.
-        .with_target(false)
-        // disabling time is handy because CloudWatch will add the ingestion time.
-        .without_time()
-        .init();
+    let config = Arc::new(Config::from_env()?);
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(config.log_level)
+        .with_target(config.log_target);
+
+    // disabling time is handy because CloudWatch will add the ingestion time.
+    if config.log_time {
+        subscriber.init();
+    } else {
+        subscriber.without_time().init();
+    }
 
-    let (_, log_store_address) = env::vars().find(|(k, _)| k == ADDRESS_ENV_NAME)
-        .ok_or_else(|| format!("Unable to find environment variable: {}", ADDRESS_ENV_NAME))?;
+    for warning in config.warnings.iter() {
+        warn!("Invalid value for {}: {:?} ({}); using {}", warning.field, warning.given, warning.reason, warning.used);
+    }
 
-    let (sender, mut recver) = channel(1024);
+    info!("Config: {:?}", config);
 
-    let logs_processor = SharedService::new(service_fn(move |logs| {
-        let sender_clone = sender.clone();
+    let mut init_errors = Vec::new();
 
-        async move {
-            handler(logs, sender_clone).await
+    if config.shutdown_dump == ShutdownDump::Spill {
+        if let Err(e) = Spill::new(config.spill_dir.as_str()).check() {
+            init_errors.push(init_error(format!("Unable to spill to {}: {}", config.spill_dir, e)));
         }
-    }));
+    }
+
+    let sigterm = match signal(SignalKind::terminate()) {
+        Ok(s) => Some(s),
+        Err(e) => {
+            init_errors.push(init_error(format!("Unable to listen for SIGTERM: {}", e)));
+            None
+        }
+    };
+
+    let (sender, recver) = channel(1024);
+    let mut warnings_senders = vec![sender.clone()];
+    let stats = Arc::new(Stats::new(config.max_inflight_bytes).with_memory_budget(config.memory_budget_bytes));
+
+    stats.watch_queue(&sender);
+
+    let mut state = HandlerState::new(&config, sender, stats.clone());
+    let (shutdown_handle, shutdown_listener) = shutdown_channel();
+    let mut shutdown_handle = shutdown_handle.with_dump(config.shutdown_dump);
+
+    // the mirror has its own queue, writer, and counters, so neither sink can hold up the other
+    let mut mirror = None;
+
+    if let Some(mirror_address) = config.mirror_address.clone() {
+        let (mirror_sender, mirror_recver) = channel(1024);
+        let mirror_stats = Arc::new(Stats::new(config.max_inflight_bytes));
+
+        mirror_stats.watch_queue(&mirror_sender);
+        warnings_senders.push(mirror_sender.clone());
+        state = state.with_mirror(mirror_sender, mirror_stats.clone());
+        mirror = Some((mirror_address, mirror_stats, mirror_recver));
+    }
+
+    let state = Arc::new(state);
+    // made by the handlers' state, once it's shared, for both writers
+    let health = Health::new(&config, &state);
+
+    if let Some((mirror_address, mirror_stats, mirror_recver)) = mirror {
+        spawn_writer(mirror_address, config.clone(), mirror_stats, mirror_recver, shutdown_handle.listener(), health.clone()).await;
+    }
+
+    let logs_state = state.clone();
+    let events_state = state.clone();
+    // the futures the runtime is handed have to own what they use, rather than borrow from the closure, so each
+    // call gets its own handle on the state: a reference count, about 1% of a call (see benches/handler.rs)
+    let logs_processor = SharedService::new(service_fn(move |logs| handler(logs, logs_state.clone())));
+    let telemetry_processor = SharedService::new(service_fn(move |events| telemetry_handler(events, state.clone())));
+
+    let shutdown_handle = Arc::new(shutdown_handle);
+
+    tokio::spawn(stats::log_periodically(stats.clone(), Duration::from_secs(config.stats_log_secs)));
+
+    spawn_writer(config.address.sink().clone(), config.clone(), stats, recver, shutdown_listener, health).await;
+
+    // queued until the writers have somewhere to send them
+    if config.ship_config_warnings || config.ship_init_errors {
+        for warning in config.warnings.iter() {
+            ship(&config, &warnings_senders, warning.to_json()).await?;
+        }
+    }
+
+    if config.ship_init_errors {
+        for init_error in init_errors.iter() {
+            ship(&config, &warnings_senders, init_error.to_json()).await?;
+        }
+    }
+
+    let signal_shutdown = shutdown_handle.clone();
 
     tokio::spawn(async move {
-        let stream = match TcpStream::connect(log_store_address.as_str()).await {
-            Ok(s) => s,
-            Err(e) => {
-                eprintln!("Error connecting to log-store instance at {}: {}", log_store_address, e);
-                eprintln!("Logs will be written to STDOUT instead");
-                while let Some(json) = recver.recv().await {
-                    println!("{}", json);
-                }
-                return;
-            }
+        let mut sigterm = match sigterm {
+            Some(s) => s,
+            None => return,
         };
 
-        let mut stream = BufWriter::new(stream);
+        tokio::select! {
+            _ = sigterm.recv() => (),
+            _ = tokio::signal::ctrl_c() => (),
+        }
 
-        while let Some(json) = recver.recv().await {
-            // convert to a string
-            let json_str = format!("{}\n", json);
+        shutdown(&signal_shutdown, ShutdownReason::Signal, None).await;
+        std::process::exit(0);
+    });
 
-            if let Err(e) = stream.write_all(json_str.as_bytes()).await {
-                eprintln!("Error writing to log-store: {}", e);
-                continue
-            }
+    let events_shutdown = shutdown_handle.clone();
+    let events_processor = service_fn(move |event: LambdaEvent| {
+        let events_shutdown = events_shutdown.clone();
+        let events_state = events_state.clone();
 
-            if let Err(e) = stream.flush().await {
-                eprintln!("Error flushing stream: {}", e);
-                continue
+        async move {
+            match event.next {
+                NextEvent::Invoke(event) => events_state.invoked(event.request_id.as_str(), event.tracing.value.as_str()),
+                NextEvent::Shutdown(event) => {
+                    shutdown(&events_shutdown, ShutdownReason::Event(event.shutdown_reason), Some(event.deadline_ms)).await;
+                }
             }
-        }
 
-        if let Err(e) = stream.shutdown().await {
-            error!("Error shutting down stream: {}", e);
+            Ok::<(), Error>(())
         }
     });
 
-    // set to the min, to try and speed up logging
-    let buffering = LogBuffering {
-        timeout_ms: 25,
-        max_bytes: 262_144,
-        max_items: 1_000,
-    };
+    let mut attempt = 0;
 
-    Extension::new()
-        .with_log_buffering(buffering)
-        .with_logs_processor(logs_processor)
-        .run().await?;
+    loop {
+        // a failed attempt can leave the logs server bound to its port, so each retry gets a fresh one
+        let res = match config.source {
+            Source::Logs => Extension::new()
+                .with_events_processor(events_processor.clone())
+                .with_log_buffering(config.log_buffering())
+                .with_log_port_number(LOG_PORT + attempt as u16)
+                .with_logs_processor(logs_processor.clone())
+                .run().await,
+            Source::Telemetry => Extension::new()
+                .with_events_processor(events_processor.clone())
+                .with_telemetry_buffering(config.log_buffering())
+                .with_telemetry_port_number(TELEMETRY_PORT + attempt as u16)
+                .with_telemetry_processor(telemetry_processor.clone())
+                .run().await,
+        };
+
+        match res {
+            Ok(()) => break,
+            Err(e) if attempt < config.subscribe_retries && is_transient(&e) => {
+                let delay = backoff::delay(attempt, SUBSCRIBE_BACKOFF_MS, SUBSCRIBE_MAX_BACKOFF_MS);
+
+                attempt += 1;
+
+                let init_error = init_error(format!("Error subscribing to the {} API (attempt {} of {}), retrying in {:?}: {}",
+                                                    config.source, attempt, config.subscribe_retries + 1, delay, e));
+
+                if config.ship_init_errors {
+                    ship(&config, &warnings_senders, init_error.to_json()).await?;
+                }
+
+                tokio::time::sleep(delay).await;
+            }
+            Err(e) => {
+                shutdown(&shutdown_handle, ShutdownReason::Error(e.to_string()), None).await;
+                return Err(e);
+            }
+        }
+    }
 
     Ok(())
 }
@@ -0,0 +1,70 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicI64, Ordering};
+use json::JsonValue;
+
+/// The field marking a record whose `t` was moved forward, with `monotonic_time=clamp`.
+pub const CLAMPED_FIELD: &str = "t_clamped";
+/// The field holding the latest `t` so far, with `monotonic_time=mt`.
+pub const MONOTONIC_FIELD: &str = "mt";
+
+/// How records whose `t` is earlier than one before them (e.g. after a clock adjustment) are made to fit a
+/// non-decreasing stream of timestamps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MonotonicTime {
+    /// Move `t` forward to the latest so far, and mark the record `t_clamped`
+    Clamp,
+    /// Keep `t`, and add the latest so far as `mt` to every record
+    Field,
+}
+
+impl FromStr for MonotonicTime {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "clamp" => Ok(MonotonicTime::Clamp),
+            "mt" => Ok(MonotonicTime::Field),
+            _ => Err(format!("unknown monotonic time handling {:?}, expected clamp or mt", s)),
+        }
+    }
+}
+
+impl Display for MonotonicTime {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MonotonicTime::Clamp => write!(f, "clamp"),
+            MonotonicTime::Field => write!(f, "mt"),
+        }
+    }
+}
+
+/// Tracks the latest `t` stamped, for the life of the extension.
+pub struct Monotonic {
+    mode: MonotonicTime,
+    latest: AtomicI64,
+}
+
+impl Monotonic {
+    pub fn new(mode: MonotonicTime) -> Monotonic {
+        Monotonic { mode, latest: AtomicI64::new(i64::MIN) }
+    }
+
+    /// Clamps `t`, or adds `mt`, per the mode.
+    pub fn stamp(&self, json: &mut JsonValue) -> Result<(), json::Error> {
+        let t = match json["t"].as_i64() {
+            Some(t) => t,
+            None => return Ok(()),
+        };
+        let latest = self.latest.fetch_max(t, Ordering::Relaxed).max(t);
+
+        match self.mode {
+            MonotonicTime::Clamp if t < latest => {
+                json.insert("t", latest)?;
+                json.insert(CLAMPED_FIELD, true)
+            }
+            MonotonicTime::Clamp => Ok(()),
+            MonotonicTime::Field => json.insert(MONOTONIC_FIELD, latest),
+        }
+    }
+}
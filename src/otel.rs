@@ -0,0 +1,118 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use json::{JsonValue, object};
+
+use crate::severity::SEVERITIES;
+
+// fields of a function record used as the OTel body, in order; the record's type is used if there's none
+const BODY_FIELDS: [&str; 3] = ["message", "msg", "record"];
+
+// the OTel severity number for each of `SEVERITIES`: the first of each range
+const SEVERITY_NUMBERS: [u8; 6] = [1, 5, 9, 13, 17, 21];
+
+/// The shape of the records shipped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Our own fields, arranged per the layout
+    Json,
+    /// The OpenTelemetry logs data model, as OTLP/JSON `LogRecord`s
+    Otel,
+    /// Grafana Loki push requests
+    Loki,
+    /// The flat layout's fields as `key=value` pairs, for tools that parse logfmt
+    Logfmt,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(Format::Json),
+            "otel" => Ok(Format::Otel),
+            "loki" => Ok(Format::Loki),
+            "logfmt" => Ok(Format::Logfmt),
+            _ => Err(format!("unknown format {:?}, expected json, otel, loki, or logfmt", s)),
+        }
+    }
+}
+
+impl Display for Format {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Format::Json => write!(f, "json"),
+            Format::Otel => write!(f, "otel"),
+            Format::Loki => write!(f, "loki"),
+            Format::Logfmt => write!(f, "logfmt"),
+        }
+    }
+}
+
+/// Maps an envelope (see `layout::envelope`) to an OTel log record: `t` and `it` become the timestamps,
+/// `severity` the severity number and text, a function's message the body, and everything else attributes.
+pub fn log_record(mut record: JsonValue) -> JsonValue {
+    let meta = &mut record["meta"];
+    let severity = meta["severity"].take();
+    let severity = severity.as_str().unwrap_or("info");
+    let number = SEVERITIES.iter().position(|s| *s == severity).map(|i| SEVERITY_NUMBERS[i]).unwrap_or(9);
+    let mut json = object! {
+        "timeUnixNano": unix_nanos(&meta["t"].take()),
+        "severityNumber": number,
+        "severityText": severity.to_ascii_uppercase(),
+    };
+
+    if meta.has_key("it") {
+        let _ = json.insert("observedTimeUnixNano", unix_nanos(&meta["it"].take()));
+    }
+
+    let body_field = BODY_FIELDS.iter().find(|f| record["body"].has_key(f));
+    let body = match body_field {
+        Some(field) => record["body"].remove(field),
+        None => record["meta"]["type"].clone(),
+    };
+    let mut attributes = JsonValue::new_array();
+
+    for part in ["meta", "body"] {
+        for (k, v) in record[part].entries() {
+            if !v.is_null() {
+                let _ = attributes.push(object! { "key": k, "value": any_value(v) });
+            }
+        }
+    }
+
+    let _ = json.insert("body", any_value(&body));
+    let _ = json.insert("attributes", attributes);
+
+    json
+}
+
+/// Milliseconds since the epoch as OTLP/JSON nanoseconds: a string, as it's a 64-bit integer.
+fn unix_nanos(ms: &JsonValue) -> String {
+    (ms.as_i64().unwrap_or_default() as i128 * 1_000_000).to_string()
+}
+
+/// An OTLP/JSON attribute: a `KeyValue` of `key` and `value` as an `AnyValue`.
+pub fn attribute(key: &str, value: &JsonValue) -> JsonValue {
+    object! { "key": key, "value": any_value(value) }
+}
+
+/// A JSON value as an OTLP/JSON `AnyValue`.
+fn any_value(value: &JsonValue) -> JsonValue {
+    match value {
+        JsonValue::Null => object! {},
+        JsonValue::Boolean(b) => object! { "boolValue": *b },
+        JsonValue::Short(_) | JsonValue::String(_) => object! { "stringValue": value.as_str() },
+        JsonValue::Number(_) => match value.as_i64() {
+            Some(i) if value.as_f64() == Some(i as f64) => object! { "intValue": i.to_string() },
+            _ => object! { "doubleValue": value.as_f64() },
+        },
+        JsonValue::Array(values) => object! {
+            "arrayValue": { "values": values.iter().map(any_value).collect::<Vec<_>>() }
+        },
+        JsonValue::Object(obj) => object! {
+            "kvlistValue": {
+                "values": obj.iter().map(|(k, v)| object! { "key": k, "value": any_value(v) }).collect::<Vec<_>>()
+            }
+        },
+    }
+}
@@ -0,0 +1,62 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use json::JsonValue;
+
+/// The field a record's partition key is shipped under, with `partition_key`.
+pub const PARTITION_KEY_FIELD: &str = "pk";
+
+/// What a record's partition key is taken from, so a log-store (or a Kafka or Kinesis proxy in front of it) that
+/// partitions by it keeps related records together, and in order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PartitionKey {
+    /// The invocation the record belongs to: a platform record's own `request_id`, otherwise that of the
+    /// invocation in progress
+    RequestId,
+    /// The function's name, from `AWS_LAMBDA_FUNCTION_NAME`
+    FunctionName,
+    /// A field of the record, or of a function's JSON log, if it's a string, number, or boolean
+    Field(String),
+}
+
+impl PartitionKey {
+    /// The key for a record, from the fields the extension built (`json`) and a function's own (`body`), what's
+    /// known of the invocation in progress, and the function's name; `None` if there's nothing to take it from.
+    pub fn key(&self, json: &JsonValue, body: &JsonValue, request_id: Option<&str>, function_name: Option<&str>) -> Option<String> {
+        match self {
+            PartitionKey::RequestId => json["request_id"].as_str().or(request_id).map(str::to_string),
+            PartitionKey::FunctionName => function_name.map(str::to_string),
+            PartitionKey::Field(field) => {
+                let value = if json.has_key(field) { &json[field] } else { &body[field] };
+
+                match value {
+                    JsonValue::Short(_) | JsonValue::String(_) => value.as_str().map(str::to_string),
+                    JsonValue::Number(_) | JsonValue::Boolean(_) => Some(value.dump()),
+                    _ => None,
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for PartitionKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "" => Err("expected request_id, fn, or a field name".to_string()),
+            "request_id" => Ok(PartitionKey::RequestId),
+            "fn" => Ok(PartitionKey::FunctionName),
+            field => Ok(PartitionKey::Field(field.to_string())),
+        }
+    }
+}
+
+impl Display for PartitionKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PartitionKey::RequestId => write!(f, "request_id"),
+            PartitionKey::FunctionName => write!(f, "fn"),
+            PartitionKey::Field(field) => write!(f, "{}", field),
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use std::fmt::{Display, Formatter};
+use std::sync::Mutex;
+
+/// Which part of the execution environment's life a record came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Before the first `platform_start`
+    Init,
+    /// Between a `platform_start` and its `platform_end` (or `platform_runtime_done`)
+    Invoke,
+    /// After an invocation ended, until the next one starts
+    Shutdown,
+}
+
+impl Display for Phase {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Phase::Init => write!(f, "init"),
+            Phase::Invoke => write!(f, "invoke"),
+            Phase::Shutdown => write!(f, "shutdown"),
+        }
+    }
+}
+
+/// Follows the phase from the platform records seen, in the order they arrive.
+pub struct PhaseTracker {
+    phase: Mutex<Phase>,
+}
+
+impl PhaseTracker {
+    pub fn new() -> PhaseTracker {
+        PhaseTracker { phase: Mutex::new(Phase::Init) }
+    }
+
+    /// Moves to the phase a record of `record_type` starts, if any, returning the phase it's in.
+    pub fn observe(&self, record_type: &str) -> Phase {
+        let mut phase = self.phase.lock().unwrap_or_else(|e| e.into_inner());
+
+        match record_type {
+            "platform_start" => *phase = Phase::Invoke,
+            "platform_end" | "platform_runtime_done" => *phase = Phase::Shutdown,
+            _ => (),
+        }
+
+        *phase
+    }
+}
+
+impl Default for PhaseTracker {
+    fn default() -> Self {
+        PhaseTracker::new()
+    }
+}
@@ -0,0 +1,96 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use json::JsonValue;
+use json::number::Number;
+
+/// Holds the part of a record's time finer than a millisecond (in nanoseconds) between `new_record` and
+/// `finish_record`, with a `time_precision` that can show it; it's never shipped.
+pub const SUB_MS_FIELD: &str = "_t_sub_ms";
+
+// the fields holding a time, in milliseconds until they're shipped
+const TIME_FIELDS: [&str; 3] = ["t", "it", "mt"];
+
+const NANOS_PER_MS: i64 = 1_000_000;
+
+/// How a record's times (`t`, and `it` and `mt` with them) are shipped.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TimePrecision {
+    /// Integer milliseconds since the epoch
+    #[default]
+    Millis,
+    /// Integer microseconds since the epoch
+    Micros,
+    /// Integer nanoseconds since the epoch
+    Nanos,
+    /// Seconds since the epoch, with microseconds as the fraction (`1712345678.123456`)
+    SecondsFloat,
+}
+
+impl TimePrecision {
+    /// A time, in nanoseconds since the epoch, as it's shipped.
+    pub fn render(&self, nanos: i64) -> JsonValue {
+        match self {
+            TimePrecision::Millis => nanos.div_euclid(NANOS_PER_MS).into(),
+            TimePrecision::Micros => nanos.div_euclid(1_000).into(),
+            TimePrecision::Nanos => nanos.into(),
+            TimePrecision::SecondsFloat => {
+                let micros = nanos.div_euclid(1_000);
+
+                Number::from_parts(micros >= 0, micros.unsigned_abs(), -6).into()
+            }
+        }
+    }
+
+    /// A time as it's shipped, back in milliseconds since the epoch.
+    pub fn to_ms(&self, t: &JsonValue) -> Option<i64> {
+        match self {
+            TimePrecision::Millis => t.as_i64(),
+            TimePrecision::Micros => t.as_i64().map(|micros| micros.div_euclid(1_000)),
+            TimePrecision::Nanos => t.as_i64().map(|nanos| nanos.div_euclid(NANOS_PER_MS)),
+            TimePrecision::SecondsFloat => t.as_fixed_point_i64(3),
+        }
+    }
+
+    /// Converts a laid-out record's times from milliseconds, adding `sub_ms_nanos` to `t`. They're at the top
+    /// level, or under `meta` in the envelope layout.
+    pub fn apply(&self, json: &mut JsonValue, sub_ms_nanos: i64) {
+        if *self == TimePrecision::Millis {
+            return;
+        }
+
+        let fields = if json.has_key("meta") { &mut json["meta"] } else { json };
+
+        for field in TIME_FIELDS {
+            if let Some(ms) = fields[field].as_i64() {
+                let extra = if field == "t" { sub_ms_nanos } else { 0 };
+
+                fields[field] = self.render(ms * NANOS_PER_MS + extra);
+            }
+        }
+    }
+}
+
+impl FromStr for TimePrecision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "millis" => Ok(TimePrecision::Millis),
+            "micros" => Ok(TimePrecision::Micros),
+            "nanos" => Ok(TimePrecision::Nanos),
+            "seconds_float" => Ok(TimePrecision::SecondsFloat),
+            _ => Err(format!("unknown time precision {:?}, expected millis, micros, nanos, or seconds_float", s)),
+        }
+    }
+}
+
+impl Display for TimePrecision {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TimePrecision::Millis => write!(f, "millis"),
+            TimePrecision::Micros => write!(f, "micros"),
+            TimePrecision::Nanos => write!(f, "nanos"),
+            TimePrecision::SecondsFloat => write!(f, "seconds_float"),
+        }
+    }
+}
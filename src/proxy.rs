@@ -0,0 +1,42 @@
+use std::io::{Error, ErrorKind};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+// the most of the proxy's response that's read looking for the end of its headers
+const MAX_RESPONSE_BYTES: usize = 8 * 1024;
+
+/// Opens a tunnel to `address` through the HTTP proxy at `proxy` (`http://host:port` or `host:port`),
+/// with `auth` (`user:password`) sent as basic proxy authorization.
+pub async fn connect(proxy: &str, address: &str, auth: Option<&str>) -> std::io::Result<TcpStream> {
+    let proxy = proxy.strip_prefix("http://").unwrap_or(proxy).trim_end_matches('/');
+    let mut stream = TcpStream::connect(proxy).await?;
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", address);
+
+    if let Some(auth) = auth {
+        request.push_str(format!("Proxy-Authorization: Basic {}\r\n", BASE64.encode(auth)).as_str());
+    }
+
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // read a byte at a time, so nothing after the headers is taken off the tunnel
+    let mut response = Vec::new();
+
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_RESPONSE_BYTES {
+            return Err(Error::new(ErrorKind::InvalidData, "proxy response headers are too long"));
+        }
+
+        response.push(stream.read_u8().await?);
+    }
+
+    let response = String::from_utf8_lossy(&response);
+    let status = response.lines().next().unwrap_or_default();
+
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(stream),
+        _ => Err(Error::new(ErrorKind::ConnectionRefused, format!("proxy {} refused the tunnel: {}", proxy, status))),
+    }
+}
@@ -0,0 +1,91 @@
+use std::sync::Mutex;
+
+// the most that's held waiting to parse
+const MAX_BYTES: usize = 1024 * 1024;
+
+/// The pieces of a line held so far, with their times.
+struct Held {
+    joined: String,
+    pieces: Vec<(i64, String)>,
+}
+
+/// Joins a function's log line that Lambda split across records (or deliveries) back together. Only long lines
+/// are split, so a line of at least `min_bytes` that looks like JSON but doesn't parse is held, and the lines
+/// after it appended, until what's held parses; it's then handed back as one line, at the time of its first
+/// piece. If it doesn't, by a line shorter than `min_bytes` (which would have been the last piece), by 1MB, or
+/// by another kind of record coming in, the pieces are handed back as they came, marked split.
+pub struct Reassembler {
+    min_bytes: usize,
+    held: Mutex<Option<Held>>,
+}
+
+impl Reassembler {
+    pub fn new(min_bytes: usize) -> Reassembler {
+        Reassembler { min_bytes, held: Mutex::new(None) }
+    }
+
+    /// Takes a function's log line, returning the lines ready to ship, and whether each is a piece of one.
+    pub fn push(&self, time_ms: i64, line: String) -> Vec<(i64, String, bool)> {
+        let mut held = self.held.lock().unwrap_or_else(|e| e.into_inner());
+        let long = line.len() >= self.min_bytes;
+
+        let mut current = match held.take() {
+            Some(current) => current,
+            None if long && line.trim_start().starts_with(['{', '[']) && json::parse(line.as_str()).is_err() => {
+                *held = Some(Held { joined: line.clone(), pieces: vec![(time_ms, line)] });
+                return vec![];
+            }
+            None => return vec![(time_ms, line, false)],
+        };
+
+        current.joined.push_str(line.as_str());
+        current.pieces.push((time_ms, line));
+
+        if json::parse(current.joined.as_str()).is_ok() {
+            return vec![(current.pieces[0].0, current.joined, false)];
+        }
+
+        if !long || current.joined.len() > MAX_BYTES {
+            return split(current);
+        }
+
+        *held = Some(current);
+        vec![]
+    }
+
+    /// Called on any other record: the pieces held can't be joined any more.
+    pub fn flush(&self) -> Vec<(i64, String, bool)> {
+        match self.held.lock().unwrap_or_else(|e| e.into_inner()).take() {
+            Some(held) => split(held),
+            None => vec![],
+        }
+    }
+
+    /// Runs a batch through `push` and `flush`: `line` takes a function's log line out of a record (`None`
+    /// for any other record), and `function` makes a record of one.
+    pub fn reassemble<R>(&self, records: impl Iterator<Item = (i64, R)>, line: fn(&mut R) -> Option<String>,
+                         function: fn(String) -> R) -> Vec<(i64, R, bool)> {
+        let mut out = Vec::new();
+
+        for (time_ms, mut record) in records {
+            match line(&mut record) {
+                Some(line) => {
+                    out.extend(self.push(time_ms, line).into_iter().map(|(t, line, split)| (t, function(line), split)));
+                }
+                None => {
+                    out.extend(self.flush().into_iter().map(|(t, line, split)| (t, function(line), split)));
+                    out.push((time_ms, record, false));
+                }
+            }
+        }
+
+        out
+    }
+}
+
+fn split(held: Held) -> Vec<(i64, String, bool)> {
+    // a single piece is just a line that isn't valid JSON
+    let split = held.pieces.len() > 1;
+
+    held.pieces.into_iter().map(|(time_ms, line)| (time_ms, line, split)).collect()
+}
@@ -0,0 +1,183 @@
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
+use std::str::FromStr;
+use json::JsonValue;
+
+const NANOS_PER_SEC: i64 = 1_000_000_000;
+
+// an epoch time under each of these is taken to be in seconds, milliseconds, or microseconds, in that order;
+// anything bigger is nanoseconds. As milliseconds, the first is in 1973, so recent times can't be mistaken
+const SECONDS_UNDER: u64 = 100_000_000_000;
+const MILLIS_UNDER: u64 = 100_000_000_000_000;
+const MICROS_UNDER: u64 = 100_000_000_000_000_000;
+
+/// The field of a function's JSON log holding the time it logged the event, with `prefer_record_time`, to use for
+/// the record's `t` in place of the time Lambda captured the line.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecordTime {
+    field: String,
+}
+
+impl RecordTime {
+    /// The time in `body`'s field, in nanoseconds since the epoch: an RFC 3339 time (`2024-04-05T19:34:38.123Z`,
+    /// or with an offset), or an epoch time, as a number or a string of one, in seconds (with a fraction, or not),
+    /// milliseconds, microseconds, or nanoseconds, told apart by its size. `None` if it's missing or can't be read.
+    pub fn time_ns(&self, body: &JsonValue) -> Option<i64> {
+        let value = &body[self.field.as_str()];
+
+        if let Some(n) = value.as_i64() {
+            return epoch_ns(n as f64, n);
+        }
+
+        match value.as_str().map(str::trim) {
+            Some(s) if !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit() || b == b'.') => match s.split_once('.') {
+                Some((whole, fraction)) => decimal_epoch_ns(whole, fraction),
+                None => s.parse::<i64>().ok().and_then(|n| epoch_ns(n as f64, n)),
+            },
+            Some(s) => rfc3339_ns(s),
+            None => value.as_f64().and_then(|n| epoch_ns(n, n as i64)),
+        }
+    }
+}
+
+/// The nanoseconds in a unit of an epoch time whose integer part is `whole`, by its size; compared as an integer,
+/// as an `f64` that big can round across a boundary.
+fn scale(whole: i64) -> i64 {
+    match whole.unsigned_abs() {
+        n if n < SECONDS_UNDER => NANOS_PER_SEC,
+        n if n < MILLIS_UNDER => 1_000_000,
+        n if n < MICROS_UNDER => 1_000,
+        _ => 1,
+    }
+}
+
+/// An epoch time in nanoseconds, from `n`, with `whole` its integer form (exact, where `n` may not be).
+fn epoch_ns(n: f64, whole: i64) -> Option<i64> {
+    let scale = scale(whole);
+
+    // past what an i64 holds (or not a number at all), `whole` is clamped to it, and isn't a time
+    if n.is_nan() || n.abs() >= i64::MAX as f64 {
+        return None;
+    }
+
+    match n.fract() == 0.0 {
+        true => whole.checked_mul(scale),
+        false => Some(n * scale as f64).filter(|ns| ns.abs() < i64::MAX as f64).map(|ns| ns as i64),
+    }
+}
+
+/// An epoch time written with a fraction, in nanoseconds, worked out from the digits rather than as an `f64`,
+/// which can't hold a time in seconds to the nanosecond; digits past the nanosecond are dropped.
+fn decimal_epoch_ns(whole: &str, fraction: &str) -> Option<i64> {
+    if fraction.is_empty() || fraction.contains('.') {
+        return None;
+    }
+
+    let whole = match whole {
+        "" => 0,
+        whole => whole.parse::<i64>().ok()?,
+    };
+    let scale = scale(whole);
+    let places = scale.ilog10();
+    let mut ns = whole.checked_mul(scale)?;
+
+    for (i, b) in fraction.bytes().take(places as usize).enumerate() {
+        ns = ns.checked_add((b - b'0') as i64 * 10i64.pow(places - 1 - i as u32))?;
+    }
+
+    Some(ns)
+}
+
+/// An RFC 3339 time, in nanoseconds since the epoch; a space or lowercase `t` may separate the date and time.
+fn rfc3339_ns(s: &str) -> Option<i64> {
+    let bytes = s.as_bytes();
+    let digits = |range: Range<usize>| {
+        s.get(range).filter(|d| d.bytes().all(|b| b.is_ascii_digit())).and_then(|d| d.parse::<i64>().ok())
+    };
+
+    if bytes.len() < 20 || bytes[4] != b'-' || bytes[7] != b'-' || !matches!(bytes[10], b'T' | b't' | b' ') || bytes[13] != b':' || bytes[16] != b':' {
+        return None;
+    }
+
+    let (year, month, day) = (digits(0..4)?, digits(5..7)?, digits(8..10)?);
+    let (hour, minute, second) = (digits(11..13)?, digits(14..16)?, digits(17..19)?);
+
+    // a leap second is taken as the one before
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    let mut rest = &s[19..];
+    let mut nanos = 0;
+
+    if let Some(fraction) = rest.strip_prefix('.') {
+        let len = fraction.bytes().take_while(u8::is_ascii_digit).count();
+
+        if len == 0 {
+            return None;
+        }
+
+        // past nanoseconds, the digits are dropped
+        for (i, b) in fraction.bytes().take(len.min(9)).enumerate() {
+            nanos += (b - b'0') as i64 * 10i64.pow(8 - i as u32);
+        }
+
+        rest = &fraction[len..];
+    }
+
+    let offset = match rest {
+        "Z" | "z" => 0,
+        _ => {
+            let sign = match rest.as_bytes().first() {
+                Some(b'+') => 1,
+                Some(b'-') => -1,
+                _ => return None,
+            };
+            let start = s.len() - rest.len() + 1;
+
+            if rest.len() != 6 || bytes[start + 2] != b':' {
+                return None;
+            }
+
+            let (hours, minutes) = (digits(start..start + 2)?, digits(start + 3..start + 5)?);
+
+            if hours > 23 || minutes > 59 {
+                return None;
+            }
+
+            sign * (hours * 3_600 + minutes * 60)
+        }
+    };
+    let secs = days_from_civil(year, month, day) * 86_400 + hour * 3_600 + minute * 60 + second.min(59) - offset;
+
+    secs.checked_mul(NANOS_PER_SEC)?.checked_add(nanos)
+}
+
+/// The days since 1970-01-01 of a Gregorian date (Howard Hinnant's days_from_civil).
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    era * 146_097 + doe - 719_468
+}
+
+impl FromStr for RecordTime {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "" => Err("expected the name of a field".to_string()),
+            field => Ok(RecordTime { field: field.to_string() }),
+        }
+    }
+}
+
+impl Display for RecordTime {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.field)
+    }
+}
@@ -0,0 +1,94 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use json::{JsonValue, object};
+
+/// Fields renamed as records are shipped, from `LOG_STORE_RENAME` (`duration_ms:dur,billed_duration_ms:billed`),
+/// so a log-store that expects its own names for them gets them.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FieldRenames {
+    entries: Vec<(String, String)>,
+}
+
+impl FieldRenames {
+    /// Renames the fields of a laid-out record (or each record of an invocation's frame): those at the top level, or
+    /// under `meta` or `body` in the envelope layout, in place. A field isn't renamed to one the record has already;
+    /// those skipped are returned, as `(from, to)`.
+    pub fn rename(&self, json: &mut JsonValue) -> Vec<(String, String)> {
+        let mut skipped = Vec::new();
+
+        if json.is_array() {
+            json.members_mut().for_each(|record| skipped.extend(self.rename(record)));
+            return skipped;
+        }
+
+        for key in ["meta", "body"] {
+            if json[key].is_object() {
+                skipped.extend(self.rename_fields(&mut json[key]));
+            }
+        }
+
+        skipped.extend(self.rename_fields(json));
+        skipped
+    }
+
+    fn rename_fields(&self, fields: &mut JsonValue) -> Vec<(String, String)> {
+        let mut skipped = Vec::new();
+        let mut renames = Vec::new();
+
+        for (from, to) in self.entries.iter().filter(|(from, _)| fields.has_key(from)) {
+            match fields.has_key(to) {
+                true => skipped.push((from.clone(), to.clone())),
+                false => renames.push((from, to)),
+            }
+        }
+
+        if renames.is_empty() {
+            return skipped;
+        }
+
+        // in place, so the fields keep their order
+        let mut renamed = object! {};
+
+        for (k, v) in fields.entries_mut() {
+            let name = renames.iter().find(|(from, _)| from.as_str() == k).map_or(k, |(_, to)| to.as_str());
+            let _ = renamed.insert(name, v.take());
+        }
+
+        *fields = renamed;
+        skipped
+    }
+}
+
+impl FromStr for FieldRenames {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut entries: Vec<(String, String)> = Vec::new();
+
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (from, to) = entry.split_once(':')
+                .map(|(from, to)| (from.trim(), to.trim()))
+                .filter(|(from, to)| !from.is_empty() && !to.is_empty())
+                .ok_or_else(|| format!("expected from:to, got {:?}", entry))?;
+
+            entries.retain(|(f, _)| f != from);
+
+            // one after another, they'd depend on the order they're applied in
+            if entries.iter().any(|(f, t)| f == to || t == from || t == to) {
+                return Err(format!("{:?} renames to or from a field another entry does", entry));
+            }
+
+            entries.push((from.to_string(), to.to_string()));
+        }
+
+        Ok(FieldRenames { entries })
+    }
+}
+
+impl Display for FieldRenames {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let entries: Vec<String> = self.entries.iter().map(|(from, to)| format!("{}:{}", from, to)).collect();
+
+        write!(f, "{}", entries.join(","))
+    }
+}
@@ -0,0 +1,285 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use json::JsonValue;
+
+/// The field listing why a record doesn't match the schema, with `schema_on_fail=mark`.
+pub const SCHEMA_INVALID_FIELD: &str = "_schema_invalid";
+
+// how many of a record's errors are reported; past a few, the schema is the wrong one
+const MAX_ERRORS: usize = 10;
+
+// the keywords that are checked
+const KEYWORDS: [&str; 19] = [
+    "type", "enum", "const", "required", "properties", "additionalProperties", "items",
+    "minimum", "maximum", "exclusiveMinimum", "exclusiveMaximum", "minLength", "maxLength", "minItems", "maxItems",
+    "allOf", "anyOf", "oneOf", "not",
+];
+
+// the keywords that describe a value without constraining it
+const ANNOTATIONS: [&str; 11] = [
+    "$schema", "$id", "$comment", "title", "description", "default", "examples", "format", "deprecated", "readOnly", "writeOnly",
+];
+
+const TYPES: [&str; 7] = ["null", "boolean", "object", "array", "number", "string", "integer"];
+
+/// What to do with a record that doesn't match the schema.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SchemaOnFail {
+    /// Don't ship it
+    Drop,
+    /// Ship it as it is
+    Ship,
+    /// Ship it with `_schema_invalid`, the reasons it doesn't match
+    #[default]
+    Mark,
+}
+
+/// A JSON Schema records are checked against before they're shipped, with `schema_file`.
+///
+/// Only the keywords producers' records are usually held to are checked (`KEYWORDS`, as in draft 2020-12, with
+/// `items` a single schema), with no crate to lean on; any other keyword (`pattern`, `$ref`, and so on) is
+/// rejected by `new`, rather than have a schema pass records it was written to catch. Annotations are ignored.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Schema {
+    root: JsonValue,
+}
+
+impl Schema {
+    pub fn new(root: JsonValue) -> Result<Schema, String> {
+        check(&root, "")?;
+
+        Ok(Schema { root })
+    }
+
+    /// Why `json` doesn't match, each reason starting with the JSON pointer of the value it's about; empty if it does.
+    pub fn validate(&self, json: &JsonValue) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        validate(&self.root, json, "", &mut errors);
+        errors.truncate(MAX_ERRORS);
+        errors
+    }
+}
+
+/// An error if `schema`, at `path` in the whole, isn't a schema `validate` can check.
+fn check(schema: &JsonValue, path: &str) -> Result<(), String> {
+    if schema.is_boolean() {
+        return Ok(());
+    }
+
+    if !schema.is_object() {
+        return Err(format!("{} isn't a schema (an object or boolean)", pointer(path)));
+    }
+
+    for (keyword, value) in schema.entries() {
+        let path = format!("{}/{}", path, escape(keyword));
+        let valid = match keyword {
+            "type" => value.as_str().map(|t| TYPES.contains(&t))
+                .unwrap_or_else(|| value.is_array() && value.members().all(|t| t.as_str().is_some_and(|t| TYPES.contains(&t)))),
+            "enum" => value.is_array(),
+            "required" => value.is_array() && value.members().all(JsonValue::is_string),
+            "properties" => {
+                if !value.is_object() {
+                    return Err(format!("{} isn't an object", pointer(&path)));
+                }
+
+                for (name, property) in value.entries() {
+                    check(property, format!("{}/{}", path, escape(name)).as_str())?;
+                }
+
+                true
+            }
+            "additionalProperties" | "items" | "not" => {
+                check(value, &path)?;
+                true
+            }
+            "allOf" | "anyOf" | "oneOf" => {
+                if value.is_empty() || !value.is_array() {
+                    return Err(format!("{} isn't a non-empty array", pointer(&path)));
+                }
+
+                for (i, member) in value.members().enumerate() {
+                    check(member, format!("{}/{}", path, i).as_str())?;
+                }
+
+                true
+            }
+            "minimum" | "maximum" | "exclusiveMinimum" | "exclusiveMaximum" => value.is_number(),
+            "minLength" | "maxLength" | "minItems" | "maxItems" => value.as_u64().is_some(),
+            "const" => true,
+            _ if ANNOTATIONS.contains(&keyword) => true,
+            _ => return Err(format!("{} is an unsupported keyword (supported are {})", pointer(&path), KEYWORDS.join(", "))),
+        };
+
+        if !valid {
+            return Err(format!("{} isn't a valid {:?}", pointer(&path), keyword));
+        }
+    }
+
+    Ok(())
+}
+
+fn validate(schema: &JsonValue, value: &JsonValue, path: &str, errors: &mut Vec<String>) {
+    match schema.as_bool() {
+        Some(true) => return,
+        Some(false) => return errors.push(format!("{}: not allowed", pointer(path))),
+        None => (),
+    }
+
+    let types: Vec<&str> = match schema["type"].as_str() {
+        Some(t) => vec![t],
+        None => schema["type"].members().filter_map(JsonValue::as_str).collect(),
+    };
+
+    if !types.is_empty() && !types.iter().any(|t| is_type(value, t)) {
+        // nothing else is worth saying about a value of the wrong type
+        return errors.push(format!("{}: expected {}, got {}", pointer(path), types.join(" or "), type_of(value)));
+    }
+
+    if schema["enum"].is_array() && !schema["enum"].members().any(|allowed| allowed == value) {
+        errors.push(format!("{}: not one of the allowed values", pointer(path)));
+    }
+
+    if schema.has_key("const") && schema["const"] != *value {
+        errors.push(format!("{}: not {}", pointer(path), schema["const"].dump()));
+    }
+
+    if let Some(n) = value.as_f64() {
+        for (keyword, fails) in [
+            ("minimum", (|n, limit| n < limit) as fn(f64, f64) -> bool),
+            ("maximum", |n, limit| n > limit),
+            ("exclusiveMinimum", |n, limit| n <= limit),
+            ("exclusiveMaximum", |n, limit| n >= limit),
+        ] {
+            if let Some(limit) = schema[keyword].as_f64().filter(|limit| fails(n, *limit)) {
+                errors.push(format!("{}: {} is past {} {}", pointer(path), n, keyword, limit));
+            }
+        }
+    }
+
+    if let Some(s) = value.as_str() {
+        let len = s.chars().count() as u64;
+
+        check_len(schema, "minLength", "maxLength", len, path, errors);
+    }
+
+    if value.is_array() {
+        check_len(schema, "minItems", "maxItems", value.len() as u64, path, errors);
+
+        if schema.has_key("items") {
+            for (i, member) in value.members().enumerate() {
+                validate(&schema["items"], member, format!("{}/{}", path, i).as_str(), errors);
+            }
+        }
+    }
+
+    if value.is_object() {
+        for name in schema["required"].members().filter_map(JsonValue::as_str) {
+            if !value.has_key(name) {
+                errors.push(format!("{}: missing required field {:?}", pointer(path), name));
+            }
+        }
+
+        for (name, member) in value.entries() {
+            let member_path = format!("{}/{}", path, escape(name));
+
+            if schema["properties"].has_key(name) {
+                validate(&schema["properties"][name], member, &member_path, errors);
+            } else if schema.has_key("additionalProperties") {
+                validate(&schema["additionalProperties"], member, &member_path, errors);
+            }
+        }
+    }
+
+    for member in schema["allOf"].members() {
+        validate(member, value, path, errors);
+    }
+
+    if schema["anyOf"].is_array() && matching(&schema["anyOf"], value, path) == 0 {
+        errors.push(format!("{}: matches none of anyOf", pointer(path)));
+    }
+
+    if schema["oneOf"].is_array() {
+        match matching(&schema["oneOf"], value, path) {
+            1 => (),
+            n => errors.push(format!("{}: matches {} of oneOf, not 1", pointer(path), n)),
+        }
+    }
+
+    if schema.has_key("not") && matching(&schema["not"], value, path) == 1 {
+        errors.push(format!("{}: matches not", pointer(path)));
+    }
+}
+
+/// How many of `schemas` (an array, or a single schema) `value` matches.
+fn matching(schemas: &JsonValue, value: &JsonValue, path: &str) -> usize {
+    let single = (!schemas.is_array()).then_some(schemas);
+
+    schemas.members().chain(single).filter(|schema| {
+        let mut errors = Vec::new();
+
+        validate(schema, value, path, &mut errors);
+        errors.is_empty()
+    }).count()
+}
+
+fn check_len(schema: &JsonValue, min: &str, max: &str, len: u64, path: &str, errors: &mut Vec<String>) {
+    if let Some(min) = schema[min].as_u64().filter(|min| len < *min) {
+        errors.push(format!("{}: length {} is under {}", pointer(path), len, min));
+    }
+
+    if let Some(max) = schema[max].as_u64().filter(|max| len > *max) {
+        errors.push(format!("{}: length {} is over {}", pointer(path), len, max));
+    }
+}
+
+fn is_type(value: &JsonValue, t: &str) -> bool {
+    match t {
+        "integer" => value.as_f64().is_some_and(|n| n.fract() == 0.0),
+        "number" => value.is_number(),
+        t => type_of(value) == t,
+    }
+}
+
+fn type_of(value: &JsonValue) -> &'static str {
+    match value {
+        JsonValue::Null => "null",
+        JsonValue::Boolean(_) => "boolean",
+        JsonValue::Number(_) => "number",
+        JsonValue::Short(_) | JsonValue::String(_) => "string",
+        JsonValue::Object(_) => "object",
+        JsonValue::Array(_) => "array",
+    }
+}
+
+/// A JSON pointer's reference token for `name` (RFC 6901).
+fn escape(name: &str) -> String {
+    name.replace('~', "~0").replace('/', "~1")
+}
+
+fn pointer(path: &str) -> &str {
+    if path.is_empty() { "/" } else { path }
+}
+
+impl FromStr for SchemaOnFail {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "drop" => Ok(SchemaOnFail::Drop),
+            "ship" => Ok(SchemaOnFail::Ship),
+            "mark" => Ok(SchemaOnFail::Mark),
+            _ => Err(format!("unknown schema failure handling {:?}, expected drop, ship, or mark", s)),
+        }
+    }
+}
+
+impl Display for SchemaOnFail {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SchemaOnFail::Drop => write!(f, "drop"),
+            SchemaOnFail::Ship => write!(f, "ship"),
+            SchemaOnFail::Mark => write!(f, "mark"),
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::Mutex;
+use json::JsonValue;
+
+/// What a sequence number counts within.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SeqScope {
+    /// A single counter for the life of the extension.
+    Global,
+    /// Reset to 0 at every `PlatformStart`; records before the first start are in the `init` scope.
+    Invocation,
+}
+
+impl FromStr for SeqScope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "global" => Ok(SeqScope::Global),
+            "invocation" => Ok(SeqScope::Invocation),
+            _ => Err(format!("unknown sequence scope {:?}, expected global or invocation", s)),
+        }
+    }
+}
+
+impl Display for SeqScope {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SeqScope::Global => write!(f, "global"),
+            SeqScope::Invocation => write!(f, "invocation"),
+        }
+    }
+}
+
+struct SeqState {
+    next: u64,
+    started: bool,
+}
+
+/// Stamps a `seq` ordinal onto each record.
+pub struct Sequencer {
+    scope: SeqScope,
+    state: Mutex<SeqState>,
+}
+
+impl Sequencer {
+    pub fn new(scope: SeqScope) -> Sequencer {
+        Sequencer {
+            scope,
+            state: Mutex::new(SeqState { next: 0, started: false }),
+        }
+    }
+
+    /// Adds `seq` (and `seq_scope` for init records when scoped by invocation) to `json`.
+    /// `starts_invocation` is true for `PlatformStart` records.
+    pub fn stamp(&self, starts_invocation: bool, json: &mut JsonValue) -> Result<(), json::Error> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+
+        if self.scope == SeqScope::Invocation && starts_invocation {
+            state.next = 0;
+            state.started = true;
+        }
+
+        json.insert("seq", state.next)?;
+        state.next += 1;
+
+        if self.scope == SeqScope::Invocation && !state.started {
+            json.insert("seq_scope", "init")?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,47 @@
+use std::collections::hash_map::RandomState;
+use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
+use std::io::Read;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The field holding a record's session id, when `include_session` is set.
+pub const SESSION_FIELD: &str = "sid";
+
+/// The field holding a record's place among those sent on its connection, when `include_seq` is set.
+pub const DELIVERY_FIELD: &str = "n";
+
+/// A random (version 4) UUID, identifying one connection to the log-store.
+pub fn new_id() -> String {
+    let mut bytes = random_bytes();
+
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    format_uuid(&bytes)
+}
+
+/// 16 bytes from `/dev/urandom`, or failing that, from std's randomly seeded hasher.
+pub(crate) fn random_bytes() -> [u8; 16] {
+    let mut bytes = [0u8; 16];
+
+    if File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut bytes)).is_err() {
+        // std seeds every RandomState from the OS, so this is still unpredictable enough to tell sessions apart
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+
+        for half in bytes.chunks_mut(8) {
+            let mut hasher = RandomState::new().build_hasher();
+
+            hasher.write_u64(nanos);
+            half.copy_from_slice(&hasher.finish().to_le_bytes());
+        }
+    }
+
+    bytes
+}
+
+/// A UUID's bytes in its usual form, e.g. `1b4e28ba-2fa1-41d2-883f-0016d3cca427`.
+pub(crate) fn format_uuid(bytes: &[u8; 16]) -> String {
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+
+    format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+}
@@ -0,0 +1,117 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use json::JsonValue;
+
+/// The severities records are normalized to, from least to most severe.
+pub const SEVERITIES: [&str; 6] = ["trace", "debug", "info", "warn", "error", "fatal"];
+
+// fields of a function's JSON log checked, in order, for its level
+pub(crate) const LEVEL_FIELDS: [&str; 6] = ["level", "severity", "lvl", "log_level", "loglevel", "levelname"];
+
+// how many leading tokens of a plain text log are checked for a level (Node puts it 3rd)
+const MAX_LEVEL_TOKEN: usize = 3;
+
+/// Normalizes the many spellings of a level (`WARNING`, `Err`, `CRITICAL`, ...) into one of `SEVERITIES`.
+pub fn normalize(level: &str) -> Option<&'static str> {
+    let level = level.trim_matches(|c: char| !c.is_ascii_alphabetic());
+
+    match level.to_ascii_lowercase().as_str() {
+        "trace" | "verbose" => Some("trace"),
+        "debug" => Some("debug"),
+        "info" | "information" | "notice" => Some("info"),
+        "warn" | "warning" => Some("warn"),
+        "error" | "err" => Some("error"),
+        "fatal" | "critical" | "crit" | "panic" | "emerg" | "alert" => Some("fatal"),
+        _ => None,
+    }
+}
+
+/// Extracts the level from a flattened function record: either one of the common level fields
+/// of a JSON log, or a level token near the start of a plain text log (`[ERROR] ...`, `<ts>\t<id>\tWARN\t...`).
+pub fn extract_level(json: &JsonValue) -> Option<&'static str> {
+    for field in LEVEL_FIELDS {
+        if let Some(level) = json[field].as_str().and_then(normalize) {
+            return Some(level);
+        }
+    }
+
+    json["record"].as_str()?
+        .split(|c: char| c.is_whitespace())
+        .filter(|t| !t.is_empty())
+        .take(MAX_LEVEL_TOKEN)
+        .find_map(normalize)
+}
+
+/// Maps record types to a severity; function records use their extracted level instead.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SeverityMap {
+    entries: Vec<(String, &'static str)>,
+}
+
+impl SeverityMap {
+    /// The severity of a record: the level extracted from a function record's own `fields`, otherwise from the map.
+    pub fn severity(&self, record_type: &str, fields: &JsonValue) -> &'static str {
+        if record_type == "function" {
+            if let Some(level) = extract_level(fields) {
+                return level;
+            }
+        }
+
+        let lookup = |record_type: &str| self.entries.iter()
+            .find(|(t, _)| t == record_type)
+            .map(|(_, severity)| *severity);
+
+        lookup(record_type).or_else(|| lookup("*")).unwrap_or("info")
+    }
+}
+
+impl Default for SeverityMap {
+    fn default() -> Self {
+        let entries = [
+            ("platform_fault", "error"),
+            ("config_warning", "warn"),
+            ("platform_logs_dropped", "warn"),
+            ("truncated_invocation", "warn"),
+            ("platform_start", "debug"),
+            ("platform_end", "debug"),
+            ("platform_runtime_done", "debug"),
+            ("platform_init_start", "debug"),
+            ("platform_init_runtime_done", "debug"),
+            ("span", "debug"),
+            ("*", "info"),
+        ];
+
+        SeverityMap {
+            entries: entries.iter().map(|(t, s)| (t.to_string(), *s)).collect(),
+        }
+    }
+}
+
+impl FromStr for SeverityMap {
+    type Err = String;
+
+    /// Parses `type:severity,...`, overriding (or adding to) the defaults. `*` sets the fallback.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut map = SeverityMap::default();
+
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (record_type, severity) = entry.split_once(':')
+                .ok_or_else(|| format!("expected type:severity, got {:?}", entry))?;
+            let severity = normalize(severity)
+                .ok_or_else(|| format!("unknown severity {:?} for {}", severity, record_type))?;
+
+            map.entries.retain(|(t, _)| t != record_type.trim());
+            map.entries.push((record_type.trim().to_string(), severity));
+        }
+
+        Ok(map)
+    }
+}
+
+impl Display for SeverityMap {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let entries: Vec<String> = self.entries.iter().map(|(t, s)| format!("{}:{}", t, s)).collect();
+
+        write!(f, "{}", entries.join(","))
+    }
+}
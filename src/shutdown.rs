@@ -0,0 +1,209 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+use json::{JsonValue, object};
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::watch;
+use tokio::time::{Instant, timeout_at};
+
+use crate::stats::Stats;
+
+/// Why the extension is shutting down.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ShutdownReason {
+    /// A `SHUTDOWN` event, with Lambda's reason (`SPINDOWN`, `TIMEOUT`, or `FAILURE`)
+    Event(String),
+    /// SIGTERM or Ctrl-C, e.g. under `sam local`
+    Signal,
+    /// The extension itself failed
+    Error(String),
+}
+
+impl Display for ShutdownReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ShutdownReason::Event(_) => write!(f, "shutdown_event"),
+            ShutdownReason::Signal => write!(f, "signal"),
+            ShutdownReason::Error(_) => write!(f, "error"),
+        }
+    }
+}
+
+impl ShutdownReason {
+    /// The last record written: totals for the session, for the log-store to reconcile against.
+    /// `drained` is how many records were written since the shutdown was asked for.
+    pub fn summary(&self, stats: &Stats, drained: u64) -> JsonValue {
+        let mut json = object! {
+            "t": stats.now_ms(),
+            "type": "shutdown_summary",
+            "severity": "info",
+            "total_records": stats.records_written.load(Ordering::Relaxed),
+            "total_bytes": stats.bytes_written.load(Ordering::Relaxed),
+            "reconnects": stats.reconnects.load(Ordering::Relaxed),
+            "dropped": stats.dropped.load(Ordering::Relaxed),
+            "stale_dropped": stats.stale_dropped.load(Ordering::Relaxed),
+            "drained": drained,
+            "dumped": stats.shutdown_dumped.load(Ordering::Relaxed),
+            "spilled": stats.shutdown_spilled.load(Ordering::Relaxed),
+            "platform_dropped": stats.platform_dropped.load(Ordering::Relaxed),
+            "platform_deduped": stats.platform_deduped.load(Ordering::Relaxed),
+            "uptime_secs": stats.uptime().as_secs(),
+            "reason": self.to_string(),
+        };
+
+        if stats.memory_budget().is_some() {
+            let _ = json.insert("memory_peak_bytes", stats.memory_used().max(stats.memory_peak.load(Ordering::Relaxed)));
+        }
+
+        match self {
+            ShutdownReason::Event(detail) | ShutdownReason::Error(detail) => {
+                let _ = json.insert("detail", detail.as_str());
+            }
+            ShutdownReason::Signal => (),
+        }
+
+        json
+    }
+}
+
+/// What the writer does with the records still queued when the drain deadline is about to pass.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShutdownDump {
+    /// Print them, so CloudWatch has them at least
+    Stdout,
+    /// Drop them, counting them as dropped
+    Drop,
+    /// Write them to the spill directory, for the next process on this host to replay
+    Spill,
+}
+
+impl FromStr for ShutdownDump {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "stdout" => Ok(ShutdownDump::Stdout),
+            "drop" => Ok(ShutdownDump::Drop),
+            "spill" => Ok(ShutdownDump::Spill),
+            _ => Err(format!("unknown shutdown dump {:?}, expected stdout, drop, or spill", s)),
+        }
+    }
+}
+
+impl Display for ShutdownDump {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ShutdownDump::Stdout => write!(f, "stdout"),
+            ShutdownDump::Drop => write!(f, "drop"),
+            ShutdownDump::Spill => write!(f, "spill"),
+        }
+    }
+}
+
+/// A shutdown, as the writer sees it: why, and what to do with what's left by when.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Drain {
+    pub reason: ShutdownReason,
+    pub deadline: Instant,
+    pub dump: ShutdownDump,
+}
+
+/// Tells the writers to shut down, and waits for them to finish.
+pub struct Shutdown {
+    drain: watch::Sender<Option<Drain>>,
+    done: Vec<watch::Receiver<bool>>,
+    dump: ShutdownDump,
+}
+
+/// Where a supervised writer's channel is handed back when it ends, for the next writer to carry on with.
+pub type Salvage = Arc<Mutex<Option<Receiver<JsonValue>>>>;
+
+/// The writer's end of `Shutdown`.
+pub struct ShutdownListener {
+    drain: watch::Receiver<Option<Drain>>,
+    done: watch::Sender<bool>,
+    /// Set for a writer run by `writer::supervise`
+    salvage: Option<Salvage>,
+}
+
+pub fn shutdown_channel() -> (Shutdown, ShutdownListener) {
+    let (drain_tx, drain_rx) = watch::channel(None);
+    let (done_tx, done_rx) = watch::channel(false);
+
+    (Shutdown { drain: drain_tx, done: vec![done_rx], dump: ShutdownDump::Stdout }, ShutdownListener { drain: drain_rx, done: done_tx, salvage: None })
+}
+
+impl Shutdown {
+    /// Sets what the writer does with records it can't drain in time; `stdout` by default.
+    pub fn with_dump(mut self, dump: ShutdownDump) -> Shutdown {
+        self.dump = dump;
+        self
+    }
+
+    /// Another listener, for a second writer (e.g. the mirror's) that gets the same shutdown and is waited for too.
+    pub fn listener(&mut self) -> ShutdownListener {
+        let (done_tx, done_rx) = watch::channel(false);
+
+        self.done.push(done_rx);
+        ShutdownListener { drain: self.drain.subscribe(), done: done_tx, salvage: None }
+    }
+
+    /// Asks the writers to drain and write their summaries, waiting until they have or `deadline` passes.
+    /// Only the first reason counts. Returns false if a writer didn't finish in time.
+    pub async fn shutdown(&self, reason: ShutdownReason, deadline: Instant) -> bool {
+        self.drain.send_if_modified(|current| match current {
+            Some(_) => false,
+            None => {
+                *current = Some(Drain { reason, deadline, dump: self.dump });
+                true
+            }
+        });
+
+        // an inner Err means the writer is gone, which is as done as it gets
+        let finished = async {
+            for done in self.done.iter() {
+                let _ = done.clone().wait_for(|done| *done).await;
+            }
+        };
+
+        timeout_at(deadline, finished).await.is_ok()
+    }
+}
+
+impl ShutdownListener {
+    /// Resolves once a shutdown has been asked for; never, if the `Shutdown` is dropped without asking.
+    pub async fn requested(&mut self) -> Drain {
+        let drain = self.drain.wait_for(Option::is_some).await.map(|drain| drain.clone());
+
+        match drain {
+            Ok(Some(drain)) => drain,
+            _ => std::future::pending().await,
+        }
+    }
+
+    /// Called by the writer once everything, including the summary, has been written.
+    pub fn finished(&self) {
+        let _ = self.done.send(true);
+    }
+
+    /// A listener for a writer run on this one's behalf, that's told of the same shutdown and hands its
+    /// channel back to `salvage`; the supervisor keeps this one, and says when it's finished.
+    pub fn supervised(&self, salvage: Salvage) -> ShutdownListener {
+        let (done_tx, _) = watch::channel(false);
+
+        ShutdownListener { drain: self.drain.clone(), done: done_tx, salvage: Some(salvage) }
+    }
+
+    /// True if the writer's channel is to be handed back when it ends.
+    pub fn is_supervised(&self) -> bool {
+        self.salvage.is_some()
+    }
+
+    /// Hands a supervised writer's channel back, as it ends.
+    pub fn hand_back(&self, recver: Receiver<JsonValue>) {
+        if let Some(salvage) = &self.salvage {
+            *salvage.lock().unwrap_or_else(|e| e.into_inner()) = Some(recver);
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use std::future::Future;
+use std::pin::Pin;
+use json::JsonValue;
+use lambda_extension::Error;
+
+use crate::encoder;
+use crate::file_sink::FileSink;
+
+/// What a `Sink`'s methods return: boxed, so a `Box<dyn Sink>` can be handed to `writer::write_sink`.
+pub type SinkFuture<'a> = Pin<Box<dyn Future<Output = Result<(), Error>> + Send + 'a>>;
+
+/// Somewhere records are shipped to, for those embedding the library to plug in their own (an in-process
+/// queue, another SDK) in place of the built-in sinks; see `writer::write_sink`, which drives one.
+///
+/// `write_batch` is given records in the order they were received, already laid out and formatted per
+/// `LOG_STORE_LAYOUT` and `LOG_STORE_FORMAT`, as many at a time as were waiting (up to `writer::SINK_BATCH_MAX`).
+/// A record is a JSON array when it's an invocation's records. A sink may write them right away or hold them,
+/// but must have written everything it was given once `flush` returns, which it's called whenever nothing
+/// more is waiting. An error from `write_batch` means the whole batch is lost: it's logged and counted as
+/// dropped, and isn't retried, so a sink that can retry should do so itself before giving up. The last
+/// record is the `shutdown_summary`, after which `flush` and then `shutdown` are called, and nothing more.
+pub trait Sink: Send {
+    fn write_batch<'a>(&'a mut self, records: &'a [JsonValue]) -> SinkFuture<'a>;
+
+    fn flush(&mut self) -> SinkFuture<'_> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn shutdown(&mut self) -> SinkFuture<'_> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Records as JSON lines on stdout, each at most `max_line_bytes` (see `encoder::fit_line`).
+pub struct Stdout {
+    max_line_bytes: usize,
+}
+
+impl Stdout {
+    pub fn new(max_line_bytes: usize) -> Stdout {
+        Stdout { max_line_bytes }
+    }
+}
+
+impl Sink for Stdout {
+    fn write_batch<'a>(&'a mut self, records: &'a [JsonValue]) -> SinkFuture<'a> {
+        Box::pin(async move {
+            for json in records {
+                println!("{}", encoder::fit_line(json.dump().as_str(), self.max_line_bytes));
+            }
+
+            Ok(())
+        })
+    }
+}
+
+impl Sink for FileSink {
+    fn write_batch<'a>(&'a mut self, records: &'a [JsonValue]) -> SinkFuture<'a> {
+        Box::pin(async move {
+            for json in records {
+                let line = self.encode(json);
+
+                self.write(line.as_str()).await?;
+            }
+
+            Ok(())
+        })
+    }
+
+    fn shutdown(&mut self) -> SinkFuture<'_> {
+        Box::pin(async move { Ok(FileSink::shutdown(self).await?) })
+    }
+}
@@ -0,0 +1,106 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+// `spill-<pid>.ndjson` while a process is spilling to it, `replay-<pid>-<n>.ndjson` once one has claimed it
+const SPILL_PREFIX: &str = "spill-";
+const REPLAY_PREFIX: &str = "replay-";
+const SPILL_EXTENSION: &str = ".ndjson";
+
+/// A directory that records which couldn't be written at shutdown are spilled to, one file per process, for
+/// the next process to replay. Lambda can start a new container on the same host, with `/tmp` as it was left.
+pub struct Spill {
+    dir: PathBuf,
+    path: PathBuf,
+    /// Opened on the first `append`, so a shutdown spilling many records doesn't open the file for each
+    file: Option<File>,
+}
+
+impl Spill {
+    pub fn new(dir: &str) -> Spill {
+        let dir = PathBuf::from(dir);
+        let path = dir.join(format!("{}{}{}", SPILL_PREFIX, std::process::id(), SPILL_EXTENSION));
+
+        Spill { dir, path, file: None }
+    }
+
+    /// Appends a record (`line`, without its newline) to this process's file.
+    pub fn append(&mut self, line: &str) -> std::io::Result<()> {
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => {
+                fs::create_dir_all(&self.dir)?;
+                self.file.insert(OpenOptions::new().create(true).append(true).open(&self.path)?)
+            }
+        };
+
+        file.write_all(format!("{}\n", line).as_bytes())
+    }
+
+    /// Checks the directory can be spilled to, by writing (and removing) a file that isn't replayed.
+    pub fn check(&self) -> std::io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+
+        let probe = self.dir.join(format!(".probe-{}", std::process::id()));
+
+        fs::write(&probe, b"")?;
+        fs::remove_file(&probe)
+    }
+
+    /// Claims the files left by processes that are gone, renaming each so no other process replays it too,
+    /// and returns them oldest first. A file whose process is still running (e.g. another extension sharing
+    /// the directory) is left alone; so is everything, if the directory can't be read.
+    pub fn claim_leftovers(&self) -> Vec<PathBuf> {
+        let mut leftovers: Vec<_> = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries.flatten()
+                .filter(|entry| entry.file_name().to_str().and_then(owner).is_some_and(is_gone))
+                .filter_map(|entry| Some((entry.metadata().ok()?.modified().ok()?, entry.path())))
+                .collect(),
+            Err(_) => return vec![],
+        };
+
+        leftovers.sort();
+
+        let claimed_prefix = format!("{}{}-", REPLAY_PREFIX, std::process::id());
+        let mut n = 0;
+
+        leftovers.into_iter()
+            .filter_map(|(_, path)| {
+                // claimed by a process with the same pid as this one, that didn't get to replay it
+                if path.file_name()?.to_str()?.starts_with(claimed_prefix.as_str()) {
+                    return Some(path);
+                }
+
+                // no other running process has this pid, so nothing else makes names with this prefix
+                let claimed = loop {
+                    let claimed = self.dir.join(format!("{}{}{}", claimed_prefix, n, SPILL_EXTENSION));
+
+                    n += 1;
+
+                    if !claimed.exists() {
+                        break claimed;
+                    }
+                };
+
+                // only one process gets to rename it
+                fs::rename(&path, &claimed).ok().map(|_| claimed)
+            })
+            .collect()
+    }
+}
+
+/// The pid of the process a spill (or claimed) file belongs to.
+fn owner(file_name: &str) -> Option<u32> {
+    let stem = file_name.strip_suffix(SPILL_EXTENSION)?;
+
+    match stem.strip_prefix(SPILL_PREFIX) {
+        Some(pid) => pid.parse().ok(),
+        None => stem.strip_prefix(REPLAY_PREFIX)?.split('-').next()?.parse().ok(),
+    }
+}
+
+/// True if `pid` isn't a running process, other than this one: a new container can reuse the pid of the
+/// process that left a file, and this one hasn't spilled anything yet when it looks.
+fn is_gone(pid: u32) -> bool {
+    pid == std::process::id() || !Path::new("/proc").join(pid.to_string()).exists()
+}
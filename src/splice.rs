@@ -0,0 +1,220 @@
+// as deep as the `json` crate parses; a line nested deeper isn't a JSON object to it
+const MAX_DEPTH: usize = 512;
+
+/// A function's log line that's a JSON object, as `object` found it.
+pub struct Object<'a> {
+    /// Everything between its braces, as it was written
+    pub inner: &'a str,
+    /// Its members: each key (without its quotes) and its value's text
+    pub members: Vec<(&'a str, &'a str)>,
+}
+
+/// `line` as a JSON object that can be copied into a record as it was written, checked without building it.
+/// `None` if it isn't valid JSON or isn't an object, or if copying it would ship what parsing it wouldn't: a
+/// newline (which would end the record's line early), or a key that's in some object more than once (which
+/// parsing collapses to one). An escaped key isn't compared with the others, so it's `None` as well.
+pub fn object(line: &str) -> Option<Object<'_>> {
+    if line.bytes().any(|b| b == b'\n' || b == b'\r') {
+        return None;
+    }
+
+    let mut scanner = Scanner { bytes: line.as_bytes(), i: 0 };
+    let mut members = Vec::new();
+
+    scanner.skip_ws();
+    scanner.expect(b'{')?;
+
+    let start = scanner.i;
+
+    scanner.members(0, |key, value| members.push((&line[key], &line[value])))?;
+
+    let end = scanner.i - 1;
+
+    scanner.skip_ws();
+
+    match scanner.i == line.len() {
+        true => Some(Object { inner: line[start..end].trim(), members }),
+        false => None,
+    }
+}
+
+struct Scanner<'a> {
+    bytes: &'a [u8],
+    i: usize,
+}
+
+impl Scanner<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.i).copied()
+    }
+
+    fn expect(&mut self, b: u8) -> Option<()> {
+        (self.peek() == Some(b)).then(|| self.i += 1)
+    }
+
+    fn skip_ws(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t')) {
+            self.i += 1;
+        }
+    }
+
+    /// The members of an object whose `{` has been read, through its `}`, each key's and value's place in the
+    /// line passed to `member` (the key's without its quotes).
+    fn members(&mut self, depth: usize, mut member: impl FnMut(std::ops::Range<usize>, std::ops::Range<usize>)) -> Option<()> {
+        let mut keys: Vec<&[u8]> = Vec::new();
+
+        self.skip_ws();
+
+        if self.expect(b'}').is_some() {
+            return Some(());
+        }
+
+        loop {
+            self.skip_ws();
+
+            let key_start = self.i + 1;
+
+            if self.string()? {
+                return None;
+            }
+
+            let key = &self.bytes[key_start..self.i - 1];
+
+            if keys.contains(&key) {
+                return None;
+            }
+
+            keys.push(key);
+            self.skip_ws();
+            self.expect(b':')?;
+            self.skip_ws();
+
+            let value_start = self.i;
+
+            self.value(depth)?;
+            member(key_start..key_start + key.len(), value_start..self.i);
+            self.skip_ws();
+
+            match self.peek()? {
+                b',' => self.i += 1,
+                b'}' => return self.expect(b'}'),
+                _ => return None,
+            }
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> Option<()> {
+        match self.peek()? {
+            b'{' | b'[' if depth + 1 >= MAX_DEPTH => None,
+            b'{' => {
+                self.i += 1;
+                self.members(depth + 1, |_, _| ())
+            }
+            b'[' => {
+                self.i += 1;
+                self.skip_ws();
+
+                if self.expect(b']').is_some() {
+                    return Some(());
+                }
+
+                loop {
+                    self.skip_ws();
+                    self.value(depth + 1)?;
+                    self.skip_ws();
+
+                    match self.peek()? {
+                        b',' => self.i += 1,
+                        b']' => return self.expect(b']'),
+                        _ => return None,
+                    }
+                }
+            }
+            b'"' => self.string().map(|_| ()),
+            b't' => self.literal(b"true"),
+            b'f' => self.literal(b"false"),
+            b'n' => self.literal(b"null"),
+            _ => self.number(),
+        }
+    }
+
+    /// A string, through its closing quote; whether it has an escape in it.
+    fn string(&mut self) -> Option<bool> {
+        let mut escaped = false;
+
+        self.expect(b'"')?;
+
+        loop {
+            match self.peek()? {
+                b'"' => {
+                    self.i += 1;
+                    return Some(escaped);
+                }
+                b'\\' => {
+                    escaped = true;
+                    self.i += 1;
+
+                    match self.peek()? {
+                        b'"' | b'\\' | b'/' | b'b' | b'f' | b'n' | b'r' | b't' => self.i += 1,
+                        b'u' => {
+                            let code = self.bytes.get(self.i + 1..self.i + 5)
+                                .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+                                .and_then(|hex| u16::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok())?;
+
+                            // the `json` crate only takes a surrogate in a valid pair; one is left to it
+                            if (0xd800..=0xdfff).contains(&code) {
+                                return None;
+                            }
+
+                            self.i += 5;
+                        }
+                        _ => return None,
+                    }
+                }
+                b if b < 0x20 => return None,
+                _ => self.i += 1,
+            }
+        }
+    }
+
+    fn literal(&mut self, literal: &[u8]) -> Option<()> {
+        self.bytes[self.i..].starts_with(literal).then(|| self.i += literal.len())
+    }
+
+    /// A number, as JSON has them: `-`, an integer part without leading zeros, a fraction, and an exponent.
+    fn number(&mut self) -> Option<()> {
+        let digits = |scanner: &mut Self| {
+            let start = scanner.i;
+
+            while matches!(scanner.peek(), Some(b'0'..=b'9')) {
+                scanner.i += 1;
+            }
+
+            (scanner.i > start).then_some(())
+        };
+
+        let _ = self.expect(b'-');
+
+        match self.peek()? {
+            b'0' => self.i += 1,
+            b'1'..=b'9' => digits(self)?,
+            _ => return None,
+        }
+
+        if self.expect(b'.').is_some() {
+            digits(self)?;
+        }
+
+        if matches!(self.peek(), Some(b'e' | b'E')) {
+            self.i += 1;
+
+            if matches!(self.peek(), Some(b'+' | b'-')) {
+                self.i += 1;
+            }
+
+            digits(self)?;
+        }
+
+        Some(())
+    }
+}
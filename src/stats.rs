@@ -0,0 +1,299 @@
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use json::JsonValue;
+use tokio::sync::Notify;
+use tokio::sync::mpsc::{Sender, WeakSender};
+use tokio::time::MissedTickBehavior;
+use tracing::info;
+
+use crate::clock::{Clock, SystemClock};
+
+/// How close the estimated bytes buffered are to `memory_budget_bytes`, and what the TCP writer does about it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Pressure {
+    /// Under `FLUSH_AT`: nothing
+    Normal,
+    /// Past it: flush what's queued and hand back what's held right away, rather than on time or size
+    Flush,
+    /// At the budget: drop the oldest records, until the estimate is back under `FLUSH_AT`
+    Shed,
+}
+
+// the share of the memory budget, in percent, past which the TCP writer stops buffering
+const FLUSH_AT: u64 = 75;
+
+/// Counters shared between the handlers and the writer, and the clock they all go by.
+#[derive(Debug)]
+pub struct Stats {
+    inflight_limit: Option<u64>,
+    memory_budget: Option<u64>,
+    pub batches_received: AtomicU64,
+    pub records_received: AtomicU64,
+    pub largest_batch: AtomicU64,
+    pub dropped: AtomicU64,
+    /// Records Lambda reported dropping itself, before they reached the extension
+    pub platform_dropped: AtomicU64,
+    /// Records the TCP writer dropped for being older than `max_record_age_ms`
+    pub stale_dropped: AtomicU64,
+    /// Platform records skipped as already written, with `dedup_platform`
+    pub platform_deduped: AtomicU64,
+    /// Records printed to stdout (or spilled) as the shutdown deadline was about to pass, rather than written to the sink
+    pub shutdown_dumped: AtomicU64,
+    /// Of those, the records spilled to disk, with `shutdown_dump=spill`
+    pub shutdown_spilled: AtomicU64,
+    /// Function/extension records with no content; counted whether or not they're dropped
+    pub empty_records: AtomicU64,
+    /// Function records that had invalid UTF-8; counted whatever `nonutf8` does with them
+    pub nonutf8_records: AtomicU64,
+    /// Records that didn't match the `schema_file`; counted whatever `schema_on_fail` does with them
+    pub schema_invalid: AtomicU64,
+    /// Estimated bytes of records enqueued but not yet written; only tracked with `max_inflight_bytes`
+    pub inflight_bytes: AtomicU64,
+    /// Estimated bytes the TCP writer has queued or held; only tracked with `memory_budget_bytes`
+    pub buffered_bytes: AtomicU64,
+    /// The most `memory_used` has been
+    pub memory_peak: AtomicU64,
+    released: Notify,
+    thawed: Notify,
+    pub records_written: AtomicU64,
+    pub bytes_written: AtomicU64,
+    pub reconnects: AtomicU64,
+    /// When a record was last written, in milliseconds since the epoch; 0 if none has been
+    pub last_written_ms: AtomicU64,
+    /// Whether the TCP writer's circuit breaker is open (or half-open), and how often it has opened
+    pub circuit_open: AtomicBool,
+    pub circuit_trips: AtomicU64,
+    /// Whether a write to the log-store has been blocked for longer than `slow_sink_ms`, and how often one has
+    pub sink_slow: AtomicBool,
+    pub slow_writes: AtomicU64,
+    queue: OnceLock<WeakSender<JsonValue>>,
+    clock: Arc<dyn Clock>,
+    started: Instant,
+}
+
+impl Stats {
+    /// With an `inflight_limit`, the estimated bytes of records between the handlers and the log-store are tracked.
+    pub fn new(inflight_limit: Option<u64>) -> Stats {
+        Stats {
+            inflight_limit,
+            memory_budget: None,
+            batches_received: AtomicU64::new(0),
+            records_received: AtomicU64::new(0),
+            largest_batch: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            platform_dropped: AtomicU64::new(0),
+            stale_dropped: AtomicU64::new(0),
+            platform_deduped: AtomicU64::new(0),
+            shutdown_dumped: AtomicU64::new(0),
+            shutdown_spilled: AtomicU64::new(0),
+            empty_records: AtomicU64::new(0),
+            nonutf8_records: AtomicU64::new(0),
+            schema_invalid: AtomicU64::new(0),
+            inflight_bytes: AtomicU64::new(0),
+            buffered_bytes: AtomicU64::new(0),
+            memory_peak: AtomicU64::new(0),
+            released: Notify::new(),
+            thawed: Notify::new(),
+            records_written: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            reconnects: AtomicU64::new(0),
+            last_written_ms: AtomicU64::new(0),
+            circuit_open: AtomicBool::new(false),
+            circuit_trips: AtomicU64::new(0),
+            sink_slow: AtomicBool::new(false),
+            slow_writes: AtomicU64::new(0),
+            queue: OnceLock::new(),
+            clock: Arc::new(SystemClock),
+            started: Instant::now(),
+        }
+    }
+
+    /// With a `memory_budget`, in-flight bytes are tracked too, along with what the TCP writer has buffered.
+    pub fn with_memory_budget(mut self, memory_budget: Option<u64>) -> Stats {
+        self.memory_budget = memory_budget;
+        self
+    }
+
+    pub fn memory_budget(&self) -> Option<u64> {
+        self.memory_budget
+    }
+
+    /// The estimated bytes of records between the handlers and the log-store, noting the peak.
+    pub fn memory_used(&self) -> u64 {
+        let used = self.inflight_bytes.load(Ordering::Relaxed) + self.buffered_bytes.load(Ordering::Relaxed);
+
+        self.memory_peak.fetch_max(used, Ordering::Relaxed);
+        used
+    }
+
+    pub fn pressure(&self) -> Pressure {
+        let budget = match self.memory_budget {
+            Some(budget) => budget,
+            None => return Pressure::Normal,
+        };
+        let used = self.memory_used();
+
+        if used >= budget {
+            Pressure::Shed
+        } else if used >= budget.saturating_mul(FLUSH_AT) / 100 {
+            Pressure::Flush
+        } else {
+            Pressure::Normal
+        }
+    }
+
+    /// Replaces the system clock, e.g. with a `MockClock`; uptime is counted from here.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Stats {
+        self.started = clock.instant();
+        self.clock = clock;
+        self
+    }
+
+    /// Milliseconds since the epoch, by the clock.
+    pub fn now_ms(&self) -> u64 {
+        self.clock.now_ms()
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.clock.instant().saturating_duration_since(self.started)
+    }
+
+    pub fn record_batch(&self, size: usize) {
+        self.batches_received.fetch_add(1, Ordering::Relaxed);
+        self.records_received.fetch_add(size as u64, Ordering::Relaxed);
+        self.largest_batch.fetch_max(size as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_dropped(&self, count: u64) {
+        self.dropped.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Records dropped for any reason: by the handlers and writers, the platform's, and those too old to ship.
+    pub fn dropped_total(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed) + self.platform_dropped.load(Ordering::Relaxed)
+            + self.stale_dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn record_written(&self, bytes: usize) {
+        self.frame_written(1, bytes);
+    }
+
+    /// Several records written as one, e.g. an invocation's.
+    pub fn frame_written(&self, records: usize, bytes: usize) {
+        self.records_written.fetch_add(records as u64, Ordering::Relaxed);
+        self.bytes_written.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.last_written_ms.store(self.now_ms(), Ordering::Relaxed);
+    }
+
+    /// Keeps hold of the sending side of the queue the handlers feed the writer through, so `queue_depth` can
+    /// tell how much is waiting on it, without keeping it open.
+    pub fn watch_queue(&self, sender: &Sender<JsonValue>) {
+        let _ = self.queue.set(sender.downgrade());
+    }
+
+    /// Records waiting on the queue for the writer, with `watch_queue`; 0 without.
+    pub fn queue_depth(&self) -> usize {
+        match self.queue.get().and_then(WeakSender::upgrade) {
+            Some(sender) => sender.max_capacity() - sender.capacity(),
+            None => 0,
+        }
+    }
+
+    /// The size a record counts for against the in-flight limit (and memory budget); 0 when there's neither.
+    pub fn inflight_size(&self, json: &JsonValue) -> u64 {
+        match self.inflight_limit.or(self.memory_budget) {
+            Some(_) => estimated_size(json),
+            None => 0,
+        }
+    }
+
+    /// Reserves `size` in-flight bytes if that keeps the total within the limit. A record is always
+    /// allowed when nothing is in flight, so a single record larger than the limit can't get stuck.
+    pub fn try_acquire(&self, size: u64) -> bool {
+        let max = match (self.inflight_limit, self.memory_budget) {
+            (Some(max), _) => max,
+            // only counted, for the memory budget
+            (None, Some(_)) => u64::MAX,
+            (None, None) => return true,
+        };
+
+        self.inflight_bytes.fetch_update(Ordering::AcqRel, Ordering::Acquire, |current| {
+            (current == 0 || current.saturating_add(size) <= max).then_some(current.saturating_add(size))
+        }).is_ok()
+    }
+
+    /// Called on an invocation after a long freeze, for the writer to check its connection.
+    pub fn thawed(&self) {
+        self.thawed.notify_one();
+    }
+
+    /// Waits for `thawed`.
+    pub async fn wait_for_thaw(&self) {
+        self.thawed.notified().await
+    }
+
+    /// Waits until `size` in-flight bytes can be reserved.
+    pub async fn acquire(&self, size: u64) {
+        loop {
+            // register for the wakeup before checking, so a release in between isn't missed
+            let released = self.released.notified();
+
+            if self.try_acquire(size) {
+                return;
+            }
+
+            released.await;
+        }
+    }
+
+    /// Called by the writer once a record has been written (or by the handler if it's dropped).
+    pub fn release(&self, size: u64) {
+        if size == 0 {
+            return;
+        }
+
+        let _ = self.inflight_bytes.fetch_update(Ordering::AcqRel, Ordering::Acquire, |current| {
+            Some(current.saturating_sub(size))
+        });
+
+        self.released.notify_waiters();
+    }
+}
+
+/// With `stats_log_secs`, logs what's been received and shipped every `every`, to the function's log group;
+/// returns straight away if `every` is zero.
+pub async fn log_periodically(stats: Arc<Stats>, every: Duration) {
+    if every.is_zero() {
+        return;
+    }
+
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + every, every);
+
+    // those that came due while the environment was frozen aren't all logged at once on thaw
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        interval.tick().await;
+        info!("Stats: {} records received, {} sent ({} bytes), {} dropped in all, {} reconnects, {} queued",
+              stats.records_received.load(Ordering::Relaxed),
+              stats.records_written.load(Ordering::Relaxed),
+              stats.bytes_written.load(Ordering::Relaxed),
+              stats.dropped_total(),
+              stats.reconnects.load(Ordering::Relaxed),
+              stats.queue_depth());
+    }
+}
+
+/// A cheap estimate of a record's serialized size, without serializing it.
+pub fn estimated_size(json: &JsonValue) -> u64 {
+    match json {
+        JsonValue::Null => 4,
+        JsonValue::Boolean(_) => 5,
+        JsonValue::Number(_) => 8,
+        JsonValue::Short(s) => s.len() as u64 + 2,
+        JsonValue::String(s) => s.len() as u64 + 2,
+        JsonValue::Array(values) => 2 + values.iter().map(|v| estimated_size(v) + 1).sum::<u64>(),
+        JsonValue::Object(obj) => 2 + obj.iter().map(|(k, v)| k.len() as u64 + 4 + estimated_size(v)).sum::<u64>(),
+    }
+}
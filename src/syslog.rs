@@ -0,0 +1,281 @@
+use std::time::Duration;
+use json::JsonValue;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::net::{TcpStream, UdpSocket};
+
+use crate::config::Config;
+use crate::layout::Layout;
+use crate::precision::TimePrecision;
+use crate::sink::{Sink, SinkFuture};
+
+// user-level messages; the severity says the rest
+const FACILITY: u8 = 1;
+
+// the SD-ID the fields go under: a name of our own, at the enterprise number set aside for examples (RFC 5612)
+const SD_ID: &str = "fields@32473";
+
+// the longest of each header field (RFC 5424, section 6)
+const HOSTNAME_MAX: usize = 255;
+const APP_NAME_MAX: usize = 48;
+const MSGID_MAX: usize = 32;
+const SD_NAME_MAX: usize = 32;
+
+// the most a UDP datagram over IPv4 can carry
+const UDP_MAX_BYTES: usize = 65_507;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// the fields the header is made from, which aren't repeated as structured data
+const HEADER_FIELDS: [&str; 3] = ["t", "type", "severity"];
+
+/// How messages reach the syslog server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyslogTransport {
+    /// Over a connection, each message preceded by its length in bytes and a space (octet counting, RFC 6587)
+    Tcp,
+    /// A datagram per message (RFC 5426), cut short if it won't fit in one
+    Udp,
+}
+
+/// The syslog severity (RFC 5424) of one of `severity::SEVERITIES`; a record with none is informational.
+pub fn severity(severity: Option<&str>) -> u8 {
+    match severity {
+        Some("fatal") => 2,
+        Some("error") => 3,
+        Some("warn") => 4,
+        Some("debug") | Some("trace") => 7,
+        _ => 6,
+    }
+}
+
+/// `syslog+tcp://` and `syslog+udp://` addresses: records as RFC 5424 messages to a syslog server, with the
+/// priority from the record's `severity` (at the user facility), the timestamp from its `t`, the function's
+/// name as the hostname and app name, its `type` as the message id, and the rest of its fields, flattened to
+/// dotted names, as the structured data. A function's log line (or its `message` or `msg`) is the message.
+///
+/// A connection that fails loses the batch being written, and is made again for the next.
+pub struct Syslog {
+    transport: SyslogTransport,
+    address: String,
+    hostname: String,
+    app_name: String,
+    message_key: String,
+    layout: Layout,
+    time_precision: TimePrecision,
+    tcp: Option<BufWriter<TcpStream>>,
+    udp: Option<UdpSocket>,
+}
+
+impl Syslog {
+    pub fn new(transport: SyslogTransport, address: String, config: &Config) -> Syslog {
+        let name = config.function_name.as_deref().unwrap_or_default();
+
+        Syslog {
+            transport,
+            address,
+            hostname: header_field(name, HOSTNAME_MAX),
+            app_name: header_field(name, APP_NAME_MAX),
+            message_key: config.message_key.clone(),
+            layout: config.layout,
+            time_precision: config.time_precision,
+            tcp: None,
+            udp: None,
+        }
+    }
+
+    /// A record as an RFC 5424 message, without any framing.
+    pub fn message(&self, json: &JsonValue) -> String {
+        let flat = match self.layout {
+            Layout::Envelope => Layout::Flat.apply(json.clone()),
+            Layout::Flat => json.clone(),
+        };
+        let pri = FACILITY * 8 + severity(flat["severity"].as_str());
+        let timestamp = self.time_precision.to_ms(&flat["t"]).map(rfc3339).unwrap_or_else(|| "-".to_string());
+        let msgid = header_field(flat["type"].as_str().unwrap_or_default(), MSGID_MAX);
+        let message_field = [self.message_key.as_str(), "record", "message", "msg"].into_iter()
+            .find(|field| flat[*field].is_string());
+        let mut params = Vec::new();
+
+        for (k, v) in flat.entries().filter(|(k, _)| !HEADER_FIELDS.contains(k) && Some(*k) != message_field) {
+            flatten(k, v, &mut params);
+        }
+
+        let mut message = format!("<{}>1 {} {} {} - {}", pri, timestamp, self.hostname, self.app_name, msgid);
+
+        match params.is_empty() {
+            true => message.push_str(" -"),
+            false => {
+                message.push_str(" [");
+                message.push_str(SD_ID);
+
+                for (name, value) in params {
+                    message.push_str(format!(" {}=\"{}\"", name, escape(value.as_str())).as_str());
+                }
+
+                message.push(']');
+            }
+        }
+
+        if let Some(text) = message_field.and_then(|field| flat[field].as_str()) {
+            // a UTF-8 message starts with a BOM (RFC 5424, section 6.4)
+            message.push_str(" \u{feff}");
+            message.push_str(text);
+        }
+
+        message
+    }
+
+    async fn send(&mut self, messages: Vec<String>) -> std::io::Result<()> {
+        match self.transport {
+            // a connection (or socket) that fails isn't put back, so the next batch gets a new one
+            SyslogTransport::Tcp => {
+                let mut stream = match self.tcp.take() {
+                    Some(stream) => stream,
+                    None => BufWriter::new(tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(self.address.as_str())).await
+                        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out connecting to the syslog server"))??),
+                };
+
+                for message in messages {
+                    stream.write_all(format!("{} {}", message.len(), message).as_bytes()).await?;
+                }
+
+                self.tcp = Some(stream);
+            }
+            SyslogTransport::Udp => {
+                let socket = match self.udp.take() {
+                    Some(socket) => socket,
+                    None => {
+                        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+
+                        socket.connect(self.address.as_str()).await?;
+                        socket
+                    }
+                };
+
+                for message in messages {
+                    socket.send(truncate(message.as_str(), UDP_MAX_BYTES).as_bytes()).await?;
+                }
+
+                self.udp = Some(socket);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Sink for Syslog {
+    fn write_batch<'a>(&'a mut self, records: &'a [JsonValue]) -> SinkFuture<'a> {
+        Box::pin(async move {
+            let mut messages = Vec::with_capacity(records.len());
+
+            // an invocation's records are a message each
+            for json in records {
+                match json.is_array() {
+                    true => messages.extend(json.members().map(|member| self.message(member))),
+                    false => messages.push(self.message(json)),
+                }
+            }
+
+            Ok(self.send(messages).await?)
+        })
+    }
+
+    fn flush(&mut self) -> SinkFuture<'_> {
+        Box::pin(async move {
+            if let Some(mut stream) = self.tcp.take() {
+                stream.flush().await?;
+                self.tcp = Some(stream);
+            }
+
+            Ok(())
+        })
+    }
+
+    fn shutdown(&mut self) -> SinkFuture<'_> {
+        Box::pin(async move {
+            if let Some(mut stream) = self.tcp.take() {
+                stream.shutdown().await?;
+            }
+
+            Ok(())
+        })
+    }
+}
+
+/// `name` and `value` as SD-PARAMs, `value`'s fields under `name.` if it's an object.
+fn flatten(name: &str, value: &JsonValue, params: &mut Vec<(String, String)>) {
+    match value {
+        JsonValue::Object(_) => {
+            for (k, v) in value.entries() {
+                flatten(format!("{}.{}", name, k).as_str(), v, params);
+            }
+        }
+        JsonValue::Short(_) | JsonValue::String(_) => params.push((sd_name(name), value.as_str().unwrap_or_default().to_string())),
+        JsonValue::Null => params.push((sd_name(name), String::new())),
+        _ => params.push((sd_name(name), value.dump())),
+    }
+}
+
+/// An SD-NAME: printable ASCII other than `=`, space, `]`, and `"`, the rest replaced, and at most 32 characters.
+fn sd_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_graphic() && !matches!(c, '=' | ']' | '"') { c } else { '_' })
+        .take(SD_NAME_MAX)
+        .collect()
+}
+
+/// A PARAM-VALUE, with the characters that would end it escaped.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | ']') {
+            escaped.push('\\');
+        }
+
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+/// A header field of printable ASCII, the rest replaced, at most `max` characters; `-` (nil) if it's empty.
+fn header_field(value: &str, max: usize) -> String {
+    match value.is_empty() {
+        true => "-".to_string(),
+        false => value.chars().map(|c| if c.is_ascii_graphic() { c } else { '_' }).take(max).collect(),
+    }
+}
+
+/// At most `max` bytes of `s`, ending on a character boundary.
+fn truncate(s: &str, max: usize) -> &str {
+    let mut end = s.len().min(max);
+
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    &s[..end]
+}
+
+/// Milliseconds since the epoch as an RFC 3339 time in UTC, to the millisecond.
+fn rfc3339(ms: i64) -> String {
+    let secs = ms.div_euclid(1_000);
+    let days = secs.div_euclid(86_400);
+    let secs_of_day = secs.rem_euclid(86_400);
+
+    // the days since 1970-01-01 as a Gregorian date (Howard Hinnant's civil_from_days)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            year, month, day, secs_of_day / 3_600, secs_of_day % 3_600 / 60, secs_of_day % 60, ms.rem_euclid(1_000))
+}
@@ -0,0 +1,32 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Lambda freezes the environment as soon as an invocation returns, but a gap this long between INVOKE events
+// is a freeze long enough for a connection to have been dropped somewhere along the way
+const THAW_GAP: Duration = Duration::from_secs(10);
+
+/// Spots the INVOKE events that come after the environment has been frozen for a while, with `probe_on_thaw`.
+pub struct ThawDetector {
+    last_invoked: Mutex<Duration>,
+}
+
+impl ThawDetector {
+    pub fn new() -> ThawDetector {
+        ThawDetector { last_invoked: Mutex::new(Duration::ZERO) }
+    }
+
+    /// Called on an INVOKE event, with the extension's uptime: true if it's the first in a while.
+    pub fn invoked(&self, uptime: Duration) -> bool {
+        let mut last_invoked = self.last_invoked.lock().unwrap_or_else(|e| e.into_inner());
+        let thawed = uptime.saturating_sub(*last_invoked) >= THAW_GAP;
+
+        *last_invoked = uptime;
+        thawed
+    }
+}
+
+impl Default for ThawDetector {
+    fn default() -> Self {
+        ThawDetector::new()
+    }
+}
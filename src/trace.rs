@@ -0,0 +1,65 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// The field holding the X-Ray trace id of the invocation a record belongs to, with `include_trace_id`.
+pub const TRACE_ID_FIELD: &str = "trace_id";
+
+// INVOKE events that haven't had their platform_start yet; there's only ever one or two
+const MAX_PENDING: usize = 16;
+
+struct TraceState {
+    pending: VecDeque<(String, String)>,
+    current: Option<String>,
+}
+
+/// Follows the X-Ray trace id of the invocation in progress. INVOKE events carry it, but arrive ahead of the
+/// invocation's logs, so it's kept by request id until the `platform_start` with that id comes through.
+pub struct TraceIds {
+    state: Mutex<TraceState>,
+}
+
+impl TraceIds {
+    pub fn new() -> TraceIds {
+        TraceIds { state: Mutex::new(TraceState { pending: VecDeque::new(), current: None }) }
+    }
+
+    /// Called on an INVOKE event, with its tracing header (`Root=...;Parent=...;Sampled=...`).
+    pub fn invoked(&self, request_id: &str, header: &str) {
+        let root = match root(header) {
+            Some(root) => root.to_string(),
+            None => return,
+        };
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+
+        if state.pending.len() == MAX_PENDING {
+            state.pending.pop_front();
+        }
+
+        state.pending.push_back((request_id.to_string(), root));
+    }
+
+    /// Called on `platform_start`: the invocation's trace id is current until the next one starts.
+    pub fn start(&self, request_id: &str) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let found = state.pending.iter().position(|(id, _)| id == request_id);
+
+        state.current = found.and_then(|i| state.pending.remove(i)).map(|(_, root)| root);
+    }
+
+    pub fn current(&self) -> Option<String> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).current.clone()
+    }
+}
+
+impl Default for TraceIds {
+    fn default() -> Self {
+        TraceIds::new()
+    }
+}
+
+/// The root trace id (e.g. `1-5759e988-bd862e3fe1be46a994272793`) from an X-Ray tracing header.
+pub fn root(header: &str) -> Option<&str> {
+    header.split(';')
+        .find_map(|part| part.trim().strip_prefix("Root="))
+        .filter(|root| !root.is_empty())
+}
@@ -0,0 +1,448 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use json::JsonValue;
+
+use crate::severity::{self, SeverityMap};
+
+/// The field holding the days the log-store should keep a record for, with `ttl_map`.
+pub const TTL_FIELD: &str = "ttl_days";
+
+/// A change made to every record once it's built, before it's enqueued. Power users embedding the
+/// library can register their own with `HandlerState::with_transform`.
+///
+/// Transforms run in the order they're registered, after the built-in ones, and always see the
+/// record as `{"meta":{...},"body":{...}}` whatever `LOG_STORE_LAYOUT` is: the fields the extension adds
+/// (`layout::META_FIELDS`) under `meta`, and everything else, including a function's own fields, under `body`.
+/// The layout is applied after the last transform, so anything added under `meta` or `body` is kept.
+pub trait RecordTransform: Send + Sync {
+    fn transform(&self, record: &mut JsonValue);
+}
+
+/// Stamps `severity` on every record, from `SeverityMap`.
+pub struct Leveler {
+    map: SeverityMap,
+}
+
+impl Leveler {
+    pub fn new(map: SeverityMap) -> Leveler {
+        Leveler { map }
+    }
+}
+
+impl RecordTransform for Leveler {
+    fn transform(&self, record: &mut JsonValue) {
+        let severity = self.map.severity(record["meta"]["type"].as_str().unwrap_or_default(), &record["body"]);
+
+        // meta is always an object
+        let _ = record["meta"].insert("severity", severity);
+    }
+}
+
+// where the extension puts a log line that isn't a JSON object, and its marks on it; never dropped by `KeepFields`
+pub(crate) const LINE_FIELDS: [&str; 4] = ["record", "_b64", "parse_failed", "split"];
+
+/// Keeps only the listed fields of function and extension records, dropping the rest of their `body`; `meta`
+/// and platform records are left alone, as is a plain text line. A field can be a dotted path into nested
+/// objects, e.g. `http.status`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeepFields {
+    // shortest first, so a path inside one that's already kept finds nothing left to move
+    paths: Vec<Vec<String>>,
+}
+
+impl RecordTransform for KeepFields {
+    fn transform(&self, record: &mut JsonValue) {
+        if !matches!(record["meta"]["type"].as_str(), Some("function") | Some("extension")) {
+            return;
+        }
+
+        let mut kept = JsonValue::new_object();
+
+        for field in LINE_FIELDS {
+            if record["body"].has_key(field) {
+                let _ = kept.insert(field, record["body"].remove(field));
+            }
+        }
+
+        for path in self.paths.iter() {
+            let mut from = &mut record["body"];
+
+            for key in path.iter() {
+                from = &mut from[key.as_str()];
+            }
+
+            if from.is_null() {
+                continue;
+            }
+
+            let value = from.take();
+            let mut to = &mut kept;
+
+            for key in path.iter() {
+                to = &mut to[key.as_str()];
+            }
+
+            *to = value;
+        }
+
+        record["body"] = kept;
+    }
+}
+
+impl FromStr for KeepFields {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut paths = s.split(',')
+            .map(str::trim)
+            .filter(|field| !field.is_empty())
+            .map(|field| field.split('.').map(str::to_string).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+
+        if paths.is_empty() {
+            return Err("expected a comma-separated list of fields".to_string());
+        }
+
+        paths.sort_by_key(Vec::len);
+        Ok(KeepFields { paths })
+    }
+}
+
+impl Display for KeepFields {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let fields = self.paths.iter().map(|path| path.join(".")).collect::<Vec<_>>();
+
+        write!(f, "{}", fields.join(","))
+    }
+}
+
+/// Stamps `ttl_days` on records, from a mapping of record types and severities to days, parsed from e.g.
+/// `error:90,warn:30,platform_report:14,*:7`: a key that's a severity is matched against the `severity` the
+/// `Leveler` stamped, `*` matches everything, and any other key is a record type. A record's type is looked
+/// up first, then its severity, then `*`; a record none of them match gets no `ttl_days`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TtlMap {
+    types: Vec<(String, u32)>,
+    severities: Vec<(&'static str, u32)>,
+    default: Option<u32>,
+}
+
+impl RecordTransform for TtlMap {
+    fn transform(&self, record: &mut JsonValue) {
+        let meta = &record["meta"];
+        let record_type = meta["type"].as_str();
+        let severity = meta["severity"].as_str();
+
+        let days = self.types.iter().find(|(key, _)| Some(key.as_str()) == record_type).map(|(_, days)| *days)
+            .or_else(|| self.severities.iter().find(|(key, _)| Some(*key) == severity).map(|(_, days)| *days))
+            .or(self.default);
+
+        if let Some(days) = days {
+            let _ = record["meta"].insert(TTL_FIELD, days);
+        }
+    }
+}
+
+impl FromStr for TtlMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut ttl_map = TtlMap { types: Vec::new(), severities: Vec::new(), default: None };
+
+        for entry in s.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (key, days) = entry.split_once(':').ok_or_else(|| format!("expected key:days, got {:?}", entry))?;
+            let days = days.trim().parse().map_err(|_| format!("expected a number of days, got {:?}", days))?;
+
+            match (key.trim(), severity::normalize(key)) {
+                ("*", _) => ttl_map.default = Some(days),
+                (_, Some(severity)) => ttl_map.severities.push((severity, days)),
+                (key, None) => ttl_map.types.push((key.to_string(), days)),
+            }
+        }
+
+        if ttl_map.types.is_empty() && ttl_map.severities.is_empty() && ttl_map.default.is_none() {
+            return Err("expected a comma-separated list of key:days".to_string());
+        }
+
+        Ok(ttl_map)
+    }
+}
+
+impl Display for TtlMap {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let entries = self.types.iter().map(|(key, days)| format!("{}:{}", key, days))
+            .chain(self.severities.iter().map(|(key, days)| format!("{}:{}", key, days)))
+            .chain(self.default.map(|days| format!("*:{}", days)))
+            .collect::<Vec<_>>();
+
+        write!(f, "{}", entries.join(","))
+    }
+}
+
+/// Which of a record's own field and `Enricher`'s wins when both have the same name.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EnrichPrecedence {
+    /// The record's own
+    #[default]
+    Record,
+    /// The enrichment file's
+    File,
+}
+
+impl FromStr for EnrichPrecedence {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "record" => Ok(EnrichPrecedence::Record),
+            "file" => Ok(EnrichPrecedence::File),
+            _ => Err(format!("unknown enrich precedence {:?}, expected record or file", s)),
+        }
+    }
+}
+
+impl Display for EnrichPrecedence {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EnrichPrecedence::Record => write!(f, "record"),
+            EnrichPrecedence::File => write!(f, "file"),
+        }
+    }
+}
+
+/// Merges the top-level fields of a static JSON object (build info, deployment details) into the `body` of
+/// every record. A field the record has already is left alone or replaced, per `EnrichPrecedence`; the
+/// fields the extension puts a log line under (`LINE_FIELDS`) never are, and are left out of the object.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Enricher {
+    fields: Vec<(String, JsonValue)>,
+    precedence: EnrichPrecedence,
+}
+
+impl Enricher {
+    /// `Err` with why if `object` isn't a JSON object.
+    pub fn new(object: JsonValue, precedence: EnrichPrecedence) -> Result<Enricher, String> {
+        if !object.is_object() {
+            return Err("expected a JSON object".to_string());
+        }
+
+        let fields = object.entries()
+            .filter(|(key, _)| !LINE_FIELDS.contains(key))
+            .map(|(key, value)| (key.to_string(), value.clone()))
+            .collect();
+
+        Ok(Enricher { fields, precedence })
+    }
+}
+
+impl RecordTransform for Enricher {
+    fn transform(&self, record: &mut JsonValue) {
+        let body = &mut record["body"];
+
+        if !body.is_object() {
+            return;
+        }
+
+        for (key, value) in self.fields.iter() {
+            if self.precedence == EnrichPrecedence::File || !body.has_key(key) {
+                body[key.as_str()] = value.clone();
+            }
+        }
+    }
+}
+
+/// What `NewlineNormalizer` puts in place of a line break.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NewlineReplacement {
+    /// The given text, a space by default
+    Text(String),
+    /// Its JSON escape spelled out, e.g. `\n` as a backslash and an `n`
+    Escape,
+}
+
+impl FromStr for NewlineReplacement {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "escape" => Ok(NewlineReplacement::Escape),
+            _ => Ok(NewlineReplacement::Text(s.to_string())),
+        }
+    }
+}
+
+impl Display for NewlineReplacement {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NewlineReplacement::Text(text) => write!(f, "{:?}", text),
+            NewlineReplacement::Escape => write!(f, "escape"),
+        }
+    }
+}
+
+// the line breaks some JSON parsers downstream mishandle, even escaped
+const LINE_BREAKS: [char; 4] = ['\r', '\n', '\u{2028}', '\u{2029}'];
+
+/// Replaces line breaks (CR, LF, CRLF, U+2028, and U+2029) in every string of a record, however deeply nested.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NewlineNormalizer {
+    replacement: NewlineReplacement,
+}
+
+impl NewlineNormalizer {
+    pub fn new(replacement: NewlineReplacement) -> NewlineNormalizer {
+        NewlineNormalizer { replacement }
+    }
+
+    fn normalize(&self, value: &mut JsonValue) {
+        match value {
+            JsonValue::Short(_) | JsonValue::String(_) => {
+                let text = value.as_str().unwrap_or_default();
+
+                if text.contains(LINE_BREAKS) {
+                    *value = self.replace(text).into();
+                }
+            }
+            JsonValue::Array(values) => values.iter_mut().for_each(|v| self.normalize(v)),
+            JsonValue::Object(_) => value.entries_mut().for_each(|(_, v)| self.normalize(v)),
+            _ => (),
+        }
+    }
+
+    fn replace(&self, text: &str) -> String {
+        let mut out = String::with_capacity(text.len());
+        let mut chars = text.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            if !LINE_BREAKS.contains(&c) {
+                out.push(c);
+                continue;
+            }
+
+            match &self.replacement {
+                // a CRLF is a single break
+                NewlineReplacement::Text(text) => {
+                    if c == '\r' && chars.peek() == Some(&'\n') {
+                        chars.next();
+                    }
+
+                    out.push_str(text);
+                }
+                // as JSON would escape them
+                NewlineReplacement::Escape => match c {
+                    '\r' => out.push_str("\\r"),
+                    '\n' => out.push_str("\\n"),
+                    _ => out.push_str(format!("\\u{:04x}", c as u32).as_str()),
+                },
+            }
+        }
+
+        out
+    }
+}
+
+impl RecordTransform for NewlineNormalizer {
+    fn transform(&self, record: &mut JsonValue) {
+        self.normalize(record);
+    }
+}
+
+const ESC: char = '\u{1b}';
+const BEL: char = '\u{7}';
+
+/// Removes ANSI escape sequences (colors and the like, from loggers made for a terminal) from every string of
+/// a record, however deeply nested. Only complete sequences are removed: CSI (`ESC [`, parameters, a final
+/// byte), OSC (`ESC ]` up to BEL or `ESC \`), and two-byte escapes like `ESC ( B`. A lone ESC, or one that
+/// doesn't start a sequence, is left as it is.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AnsiStripper;
+
+impl AnsiStripper {
+    fn strip_all(&self, value: &mut JsonValue) {
+        match value {
+            JsonValue::Short(_) | JsonValue::String(_) => {
+                let text = value.as_str().unwrap_or_default();
+
+                if text.contains(ESC) {
+                    *value = strip_ansi(text).into();
+                }
+            }
+            JsonValue::Array(values) => values.iter_mut().for_each(|v| self.strip_all(v)),
+            JsonValue::Object(_) => value.entries_mut().for_each(|(_, v)| self.strip_all(v)),
+            _ => (),
+        }
+    }
+}
+
+impl RecordTransform for AnsiStripper {
+    fn transform(&self, record: &mut JsonValue) {
+        self.strip_all(record);
+    }
+}
+
+/// `text` without the ANSI escape sequences `AnsiStripper` removes.
+pub fn strip_ansi(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find(ESC) {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        match escape_len(rest) {
+            Some(len) => rest = &rest[len..],
+            None => {
+                out.push(ESC);
+                rest = &rest[ESC.len_utf8()..];
+            }
+        }
+    }
+
+    out.push_str(rest);
+    out
+}
+
+/// The length of the escape sequence `text` starts with (at its ESC), if it's a complete one.
+fn escape_len(text: &str) -> Option<usize> {
+    let bytes = text.as_bytes();
+
+    match bytes.get(1)? {
+        // CSI: parameter bytes, then intermediate bytes, then the final byte
+        b'[' => {
+            let mut i = 2;
+
+            while bytes.get(i).is_some_and(|b| (0x30..=0x3f).contains(b)) {
+                i += 1;
+            }
+
+            while bytes.get(i).is_some_and(|b| (0x20..=0x2f).contains(b)) {
+                i += 1;
+            }
+
+            bytes.get(i).filter(|b| (0x40..=0x7e).contains(*b)).map(|_| i + 1)
+        }
+        // OSC (e.g. a hyperlink or window title): up to BEL or ST (ESC \)
+        b']' => {
+            let end = text[2..].find([BEL, ESC])? + 2;
+
+            match bytes[end] {
+                b'\x07' => Some(end + 1),
+                _ if bytes.get(end + 1) == Some(&b'\\') => Some(end + 2),
+                _ => None,
+            }
+        }
+        // intermediate bytes then a final byte, e.g. ESC ( B to pick a character set
+        b if (0x20..=0x2f).contains(b) => {
+            let mut i = 2;
+
+            while bytes.get(i).is_some_and(|b| (0x20..=0x2f).contains(b)) {
+                i += 1;
+            }
+
+            bytes.get(i).filter(|b| (0x30..=0x7e).contains(*b)).map(|_| i + 1)
+        }
+        // a single byte, e.g. ESC M or ESC 7
+        b if (0x30..=0x7e).contains(b) => Some(2),
+        _ => None,
+    }
+}
@@ -0,0 +1,44 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+/// What to do with a function's log line that wasn't valid UTF-8.
+///
+/// Lambda hands log lines over as JSON strings, so by the time they reach the extension invalid
+/// sequences have already been replaced with U+FFFD; that replacement character is what's detected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NonUtf8 {
+    /// Keep the line, with the replacement characters
+    Replace,
+    /// Send the line's bytes base64 encoded under `_b64`, instead of parsing it
+    Base64,
+    /// Drop the record
+    Drop,
+}
+
+/// True if `record` had invalid UTF-8 replaced on its way to us.
+pub fn is_lossy(record: &str) -> bool {
+    record.contains(char::REPLACEMENT_CHARACTER)
+}
+
+impl FromStr for NonUtf8 {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "replace" => Ok(NonUtf8::Replace),
+            "base64" => Ok(NonUtf8::Base64),
+            "drop" => Ok(NonUtf8::Drop),
+            _ => Err(format!("unknown non-UTF-8 handling {:?}, expected replace, base64, or drop", s)),
+        }
+    }
+}
+
+impl Display for NonUtf8 {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NonUtf8::Replace => write!(f, "replace"),
+            NonUtf8::Base64 => write!(f, "base64"),
+            NonUtf8::Drop => write!(f, "drop"),
+        }
+    }
+}
@@ -0,0 +1,1614 @@
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use json::{JsonValue, object};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::mpsc::{channel, Receiver};
+use tokio::time::{Instant, timeout_at};
+use tracing::{debug, error, info, warn};
+
+use crate::backoff;
+use crate::checksum::BatchChecksum;
+use crate::circuit::{CircuitBreaker, CircuitState};
+use crate::config::{Config, FlushMode, Secret};
+use crate::dedup::PlatformDedup;
+use crate::dns::DnsCache;
+use crate::encoder::{self, pretty, sort_keys, Encoder};
+use crate::file_sink::FileSink;
+use crate::framing::Framing;
+use crate::health::Health;
+use crate::invocation::{BatchBy, InvocationBatcher};
+use crate::layout;
+use crate::loki;
+use crate::otel::{self, Format};
+use crate::precision::TimePrecision;
+use crate::proxy;
+use crate::session::{self, DELIVERY_FIELD, SESSION_FIELD};
+use crate::shutdown::{Drain, Salvage, ShutdownDump, ShutdownListener};
+use crate::sink::Sink;
+use crate::spill::Spill;
+use crate::stats::{estimated_size, Pressure, Stats};
+
+// the field added to critical records, carrying the id the log-store must acknowledge
+pub(crate) const ACK_FIELD: &str = "_ack";
+/// The field holding the milliseconds from a record's `t` to it being written, with `include_latency`.
+pub const LAG_FIELD: &str = "ship_lag_ms";
+
+const RECONNECT_BACKOFF_MS: u64 = 100;
+const RECONNECT_MAX_BACKOFF_MS: u64 = 5_000;
+// in buffered mode, the size of pending records that triggers a flush, and of each write in a flush
+const BATCH_BYTES: usize = 8 * 1024;
+// how long before the drain deadline whatever is left is dumped, so it's out before the process goes
+const SHUTDOWN_DUMP_MARGIN: Duration = Duration::from_millis(50);
+/// The most records `write_sink` gives a `Sink` at once.
+pub const SINK_BATCH_MAX: usize = 512;
+// how long a heartbeat sent after a freeze gets for the log-store to reset the connection in reply
+const THAW_PROBE_WAIT: Duration = Duration::from_millis(20);
+// with wait_for_sink_secs, how often a local log-store that isn't listening yet is tried, and reported on
+const WAIT_FOR_SINK_POLL: Duration = Duration::from_millis(250);
+const WAIT_FOR_SINK_REPORT: Duration = Duration::from_secs(5);
+// with control_channel, the longest pause the log-store can ask for at once
+const MAX_THROTTLE_PAUSE: Duration = Duration::from_secs(10);
+// what's kept of a reply from the log-store that has no line break yet
+const MAX_REPLY_BYTES: usize = 64 * 1024;
+
+/// What the writers write: records as they're received and, once a shutdown is asked for,
+/// whatever is still queued followed by the shutdown summary.
+struct Incoming {
+    recver: Receiver<JsonValue>,
+    shutdown: ShutdownListener,
+    stats: Arc<Stats>,
+    drain: Option<Drain>,
+    written_at_drain: u64,
+    done: bool,
+    /// Where records are dumped with `shutdown_dump=spill`; they go to stdout if there's none
+    spill: Option<Spill>,
+    /// How many more bytes of records may be spilled
+    spill_budget: u64,
+    stdout_max_line_bytes: usize,
+    time_precision: TimePrecision,
+    /// With `init_buffer_max`, the records taken off the channel while the first connection was being made
+    early: VecDeque<JsonValue>,
+}
+
+impl Incoming {
+    fn new(recver: Receiver<JsonValue>, shutdown: ShutdownListener, stats: Arc<Stats>) -> Incoming {
+        Incoming { recver, shutdown, stats, drain: None, written_at_drain: 0, done: false, spill: None, spill_budget: 0,
+                   stdout_max_line_bytes: 0,
+                   time_precision: TimePrecision::Millis, early: VecDeque::new() }
+    }
+
+    /// Sets the spill directory, if records are to be spilled at all, and how much, how long a line dumped to stdout
+    /// may be, and how the summary's time is shipped.
+    fn with_spill(mut self, config: &Config) -> Incoming {
+        self.spill = (config.shutdown_dump == ShutdownDump::Spill).then(|| Spill::new(config.spill_dir.as_str()));
+        self.spill_budget = config.spill_max_bytes;
+        self.stdout_max_line_bytes = config.stdout_max_line_bytes;
+        self.time_precision = config.time_precision;
+        self
+    }
+
+    /// Has `next` return `early` before anything on the channel: records that were taken off it already.
+    fn with_early(mut self, early: VecDeque<JsonValue>) -> Incoming {
+        self.early = early;
+        self
+    }
+
+    /// The next record to write, or `None` once the channel is closed or the summary has been returned.
+    async fn next(&mut self) -> Option<JsonValue> {
+        if self.done {
+            return None;
+        }
+
+        if self.draining() {
+            if let Some(dump) = self.overdue() {
+                let mut dumped = 0;
+
+                while let Some(json) = self.early.pop_front().or_else(|| self.recver.try_recv().ok()) {
+                    self.stats.release(self.stats.inflight_size(&json));
+                    self.dump(dump, encoder::json_line(&json).as_ref());
+                    dumped += 1;
+                }
+
+                if dumped > 0 {
+                    warn!("Out of time to drain, dumping the last {} records ({})", dumped, dump);
+                }
+            }
+
+            return match (self.early.pop_front().or_else(|| self.recver.try_recv().ok()), &self.drain) {
+                (Some(json), _) => Some(json),
+                (None, drain) => {
+                    let drained = self.stats.records_written.load(Ordering::Relaxed) - self.written_at_drain;
+                    let mut summary = drain.as_ref()?.reason.summary(&self.stats, drained);
+
+                    self.time_precision.apply(&mut summary, 0);
+
+                    self.recver.close();
+                    self.done = true;
+                    Some(summary)
+                }
+            };
+        }
+
+        if let Some(json) = self.early.pop_front() {
+            return Some(json);
+        }
+
+        // once a shutdown has been asked for, the drain starts right away
+        tokio::select! {
+            biased;
+            drain = self.shutdown.requested() => {
+                self.written_at_drain = self.stats.records_written.load(Ordering::Relaxed);
+                self.drain = Some(drain);
+                Box::pin(self.next()).await
+            }
+            json = self.recver.recv() => json,
+        }
+    }
+
+    /// The next record to write, with as many more (up to `max`) as are waiting already; empty once `next` is `None`.
+    async fn next_batch(&mut self, max: usize) -> Vec<JsonValue> {
+        let mut batch: Vec<_> = self.next().await.into_iter().collect();
+
+        while !batch.is_empty() && batch.len() < max && !self.done {
+            // while draining, `next` doesn't wait for anything
+            let json = match self.draining() {
+                true => self.next().await,
+                false => self.recver.try_recv().ok(),
+            };
+
+            match json {
+                Some(json) => batch.push(json),
+                None => break,
+            }
+        }
+
+        batch
+    }
+
+    /// What to do with the records left, once the drain deadline is about to pass.
+    fn overdue(&self) -> Option<ShutdownDump> {
+        self.drain.as_ref()
+            .filter(|drain| Instant::now() + SHUTDOWN_DUMP_MARGIN >= drain.deadline)
+            .map(|drain| drain.dump)
+    }
+
+    /// Gets a record (`line`, without its newline) out of the way of a shutdown that's out of time.
+    fn dump(&mut self, dump: ShutdownDump, line: &str) {
+        if dump == ShutdownDump::Drop {
+            return self.stats.add_dropped(1);
+        }
+
+        let size = line.len() as u64 + 1;
+
+        // without a spill directory (e.g. on stdout), if it can't be written to, or once the spill has used its
+        // budget (which keeps it within SHUTDOWN_DUMP_MARGIN), it's stdout
+        let spilled = match &mut self.spill {
+            Some(spill) if size <= self.spill_budget => {
+                spill.append(line).map_err(|e| warn!("Error spilling record: {}", e)).is_ok()
+            }
+            _ => false,
+        };
+
+        if spilled {
+            self.spill_budget -= size;
+            self.stats.shutdown_spilled.fetch_add(1, Ordering::Relaxed);
+        } else {
+            println!("{}", encoder::fit_line(line, self.stdout_max_line_bytes));
+        }
+
+        self.stats.shutdown_dumped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// True once a shutdown has been asked for.
+    fn draining(&self) -> bool {
+        self.drain.is_some()
+    }
+
+    /// Called once everything has been written and the sink closed.
+    fn finished(&self) {
+        self.shutdown.finished();
+    }
+}
+
+// a supervised writer's channel outlives it, even if it panics
+impl Drop for Incoming {
+    fn drop(&mut self) {
+        if self.shutdown.is_supervised() {
+            let (_, closed) = channel(1);
+
+            self.shutdown.hand_back(std::mem::replace(&mut self.recver, closed));
+        }
+    }
+}
+
+/// How `write_stdout` prints records.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdoutFormat {
+    /// Indented over several lines
+    pub pretty: bool,
+    pub sort_keys: bool,
+    pub logfmt: bool,
+    /// See `encoder::fit_line`
+    pub max_line_bytes: usize,
+    /// Records in each checksummed batch, with `batch_checksum`
+    pub checksum_records: Option<u64>,
+}
+
+impl StdoutFormat {
+    /// Plain JSON lines, as the writers falling back to stdout print them.
+    pub fn json(config: &Config) -> StdoutFormat {
+        StdoutFormat { max_line_bytes: config.stdout_max_line_bytes, ..StdoutFormat::default() }
+    }
+}
+
+/// Writes records to stdout, as JSON (indented, with `pretty_print`) or with `logfmt`, as logfmt lines.
+/// When another writer falls back to stdout, it's JSON, as CloudWatch expects. JSON lines longer than
+/// `max_line_bytes` are truncated (see `encoder::fit_line`).
+pub async fn write_stdout(format: StdoutFormat, stats: Arc<Stats>, recver: Receiver<JsonValue>, shutdown: ShutdownListener) {
+    print_incoming(Incoming::new(recver, shutdown, stats.clone()), format, stats, None).await
+}
+
+/// Prints records as `write_stdout` does; with `health`, for a TCP writer that's fallen back to stdout, its health
+/// records (saying it isn't connected) are printed among them.
+async fn print_incoming(mut incoming: Incoming, format: StdoutFormat, stats: Arc<Stats>, health: Option<Health>) {
+    let mut checksum = format.checksum_records.map(BatchChecksum::new);
+    let max_line_bytes = format.max_line_bytes;
+    let mut next_health = health.as_ref().map(|health| Instant::now() + health.interval());
+    incoming.stdout_max_line_bytes = max_line_bytes;
+
+    loop {
+        let (json, queued) = tokio::select! {
+            json = incoming.next() => match json {
+                Some(json) => (json, true),
+                None => break,
+            },
+            _ = tokio::time::sleep_until(next_health.unwrap_or_else(Instant::now)), if next_health.is_some() => {
+                next_health = health.as_ref().map(|health| Instant::now() + health.interval());
+
+                match health.as_ref().and_then(|health| health.record(&stats, false)) {
+                    Some(json) => (json, false),
+                    None => continue,
+                }
+            }
+        };
+        let out = if format.sort_keys { Cow::Owned(sort_keys(&json)) } else { Cow::Borrowed(&json) };
+        let line = match (format.logfmt, format.pretty) {
+            // a pre-serialized record, from a writer that fell back to stdout, is printed as it was encoded
+            _ if json.is_string() => format!("{}\n", encoder::fit_line(encoder::json_line(&json).as_ref(), max_line_bytes)),
+            (true, _) => encoder::logfmt(&out),
+            (false, true) => pretty(&out),
+            (false, false) => format!("{}\n", encoder::fit_line(out.dump().as_str(), max_line_bytes)),
+        };
+
+        print!("{}", line);
+        stats.record_written(line.len());
+
+        if queued {
+            stats.release(stats.inflight_size(&json));
+        }
+
+        if let Some(batch) = checksum.as_mut().and_then(|checksum| checksum.add(&line)) {
+            print!("{}", batch);
+        }
+    }
+
+    if let Some(batch) = checksum.as_mut().and_then(BatchChecksum::take) {
+        print!("{}", batch);
+    }
+
+    incoming.finished();
+}
+
+pub async fn write_file(path: String, config: Arc<Config>, stats: Arc<Stats>, recver: Receiver<JsonValue>, shutdown: ShutdownListener) {
+    let mut sink = match FileSink::open(path.as_str(), config.file_max_bytes, config.file_keep).await {
+        Ok(s) => s.with_encoder(Encoder::new(&config)),
+        Err(e) => {
+            eprintln!("Error opening log file {}: {}", path, e);
+            eprintln!("Logs will be written to STDOUT instead");
+            let format = StdoutFormat {
+                pretty: config.pretty,
+                sort_keys: config.sort_keys,
+                checksum_records: config.batch_checksum.then_some(config.batch_checksum_records),
+                ..StdoutFormat::json(&config)
+            };
+
+            return write_stdout(format, stats, recver, shutdown).await;
+        }
+    };
+    let mut incoming = Incoming::new(recver, shutdown, stats.clone()).with_spill(&config);
+    let mut checksum = config.batch_checksum.then(|| BatchChecksum::new(config.batch_checksum_records));
+
+    while let Some(json) = incoming.next().await {
+        let line = sink.encode(&json);
+
+        // the batch so far is closed off in the file it was written to
+        if sink.rotates_before(line.len()) {
+            write_batch_checksum(&mut sink, checksum.as_mut().and_then(BatchChecksum::take)).await;
+        }
+
+        match sink.write(line.as_str()).await {
+            Ok(()) => {
+                stats.record_written(line.len());
+                write_batch_checksum(&mut sink, checksum.as_mut().and_then(|checksum| checksum.add(&line))).await;
+            }
+            Err(e) => eprintln!("Error writing to log file: {}", e),
+        }
+
+        stats.release(stats.inflight_size(&json));
+    }
+
+    write_batch_checksum(&mut sink, checksum.as_mut().and_then(BatchChecksum::take)).await;
+
+    if let Err(e) = sink.shutdown().await {
+        error!("Error closing log file: {}", e);
+    }
+
+    incoming.finished();
+}
+
+async fn write_batch_checksum(sink: &mut FileSink, batch: Option<String>) {
+    if let Some(batch) = batch {
+        if let Err(e) = sink.append(batch.as_str()).await {
+            eprintln!("Error writing to log file: {}", e);
+        }
+    }
+}
+
+/// Writes records to a sink of the embedder's own (see `Sink` for what it can expect), in batches of whatever was
+/// waiting. As the sink does its own encoding, the bytes written are counted by their estimated size.
+pub async fn write_sink(mut sink: Box<dyn Sink>, config: Arc<Config>, stats: Arc<Stats>, recver: Receiver<JsonValue>, shutdown: ShutdownListener) {
+    let mut incoming = Incoming::new(recver, shutdown, stats.clone()).with_spill(&config);
+
+    loop {
+        let batch = incoming.next_batch(SINK_BATCH_MAX).await;
+
+        if batch.is_empty() {
+            break;
+        }
+
+        let bytes = batch.iter().map(estimated_size).sum::<u64>();
+
+        match sink.write_batch(&batch).await {
+            Ok(()) => stats.frame_written(batch.len(), bytes as usize),
+            Err(e) => {
+                eprintln!("Error writing {} records to sink: {}", batch.len(), e);
+                stats.add_dropped(batch.len() as u64);
+            }
+        }
+
+        for json in batch.iter() {
+            stats.release(stats.inflight_size(json));
+        }
+
+        // nothing else was waiting
+        if batch.len() < SINK_BATCH_MAX {
+            if let Err(e) = sink.flush().await {
+                eprintln!("Error flushing sink: {}", e);
+            }
+        }
+    }
+
+    if let Err(e) = sink.flush().await {
+        eprintln!("Error flushing sink: {}", e);
+    }
+
+    if let Err(e) = sink.shutdown().await {
+        error!("Error closing sink: {}", e);
+    }
+
+    incoming.finished();
+}
+
+pub async fn write_tcp(log_store_address: String, config: Arc<Config>, stats: Arc<Stats>, recver: Receiver<JsonValue>, shutdown: ShutdownListener) {
+    TcpWriter::new(log_store_address, config, stats).start(recver, shutdown).await
+}
+
+/// Runs the writer `start` makes from the channel and a listener, and if it panics, starts another on the
+/// same channel (what the one that panicked had taken off it is lost), up to `restarts` times. After that,
+/// the records go to stdout, so a warm instance that keeps hitting a bug still has its logs somewhere.
+pub async fn supervise<S, F>(recver: Receiver<JsonValue>, shutdown: ShutdownListener, config: Arc<Config>, stats: Arc<Stats>, mut start: S)
+    where S: FnMut(Receiver<JsonValue>, ShutdownListener) -> F,
+          F: Future<Output = ()> + Send + 'static
+{
+    let restarts = config.writer_restarts;
+    let salvage: Salvage = Arc::new(Mutex::new(None));
+    let mut recver = recver;
+    let mut restarted = 0;
+
+    loop {
+        let panic = match tokio::spawn(start(recver, shutdown.supervised(salvage.clone()))).await {
+            Ok(()) => break,
+            Err(e) if e.is_panic() => e.into_panic(),
+            Err(_) => break,
+        };
+        let reason = panic.downcast_ref::<&str>().copied()
+            .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown");
+
+        recver = match salvage.lock().unwrap_or_else(|e| e.into_inner()).take() {
+            Some(recver) => recver,
+            None => {
+                error!("The writer panicked ({}) and took its channel with it; nothing more will be written", reason);
+                break;
+            }
+        };
+
+        if restarted == restarts {
+            error!("The writer panicked ({}) after {} restarts, writing to stdout from now on", reason, restarts);
+            return write_stdout(StdoutFormat::json(&config), stats, recver, shutdown).await;
+        }
+
+        restarted += 1;
+        warn!("The writer panicked ({}), starting a new one (restart {} of {})", reason, restarted, restarts);
+    }
+
+    shutdown.finished();
+}
+
+/// A record's `t` as it's about to be written (in milliseconds), whatever the format and `time_precision`.
+fn record_time_ms(json: &JsonValue, format: Format, precision: TimePrecision) -> Option<i64> {
+    let nanos = match format {
+        Format::Json | Format::Logfmt => return precision.to_ms(layout::field(json, "t")),
+        Format::Otel => &json["timeUnixNano"],
+        Format::Loki => &json["streams"][0]["values"][0][0],
+    };
+
+    nanos.as_str().and_then(|nanos| nanos.parse::<i128>().ok()).map(|nanos| (nanos / 1_000_000) as i64)
+}
+
+/// A record's `type` as it's about to be written, whatever the format.
+fn record_type(json: &JsonValue, format: Format) -> Option<&str> {
+    record_str(json, format, "type")
+}
+
+/// A string field of a record as it's about to be written: an attribute of an OTel record, or a label of a Loki push.
+fn record_str<'a>(json: &'a JsonValue, format: Format, key: &str) -> Option<&'a str> {
+    match format {
+        Format::Json | Format::Logfmt => layout::field(json, key).as_str(),
+        Format::Otel => json["attributes"].members().find(|a| a["key"] == key).and_then(|a| a["value"]["stringValue"].as_str()),
+        Format::Loki => json["streams"][0]["stream"][key].as_str(),
+    }
+}
+
+/// Adds `ship_lag_ms` to a record as it's about to be written: at the top level, under `meta` in the envelope
+/// layout, or as an attribute of an OTel record. Loki pushes are left as they are: their lines are already made.
+fn stamp_lag(json: &mut JsonValue, format: Format, precision: TimePrecision, now_ms: i64) {
+    match format {
+        Format::Json | Format::Logfmt => {
+            let lag = record_time_ms(json, format, precision).map(|t| (now_ms - t).max(0));
+            let fields = if json.has_key("meta") { &mut json["meta"] } else { json };
+
+            if let Some(lag) = lag {
+                let _ = fields.insert(LAG_FIELD, lag);
+            }
+        }
+        Format::Otel => {
+            if let Some(t) = record_time_ms(json, format, precision) {
+                let lag = (now_ms - t).max(0);
+                let _ = json["attributes"].push(object! { "key": LAG_FIELD, "value": { "intValue": lag.to_string() } });
+            }
+        }
+        Format::Loki => (),
+    }
+}
+
+/// Adds a field of the writer's (`sid`, `n`) to a record as it's about to be written, where `stamp_lag` puts
+/// `ship_lag_ms`: at the top level, under `meta` in the envelope layout, or as an attribute of an OTel record.
+/// Loki pushes are left as they are: their labels pick out a stream, not a record.
+fn stamp_field(json: &mut JsonValue, format: Format, key: &str, value: JsonValue) -> json::Result<()> {
+    match format {
+        Format::Json | Format::Logfmt => {
+            let fields = if json.has_key("meta") { &mut json["meta"] } else { json };
+
+            fields.insert(key, value)
+        }
+        Format::Otel => json["attributes"].push(otel::attribute(key, &value)),
+        Format::Loki => Ok(()),
+    }
+}
+
+struct Connection {
+    stream: BufWriter<OwnedWriteHalf>,
+    acks: BufReader<OwnedReadHalf>,
+    framing: Framing,
+    /// What the log-store has sent back since the last whole line read
+    replies: Vec<u8>,
+    control_channel: bool,
+    /// With `control_channel`, when a pause the log-store asked for ends
+    paused_until: Option<Instant>,
+}
+
+impl Connection {
+    async fn write(&mut self, line: &str) -> std::io::Result<()> {
+        self.stream.write_all(&self.framing.frame(line)).await?;
+        self.stream.flush().await
+    }
+
+    /// Writes whole records from the front of `pending`, removing those fully written. Once `deadline`
+    /// passes the record being written is finished, so records are never split, and the rest are left.
+    /// Returns the bytes written, and whether the deadline was hit.
+    async fn write_batch(&mut self, pending: &mut VecDeque<String>, deadline: Option<Instant>) -> std::io::Result<(usize, bool)> {
+        // nothing is left in the BufWriter in buffered mode, so this writes straight to the socket
+        let stream = self.stream.get_mut();
+        let mut total = 0;
+
+        while !pending.is_empty() {
+            // coalesce whole records into writes of about BATCH_BYTES
+            let mut chunk = Vec::new();
+            let mut ends = Vec::new();
+
+            for line in pending.iter() {
+                let frame = self.framing.frame(line);
+
+                if !chunk.is_empty() && chunk.len() + frame.len() > BATCH_BYTES {
+                    break;
+                }
+
+                chunk.extend_from_slice(&frame);
+                ends.push(chunk.len());
+            }
+
+            let mut written = 0;
+            let mut hit_deadline = false;
+
+            while written < chunk.len() {
+                let n = match deadline {
+                    Some(deadline) => match timeout_at(deadline, stream.write(&chunk[written..])).await {
+                        Ok(n) => n?,
+                        Err(_) => {
+                            hit_deadline = true;
+                            break;
+                        }
+                    },
+                    None => stream.write(&chunk[written..]).await?,
+                };
+
+                if n == 0 {
+                    return Err(ErrorKind::WriteZero.into());
+                }
+
+                written += n;
+            }
+
+            if hit_deadline {
+                // finish the record that was cut off, so the log-store never sees half of one
+                if let Some(end) = ends.iter().find(|end| **end > written) {
+                    stream.write_all(&chunk[written..*end]).await?;
+                    written = *end;
+                }
+            }
+
+            let done = ends.iter().take_while(|end| **end <= written).count();
+
+            pending.drain(..done);
+            total += written;
+
+            if hit_deadline || deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Ok((total, !pending.is_empty()));
+            }
+        }
+
+        Ok((total, false))
+    }
+
+    /// True if the log-store has closed its end (or reset the connection); checked without blocking. Whatever
+    /// it has sent back is read (see `on_reply`) on the way, so it can't fill the receive buffer and stall the
+    /// connection.
+    async fn is_closed(&mut self) -> bool {
+        loop {
+            // timeout() polls the read once before looking at the (already elapsed) deadline; a line it cuts
+            // off stays in `replies`, for the next read to finish
+            match tokio::time::timeout(Duration::ZERO, self.acks.read_until(b'\n', &mut self.replies)).await {
+                Ok(Ok(0) | Err(_)) => return true,
+                Ok(Ok(_)) => {
+                    let reply = std::mem::take(&mut self.replies);
+
+                    self.on_reply(&reply);
+                }
+                Err(_) => {
+                    if self.replies.len() > MAX_REPLY_BYTES {
+                        self.replies.clear();
+                    }
+
+                    return false;
+                }
+            }
+        }
+    }
+
+    /// Acts on a line the log-store sent back that isn't an ack being waited for. With `control_channel`,
+    /// `{"type":"throttle","pause_ms":N}` pauses writing for N milliseconds (up to `MAX_THROTTLE_PAUSE`);
+    /// anything else, like a late ack, is ignored.
+    fn on_reply(&mut self, reply: &[u8]) {
+        if !self.control_channel {
+            return;
+        }
+
+        let message = match json::parse(String::from_utf8_lossy(reply).trim()) {
+            Ok(message) => message,
+            Err(_) => return debug!("Ignoring reply from log-store: {}", String::from_utf8_lossy(reply).trim()),
+        };
+
+        match (message["type"].as_str(), message["pause_ms"].as_u64()) {
+            (Some("throttle"), Some(pause_ms)) => {
+                let pause = Duration::from_millis(pause_ms).min(MAX_THROTTLE_PAUSE);
+
+                self.paused_until = Some(Instant::now() + pause);
+            }
+            _ => debug!("Ignoring control message from log-store: {}", message.dump()),
+        }
+    }
+}
+
+/// Writes records to a log-store over TCP, reconnecting (with backoff) when the connection is lost.
+pub struct TcpWriter {
+    address: String,
+    config: Arc<Config>,
+    stats: Arc<Stats>,
+    encoder: Encoder,
+    conn: Option<Connection>,
+    /// Whether `conn` was closed for being idle, rather than lost
+    idle_closed: bool,
+    /// A new one for every connection, so the log-store can tell where the stream was interrupted
+    session_id: String,
+    /// With `include_seq`, the number the next record sent on the connection gets
+    next_n: u64,
+    next_ack_id: u64,
+    breaker: CircuitBreaker,
+    /// With `batch_by=invocation`, the records of the invocation in progress
+    batcher: Option<InvocationBatcher>,
+    /// In buffered mode, encoded records waiting for the next flush
+    pending: VecDeque<String>,
+    pending_bytes: usize,
+    /// With `coalesce_wait_ms`, set while records are being queued, as in buffered mode, to write together
+    coalescing: bool,
+    dns: DnsCache,
+    /// With `dedup_platform`, the platform records written so far
+    dedup: Option<PlatformDedup>,
+    /// With `init_buffer_max`, what `start` took off the channel while connecting, for `run` to write first
+    early: VecDeque<JsonValue>,
+    /// With `health_interval_secs`, what makes the `health` records
+    health: Option<Health>,
+}
+
+impl TcpWriter {
+    pub fn new(address: String, config: Arc<Config>, stats: Arc<Stats>) -> TcpWriter {
+        TcpWriter {
+            address,
+            encoder: Encoder::new(&config),
+            breaker: CircuitBreaker::new(config.cb_failure_threshold, Duration::from_millis(config.cb_cooldown_ms)),
+            batcher: (config.batch_by == BatchBy::Invocation).then(|| {
+                InvocationBatcher::new(Duration::from_millis(config.batch_by_timeout_ms), config.batch_by_max_bytes)
+            }),
+            dns: DnsCache::new(Duration::from_secs(config.dns_ttl_secs)),
+            dedup: config.dedup_platform.then(PlatformDedup::new),
+            early: VecDeque::new(),
+            health: None,
+            config,
+            stats,
+            conn: None,
+            idle_closed: false,
+            session_id: session::new_id(),
+            next_n: 0,
+            next_ack_id: 0,
+            pending: VecDeque::new(),
+            pending_bytes: 0,
+            coalescing: false,
+        }
+    }
+
+    /// Has the writer send `health` records, with `health_interval_secs`.
+    pub fn with_health(mut self, health: Option<Health>) -> TcpWriter {
+        self.health = health;
+        self
+    }
+
+    /// Connects to the log-store, through the proxy if there is one.
+    pub async fn connect(&mut self) -> std::io::Result<()> {
+        let stream = match &self.config.proxy {
+            Some(proxy) => {
+                let auth = self.config.proxy_auth.as_ref().map(Secret::expose);
+
+                proxy::connect(proxy.as_str(), self.address.as_str(), auth).await?
+            }
+            None => self.connect_direct().await?,
+        };
+        let (read_half, write_half) = stream.into_split();
+        let mut conn = Connection {
+            stream: BufWriter::new(write_half),
+            acks: BufReader::new(read_half),
+            framing: self.config.framing,
+            replies: Vec::new(),
+            control_channel: self.config.control_channel,
+            paused_until: None,
+        };
+
+        self.session_id = session::new_id();
+        self.next_n = 0;
+
+        if self.config.include_session {
+            let mut hello = object! {
+                "t": self.stats.now_ms(),
+                "type": "session_start",
+                "severity": "info",
+                "sid": self.session_id.as_str(),
+            };
+
+            self.config.time_precision.apply(&mut hello, 0);
+
+            conn.write(self.encoder.encode(&hello).as_str()).await?;
+        }
+
+        self.conn = Some(conn);
+
+        Ok(())
+    }
+
+    /// Connects to one of the addresses the log-store resolves to. If none of the cached ones take, they're
+    /// looked up again and tried once more, in case the log-store has moved.
+    async fn connect_direct(&mut self) -> std::io::Result<TcpStream> {
+        let (addrs, cached) = self.dns.resolve(self.address.as_str()).await?;
+
+        match TcpStream::connect(addrs.as_slice()).await {
+            Ok(stream) => Ok(stream),
+            Err(_) if cached => {
+                self.dns.invalidate();
+
+                let (addrs, _) = self.dns.resolve(self.address.as_str()).await?;
+
+                TcpStream::connect(addrs.as_slice()).await.inspect_err(|_| self.dns.invalidate())
+            }
+            Err(e) => {
+                self.dns.invalidate();
+                Err(e)
+            }
+        }
+    }
+
+    /// Checks the connection is still there after a freeze, by sending a heartbeat record, and reconnects
+    /// if it's not. The log-store may well have dropped it while the environment was frozen, without the
+    /// kernel here hearing about it until something is written.
+    async fn probe(&mut self) {
+        let mut heartbeat = object! { "t": self.stats.now_ms(), "type": "heartbeat", "severity": "debug" };
+
+        self.config.time_precision.apply(&mut heartbeat, 0);
+        let line = self.encoder.encode(&heartbeat);
+        let conn = match self.conn.as_mut() {
+            Some(conn) => conn,
+            None => return,
+        };
+
+        if !conn.is_closed().await && conn.write(line.as_str()).await.is_ok() && conn.stream.flush().await.is_ok() {
+            // a reset in reply to the heartbeat takes a round trip to arrive
+            tokio::time::sleep(THAW_PROBE_WAIT).await;
+
+            if !conn.is_closed().await {
+                return;
+            }
+        }
+
+        warn!("Connection to log-store at {} didn't survive the freeze, reconnecting", self.address);
+        self.conn = None;
+
+        if let Err(e) = self.reconnect().await {
+            warn!("Error reconnecting to log-store at {}: {}", self.address, e);
+        }
+    }
+
+    /// Connects, retrying up to `retries` times with backoff.
+    pub async fn connect_with_retries(&mut self, retries: u32) -> std::io::Result<()> {
+        let mut attempt = 0;
+
+        loop {
+            match self.connect().await {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt < retries => {
+                    let delay = backoff::delay(attempt, RECONNECT_BACKOFF_MS, RECONNECT_MAX_BACKOFF_MS);
+
+                    attempt += 1;
+                    warn!("Error connecting to log-store at {} (attempt {} of {}), retrying in {:?}: {}",
+                          self.address, attempt, retries + 1, delay, e);
+                    tokio::time::sleep(delay).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Connects (with `initial_connect_retries`) unless that takes longer than `preconnect_timeout_ms`; called
+    /// during init, so the connection is there by the time the first records are. If it's not, `start` tries again.
+    pub async fn preconnect(&mut self) {
+        let deadline = Instant::now() + Duration::from_millis(self.config.preconnect_timeout_ms);
+
+        match timeout_at(deadline, self.connect_with_retries(self.config.initial_connect_retries)).await {
+            Ok(Ok(())) => info!("Connected to log-store at {}", self.address),
+            Ok(Err(e)) => warn!("Error connecting to log-store at {} during init: {}", self.address, e),
+            Err(_) => warn!("Not connected to log-store at {} after {}ms, registering anyway",
+                            self.address, self.config.preconnect_timeout_ms),
+        }
+    }
+
+    /// Writes everything received on `recver`, as `run`, connecting first if `preconnect` didn't.
+    /// If that fails, it all goes to stdout instead.
+    /// With `init_buffer_max`, the records that arrive meanwhile are taken off the channel (up to that many), so
+    /// the handlers aren't held up, and written before the rest.
+    pub async fn start(mut self, mut recver: Receiver<JsonValue>, shutdown: ShutdownListener) {
+        // the log-store may still be starting up, e.g. during a coordinated deploy
+        if self.conn.is_none() {
+            let max_early = self.config.init_buffer_max.unwrap_or_default();
+            let mut early = VecDeque::new();
+            let connected = {
+                let connecting = async {
+                    match self.connect_with_retries(self.config.initial_connect_retries).await {
+                        Err(e) => self.wait_for_sink(e).await,
+                        connected => connected,
+                    }
+                };
+
+                tokio::pin!(connecting);
+
+                loop {
+                    tokio::select! {
+                        connected = &mut connecting => break connected,
+                        Some(json) = recver.recv(), if early.len() < max_early => early.push_back(json),
+                    }
+                }
+            };
+
+            if !early.is_empty() {
+                info!("Connected, writing the {} records that arrived while connecting", early.len());
+            }
+
+            if let Err(e) = connected {
+                eprintln!("Error connecting to log-store instance at {}: {}", self.address, e);
+                eprintln!("Logs will be written to STDOUT instead");
+                let format = StdoutFormat { sort_keys: self.config.sort_keys, ..StdoutFormat::json(&self.config) };
+                let incoming = Incoming::new(recver, shutdown, self.stats.clone()).with_early(early);
+
+                return print_incoming(incoming, format, self.stats, self.health).await;
+            }
+
+            self.early = early;
+        }
+
+        self.run(recver, shutdown).await
+    }
+
+    /// With `wait_for_sink_secs`, keeps trying a log-store on a loopback address that isn't listening yet, for
+    /// local development where it may start after the extension; otherwise gives up with the error from connecting.
+    async fn wait_for_sink(&mut self, e: std::io::Error) -> std::io::Result<()> {
+        if self.config.wait_for_sink_secs == 0 || self.config.proxy.is_some() {
+            return Err(e);
+        }
+
+        match self.dns.resolve(self.address.as_str()).await {
+            Ok((addrs, _)) if !addrs.is_empty() && addrs.iter().all(|addr| addr.ip().is_loopback()) => (),
+            _ => return Err(e),
+        }
+
+        let wait = Duration::from_secs(self.config.wait_for_sink_secs);
+        let started = Instant::now();
+        let mut reported = started;
+
+        info!("Log-store at {} isn't listening yet, waiting up to {:?} for it to start", self.address, wait);
+
+        loop {
+            tokio::time::sleep(WAIT_FOR_SINK_POLL).await;
+
+            match self.connect().await {
+                Ok(()) => {
+                    info!("Connected to log-store at {} after waiting {:?}", self.address, started.elapsed());
+                    return Ok(());
+                }
+                Err(e) if started.elapsed() >= wait => return Err(e),
+                Err(_) if reported.elapsed() >= WAIT_FOR_SINK_REPORT => {
+                    reported = Instant::now();
+                    info!("Still waiting for log-store at {} to start listening ({}s of {}s)",
+                          self.address, started.elapsed().as_secs(), wait.as_secs());
+                }
+                Err(_) => (),
+            }
+        }
+    }
+
+    /// Connects again after losing the connection (or closing it while idle), retrying up to `reconnect_retries` times.
+    async fn reconnect(&mut self) -> std::io::Result<()> {
+        self.connect_with_retries(self.config.reconnect_retries).await?;
+
+        // picking up after going idle isn't a lost connection
+        if !std::mem::take(&mut self.idle_closed) {
+            self.stats.reconnects.fetch_add(1, Ordering::Relaxed);
+        }
+
+        Ok(())
+    }
+
+    /// Writes a record, or in buffered mode queues it for the next flush (flushing once `BATCH_BYTES` are queued).
+    /// An invocation's frame (an array of records) is written right away, as it's a batch already.
+    /// If the connection has been lost, reconnects and writes it again on the new connection.
+    pub async fn write(&mut self, mut json: JsonValue) -> std::io::Result<()> {
+        let critical = self.is_critical(&json);
+        let frame = json.is_array();
+
+        if self.config.max_record_age_ms.is_some() {
+            match frame {
+                true => {
+                    let records = json.members().filter(|record| !self.is_stale(record)).cloned().collect::<Vec<_>>();
+
+                    self.stats.stale_dropped.fetch_add((json.len() - records.len()) as u64, Ordering::Relaxed);
+                    json = JsonValue::Array(records);
+
+                    if json.is_empty() {
+                        return Ok(());
+                    }
+                }
+                false if self.is_stale(&json) => {
+                    self.stats.stale_dropped.fetch_add(1, Ordering::Relaxed);
+                    return Ok(());
+                }
+                false => (),
+            }
+        }
+
+        if self.dedup.is_some() {
+            match frame {
+                true => {
+                    let records = json.members().filter(|record| self.is_first(record)).cloned().collect::<Vec<_>>();
+
+                    json = JsonValue::Array(records);
+
+                    if json.is_empty() {
+                        return Ok(());
+                    }
+                }
+                false if !self.is_first(&json) => return Ok(()),
+                false => (),
+            }
+        }
+
+        let records = if frame { json.len() } else { 1 };
+
+        // an invocation's Loki pushes go as one
+        if frame && self.config.format == Format::Loki {
+            json = loki::merge(json);
+        }
+
+        if self.config.include_latency {
+            let now_ms = self.stats.now_ms() as i64;
+
+            match frame {
+                true => json.members_mut().for_each(|record| stamp_lag(record, self.config.format, self.config.time_precision, now_ms)),
+                false => stamp_lag(&mut json, self.config.format, self.config.time_precision, now_ms),
+            }
+        }
+
+        if critical {
+            self.next_ack_id += 1;
+            json.insert(ACK_FIELD, self.next_ack_id).map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+        }
+
+        // with `preserialize`, the handler has encoded it already, and nothing here adds to it
+        let mut line = match json.as_str() {
+            Some(line) => line.to_string(),
+            None => self.encode(&mut json)?,
+        };
+
+        if self.config.flush_mode == FlushMode::Buffered || self.coalescing {
+            if !critical && !frame {
+                self.pending_bytes += line.len();
+                self.pending.push_back(line);
+
+                return match self.pending_bytes >= BATCH_BYTES {
+                    true => self.flush().await,
+                    false => Ok(()),
+                };
+            }
+
+            // keep the records before a critical one (or a frame) ahead of it
+            self.flush().await?;
+        }
+
+        let mut reconnected = false;
+
+        loop {
+            if let Some(conn) = self.conn.as_mut() {
+                if conn.is_closed().await {
+                    warn!("Log-store at {} closed the connection", self.address);
+                    self.conn = None;
+                }
+            }
+
+            self.throttle().await;
+
+            let res = match self.conn.as_mut() {
+                Some(conn) if critical => write_acked(conn, line.as_str(), self.next_ack_id, &self.config).await,
+                Some(conn) => {
+                    let queued = self.pending.len();
+
+                    watch_slow(conn.write(line.as_str()), queued, &self.config, &self.stats).await
+                }
+                None => Err(ErrorKind::NotConnected.into()),
+            };
+
+            match res {
+                Ok(()) => {
+                    self.stats.frame_written(records, line.len());
+                    return Ok(());
+                }
+                // an unacknowledged record isn't a connection problem
+                Err(e) if e.kind() == ErrorKind::TimedOut => return Err(e),
+                Err(_) if !reconnected => {
+                    self.conn = None;
+                    self.reconnect().await?;
+                    reconnected = true;
+
+                    // it's going out on the new session
+                    if self.config.include_session || self.config.include_seq {
+                        line = self.encode(&mut json)?;
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Encodes a record, stamped with the current session id if `include_session` is set, and numbered if
+    /// `include_seq` is. Records queued in buffered mode keep the session (and number) they were queued under.
+    fn encode(&mut self, json: &mut JsonValue) -> std::io::Result<String> {
+        if self.config.include_session || self.config.include_seq {
+            let records = match &mut *json {
+                JsonValue::Array(records) => records.iter_mut().collect(),
+                json => vec![json],
+            };
+
+            let format = self.config.format;
+
+            for json in records {
+                if self.config.include_session {
+                    stamp_field(json, format, SESSION_FIELD, self.session_id.as_str().into())
+                        .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+                }
+
+                if self.config.include_seq {
+                    stamp_field(json, format, DELIVERY_FIELD, self.next_n.into())
+                        .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+                    self.next_n += 1;
+                }
+            }
+        }
+
+        Ok(self.encoder.encode(json))
+    }
+
+    /// True if a record is older than `max_record_age_ms`, and isn't an exempt platform record.
+    fn is_stale(&self, json: &JsonValue) -> bool {
+        let max_age_ms = match self.config.max_record_age_ms {
+            Some(max_age_ms) => max_age_ms as i64,
+            None => return false,
+        };
+        let format = self.config.format;
+
+        if self.config.max_record_age_exempt_platform && record_type(json, format).is_some_and(|t| t.starts_with("platform")) {
+            return false;
+        }
+
+        record_time_ms(json, format, self.config.time_precision).is_some_and(|t| self.stats.now_ms() as i64 - t > max_age_ms)
+    }
+
+    /// With `dedup_platform`, false for a platform record that's been written already, which is counted as
+    /// skipped; a platform record is taken as written from here on.
+    fn is_first(&mut self, json: &JsonValue) -> bool {
+        let format = self.config.format;
+        let (dedup, record_type) = match (self.dedup.as_mut(), record_type(json, format)) {
+            (Some(dedup), Some(record_type)) if record_type.starts_with("platform") => (dedup, record_type),
+            _ => return true,
+        };
+        let first = record_str(json, format, "request_id").is_none_or(|request_id| dedup.first(record_type, request_id));
+
+        if !first {
+            debug!("Skipping {} record, already written", record_type);
+            self.stats.platform_deduped.fetch_add(1, Ordering::Relaxed);
+        }
+
+        first
+    }
+
+    /// Waits out a pause the log-store asked for, with `control_channel`.
+    async fn throttle(&mut self) {
+        if let Some(until) = self.conn.as_mut().and_then(|conn| conn.paused_until.take()) {
+            info!("Log-store at {} asked for a pause, waiting {}ms", self.address, until.saturating_duration_since(Instant::now()).as_millis());
+            tokio::time::sleep_until(until).await;
+        }
+    }
+
+    fn is_critical(&self, json: &JsonValue) -> bool {
+        self.config.ack_critical && self.config.critical_match.matches(json)
+    }
+
+    /// Writes a record, unless the circuit is open and it goes to stdout instead. Once the cooldown is over,
+    /// the record is a probe: a single connection attempt, going to stdout if that or the write fails.
+    async fn deliver(&mut self, json: JsonValue) -> std::io::Result<()> {
+        self.deliver_as(json, true).await
+    }
+
+    /// `deliver`, with `print_probe` false for a record the caller prints to stdout itself if it isn't written,
+    /// so a probe that fails isn't printed twice.
+    async fn deliver_as(&mut self, json: JsonValue, print_probe: bool) -> std::io::Result<()> {
+        let state = self.breaker.state();
+
+        if state == CircuitState::Open {
+            println!("{}", encoder::fit_line(encoder::json_line(&json).as_ref(), self.config.stdout_max_line_bytes));
+            return Ok(());
+        }
+
+        // only a probe needs to be kept, in case it doesn't get through
+        let probing = state == CircuitState::HalfOpen;
+        let probe = (probing && print_probe).then(|| json.clone());
+
+        if probing && self.conn.is_none() {
+            if let Err(e) = self.connect().await {
+                self.failed(probe);
+                return Err(e);
+            }
+        }
+
+        let res = self.write(json).await;
+
+        match &res {
+            Ok(()) => {
+                if probing {
+                    info!("Log-store at {} is back, closing the circuit", self.address);
+                }
+
+                self.breaker.success();
+                self.stats.circuit_open.store(false, Ordering::Relaxed);
+            }
+            // an unacknowledged record isn't a connection problem
+            Err(e) if e.kind() == ErrorKind::TimedOut => (),
+            Err(_) => self.failed(probe),
+        }
+
+        res
+    }
+
+    /// Counts a failed write or connect, opening the circuit if that's one too many.
+    fn failed(&mut self, probe: Option<JsonValue>) {
+        if let Some(json) = probe {
+            println!("{}", encoder::fit_line(encoder::json_line(&json).as_ref(), self.config.stdout_max_line_bytes));
+        }
+
+        if self.breaker.failure() {
+            warn!("Log-store at {} keeps failing, sending records to stdout for {}ms",
+                  self.address, self.config.cb_cooldown_ms);
+            self.stats.circuit_open.store(true, Ordering::Relaxed);
+            self.stats.circuit_trips.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Writes the records queued in buffered mode, reconnecting first if the connection was lost; if that
+    /// fails they're lost. With `batch_deadline_ms`, records still queued when the deadline passes are kept
+    /// for the next flush, and the connection is dropped as suspect so the next flush starts on a fresh one.
+    pub async fn flush(&mut self) -> std::io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        if let Some(conn) = self.conn.as_mut() {
+            if conn.is_closed().await {
+                warn!("Log-store at {} closed the connection", self.address);
+                self.conn = None;
+            }
+        }
+
+        self.throttle().await;
+
+        if self.conn.is_none() {
+            if let Err(e) = self.reconnect().await {
+                self.pending.clear();
+                self.pending_bytes = 0;
+                return Err(e);
+            }
+        }
+
+        let deadline = self.config.batch_deadline_ms.map(|ms| Instant::now() + Duration::from_millis(ms));
+        let count = self.pending.len();
+        let res = match self.conn.as_mut() {
+            Some(conn) => watch_slow(conn.write_batch(&mut self.pending, deadline), count, &self.config, &self.stats).await,
+            None => Err(ErrorKind::NotConnected.into()),
+        };
+
+        let written = count - self.pending.len();
+
+        self.stats.records_written.fetch_add(written as u64, Ordering::Relaxed);
+
+        if written > 0 {
+            self.stats.last_written_ms.store(self.stats.now_ms(), Ordering::Relaxed);
+        }
+
+        match res {
+            Ok((bytes, hit_deadline)) => {
+                self.stats.bytes_written.fetch_add(bytes as u64, Ordering::Relaxed);
+                self.pending_bytes = self.pending.iter().map(String::len).sum();
+
+                if hit_deadline {
+                    warn!("Flush to log-store at {} passed its {}ms deadline with {} records left; dropping the connection",
+                          self.address, self.config.batch_deadline_ms.unwrap_or_default(), self.pending.len());
+                    self.conn = None;
+                }
+
+                Ok(())
+            }
+            Err(e) => {
+                self.conn = None;
+                self.pending.clear();
+                self.pending_bytes = 0;
+                Err(e)
+            }
+        }
+    }
+
+    /// Writes the records spilled by processes that are gone, as they were spilled, deleting each file once
+    /// it's all been written. If a write fails, the file is left for the next process, which writes it whole.
+    async fn replay(&mut self, spill: &Spill) {
+        for path in spill.claim_leftovers() {
+            let contents = match tokio::fs::read_to_string(&path).await {
+                Ok(contents) => contents,
+                Err(e) => {
+                    warn!("Error reading spilled records from {}: {}", path.display(), e);
+                    continue
+                }
+            };
+            let mut replayed = 0;
+
+            for line in contents.lines().filter(|line| !line.is_empty()) {
+                // a logfmt line doesn't parse, so is never too old, or a duplicate
+                let json = json::parse(line).ok();
+
+                if json.as_ref().is_some_and(|json| self.is_stale(json)) {
+                    self.stats.stale_dropped.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+
+                if json.as_ref().is_some_and(|json| !self.is_first(json)) {
+                    continue;
+                }
+
+                let line = format!("{}\n", line);
+                let res = match self.conn.as_mut() {
+                    Some(conn) => conn.write(line.as_str()).await,
+                    None => Err(ErrorKind::NotConnected.into()),
+                };
+
+                if let Err(e) = res {
+                    warn!("Error replaying spilled records from {}, leaving them for next time: {}", path.display(), e);
+                    self.conn = None;
+                    return;
+                }
+
+                self.stats.frame_written(1, line.len());
+                replayed += 1;
+            }
+
+            info!("Replayed {} records spilled by a previous process", replayed);
+
+            if let Err(e) = tokio::fs::remove_file(&path).await {
+                warn!("Error removing replayed spill file {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// The estimated size of what's queued in buffered mode and held with `batch_by=invocation`.
+    fn buffered_bytes(&self) -> u64 {
+        self.pending_bytes as u64 + self.batcher.as_ref().map_or(0, InvocationBatcher::bytes)
+    }
+
+    pub async fn shutdown(&mut self) -> std::io::Result<()> {
+        match self.conn.take() {
+            Some(mut conn) => conn.stream.shutdown().await,
+            None => Ok(()),
+        }
+    }
+
+    /// Writes everything received on `recver` until the channel is closed, or a shutdown is asked for
+    /// and the summary has been written.
+    pub async fn run(mut self, recver: Receiver<JsonValue>, shutdown: ShutdownListener) {
+        let early = std::mem::take(&mut self.early);
+        let mut incoming = Incoming::new(recver, shutdown, self.stats.clone()).with_spill(&self.config).with_early(early);
+
+        // what the last process on this host couldn't get out goes before anything new
+        if self.config.shutdown_dump == ShutdownDump::Spill {
+            self.replay(&Spill::new(self.config.spill_dir.as_str())).await;
+        }
+
+        // in buffered mode, the time by which anything written must be flushed
+        let flush_interval = Duration::from_millis(self.config.flush_interval_ms);
+        let flush_timer = tokio::time::sleep(flush_interval);
+        let mut dirty = false;
+        // with `idle_disconnect_secs`, the connection is closed once nothing has been written for that long
+        let idle_timeout = Duration::from_secs(self.config.idle_disconnect_secs);
+        let mut last_written = Instant::now();
+        // records dropped since going over the memory budget
+        let mut shed = 0;
+        // with `coalesce_wait_ms`, while records arriving are to be written with the one before them
+        let coalesce_wait = Duration::from_millis(self.config.coalesce_wait_ms);
+        let mut coalesce_until = None;
+
+        // with `probe_on_thaw`, told when an invocation comes after a long freeze
+        let stats = self.stats.clone();
+        // with `health_interval_secs`, when the next health record is due
+        let mut next_health = self.health.as_ref().map(|health| Instant::now() + health.interval());
+
+        tokio::pin!(flush_timer);
+
+        loop {
+            let hold_deadline = self.batcher.as_ref().and_then(InvocationBatcher::deadline);
+
+            if self.stats.memory_budget().is_some() {
+                self.stats.buffered_bytes.store(self.buffered_bytes(), Ordering::Relaxed);
+            }
+
+            let idle = !idle_timeout.is_zero() && self.conn.is_some() && self.pending.is_empty();
+
+            let json = tokio::select! {
+                json = incoming.next() => match json {
+                    Some(json) => json,
+                    None => break,
+                },
+                _ = tokio::time::sleep_until(hold_deadline.unwrap_or_else(Instant::now)), if hold_deadline.is_some() => {
+                    warn!("No platform_report after {}ms, writing the invocation's records so far", self.config.batch_by_timeout_ms);
+
+                    if let Some(frame) = self.batcher.as_mut().and_then(InvocationBatcher::take) {
+                        if let Err(e) = self.deliver(frame).await {
+                            eprintln!("Error writing to log-store: {}", e);
+                        }
+                    }
+
+                    continue
+                }
+                _ = tokio::time::sleep_until(last_written + idle_timeout), if idle => {
+                    info!("Nothing written to log-store at {} for {}s, closing the connection until the next record",
+                          self.address, self.config.idle_disconnect_secs);
+
+                    if let Err(e) = self.shutdown().await {
+                        warn!("Error closing idle connection: {}", e);
+                    }
+
+                    self.idle_closed = true;
+                    continue
+                }
+                _ = tokio::time::sleep_until(next_health.unwrap_or_else(Instant::now)), if next_health.is_some() => {
+                    next_health = self.health.as_ref().map(|health| Instant::now() + health.interval());
+
+                    // with the log-store down, it's on stdout for CloudWatch instead (printed here, not by `deliver`)
+                    if let Some(json) = self.health.as_ref().and_then(|health| health.record(&self.stats, self.conn.is_some())) {
+                        if let Err(e) = self.deliver_as(json.clone(), false).await {
+                            warn!("Error writing health record to log-store, writing it to stdout: {}", e);
+                            println!("{}", encoder::fit_line(json.dump().as_str(), self.config.stdout_max_line_bytes));
+                        }
+                    }
+
+                    continue
+                }
+                _ = stats.wait_for_thaw(), if self.config.probe_on_thaw => {
+                    self.probe().await;
+                    continue
+                }
+                _ = tokio::time::sleep_until(coalesce_until.unwrap_or_else(Instant::now)), if coalesce_until.is_some() => {
+                    coalesce_until = None;
+                    self.coalescing = false;
+
+                    if let Err(e) = self.flush().await {
+                        eprintln!("Error flushing stream: {}", e);
+                        self.failed(None);
+                    }
+
+                    continue
+                }
+                _ = &mut flush_timer, if dirty => {
+                    dirty = false;
+
+                    if let Err(e) = self.flush().await {
+                        eprintln!("Error flushing stream: {}", e);
+                        self.failed(None);
+                    }
+
+                    // what was left past the batch deadline goes in the next flush
+                    if !self.pending.is_empty() {
+                        flush_timer.as_mut().reset(Instant::now() + flush_interval);
+                        dirty = true;
+                    }
+
+                    continue
+                }
+            };
+
+            let size = self.stats.inflight_size(&json);
+            let critical = self.is_critical(&json);
+            // with `flush_types`, written and flushed as soon as it's here
+            let urgent = self.config.flush_types.matches(&json);
+            let pressure = self.stats.pressure();
+
+            last_written = Instant::now();
+
+            // at the memory budget, the records coming off the channel are the oldest there are; once dropping,
+            // that goes on until the estimate is back under the flush threshold
+            let shedding = !critical && !incoming.draining() && match pressure {
+                Pressure::Shed => true,
+                Pressure::Flush => shed > 0,
+                Pressure::Normal => false,
+            };
+
+            if shedding {
+                if shed == 0 {
+                    warn!("Over the {}B memory budget, dropping the oldest records", self.config.memory_budget_bytes.unwrap_or_default());
+                }
+
+                self.stats.release(size);
+                self.stats.add_dropped(1);
+                shed += 1;
+                continue
+            }
+
+            if shed > 0 && pressure == Pressure::Normal {
+                info!("Back under the memory budget, after dropping {} records", shed);
+                shed = 0;
+            }
+
+            // the first record in a while waits up to `coalesce_wait_ms` for more, to be written with them
+            if !coalesce_wait.is_zero() && coalesce_until.is_none() && self.config.flush_mode == FlushMode::Eager && !incoming.draining() {
+                coalesce_until = Some(Instant::now() + coalesce_wait);
+                self.coalescing = true;
+            }
+
+            // critical records aren't held, so they can be acked; nor is anything once the drain has started
+            let frames = match self.batcher.as_mut() {
+                Some(batcher) if !critical && !urgent && !incoming.draining() => batcher.push(json),
+                Some(batcher) => batcher.take().into_iter().chain([json]).collect(),
+                None => vec![json],
+            };
+
+            // queued (and held) records are counted as written; BATCH_BYTES is small next to any sensible limit
+            self.stats.release(size);
+
+            for frame in frames {
+                if let Err(e) = self.deliver(frame).await {
+                    eprintln!("Error writing to log-store: {}", e);
+                    continue
+                }
+
+                if self.config.flush_mode == FlushMode::Buffered && !dirty {
+                    flush_timer.as_mut().reset(Instant::now() + flush_interval);
+                    dirty = true;
+                }
+            }
+
+            // nearing the memory budget, nothing is buffered for long
+            if urgent || pressure >= Pressure::Flush {
+                if let Some(frame) = self.batcher.as_mut().and_then(InvocationBatcher::take) {
+                    if let Err(e) = self.deliver(frame).await {
+                        eprintln!("Error writing to log-store: {}", e);
+                    }
+                }
+
+                if let Err(e) = self.flush().await {
+                    eprintln!("Error flushing stream: {}", e);
+                    self.failed(None);
+                }
+            }
+        }
+
+        // the channel closed with an invocation still held
+        if let Some(frame) = self.batcher.as_mut().and_then(InvocationBatcher::take) {
+            if let Err(e) = self.deliver(frame).await {
+                eprintln!("Error writing to log-store: {}", e);
+            }
+        }
+
+        // every flush writes at least one record, and the whole drain is bounded by the shutdown deadline
+        while !self.pending.is_empty() {
+            if let Some(dump) = incoming.overdue() {
+                warn!("Out of time to drain, dumping the last {} records ({})", self.pending.len(), dump);
+
+                for line in self.pending.drain(..) {
+                    incoming.dump(dump, line.trim_end_matches('\n'));
+                }
+
+                self.pending_bytes = 0;
+                break;
+            }
+
+            if let Err(e) = self.flush().await {
+                error!("Error flushing stream: {}", e);
+            }
+        }
+
+        if let Err(e) = self.shutdown().await {
+            error!("Error shutting down stream: {}", e);
+        }
+
+        incoming.finished();
+    }
+}
+
+/// Awaits a write to the log-store. With `slow_sink_ms`, one still blocked after that long (the log-store isn't
+/// reading fast enough to keep the send buffer from filling, rather than being gone) is reported on stdout with a
+/// `sink_slow` record, and `stats.sink_slow` is set until it completes, so the handlers apply the overflow policy
+/// without waiting for room. `queued` is how many records the writer has queued, besides those on the channel.
+async fn watch_slow<F, T>(write: F, queued: usize, config: &Config, stats: &Stats) -> T
+    where F: Future<Output = T>
+{
+    let slow_after = match config.slow_sink_ms {
+        Some(ms) => Duration::from_millis(ms),
+        None => return write.await,
+    };
+    let started = Instant::now();
+
+    tokio::pin!(write);
+
+    tokio::select! {
+        res = &mut write => return res,
+        _ = tokio::time::sleep(slow_after) => (),
+    }
+
+    let mut slow = object! {
+        "t": stats.now_ms(),
+        "type": "sink_slow",
+        "severity": "warn",
+        "queued": queued + stats.queue_depth(),
+        "blocked_ms": started.elapsed().as_millis() as u64,
+    };
+
+    config.time_precision.apply(&mut slow, 0);
+    println!("{}", encoder::fit_line(slow.dump().as_str(), config.stdout_max_line_bytes));
+    stats.sink_slow.store(true, Ordering::Relaxed);
+    stats.slow_writes.fetch_add(1, Ordering::Relaxed);
+
+    let res = write.await;
+
+    stats.sink_slow.store(false, Ordering::Relaxed);
+    info!("Write to log-store unblocked after {}ms", started.elapsed().as_millis());
+    res
+}
+
+/// Writes a critical record, already tagged with `"_ack": <id>`, and waits for the log-store to reply with
+/// the line `ack <id>`; re-sending it up to `ack_retries` times if no ack arrives within `ack_timeout_ms`.
+/// Acks for other ids (e.g. late ones for a record that already timed out) are skipped.
+async fn write_acked(conn: &mut Connection, line: &str, id: u64, config: &Config) -> std::io::Result<()> {
+    let expected = format!("ack {}", id);
+
+    for attempt in 0..=config.ack_retries {
+        conn.stream.write_all(&conn.framing.frame(line)).await?;
+        conn.stream.flush().await?;
+
+        let deadline = Instant::now() + Duration::from_millis(config.ack_timeout_ms);
+
+        loop {
+            match timeout_at(deadline, conn.acks.read_until(b'\n', &mut conn.replies)).await {
+                Ok(Ok(0)) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(Ok(_)) => {
+                    let reply = std::mem::take(&mut conn.replies);
+
+                    if String::from_utf8_lossy(&reply).trim() == expected {
+                        return Ok(());
+                    }
+
+                    conn.on_reply(&reply);
+                }
+                Ok(Err(e)) => return Err(e),
+                Err(_) => break,
+            }
+        }
+
+        warn!("No ack for critical record {} after {}ms (attempt {} of {})",
+              id, config.ack_timeout_ms, attempt + 1, config.ack_retries + 1);
+    }
+
+    Err(std::io::Error::new(ErrorKind::TimedOut, format!("record {} was never acknowledged", id)))
+}
@@ -0,0 +1,38 @@
+use std::time::Duration;
+use log_store_extension::circuit::{CircuitBreaker, CircuitState};
+
+#[tokio::test]
+async fn opens_after_threshold_and_closes_on_probe() {
+    let mut breaker = CircuitBreaker::new(3, Duration::from_millis(20));
+
+    assert!(!breaker.failure());
+    assert!(!breaker.failure());
+    assert!(breaker.failure());
+    assert_eq!(breaker.state(), CircuitState::Open);
+    assert!(!breaker.allow());
+
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    assert_eq!(breaker.state(), CircuitState::HalfOpen);
+
+    // a failed probe re-opens it straight away
+    assert!(breaker.failure());
+    assert_eq!(breaker.state(), CircuitState::Open);
+
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    breaker.success();
+    assert_eq!(breaker.state(), CircuitState::Closed);
+
+    // and the count starts over
+    assert!(!breaker.failure());
+}
+
+#[test]
+fn zero_threshold_never_opens() {
+    let mut breaker = CircuitBreaker::new(0, Duration::from_millis(20));
+
+    for _ in 0..10 {
+        assert!(!breaker.failure());
+    }
+
+    assert_eq!(breaker.state(), CircuitState::Closed);
+}
@@ -0,0 +1,81 @@
+use std::env;
+use log_store_extension::config::{Config, SinkAddress, ADDRESS_ENV_NAME, ADDRESS_FILE_ENV_NAME, LOG_LEVEL_ENV_NAME, SEQ_SCOPE_ENV_NAME, SUBSCRIBE_RETRIES_ENV_NAME};
+use log_store_extension::sequence::SeqScope;
+use tracing::Level;
+
+#[test]
+fn config_is_a_snapshot() {
+    env::set_var(ADDRESS_ENV_NAME, "127.0.0.1:1234");
+    env::set_var(SUBSCRIBE_RETRIES_ENV_NAME, "5");
+    env::set_var(SEQ_SCOPE_ENV_NAME, "invocation");
+
+    let config = Config::from_env().unwrap();
+    let snapshot = config.clone();
+
+    env::set_var(ADDRESS_ENV_NAME, "file:/tmp/logs.ndjson");
+    env::set_var(SUBSCRIBE_RETRIES_ENV_NAME, "0");
+    env::remove_var(SEQ_SCOPE_ENV_NAME);
+
+    assert_eq!(config, snapshot);
+    assert_eq!(config.address, SinkAddress::Tcp("127.0.0.1:1234".to_string()));
+    assert_eq!(config.subscribe_retries, 5);
+    assert_eq!(config.seq_scope, Some(SeqScope::Invocation));
+
+    // the environment really did change, only a new snapshot sees it
+    let reloaded = Config::from_env().unwrap();
+
+    assert_ne!(config, reloaded);
+    assert_eq!(reloaded.address, SinkAddress::File("/tmp/logs.ndjson".to_string()));
+}
+
+#[test]
+fn log_level_falls_back_to_rust_log() {
+    let level = |vars: &[(&str, &str)]| {
+        let mut all = vec![(ADDRESS_ENV_NAME, "stdout")];
+        all.extend_from_slice(vars);
+        Config::from_vars(all).unwrap().log_level
+    };
+
+    assert_eq!(level(&[]), Level::INFO);
+    assert_eq!(level(&[("RUST_LOG", "debug")]), Level::DEBUG);
+    assert_eq!(level(&[("RUST_LOG", "info,hyper=warn")]), Level::INFO);
+    assert_eq!(level(&[("RUST_LOG", "debug"), (LOG_LEVEL_ENV_NAME, "WARN")]), Level::WARN);
+}
+
+#[test]
+fn cloudwatch_addresses_are_an_error() {
+    let error = Config::from_vars([(ADDRESS_ENV_NAME, "cloudwatch://my-group/my-stream")]).unwrap_err();
+
+    assert!(error.to_string().contains("CloudWatch Logs isn't supported"), "{}", error);
+    assert!(Config::from_vars([(ADDRESS_ENV_NAME, "127.0.0.1:1234"), ("LOG_STORE_MIRROR_ADDRESS", "cloudwatch://my-group/my-stream")]).is_err());
+}
+
+#[test]
+fn address_can_come_from_a_file() {
+    let path = env::temp_dir().join(format!("log-store-address-{}", std::process::id()));
+    let path_str = path.to_str().unwrap();
+
+    std::fs::write(&path, "  10.0.0.7:1234\n").unwrap();
+
+    let config = Config::from_vars([(ADDRESS_FILE_ENV_NAME, path_str)]).unwrap();
+
+    assert_eq!(config.address, SinkAddress::Tcp("10.0.0.7:1234".to_string()));
+    assert!(config.warnings.is_empty());
+
+    // nor is it logged with the config
+    assert!(!format!("{:?}", config).contains("10.0.0.7"));
+    assert!(format!("{:?}", config).contains("address: <from file>"));
+    assert!(format!("{:?}", Config::from_vars([(ADDRESS_ENV_NAME, "10.0.0.7:1234")]).unwrap()).contains("10.0.0.7:1234"));
+
+    // the variable wins, with a warning
+    let config = Config::from_vars([(ADDRESS_ENV_NAME, "stdout"), (ADDRESS_FILE_ENV_NAME, path_str)]).unwrap();
+
+    assert_eq!(config.address, SinkAddress::Stdout);
+    assert_eq!(config.warnings[0].field, ADDRESS_FILE_ENV_NAME);
+
+    std::fs::write(&path, "\n").unwrap();
+    assert!(Config::from_vars([(ADDRESS_FILE_ENV_NAME, path_str)]).is_err());
+
+    std::fs::remove_file(&path).unwrap();
+    assert!(Config::from_vars([(ADDRESS_FILE_ENV_NAME, path_str)]).is_err());
+}
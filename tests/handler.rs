@@ -0,0 +1,1065 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use chrono::{TimeZone, Utc};
+use json::{JsonValue, object};
+use lambda_extension::{LambdaLog, LambdaLogRecord, LogPlatformReportMetrics};
+use log_store_extension::clock::MockClock;
+use log_store_extension::config::Config;
+use log_store_extension::handler::{handler, HandlerState};
+use log_store_extension::stats::Stats;
+use log_store_extension::transform::RecordTransform;
+use tokio::sync::mpsc::channel;
+
+const TIME_MS: i64 = 1_712_345_678_000;
+
+/// Runs `logs` through `handler` with the given config variables, returning what it enqueued.
+async fn handle(logs: Vec<LambdaLogRecord>, vars: &[(&str, &str)]) -> Vec<JsonValue> {
+    handle_with(logs, vars, |state| state).await
+}
+
+/// Like `handle`, with a chance to register transforms on the state.
+async fn handle_with<F>(logs: Vec<LambdaLogRecord>, vars: &[(&str, &str)], f: F) -> Vec<JsonValue>
+    where F: FnOnce(HandlerState) -> HandlerState
+{
+    handle_on(logs, vars, Stats::new(None), f).await
+}
+
+/// Like `handle_with`, sharing the given `stats` (and its clock) with the handler.
+async fn handle_on<F>(logs: Vec<LambdaLogRecord>, vars: &[(&str, &str)], stats: Stats, f: F) -> Vec<JsonValue>
+    where F: FnOnce(HandlerState) -> HandlerState
+{
+    let mut all = vec![("LOG_STORE_ADDRESS", "stdout")];
+    all.extend_from_slice(vars);
+
+    let config = Config::from_vars(all).unwrap();
+    let (sender, mut recver) = channel(16);
+    let state = Arc::new(f(HandlerState::new(&config, sender, Arc::new(stats))));
+    let logs = logs.into_iter()
+        .map(|record| LambdaLog { time: Utc.timestamp_millis_opt(TIME_MS).unwrap(), record })
+        .collect();
+
+    handler(logs, state).await.unwrap();
+
+    let mut records = Vec::new();
+
+    while let Ok(json) = recver.try_recv() {
+        records.push(json);
+    }
+
+    records
+}
+
+fn function_with_type() -> LambdaLogRecord {
+    LambdaLogRecord::Function(r#"{"type":"order_placed","level":"warn","order_id":42}"#.to_string())
+}
+
+#[tokio::test]
+async fn flat_layout_merges_function_fields() {
+    let records = handle(vec![function_with_type()], &[]).await;
+
+    // the function's own `type` wins, as it always has
+    assert_eq!(records, vec![object! {
+        "t": TIME_MS,
+        "type": "order_placed",
+        "level": "warn",
+        "order_id": 42,
+        "severity": "warn",
+    }]);
+}
+
+#[tokio::test]
+async fn envelope_layout_keeps_function_fields_apart() {
+    let records = handle(vec![
+        function_with_type(),
+        LambdaLogRecord::PlatformStart { request_id: "abc".to_string() },
+    ], &[("LOG_STORE_LAYOUT", "envelope")]).await;
+
+    assert_eq!(records, vec![
+        object! {
+            "meta": { "t": TIME_MS, "type": "function", "severity": "warn" },
+            "body": { "type": "order_placed", "level": "warn", "order_id": 42 },
+        },
+        object! {
+            "meta": { "t": TIME_MS, "type": "platform_start", "severity": "debug" },
+            "body": { "request_id": "abc" },
+        },
+    ]);
+}
+
+/// Tags records with the severity the built-in leveler gave them, to show it ran first.
+struct SeenSeverity;
+
+impl RecordTransform for SeenSeverity {
+    fn transform(&self, record: &mut JsonValue) {
+        let seen = record["meta"]["severity"].clone();
+
+        record["body"].insert("seen_severity", seen).unwrap();
+    }
+}
+
+#[tokio::test]
+async fn custom_transforms_run_after_the_built_in_ones() {
+    let records = handle_with(vec![function_with_type()], &[], |state| state.with_transform(SeenSeverity)).await;
+
+    assert_eq!(records[0]["seen_severity"], "warn");
+    assert_eq!(records[0]["severity"], "warn");
+}
+
+#[tokio::test]
+async fn empty_function_records_are_dropped() {
+    let logs = vec![
+        LambdaLogRecord::Function("null".to_string()),
+        LambdaLogRecord::Function("   ".to_string()),
+        LambdaLogRecord::PlatformEnd { request_id: "abc".to_string() },
+        LambdaLogRecord::Function("hello".to_string()),
+    ];
+
+    let kept = handle(logs.clone(), &[]).await;
+    let dropped = handle(logs, &[("LOG_STORE_DROP_EMPTY", "1")]).await;
+
+    assert_eq!(kept.len(), 4);
+    assert_eq!(dropped.len(), 2);
+    assert_eq!(dropped[0]["type"], "platform_end");
+    assert_eq!(dropped[1]["record"], "hello");
+}
+
+#[tokio::test]
+async fn otel_format() {
+    let records = handle(vec![
+        LambdaLogRecord::Function(r#"{"level":"error","message":"failed","code":3}"#.to_string()),
+        LambdaLogRecord::PlatformEnd { request_id: "abc".to_string() },
+    ], &[("LOG_STORE_FORMAT", "otel")]).await;
+
+    assert_eq!(records, vec![
+        object! {
+            "timeUnixNano": "1712345678000000000",
+            "severityNumber": 17,
+            "severityText": "ERROR",
+            "body": { "stringValue": "failed" },
+            "attributes": [
+                { "key": "type", "value": { "stringValue": "function" } },
+                { "key": "level", "value": { "stringValue": "error" } },
+                { "key": "code", "value": { "intValue": "3" } },
+            ],
+        },
+        object! {
+            "timeUnixNano": "1712345678000000000",
+            "severityNumber": 5,
+            "severityText": "DEBUG",
+            "body": { "stringValue": "platform_end" },
+            "attributes": [
+                { "key": "type", "value": { "stringValue": "platform_end" } },
+                { "key": "request_id", "value": { "stringValue": "abc" } },
+            ],
+        },
+    ]);
+}
+
+#[tokio::test]
+async fn loki_format() {
+    let records = handle(vec![
+        LambdaLogRecord::Function("plain text".to_string()),
+        LambdaLogRecord::Function(r#"{"level":"error","message":"failed"}"#.to_string()),
+    ], &[("LOG_STORE_FORMAT", "loki"), ("AWS_LAMBDA_FUNCTION_NAME", "orders")]).await;
+
+    let push = |level: &str, line: &str| object! {
+        "streams": [{
+            "stream": { "type": "function", "level": level, "fn": "orders" },
+            "values": [["1712345678000000000", line]],
+        }]
+    };
+
+    assert_eq!(records, vec![
+        push("info", "plain text"),
+        push("error", r#"{"level":"error","message":"failed"}"#),
+    ]);
+}
+
+/// A log line with invalid UTF-8, as Lambda passes it on: with the bad sequences replaced.
+fn invalid_utf8() -> LambdaLogRecord {
+    LambdaLogRecord::Function(String::from_utf8_lossy(b"bad \xff\xfe bytes \xc3\x28").into_owned())
+}
+
+#[tokio::test]
+async fn invalid_utf8_handling() {
+    let valid = LambdaLogRecord::Function("fine".to_string());
+
+    let replaced = handle(vec![invalid_utf8(), valid.clone()], &[]).await;
+    let encoded = handle(vec![invalid_utf8()], &[("LOG_STORE_NONUTF8", "base64")]).await;
+    let dropped = handle(vec![invalid_utf8(), valid], &[("LOG_STORE_NONUTF8", "drop")]).await;
+
+    assert_eq!(replaced[0]["record"], "bad \u{FFFD}\u{FFFD} bytes \u{FFFD}(");
+    assert_eq!(replaced[1]["record"], "fine");
+    assert_eq!(encoded[0]["_b64"], "YmFkIO+/ve+/vSBieXRlcyDvv70o");
+    assert!(!encoded[0].has_key("record"));
+    assert_eq!(dropped.len(), 1);
+    assert_eq!(dropped[0]["record"], "fine");
+}
+
+#[tokio::test]
+async fn phase_follows_platform_records() {
+    let function = || LambdaLogRecord::Function("hello".to_string());
+    let records = handle(vec![
+        function(),
+        LambdaLogRecord::PlatformStart { request_id: "abc".to_string() },
+        function(),
+        LambdaLogRecord::PlatformEnd { request_id: "abc".to_string() },
+        function(),
+    ], &[("LOG_STORE_TAG_PHASE", "1")]).await;
+
+    let phases: Vec<_> = records.iter().map(|r| r["phase"].as_str()).collect();
+
+    assert_eq!(phases, vec![Some("init"), None, Some("invoke"), None, Some("shutdown")]);
+}
+
+#[tokio::test]
+async fn platform_drops_are_surfaced() {
+    let records = handle(vec![LambdaLogRecord::PlatformLogsDropped {
+        reason: "Consumer seems to have fallen behind".to_string(),
+        dropped_records: 12,
+        dropped_bytes: 3456,
+    }], &[]).await;
+
+    assert_eq!(records, vec![object! {
+        "t": TIME_MS,
+        "type": "platform_logs_dropped",
+        "reason": "Consumer seems to have fallen behind",
+        "dropped_records": 12,
+        "dropped_bytes": 3456,
+        "severity": "warn",
+    }]);
+}
+
+#[tokio::test]
+async fn extension_records_are_surfaced() {
+    let logs = || vec![
+        LambdaLogRecord::PlatformExtension {
+            name: "log-store-extension".to_string(),
+            state: "Ready".to_string(),
+            events: vec!["INVOKE".to_string(), "SHUTDOWN".to_string()],
+        },
+        LambdaLogRecord::PlatformLogsSubscription {
+            name: "log-store-extension".to_string(),
+            state: "Subscribed".to_string(),
+            types: vec!["platform".to_string(), "function".to_string()],
+        },
+    ];
+    let records = handle(logs(), &[]).await;
+
+    assert_eq!(records, vec![
+        object! {
+            "t": TIME_MS,
+            "type": "platform_extension",
+            "kind": "extension",
+            "name": "log-store-extension",
+            "state": "Ready",
+            "events": ["INVOKE", "SHUTDOWN"],
+            "severity": "info",
+        },
+        object! {
+            "t": TIME_MS,
+            "type": "platform_extension",
+            "kind": "logs_subscription",
+            "name": "log-store-extension",
+            "state": "Subscribed",
+            "types": ["platform", "function"],
+            "severity": "info",
+        },
+    ]);
+    assert!(handle(logs(), &[("LOG_STORE_SHIP_PLATFORM_EXTENSION", "0")]).await.is_empty());
+}
+
+#[tokio::test]
+async fn reports_can_carry_derived_efficiency_fields() {
+    let report = |memory_size_mb| LambdaLogRecord::PlatformReport {
+        request_id: "abc".to_string(),
+        metrics: LogPlatformReportMetrics {
+            duration_ms: 95.123,
+            billed_duration_ms: 100,
+            memory_size_mb,
+            max_memory_used_mb: 64,
+            init_duration_ms: None,
+        },
+    };
+    let records = handle(vec![report(128), report(0)], &[("LOG_STORE_REPORT_DERIVED", "1")]).await;
+
+    assert_eq!(records[0]["billed_overhead_ms"], 4.877);
+    assert_eq!(records[0]["memory_utilization"], 0.5);
+    assert_eq!(records[0]["right_sized"], true);
+    // there's no utilization to work out without a memory size
+    assert_eq!(records[1]["billed_overhead_ms"], 4.877);
+    assert!(!records[1].has_key("memory_utilization") && !records[1].has_key("right_sized"));
+    assert!(!handle(vec![report(128)], &[]).await[0].has_key("billed_overhead_ms"));
+}
+
+#[tokio::test]
+async fn records_can_carry_a_partition_key() {
+    let logs = || vec![
+        LambdaLogRecord::Function(r#"{"tenant":"acme","n":1}"#.to_string()),
+        LambdaLogRecord::PlatformStart { request_id: "abc".to_string() },
+        LambdaLogRecord::Function(r#"{"tenant":42}"#.to_string()),
+        LambdaLogRecord::PlatformEnd { request_id: "abc".to_string() },
+    ];
+    let by_request = handle(logs(), &[("LOG_STORE_PARTITION_KEY", "request_id")]).await;
+    let by_field = handle(logs(), &[("LOG_STORE_PARTITION_KEY", "tenant")]).await;
+    let keys = |records: &[JsonValue]| records.iter().map(|r| r["pk"].as_str().map(str::to_string)).collect::<Vec<_>>();
+
+    // nothing's known of an invocation until its platform_start
+    assert_eq!(keys(&by_request), [None, Some("abc".to_string()), Some("abc".to_string()), Some("abc".to_string())]);
+    assert_eq!(keys(&by_field), [Some("acme".to_string()), None, Some("42".to_string()), None]);
+}
+
+#[tokio::test]
+async fn uptime_is_stamped() {
+    let records = handle(vec![function_with_type()], &[("LOG_STORE_INCLUDE_UPTIME", "1")]).await;
+    let plain = handle(vec![function_with_type()], &[]).await;
+
+    assert!(records[0]["up_ms"].as_u64().is_some());
+    assert!(!plain[0].has_key("up_ms"));
+}
+
+#[tokio::test]
+async fn memory_limit_is_stamped() {
+    let logs = || vec![function_with_type(), LambdaLogRecord::PlatformStart { request_id: "abc".to_string() }];
+    let records = handle(logs(), &[("LOG_STORE_INCLUDE_MEM_LIMIT", "1"), ("AWS_LAMBDA_FUNCTION_MEMORY_SIZE", "512")]).await;
+
+    assert!(records.iter().all(|json| json["mem_limit_mb"] == 512));
+    assert!(!handle(logs(), &[("AWS_LAMBDA_FUNCTION_MEMORY_SIZE", "512")]).await[0].has_key("mem_limit_mb"));
+    // outside Lambda there's nothing to stamp
+    assert!(!handle(logs(), &[("LOG_STORE_INCLUDE_MEM_LIMIT", "1")]).await[0].has_key("mem_limit_mb"));
+}
+
+#[tokio::test]
+async fn process_and_thread_ids_are_stamped() {
+    let logs = || vec![function_with_type(), LambdaLogRecord::PlatformStart { request_id: "abc".to_string() }];
+    let records = handle(logs(), &[("LOG_STORE_INCLUDE_IDS", "1")]).await;
+
+    assert!(records.iter().all(|json| json["pid"] == std::process::id()));
+    assert!(records.iter().all(|json| json["tid"].as_u64().is_some()));
+    assert!(!handle(logs(), &[]).await[0].has_key("pid"));
+}
+
+#[tokio::test]
+async fn records_get_time_ordered_ids() {
+    let logs = vec![function_with_type(), function_with_type(), LambdaLogRecord::PlatformStart { request_id: "abc".to_string() }];
+    let records = handle(logs, &[("LOG_STORE_INCLUDE_ID", "1")]).await;
+    let ids = records.iter().map(|json| json["id"].as_str().unwrap().to_string()).collect::<Vec<_>>();
+
+    // version 7, the variant, and the record's time first
+    assert!(ids.iter().all(|id| id.len() == 36 && id.as_bytes()[14] == b'7' && matches!(id.as_bytes()[19], b'8'..=b'b')));
+    assert_eq!(u64::from_str_radix(&ids[0][..13].replace('-', ""), 16).unwrap(), TIME_MS as u64);
+
+    // the same millisecond sorts in the order they were made
+    assert!(ids[0] < ids[1] && ids[1] < ids[2]);
+    assert!(!handle(vec![function_with_type()], &[]).await[0].has_key("id"));
+}
+
+#[tokio::test]
+async fn records_can_be_queued_pre_serialized() {
+    let vars = [("LOG_STORE_ADDRESS", "127.0.0.1:1234"), ("LOG_STORE_PRESERIALIZE", "1")];
+    let records = handle(vec![function_with_type()], &vars).await;
+    let line = records[0].as_str().unwrap();
+
+    assert!(line.ends_with('\n'));
+    assert_eq!(json::parse(line).unwrap(), handle(vec![function_with_type()], &vars[..1]).await[0]);
+
+    // the writer has to see records as JSON to stamp a session id on them
+    let config = Config::from_vars([vars[0], ("LOG_STORE_PRESERIALIZE", "yes"), ("LOG_STORE_INCLUDE_SESSION", "1")]).unwrap();
+
+    assert!(!config.preserialize);
+    assert_eq!(config.warnings[0].field, "LOG_STORE_PRESERIALIZE");
+    assert_eq!(config.warnings[0].given, "yes");
+}
+
+#[tokio::test]
+async fn json_objects_are_spliced_into_their_lines() {
+    let vars = [("LOG_STORE_ADDRESS", "127.0.0.1:1234"), ("LOG_STORE_PRESERIALIZE", "1")];
+    let logs = || vec![LambdaLogRecord::Function(r#" {"level": "WARNING", "order": {"id": 42, "total": 1.50}} "#.to_string())];
+    let spliced = handle(logs(), &vars).await;
+
+    // as it was logged, after our fields
+    assert_eq!(spliced, vec![JsonValue::from(format!(
+        "{{\"t\":{},\"type\":\"function\",\"severity\":\"warn\",\"level\": \"WARNING\", \"order\": {{\"id\": 42, \"total\": 1.50}}}}\n", TIME_MS))]);
+
+    // which is the record it would be, parsed
+    let parsed = handle(logs(), &[vars[0], vars[1], ("LOG_STORE_SPLICE", "0")]).await;
+
+    assert_ne!(spliced, parsed);
+    assert_eq!(json::parse(spliced[0].as_str().unwrap()).unwrap(), json::parse(parsed[0].as_str().unwrap()).unwrap());
+}
+
+#[tokio::test]
+async fn lines_that_cant_be_spliced_are_parsed() {
+    let vars = [("LOG_STORE_ADDRESS", "127.0.0.1:1234"), ("LOG_STORE_PRESERIALIZE", "1")];
+    let lines = [
+        // a field of ours
+        r#"{"type":"order_placed","level":"warn"}"#,
+        // what parsing would change: a key twice, an escaped key (which may be another twice), a newline, a surrogate pair
+        r#"{"a":1,"a":2}"#,
+        r#"{"a":{"b":1,"b":2}}"#,
+        r#"{"\u0061":1}"#,
+        "{\"a\":\n1}",
+        r#"{"a":"\ud83d\ude00"}"#,
+        // not JSON, or not an object
+        r#"{"a":01}"#,
+        r#"{"a":1"#,
+        r#"{"a":1} {"b":2}"#,
+        r#"["a"]"#,
+        "plain text",
+        // a line, as far as the rest of the handler goes
+        r#"{"record":"ERROR failed"}"#,
+        "{}",
+    ];
+
+    for line in lines {
+        let logs = || vec![LambdaLogRecord::Function(line.to_string())];
+
+        assert_eq!(handle(logs(), &vars).await, handle(logs(), &[vars[0], vars[1], ("LOG_STORE_SPLICE", "0")]).await, "{}", line);
+    }
+
+    // nor can anything be, with a transform that may look at them
+    struct Noop;
+
+    impl RecordTransform for Noop {
+        fn transform(&self, _: &mut JsonValue) {}
+    }
+
+    let logs = || vec![LambdaLogRecord::Function(r#"{"a": 1}"#.to_string())];
+
+    assert_eq!(handle(logs(), &vars).await[0], format!("{{\"t\":{},\"type\":\"function\",\"severity\":\"info\",\"a\": 1}}\n", TIME_MS));
+    assert_eq!(handle_with(logs(), &vars, |state| state.with_transform(Noop)).await[0],
+               format!("{{\"t\":{},\"type\":\"function\",\"severity\":\"info\",\"a\":1}}\n", TIME_MS));
+}
+
+#[tokio::test]
+async fn timestamps_come_from_the_clock() {
+    let clock = Arc::new(MockClock::new(1_700_000_000_000));
+    let stats = Stats::new(None).with_clock(clock.clone());
+
+    clock.advance(Duration::from_millis(1500));
+
+    let vars = [("LOG_STORE_INCLUDE_UPTIME", "1"), ("LOG_STORE_TIME_SOURCE", "both")];
+    let records = handle_on(vec![function_with_type()], &vars, stats, |state| state).await;
+
+    assert_eq!(records[0]["t"], TIME_MS);
+    assert_eq!(records[0]["it"], 1_700_000_001_500u64);
+    assert_eq!(records[0]["up_ms"], 1500);
+}
+
+#[tokio::test]
+async fn timestamps_can_come_from_the_record() {
+    let function = |line: &str| LambdaLogRecord::Function(line.to_string());
+    let logs = || vec![
+        function(r#"{"ts":"2024-04-05T19:34:37.250Z"}"#),
+        function(r#"{"ts":"2024-04-05T21:34:36+02:00"}"#),
+        function(r#"{"ts":1712345675.5}"#),
+        function(r#"{"ts":1712345674000}"#),
+        function(r#"{"ts":"1712345673000000"}"#),
+        // Lambda's time, for a field that can't be read or isn't there
+        function(r#"{"ts":"yesterday"}"#),
+        function(r#"{"msg":"hi"}"#),
+        function("plain text"),
+        LambdaLogRecord::PlatformStart { request_id: "abc".to_string() },
+    ];
+    let records = handle(logs(), &[("LOG_STORE_PREFER_RECORD_TIME", "ts")]).await;
+    let times: Vec<_> = records.iter().map(|r| r["t"].as_i64().unwrap()).collect();
+
+    assert_eq!(times, vec![
+        1_712_345_677_250, 1_712_345_676_000, 1_712_345_675_500, 1_712_345_674_000, 1_712_345_673_000,
+        TIME_MS, TIME_MS, TIME_MS, TIME_MS,
+    ]);
+    assert_eq!(records[0]["ts"], "2024-04-05T19:34:37.250Z");
+
+    // finer than a millisecond, when it's shipped that way
+    let records = handle(vec![function(r#"{"ts":"2024-04-05T19:34:37.250123Z"}"#)], &[
+        ("LOG_STORE_PREFER_RECORD_TIME", "ts"),
+        ("LOG_STORE_TIME_PRECISION", "micros"),
+    ]).await;
+
+    assert_eq!(records[0]["t"], 1_712_345_677_250_123i64);
+
+    // nor is it used unless asked to be
+    assert!(handle(logs(), &[]).await.iter().all(|r| r["t"] == TIME_MS));
+}
+
+#[tokio::test]
+async fn floods_are_truncated_per_invocation() {
+    let function = || LambdaLogRecord::Function("again".to_string());
+    let start = || LambdaLogRecord::PlatformStart { request_id: "abc".to_string() };
+    let end = || LambdaLogRecord::PlatformEnd { request_id: "abc".to_string() };
+    let records = handle(vec![
+        function(), function(), function(),
+        start(), function(), function(), function(), function(), end(),
+        start(), function(), end(),
+    ], &[("LOG_STORE_MAX_RECORDS_PER_INVOCATION", "2")]).await;
+
+    let types: Vec<_> = records.iter().map(|r| r["type"].as_str().unwrap()).collect();
+
+    // init isn't an invocation, and the count starts over with each one
+    assert_eq!(types, vec![
+        "function", "function", "function",
+        "platform_start", "function", "function", "platform_end", "truncated_invocation",
+        "platform_start", "function", "platform_end",
+    ]);
+    assert_eq!(records[7]["request_id"], "abc");
+    assert_eq!(records[7]["dropped"], 2);
+}
+
+#[tokio::test]
+async fn dropped_records_leave_no_gap_in_the_sequence() {
+    let logs = || vec![
+        LambdaLogRecord::PlatformStart { request_id: "abc".to_string() },
+        invalid_utf8(),
+        LambdaLogRecord::Function(r#"{"msg":"one"}"#.to_string()),
+        invalid_utf8(),
+        LambdaLogRecord::Function(r#"{"msg":"two"}"#.to_string()),
+        LambdaLogRecord::Function(r#"{"msg":"three"}"#.to_string()),
+        LambdaLogRecord::Function(r#"{"msg":"four"}"#.to_string()),
+        LambdaLogRecord::PlatformEnd { request_id: "abc".to_string() },
+    ];
+    let vars = [
+        ("LOG_STORE_SEQ_SCOPE", "invocation"),
+        ("LOG_STORE_MAX_RECORDS_PER_INVOCATION", "2"),
+        ("LOG_STORE_NONUTF8", "drop"),
+    ];
+    let spliced = [&vars[..], &[("LOG_STORE_ADDRESS", "127.0.0.1:1234"), ("LOG_STORE_PRESERIALIZE", "1")]].concat();
+
+    // spliced or not, neither a dropped line nor one past the limit takes a `seq`
+    for records in [handle(logs(), &vars).await, handle(logs(), &spliced).await] {
+        let records = records.iter()
+            .map(|json| json.as_str().map_or_else(|| json.clone(), |line| json::parse(line).unwrap()))
+            .collect::<Vec<_>>();
+        let types = records.iter().map(|r| r["type"].as_str().unwrap()).collect::<Vec<_>>();
+
+        assert_eq!(types, vec!["platform_start", "function", "function", "platform_end", "truncated_invocation"]);
+        assert_eq!(records.iter().map(|r| r["seq"].as_u64().unwrap()).collect::<Vec<_>>(), vec![0, 1, 2, 3, 4]);
+        assert_eq!(records[2]["msg"], "two");
+    }
+}
+
+#[tokio::test]
+async fn parse_failures_are_marked() {
+    let logs = || vec![
+        LambdaLogRecord::Function(r#"{"message":"cut off"#.to_string()),
+        LambdaLogRecord::Function("{ not json, but plain text".to_string()),
+        LambdaLogRecord::Function("plain text".to_string()),
+    ];
+
+    let marked = handle(logs(), &[("LOG_STORE_MARK_PARSE_FAILURE", "1")]).await;
+    let unmarked = handle(logs(), &[]).await;
+
+    let flags: Vec<_> = marked.iter().map(|r| r["parse_failed"].as_bool()).collect();
+
+    assert_eq!(flags, vec![Some(true), Some(true), None]);
+    assert!(unmarked.iter().all(|r| !r.has_key("parse_failed")));
+}
+
+#[tokio::test]
+async fn only_listed_fields_are_kept() {
+    let records = handle(vec![
+        LambdaLogRecord::Function(r#"{"level":"info","msg":"hi","http":{"status":200,"path":"/"}}"#.to_string()),
+        LambdaLogRecord::Function("plain text".to_string()),
+        LambdaLogRecord::PlatformStart { request_id: "abc".to_string() },
+    ], &[("LOG_STORE_KEEP_FIELDS", "level, http.status")]).await;
+
+    assert_eq!(records[0], object! {
+        "t": TIME_MS,
+        "type": "function",
+        "level": "info",
+        "http": { "status": 200 },
+        "severity": "info",
+    });
+    assert_eq!(records[1]["record"], "plain text");
+    assert_eq!(records[2]["request_id"], "abc");
+}
+
+/// Collects the extension's own diagnostics.
+#[derive(Clone, Default)]
+struct Diagnostics(Arc<Mutex<Vec<u8>>>);
+
+impl std::io::Write for Diagnostics {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn oversized_records_are_reported() {
+    let diagnostics = Diagnostics::default();
+    let writer = diagnostics.clone();
+    let subscriber = tracing_subscriber::fmt().with_writer(move || writer.clone()).with_ansi(false).finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+    let big = format!(r#"{{"big":"{}"}}"#, "x".repeat(500));
+
+    handle(vec![
+        LambdaLogRecord::Function(big),
+        LambdaLogRecord::Function("small".to_string()),
+    ], &[("LOG_STORE_WARN_RECORD_BYTES", "300")]).await;
+
+    let logged = String::from_utf8(diagnostics.0.lock().unwrap().clone()).unwrap();
+    let warnings: Vec<_> = logged.lines().filter(|line| line.contains("is over 300")).collect();
+
+    assert_eq!(warnings.len(), 1);
+    assert!(warnings[0].contains("type function"));
+    assert!(warnings[0].contains(r#"{"t":"#));
+    assert!(!warnings[0].contains(&"x".repeat(400)));
+}
+
+#[tokio::test]
+async fn duplicates_hash_the_same() {
+    let logs = || vec![function_with_type(), function_with_type(), LambdaLogRecord::Function("other".to_string())];
+    let hashed = handle(logs(), &[("LOG_STORE_INCLUDE_HASH", "1"), ("LOG_STORE_SEQ_SCOPE", "global")]).await;
+    let everything = handle(logs(), &[
+        ("LOG_STORE_INCLUDE_HASH", "1"),
+        ("LOG_STORE_SEQ_SCOPE", "global"),
+        ("LOG_STORE_HASH_EXCLUDE", ""),
+    ]).await;
+
+    let hashes: Vec<_> = hashed.iter().map(|r| r["h"].as_str().unwrap()).collect();
+
+    assert_eq!(hashes[0].len(), 16);
+    assert_eq!(hashes[0], hashes[1]);
+    assert_ne!(hashes[0], hashes[2]);
+    // `seq` counts too, when it isn't excluded
+    assert_ne!(everything[0]["h"], everything[1]["h"]);
+}
+
+#[tokio::test]
+async fn invocations_carry_their_trace_id() {
+    let records = handle_with(vec![
+        LambdaLogRecord::Function("init".to_string()),
+        LambdaLogRecord::PlatformStart { request_id: "abc".to_string() },
+        LambdaLogRecord::Function("traced".to_string()),
+        LambdaLogRecord::PlatformStart { request_id: "def".to_string() },
+        LambdaLogRecord::Function("untraced".to_string()),
+    ], &[("LOG_STORE_INCLUDE_TRACE_ID", "1")], |state| {
+        state.invoked("abc", "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1");
+        state
+    }).await;
+
+    let trace_ids: Vec<_> = records.iter().map(|r| r["trace_id"].as_str()).collect();
+    let traced = Some("1-5759e988-bd862e3fe1be46a994272793");
+
+    assert_eq!(trace_ids, vec![None, traced, traced, None, None]);
+}
+
+#[tokio::test]
+async fn bare_primitives_keep_their_type() {
+    let logs = || vec![
+        LambdaLogRecord::Function("42".to_string()),
+        LambdaLogRecord::Function("true".to_string()),
+        LambdaLogRecord::Function("\"hi\"".to_string()),
+        LambdaLogRecord::Function("[1,2]".to_string()),
+    ];
+    let native = handle(logs(), &[]).await;
+
+    assert_eq!(native[0], object! { "t": TIME_MS, "type": "function", "record": 42, "severity": "info" });
+    assert_eq!(native[1]["record"], true);
+    assert_eq!(native[2]["record"], "hi");
+    assert_eq!(native[3]["record"], json::array![1, 2]);
+
+    let text = handle(logs(), &[("LOG_STORE_BARE_PRIMITIVES", "text")]).await;
+
+    assert_eq!(text[0]["record"], "42");
+    assert_eq!(text[1]["record"], "true");
+    assert_eq!(text[2]["record"], "\"hi\"");
+    // arrays aren't primitives
+    assert_eq!(text[3]["record"], json::array![1, 2]);
+}
+
+#[tokio::test]
+async fn nesting_is_capped_at_the_max_flatten_depth() {
+    let logs = || vec![LambdaLogRecord::Function(r#"{"a":{"b":{"c":{"d":1}}},"list":[{"x":1},2],"n":1}"#.to_string())];
+    let records = handle(logs(), &[("LOG_STORE_MAX_FLATTEN_DEPTH", "2")]).await;
+
+    assert_eq!(records[0]["a"]["b"], r#"{"c":{"d":1}}"#);
+    assert_eq!(records[0]["list"][0], r#"{"x":1}"#);
+    assert_eq!(records[0]["list"][1], 2);
+    assert_eq!(records[0]["n"], 1);
+
+    let records = handle(logs(), &[("LOG_STORE_MAX_FLATTEN_DEPTH", "1")]).await;
+
+    assert_eq!(records[0]["a"], r#"{"b":{"c":{"d":1}}}"#);
+    assert_eq!(handle(logs(), &[]).await[0]["a"]["b"]["c"]["d"], 1);
+}
+
+#[tokio::test]
+async fn big_integers_can_be_kept_exact_as_strings() {
+    let logs = || vec![LambdaLogRecord::Function(
+        r#"{"id":1234567890123456789012,"safe":9007199254740991,"unsafe":-9007199254740993,"ratio":9007199254740993.5,"note":"id 9007199254740993 \"quoted\" 12345678901234567890","ids":[18446744073709551616]}"#.to_string(),
+    )];
+    let strings = handle(logs(), &[("LOG_STORE_BIGINT", "string")]).await;
+
+    assert_eq!(strings[0]["id"], "1234567890123456789012");
+    assert_eq!(strings[0]["safe"], 9_007_199_254_740_991u64);
+    assert_eq!(strings[0]["unsafe"], "-9007199254740993");
+    assert!(strings[0]["ratio"].is_number());
+    assert_eq!(strings[0]["note"], "id 9007199254740993 \"quoted\" 12345678901234567890");
+    assert_eq!(strings[0]["ids"][0], "18446744073709551616");
+
+    // as numbers by default, which the json crate rounds past 20 digits or so
+    let kept = handle(logs(), &[]).await;
+
+    assert!(kept[0]["id"].is_number());
+    assert_eq!(kept[0]["unsafe"], -9_007_199_254_740_993i64);
+}
+
+#[tokio::test]
+async fn plain_text_goes_under_the_message_key() {
+    let records = handle(vec![
+        LambdaLogRecord::Function("[ERROR] the log line".to_string()),
+        LambdaLogRecord::Function("42".to_string()),
+        LambdaLogRecord::Function(r#"{"record":1,"msg":"hi"}"#.to_string()),
+        LambdaLogRecord::PlatformStart { request_id: "abc".to_string() },
+    ], &[("LOG_STORE_MESSAGE_KEY", "message")]).await;
+
+    assert_eq!(records[0], object! { "t": TIME_MS, "type": "function", "message": "[ERROR] the log line", "severity": "error" });
+    assert_eq!(records[1]["message"], 42);
+    // a JSON record's own fields are left alone
+    assert_eq!(records[2]["record"], 1);
+    assert!(!records[2].has_key("message"));
+    assert_eq!(records[3]["request_id"], "abc");
+}
+
+#[tokio::test]
+async fn objects_can_be_shipped_raw_as_well() {
+    let big = format!(r#"{{"level":"info","blob":"{}"}}"#, "x".repeat(200));
+    let records = handle(vec![
+        LambdaLogRecord::Function(r#"{"level":"warn","http":{"status":500,"path":"/"}}"#.to_string()),
+        LambdaLogRecord::Function("plain text".to_string()),
+        LambdaLogRecord::Function(big),
+    ], &[("LOG_STORE_EMIT_BOTH", "1"), ("LOG_STORE_EMIT_BOTH_MAX_BYTES", "100"), ("LOG_STORE_KEEP_FIELDS", "http.status")]).await;
+
+    assert_eq!(records[0], object! {
+        "t": TIME_MS,
+        "type": "function",
+        "http": { "status": 500 },
+        "_raw": { "level": "warn", "http": { "status": 500, "path": "/" } },
+        "severity": "warn",
+    });
+    // only objects, and only small ones
+    assert!(!records[1].has_key("_raw"));
+    assert!(!records[2].has_key("_raw"));
+}
+
+#[tokio::test]
+async fn newlines_are_normalized() {
+    let logs = || vec![LambdaLogRecord::Function("{\"msg\":\"a\\r\\nb\\u2028c\",\"lines\":[\"d\\re\"]}".to_string())];
+    let spaced = handle(logs(), &[("LOG_STORE_NORMALIZE_NEWLINES", "1")]).await;
+    let escaped = handle(logs(), &[("LOG_STORE_NORMALIZE_NEWLINES", "1"), ("LOG_STORE_NEWLINE_REPLACEMENT", "escape")]).await;
+
+    assert_eq!(spaced[0]["msg"], "a b c");
+    assert_eq!(spaced[0]["lines"][0], "d e");
+    assert_eq!(escaped[0]["msg"], "a\\r\\nb\\u2028c");
+}
+
+#[tokio::test]
+async fn records_are_mirrored_without_waiting_on_the_mirror() {
+    let logs = (0..3).map(|n| LambdaLogRecord::Function(format!("line {}", n))).collect();
+    let (mirror_sender, mut mirror_recver) = channel(2);
+    let mirror_stats = Arc::new(Stats::new(None));
+    let state_stats = mirror_stats.clone();
+
+    let records = handle_with(logs, &[], move |state| state.with_mirror(mirror_sender, state_stats)).await;
+
+    let mut mirrored = Vec::new();
+
+    while let Ok(json) = mirror_recver.try_recv() {
+        mirrored.push(json);
+    }
+
+    // the mirror's queue only has room for two
+    assert_eq!(records.len(), 3);
+    assert_eq!(mirrored, records[..2]);
+    assert_eq!(mirror_stats.dropped.load(std::sync::atomic::Ordering::Relaxed), 1);
+}
+
+#[tokio::test]
+async fn split_lines_are_joined() {
+    let function = |line: &str| LambdaLogRecord::Function(line.to_string());
+    let vars = [("LOG_STORE_REASSEMBLE_MIN_BYTES", "16")];
+    let joined = handle(vec![
+        function(r#"{"msg":"aaaaaaaaaaaaaaaa"#),
+        function("bbbbbbbbbbbbbbbbbbbb"),
+        function(r#"","n":1}"#),
+        function("next"),
+    ], &vars).await;
+
+    assert_eq!(joined.len(), 2);
+    assert_eq!(joined[0]["msg"], "aaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbb");
+    assert_eq!(joined[0]["n"], 1);
+    assert_eq!(joined[1]["record"], "next");
+
+    // the rest of it never came
+    let cut = handle(vec![
+        function(r#"{"msg":"aaaaaaaaaaaaaaaa"#),
+        function("bbbbbbbbbbbbbbbbbbbb"),
+        LambdaLogRecord::PlatformEnd { request_id: "abc".to_string() },
+    ], &vars).await;
+
+    let split: Vec<_> = cut.iter().map(|r| r["split"].as_bool()).collect();
+
+    assert_eq!(split, vec![Some(true), Some(true), None]);
+    assert_eq!(cut[1]["record"], "bbbbbbbbbbbbbbbbbbbb");
+}
+
+#[tokio::test]
+async fn duplicate_keys_are_handled_per_mode() {
+    let function = || LambdaLogRecord::Function(r#"{"id":1,"msg":"a, \"b\"","id":{"n":[2,"}"]},"id":3}"#.to_string());
+    let body = |records: Vec<JsonValue>| {
+        let mut record = records[0].clone();
+
+        for field in ["t", "type", "severity"] {
+            record.remove(field);
+        }
+
+        record
+    };
+
+    let last = handle(vec![function()], &[]).await;
+    let first = handle(vec![function()], &[("LOG_STORE_DUP_KEYS", "first")]).await;
+    let array = handle(vec![function()], &[("LOG_STORE_DUP_KEYS", "array")]).await;
+    let suffix = handle(vec![function()], &[("LOG_STORE_DUP_KEYS", "suffix")]).await;
+
+    assert_eq!(body(last), object! { "id": 3, "msg": "a, \"b\"" });
+    assert_eq!(body(first), object! { "id": 1, "msg": "a, \"b\"" });
+    assert_eq!(body(array), object! { "id": [1, { "n": [2, "}"] }, 3], "msg": "a, \"b\"" });
+    assert_eq!(body(suffix), object! { "id": 1, "msg": "a, \"b\"", "id_2": { "n": [2, "}"] }, "id_3": 3 });
+}
+
+#[tokio::test]
+async fn faults_are_only_parsed_if_asked() {
+    let fault = || LambdaLogRecord::PlatformFault(r#"{"errorType":"Runtime.ExitError","errorMessage":"exit status 1"}"#.to_string());
+    let raw = handle(vec![fault()], &[]).await;
+    let parsed = handle(vec![fault()], &[("LOG_STORE_PARSE_FAULT_JSON", "1")]).await;
+
+    assert_eq!(raw, vec![object! {
+        "t": TIME_MS,
+        "type": "platform_fault",
+        "record": r#"{"errorType":"Runtime.ExitError","errorMessage":"exit status 1"}"#,
+        "severity": "error",
+    }]);
+    assert_eq!(parsed, vec![object! {
+        "t": TIME_MS,
+        "type": "platform_fault",
+        "errorType": "Runtime.ExitError",
+        "errorMessage": "exit status 1",
+        "severity": "error",
+    }]);
+}
+
+#[tokio::test]
+async fn timestamps_can_be_made_monotonic() {
+    let at = |time_ms: i64, n: i64| LambdaLog {
+        time: Utc.timestamp_millis_opt(time_ms).unwrap(),
+        record: LambdaLogRecord::Function(format!(r#"{{"n":{}}}"#, n)),
+    };
+    let times = |vars: &'static [(&'static str, &'static str)]| async move {
+        let config = Config::from_vars([("LOG_STORE_ADDRESS", "stdout")].iter().chain(vars).copied()).unwrap();
+        let (sender, mut recver) = channel(16);
+        let state = Arc::new(HandlerState::new(&config, sender, Arc::new(Stats::new(None))));
+
+        handler(vec![at(TIME_MS, 0), at(TIME_MS - 5, 1), at(TIME_MS + 1, 2)], state).await.unwrap();
+
+        let mut records = Vec::new();
+
+        while let Ok(json) = recver.try_recv() {
+            records.push((json["t"].as_i64(), json["t_clamped"].as_bool(), json["mt"].as_i64()));
+        }
+
+        records
+    };
+
+    assert_eq!(times(&[("LOG_STORE_MONOTONIC_TIME", "clamp")]).await, vec![
+        (Some(TIME_MS), None, None),
+        (Some(TIME_MS), Some(true), None),
+        (Some(TIME_MS + 1), None, None),
+    ]);
+    assert_eq!(times(&[("LOG_STORE_MONOTONIC_TIME", "mt")]).await, vec![
+        (Some(TIME_MS), None, Some(TIME_MS)),
+        (Some(TIME_MS - 5), None, Some(TIME_MS)),
+        (Some(TIME_MS + 1), None, Some(TIME_MS + 1)),
+    ]);
+}
+
+#[tokio::test]
+async fn invocations_after_a_long_freeze_are_thaws() {
+    let clock = Arc::new(MockClock::new(TIME_MS as u64));
+    let stats = Arc::new(Stats::new(None).with_clock(clock.clone()));
+    let config = Config::from_vars([("LOG_STORE_ADDRESS", "stdout"), ("LOG_STORE_PROBE_ON_THAW", "1")]).unwrap();
+    let state = HandlerState::new(&config, channel(16).0, stats.clone());
+    let thawed = || tokio::time::timeout(Duration::from_millis(10), stats.wait_for_thaw());
+
+    state.invoked("a", "");
+    assert!(thawed().await.is_err());
+
+    clock.advance(Duration::from_secs(11));
+    state.invoked("b", "");
+    assert!(thawed().await.is_ok());
+
+    // one right after another isn't
+    clock.advance(Duration::from_secs(1));
+    state.invoked("c", "");
+    assert!(thawed().await.is_err());
+}
+
+#[tokio::test]
+async fn times_can_be_shipped_finer_than_millis() {
+    let time = |precision: &str| {
+        let config = Config::from_vars([("LOG_STORE_ADDRESS", "stdout"), ("LOG_STORE_TIME_PRECISION", precision)]).unwrap();
+        let (sender, mut recver) = channel(16);
+        let state = Arc::new(HandlerState::new(&config, sender, Arc::new(Stats::new(None))));
+        let log = LambdaLog {
+            time: Utc.timestamp_nanos(TIME_MS * 1_000_000 + 456_789),
+            record: LambdaLogRecord::Function("hello".to_string()),
+        };
+
+        async move {
+            handler(vec![log], state).await.unwrap();
+
+            let json = recver.try_recv().unwrap();
+
+            assert!(!json.has_key("_t_sub_ms"));
+            json["t"].dump()
+        }
+    };
+
+    assert_eq!(time("millis").await, "1712345678000");
+    assert_eq!(time("micros").await, "1712345678000456");
+    assert_eq!(time("nanos").await, "1712345678000456789");
+    assert_eq!(time("seconds_float").await, "1712345678.000456");
+    // anything else falls back to millis
+    assert_eq!(time("fortnights").await, "1712345678000");
+}
+
+#[tokio::test]
+async fn ttls_go_by_type_then_severity() {
+    let logs = || vec![
+        LambdaLogRecord::Function(r#"{"level":"ERROR","msg":"boom"}"#.to_string()),
+        LambdaLogRecord::Function(r#"{"level":"warning","msg":"hmm"}"#.to_string()),
+        LambdaLogRecord::Function("plain text".to_string()),
+        LambdaLogRecord::PlatformStart { request_id: "abc".to_string() },
+    ];
+
+    let records = handle(logs(), &[("LOG_STORE_TTL_MAP", "error:90, warn:30, platform_start:1, *:7")]).await;
+    let ttls = records.iter().map(|json| json["ttl_days"].as_u32()).collect::<Vec<_>>();
+
+    assert_eq!(ttls, [Some(90), Some(30), Some(7), Some(1)]);
+
+    // without a default, records nothing matches get none
+    let records = handle(logs(), &[("LOG_STORE_TTL_MAP", "error:90")]).await;
+    let ttls = records.iter().map(|json| json["ttl_days"].as_u32()).collect::<Vec<_>>();
+
+    assert_eq!(ttls, [Some(90), None, None, None]);
+
+    // nor does anything when it's unset
+    assert!(handle(logs(), &[]).await.iter().all(|json| !json.has_key("ttl_days")));
+}
+
+#[tokio::test]
+async fn records_are_enriched_from_a_file() {
+    let path = std::env::temp_dir().join(format!("log-store-enrich-{}.json", std::process::id()));
+    let path_str = path.to_str().unwrap();
+    let logs = || vec![
+        LambdaLogRecord::Function(r#"{"msg":"hi","service":"mine"}"#.to_string()),
+        LambdaLogRecord::Function("plain text".to_string()),
+        LambdaLogRecord::PlatformStart { request_id: "abc".to_string() },
+    ];
+
+    std::fs::write(&path, r#"{"service":"checkout","build":{"sha":"abc123"},"record":"ignored"}"#).unwrap();
+
+    let records = handle(logs(), &[("LOG_STORE_ENRICH_FILE", path_str)]).await;
+
+    assert_eq!(records[0]["service"], "mine");
+    assert_eq!(records[0]["build"]["sha"], "abc123");
+    assert_eq!(records[1]["record"], "plain text");
+    assert_eq!(records[1]["service"], "checkout");
+    assert_eq!(records[2]["service"], "checkout");
+
+    let records = handle(logs(), &[("LOG_STORE_ENRICH_FILE", path_str), ("LOG_STORE_ENRICH_PRECEDENCE", "file")]).await;
+
+    assert_eq!(records[0]["service"], "checkout");
+    assert_eq!(records[0]["msg"], "hi");
+
+    // a file that isn't a JSON object fails startup
+    std::fs::write(&path, "[1, 2]").unwrap();
+    assert!(Config::from_vars([("LOG_STORE_ADDRESS", "stdout"), ("LOG_STORE_ENRICH_FILE", path_str)]).is_err());
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn records_can_be_checked_against_a_schema() {
+    let path = std::env::temp_dir().join(format!("log-store-schema-{}.json", std::process::id()));
+    let path_str = path.to_str().unwrap();
+    let logs = || vec![
+        LambdaLogRecord::Function(r#"{"msg":"hi","status":200}"#.to_string()),
+        LambdaLogRecord::Function(r#"{"msg":"hi","status":"ok"}"#.to_string()),
+        LambdaLogRecord::Function(r#"{"status":700}"#.to_string()),
+        LambdaLogRecord::PlatformStart { request_id: "abc".to_string() },
+    ];
+
+    std::fs::write(&path, r#"{
+        "title": "function logs",
+        "anyOf": [
+            {"required": ["msg", "status"], "properties": {"status": {"type": "integer", "minimum": 100, "maximum": 599}}},
+            {"required": ["request_id"]}
+        ]
+    }"#).unwrap();
+
+    let records = handle(logs(), &[("LOG_STORE_SCHEMA_FILE", path_str)]).await;
+
+    assert_eq!(records.len(), 4);
+    assert!(!records[0].has_key("_schema_invalid"));
+    assert_eq!(records[1]["_schema_invalid"][0], "/: matches none of anyOf");
+    assert!(records[2]["_schema_invalid"].is_array());
+    assert!(!records[3].has_key("_schema_invalid"));
+
+    let records = handle(logs(), &[("LOG_STORE_SCHEMA_FILE", path_str), ("LOG_STORE_SCHEMA_ON_FAIL", "drop")]).await;
+
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0]["status"], 200);
+    assert_eq!(records[1]["type"], "platform_start");
+
+    let records = handle(logs(), &[("LOG_STORE_SCHEMA_FILE", path_str), ("LOG_STORE_SCHEMA_ON_FAIL", "ship")]).await;
+
+    assert_eq!(records.len(), 4);
+    assert!(records.iter().all(|json| !json.has_key("_schema_invalid")));
+
+    std::fs::write(&path, r#"{"required": ["status"], "properties": {"status": {"type": "integer"}, "msg": {"maxLength": 1}}}"#).unwrap();
+
+    let records = handle(logs(), &[("LOG_STORE_SCHEMA_FILE", path_str)]).await;
+
+    assert_eq!(records[0]["_schema_invalid"][0], "/msg: length 2 is over 1");
+    assert_eq!(records[1]["_schema_invalid"], json::array!["/msg: length 2 is over 1", "/status: expected integer, got string"]);
+    assert_eq!(records[3]["_schema_invalid"][0], "/: missing required field \"status\"");
+
+    // a keyword that isn't checked fails startup, rather than letting through what it was meant to catch
+    std::fs::write(&path, r#"{"properties": {"msg": {"pattern": "^h"}}}"#).unwrap();
+    assert!(Config::from_vars([("LOG_STORE_ADDRESS", "stdout"), ("LOG_STORE_SCHEMA_FILE", path_str)]).is_err());
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn ansi_escapes_are_stripped() {
+    let logs = || vec![
+        LambdaLogRecord::Function("\u{1b}[1;32mready\u{1b}[0m in \u{1b}]8;;https://example.com\u{7}3ms\u{1b}]8;;\u{7}".to_string()),
+        LambdaLogRecord::Function(r#"{"level":"\u001b[33mWARN\u001b[39m","tags":["\u001b(Bplain"],"keep":"[1m isn't an escape, nor is a trailing \u001b"}"#.to_string()),
+    ];
+    let records = handle(logs(), &[("LOG_STORE_STRIP_ANSI", "1")]).await;
+
+    assert_eq!(records[0]["record"], "ready in 3ms");
+    assert_eq!(records[1]["level"], "WARN");
+    assert_eq!(records[1]["severity"], "warn");
+    assert_eq!(records[1]["tags"][0], "plain");
+    assert_eq!(records[1]["keep"], "[1m isn't an escape, nor is a trailing \u{1b}");
+
+    // off by default
+    assert_eq!(handle(logs(), &[]).await[1]["level"], "\u{1b}[33mWARN\u{1b}[39m");
+}
@@ -0,0 +1,63 @@
+use json::object;
+use log_store_extension::record_time::RecordTime;
+
+fn time_ns(ts: json::JsonValue) -> Option<i64> {
+    "ts".parse::<RecordTime>().unwrap().time_ns(&object! { "ts": ts })
+}
+
+#[test]
+fn epoch_times_are_told_apart_by_size() {
+    // as seconds, milliseconds, microseconds, then nanoseconds
+    assert_eq!(time_ns(1_712_345_678.into()), Some(1_712_345_678_000_000_000));
+    assert_eq!(time_ns(1_712_345_678_123i64.into()), Some(1_712_345_678_123_000_000));
+    assert_eq!(time_ns(1_712_345_678_123_456i64.into()), Some(1_712_345_678_123_456_000));
+    assert_eq!(time_ns(1_712_345_678_123_456_789i64.into()), Some(1_712_345_678_123_456_789));
+
+    // at each boundary it's the next unit; just under, it's still the last, even where that's past what nanoseconds
+    // since the epoch can hold
+    for (under, at) in [(99_999_999_999i64, 100_000_000_000i64), (99_999_999_999_999, 100_000_000_000_000), (99_999_999_999_999_999, 100_000_000_000_000_000)] {
+        assert_eq!(time_ns(under.into()), None);
+        assert_eq!(time_ns(under.to_string().into()), None);
+        assert!(time_ns(at.into()).is_some());
+    }
+
+    assert_eq!(time_ns(100_000_000_000i64.into()), Some(100_000_000_000_000_000));
+    assert_eq!(time_ns("100000000000000".into()), Some(100_000_000_000_000_000));
+    assert_eq!(time_ns(100_000_000_000_000_000i64.into()), Some(100_000_000_000_000_000));
+    assert_eq!(time_ns(9_000_000_000i64.into()), Some(9_000_000_000_000_000_000));
+    assert_eq!(time_ns(1e20.into()), None);
+    assert_eq!(time_ns(9_300_000_000.5.into()), None);
+}
+
+#[test]
+fn fractional_epoch_strings_are_read_exactly() {
+    assert_eq!(time_ns("1712345678.5".into()), Some(1_712_345_678_500_000_000));
+    assert_eq!(time_ns("1712345678.123456789".into()), Some(1_712_345_678_123_456_789));
+    assert_eq!(time_ns("1712345678.1234567891".into()), Some(1_712_345_678_123_456_789));
+    assert_eq!(time_ns("1712345678123.456".into()), Some(1_712_345_678_123_456_000));
+    assert_eq!(time_ns(" 1712345678123456.7 ".into()), Some(1_712_345_678_123_456_700));
+    assert_eq!(time_ns(".5".into()), Some(500_000_000));
+
+    for unreadable in ["1712345678.", "1712345678.1.2", "."] {
+        assert_eq!(time_ns(unreadable.into()), None, "{:?}", unreadable);
+    }
+}
+
+#[test]
+fn rfc3339_fractions_and_offsets() {
+    assert_eq!(time_ns("2024-04-05T19:34:38Z".into()), Some(1_712_345_678_000_000_000));
+    assert_eq!(time_ns("2024-04-05T19:34:38.1Z".into()), Some(1_712_345_678_100_000_000));
+    assert_eq!(time_ns("2024-04-05t19:34:38.123456789z".into()), Some(1_712_345_678_123_456_789));
+    assert_eq!(time_ns("2024-04-05 19:34:38.1234567899Z".into()), Some(1_712_345_678_123_456_789));
+    assert_eq!(time_ns("2024-04-05T21:04:38.5+01:30".into()), Some(1_712_345_678_500_000_000));
+    assert_eq!(time_ns("2024-04-05T00:34:38-19:00".into()), Some(1_712_345_678_000_000_000));
+    assert_eq!(time_ns("2024-04-06T19:09:38+23:35".into()), Some(1_712_345_678_000_000_000));
+
+    for unreadable in [
+        "2024-04-05T19:34:38.Z", "2024-04-05T19:34:38", "2024-04-05T19:34:38+0100", "2024-04-05T19:34:38+01:00:00",
+        "2024-04-05T19:34:38++1:00", "2024-04-05T19:34:38+-1:00", "2024-04-05T19:34:38+01:+5", "2024-04-05T19:34:38+1:000",
+        "2024-04-05T19:34:38+24:00", "2024-04-05T19:34:38+01:60", "2024-13-05T19:34:38Z", "2024-04-05T24:34:38Z",
+    ] {
+        assert_eq!(time_ns(unreadable.into()), None, "{:?}", unreadable);
+    }
+}
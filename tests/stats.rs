@@ -0,0 +1,71 @@
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use log_store_extension::stats::{self, Stats};
+
+/// What's been logged, from a subscriber writing into it.
+#[derive(Clone, Default)]
+struct Logged(Arc<Mutex<Vec<u8>>>);
+
+impl Logged {
+    fn lines(&self) -> Vec<String> {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap().lines().map(str::to_string).collect()
+    }
+}
+
+impl Write for Logged {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn subscriber(logged: &Logged) -> impl tracing::Subscriber {
+    let logged = logged.clone();
+
+    tracing_subscriber::fmt().without_time().with_ansi(false).with_writer(move || logged.clone()).finish()
+}
+
+#[tokio::test(start_paused = true)]
+async fn stats_are_logged_each_interval() {
+    let logged = Logged::default();
+    let _subscriber = tracing::subscriber::set_default(subscriber(&logged));
+    let stats = Arc::new(Stats::new(None));
+
+    stats.record_batch(3);
+    stats.record_written(10);
+    stats.add_dropped(1);
+    stats.platform_dropped.fetch_add(2, Ordering::Relaxed);
+    stats.stale_dropped.fetch_add(4, Ordering::Relaxed);
+
+    let logger = tokio::spawn(stats::log_periodically(stats.clone(), Duration::from_secs(60)));
+
+    // nothing until the first interval is up
+    tokio::time::sleep(Duration::from_secs(59)).await;
+    assert!(logged.lines().is_empty());
+
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert_eq!(logged.lines().len(), 1);
+    assert!(logged.lines()[0].ends_with("Stats: 3 records received, 1 sent (10 bytes), 7 dropped in all, 0 reconnects, 0 queued"),
+            "{}", logged.lines()[0]);
+
+    tokio::time::sleep(Duration::from_secs(120)).await;
+    assert_eq!(logged.lines().len(), 3);
+    logger.abort();
+}
+
+#[tokio::test(start_paused = true)]
+async fn stats_arent_logged_without_an_interval() {
+    let logged = Logged::default();
+    let _subscriber = tracing::subscriber::set_default(subscriber(&logged));
+    let logger = tokio::spawn(stats::log_periodically(Arc::new(Stats::new(None)), Duration::ZERO));
+
+    tokio::time::sleep(Duration::from_secs(3_600)).await;
+    assert!(logger.is_finished());
+    assert!(logged.lines().is_empty());
+}
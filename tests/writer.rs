@@ -0,0 +1,1443 @@
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use flate2::read::GzDecoder;
+use json::{JsonValue, object};
+use log_store_extension::checksum::Sha256;
+use log_store_extension::clock::MockClock;
+use log_store_extension::config::Config;
+use log_store_extension::dns::DnsCache;
+use log_store_extension::encoder;
+use log_store_extension::framing::{self, FrameError};
+use log_store_extension::handler::HandlerState;
+use log_store_extension::health::Health;
+use log_store_extension::shutdown::{shutdown_channel, ShutdownDump, ShutdownReason};
+use log_store_extension::sink::{Sink, SinkFuture};
+use log_store_extension::otel::Format;
+use log_store_extension::stats::{Pressure, Stats};
+use log_store_extension::syslog::{Syslog, SyslogTransport};
+use log_store_extension::writer::{supervise, write_file, write_sink, write_tcp, TcpWriter};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{channel, Sender};
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+
+/// A fake log-store listening on a random local port.
+async fn fake_log_store() -> (TcpListener, String) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+
+    (listener, address)
+}
+
+fn config(address: &str, vars: &[(&str, &str)]) -> Arc<Config> {
+    let mut all = vec![("LOG_STORE_ADDRESS", address)];
+    all.extend_from_slice(vars);
+
+    Arc::new(Config::from_vars(all).unwrap())
+}
+
+/// Reads `count` records (or until EOF if `None`) off of the connection.
+async fn read_records(stream: &mut BufReader<TcpStream>, count: Option<usize>) -> Vec<JsonValue> {
+    let mut records = Vec::new();
+    let mut line = String::new();
+
+    while count.is_none_or(|c| records.len() < c) {
+        line.clear();
+
+        if stream.read_line(&mut line).await.unwrap() == 0 {
+            break;
+        }
+
+        records.push(json::parse(line.as_str()).unwrap());
+    }
+
+    records
+}
+
+fn record(n: usize) -> JsonValue {
+    object! { "t": 1_712_345_678_000i64 + n as i64, "type": "function", "n": n }
+}
+
+#[tokio::test]
+async fn clean_batch() {
+    let (listener, address) = fake_log_store().await;
+    let (sender, recver) = channel(16);
+
+    let writer = tokio::spawn(write_tcp(address.clone(), config(address.as_str(), &[]), Arc::new(Stats::new(None)), recver, shutdown_channel().1));
+    let (stream, _) = listener.accept().await.unwrap();
+
+    for n in 0..3 {
+        sender.send(record(n)).await.unwrap();
+    }
+
+    drop(sender);
+
+    let records = read_records(&mut BufReader::new(stream), None).await;
+
+    writer.await.unwrap();
+    assert_eq!(records, vec![record(0), record(1), record(2)]);
+}
+
+#[tokio::test]
+async fn reconnects_after_server_close() {
+    let (listener, address) = fake_log_store().await;
+    let (sender, recver) = channel(16);
+    let (closed_tx, closed_rx) = oneshot::channel();
+
+    let writer = tokio::spawn(write_tcp(address.clone(), config(address.as_str(), &[]), Arc::new(Stats::new(None)), recver, shutdown_channel().1));
+
+    let server = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let first = read_records(&mut BufReader::new(stream), Some(2)).await;
+
+        // the first connection is dropped (closed) here
+        closed_tx.send(()).unwrap();
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let second = read_records(&mut BufReader::new(stream), None).await;
+
+        (first, second)
+    });
+
+    sender.send(record(0)).await.unwrap();
+    sender.send(record(1)).await.unwrap();
+
+    closed_rx.await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    sender.send(record(2)).await.unwrap();
+    sender.send(record(3)).await.unwrap();
+    drop(sender);
+
+    let (first, second) = server.await.unwrap();
+
+    writer.await.unwrap();
+    assert_eq!(first, vec![record(0), record(1)]);
+    assert_eq!(second, vec![record(2), record(3)]);
+}
+
+#[tokio::test]
+async fn large_records_are_compressed() {
+    let (listener, address) = fake_log_store().await;
+    let (sender, recver) = channel(16);
+    let config = config(address.as_str(), &[("LOG_STORE_RECORD_COMPRESS_MIN_BYTES", "1024")]);
+
+    let writer = tokio::spawn(write_tcp(address.clone(), config, Arc::new(Stats::new(None)), recver, shutdown_channel().1));
+    let (stream, _) = listener.accept().await.unwrap();
+
+    let mut large = record(1);
+    large.insert("payload", "x".repeat(4096)).unwrap();
+
+    sender.send(record(0)).await.unwrap();
+    sender.send(large.clone()).await.unwrap();
+    drop(sender);
+
+    let records = read_records(&mut BufReader::new(stream), None).await;
+
+    writer.await.unwrap();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0], record(0));
+
+    let compressed = &records[1];
+
+    assert_eq!(compressed["_z"], "gzip");
+    assert_eq!(compressed["type"], "function");
+    assert_eq!(compressed["t"], large["t"]);
+
+    let bytes = BASE64.decode(compressed["payload"].as_str().unwrap()).unwrap();
+    let mut decoded = String::new();
+
+    GzDecoder::new(bytes.as_slice()).read_to_string(&mut decoded).unwrap();
+    assert!(decoded.len() > bytes.len());
+    assert_eq!(json::parse(decoded.as_str()).unwrap(), large);
+}
+
+#[tokio::test]
+async fn shutdown_summary_is_last() {
+    let (listener, address) = fake_log_store().await;
+    let (sender, recver) = channel(16);
+    let (shutdown, shutdown_listener) = shutdown_channel();
+
+    let writer = tokio::spawn(write_tcp(address.clone(), config(address.as_str(), &[]), Arc::new(Stats::new(None)), recver, shutdown_listener));
+    let (stream, _) = listener.accept().await.unwrap();
+
+    for n in 0..3 {
+        sender.send(record(n)).await.unwrap();
+    }
+
+    let reason = ShutdownReason::Event("SPINDOWN".to_string());
+
+    // the sender is still open: the writer finishes on the shutdown, not the channel closing
+    assert!(shutdown.shutdown(reason, Instant::now() + Duration::from_secs(1)).await);
+
+    let records = read_records(&mut BufReader::new(stream), None).await;
+
+    writer.await.unwrap();
+    assert_eq!(&records[..3], &[record(0), record(1), record(2)]);
+    assert_eq!(records.len(), 4);
+
+    let summary = &records[3];
+
+    assert_eq!(summary["type"], "shutdown_summary");
+    assert_eq!(summary["reason"], "shutdown_event");
+    assert_eq!(summary["detail"], "SPINDOWN");
+    assert_eq!(summary["total_records"], 3);
+    assert_eq!(summary["dropped"], 0);
+    assert_eq!(summary["dumped"], 0);
+    drop(sender);
+}
+
+#[tokio::test]
+async fn records_left_at_the_deadline_are_dumped() {
+    let (listener, address) = fake_log_store().await;
+    let (sender, recver) = channel(16);
+    let (shutdown, shutdown_listener) = shutdown_channel();
+    let config = config(address.as_str(), &[
+        ("LOG_STORE_ACK_CRITICAL", "1"),
+        ("LOG_STORE_ACK_TIMEOUT_MS", "300"),
+        ("LOG_STORE_ACK_RETRIES", "0"),
+    ]);
+
+    let writer = tokio::spawn(write_tcp(address.clone(), config, Arc::new(Stats::new(None)), recver, shutdown_listener));
+    let (stream, _) = listener.accept().await.unwrap();
+
+    let mut stream = BufReader::new(stream);
+
+    // the log-store never acks, so the writer is stuck on the first record past the deadline
+    sender.send(object! { "t": 1, "type": "function", "audit": true }).await.unwrap();
+    assert_eq!(read_records(&mut stream, Some(1)).await[0]["audit"], true);
+    sender.send(record(1)).await.unwrap();
+    sender.send(record(2)).await.unwrap();
+
+    let reason = ShutdownReason::Event("SPINDOWN".to_string());
+
+    assert!(!shutdown.shutdown(reason, Instant::now() + Duration::from_millis(20)).await);
+
+    let records = read_records(&mut stream, None).await;
+
+    writer.await.unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["type"], "shutdown_summary");
+    assert_eq!(records[0]["dumped"], 2);
+    assert_eq!(records[0]["drained"], 0);
+    drop(sender);
+}
+
+#[tokio::test]
+async fn platform_records_can_be_written_once() {
+    let (listener, address) = fake_log_store().await;
+    let (sender, recver) = channel(16);
+    let (shutdown, shutdown_listener) = shutdown_channel();
+    let config = config(address.as_str(), &[("LOG_STORE_DEDUP_PLATFORM", "1")]);
+    let report = |request_id: &str| object! { "t": 1, "type": "platform_report", "request_id": request_id, "duration_ms": 12.5 };
+
+    let writer = tokio::spawn(write_tcp(address.clone(), config, Arc::new(Stats::new(None)), recver, shutdown_listener));
+    let (stream, _) = listener.accept().await.unwrap();
+
+    // the second is a replay of the first; function records are left for the log-store to tell apart
+    for json in [report("abc"), report("abc"), report("def"), record(1), record(1)] {
+        sender.send(json).await.unwrap();
+    }
+
+    assert!(shutdown.shutdown(ShutdownReason::Event("SPINDOWN".to_string()), Instant::now() + Duration::from_secs(1)).await);
+
+    let records = read_records(&mut BufReader::new(stream), None).await;
+
+    writer.await.unwrap();
+    assert_eq!(&records[..4], &[report("abc"), report("def"), record(1), record(1)]);
+    assert_eq!(records[4]["platform_deduped"], 1);
+}
+
+#[tokio::test]
+async fn file_batches_end_with_a_checksum_of_their_bytes() {
+    let path = std::env::temp_dir().join(format!("log-store-batch-checksum-test-{}.ndjson", std::process::id()));
+    let path = path.to_str().unwrap().to_string();
+    let (sender, recver) = channel(16);
+    let (_shutdown, shutdown_listener) = shutdown_channel();
+    let config = config(format!("file:{}", path).as_str(), &[
+        ("LOG_STORE_BATCH_CHECKSUM", "1"),
+        ("LOG_STORE_BATCH_CHECKSUM_RECORDS", "2"),
+    ]);
+
+    for n in 0..3 {
+        sender.send(record(n)).await.unwrap();
+    }
+
+    drop(sender);
+    write_file(path.clone(), config, Arc::new(Stats::new(None)), recver, shutdown_listener).await;
+
+    let written = std::fs::read_to_string(&path).unwrap();
+    let mut batches = Vec::new();
+    let mut sha256 = Sha256::new();
+
+    std::fs::remove_file(&path).unwrap();
+
+    for line in written.split_inclusive('\n') {
+        let json = json::parse(line).unwrap();
+
+        if json["type"] == "_batch" {
+            assert_eq!(json["sha256"].as_str(), Some(std::mem::take(&mut sha256).finish().as_str()));
+            batches.push(json["count"].as_u64().unwrap());
+        } else {
+            sha256.update(line.as_bytes());
+        }
+    }
+
+    // the last batch is whatever's left once the channel closes
+    assert_eq!(batches, [2, 1]);
+
+    let mut abc = Sha256::new();
+
+    abc.update(b"abc");
+    assert_eq!(abc.finish(), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+}
+
+#[tokio::test]
+async fn a_file_that_cant_be_opened_is_checksummed_on_stdout() {
+    const CHILD_ENV_NAME: &str = "LOG_STORE_TEST_STDOUT_CHILD";
+
+    // what's printed can only be read from outside, so the test runs again as a child that does the writing
+    if let Some(path) = std::env::var_os(CHILD_ENV_NAME) {
+        let path = path.into_string().unwrap();
+        let (sender, recver) = channel(16);
+        let (_shutdown, shutdown_listener) = shutdown_channel();
+        let config = config(format!("file:{}", path).as_str(), &[
+            ("LOG_STORE_BATCH_CHECKSUM", "1"),
+            ("LOG_STORE_BATCH_CHECKSUM_RECORDS", "2"),
+        ]);
+
+        for n in 0..3 {
+            sender.send(record(n)).await.unwrap();
+        }
+
+        drop(sender);
+        return write_file(path, config, Arc::new(Stats::new(None)), recver, shutdown_listener).await;
+    }
+
+    // a directory in /tmp, so neither it nor the sink's fallback under /tmp (the same path) can be opened
+    let dir = std::path::Path::new("/tmp").join(format!("log-store-unopenable-test-{}", std::process::id()));
+
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let output = std::process::Command::new(std::env::current_exe().unwrap())
+        .args(["a_file_that_cant_be_opened_is_checksummed_on_stdout", "--exact", "--nocapture", "--test-threads=1"])
+        .env(CHILD_ENV_NAME, &dir)
+        .output()
+        .unwrap();
+
+    std::fs::remove_dir(&dir).unwrap();
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let mut batches = Vec::new();
+    let mut sha256 = Sha256::new();
+
+    // the first record is printed after the test's name, on the harness's line
+    for line in stdout.split_inclusive('\n').filter_map(|line| line.find('{').map(|i| &line[i..])) {
+        let json = json::parse(line).unwrap();
+
+        if json["type"] == "_batch" {
+            assert_eq!(json["sha256"].as_str(), Some(std::mem::take(&mut sha256).finish().as_str()));
+            batches.push(json["count"].as_u64().unwrap());
+        } else {
+            sha256.update(line.as_bytes());
+        }
+    }
+
+    assert_eq!(batches, [2, 1]);
+}
+
+#[tokio::test]
+async fn records_left_at_the_deadline_can_be_spilled() {
+    let dir = std::env::temp_dir().join(format!("log-store-shutdown-spill-test-{}", std::process::id()));
+    let (listener, address) = fake_log_store().await;
+    let (sender, recver) = channel(16);
+    let (shutdown, shutdown_listener) = shutdown_channel();
+    let config = config(address.as_str(), &[
+        ("LOG_STORE_ACK_CRITICAL", "1"),
+        ("LOG_STORE_ACK_TIMEOUT_MS", "300"),
+        ("LOG_STORE_ACK_RETRIES", "0"),
+        ("LOG_STORE_SHUTDOWN_DUMP", "spill"),
+        ("LOG_STORE_SPILL_DIR", dir.to_str().unwrap()),
+        // room for one record, so the other is printed
+        ("LOG_STORE_SPILL_MAX_BYTES", "64"),
+    ]);
+
+    let writer = tokio::spawn(write_tcp(address.clone(), config, Arc::new(Stats::new(None)), recver, shutdown_listener));
+    let (stream, _) = listener.accept().await.unwrap();
+
+    let mut stream = BufReader::new(stream);
+
+    // the log-store never acks, so the writer is stuck on the first record past the deadline
+    sender.send(object! { "t": 1, "type": "function", "audit": true }).await.unwrap();
+    assert_eq!(read_records(&mut stream, Some(1)).await[0]["audit"], true);
+    sender.send(record(1)).await.unwrap();
+    sender.send(record(2)).await.unwrap();
+
+    let shutdown = shutdown.with_dump(ShutdownDump::Spill);
+    let reason = ShutdownReason::Event("SPINDOWN".to_string());
+
+    assert!(!shutdown.shutdown(reason, Instant::now() + Duration::from_millis(20)).await);
+
+    let records = read_records(&mut stream, None).await;
+
+    writer.await.unwrap();
+
+    let spilled = std::fs::read_to_string(dir.join(format!("spill-{}.ndjson", std::process::id()))).unwrap();
+
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(spilled, format!("{}\n", record(1)));
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["dumped"], 2);
+    assert_eq!(records[0]["spilled"], 1);
+    drop(sender);
+}
+
+#[tokio::test]
+async fn log_store_can_ask_for_a_pause() {
+    let (listener, address) = fake_log_store().await;
+    let (sender, recver) = channel(16);
+    let config = config(address.as_str(), &[("LOG_STORE_CONTROL_CHANNEL", "1")]);
+
+    let writer = tokio::spawn(write_tcp(address.clone(), config, Arc::new(Stats::new(None)), recver, shutdown_channel().1));
+    let (stream, _) = listener.accept().await.unwrap();
+
+    let mut stream = BufReader::new(stream);
+
+    sender.send(record(0)).await.unwrap();
+    assert_eq!(read_records(&mut stream, Some(1)).await, vec![record(0)]);
+
+    // anything else the log-store sends back is read and ignored
+    stream.get_mut().write_all(b"hello\n{\"type\":\"nope\"}\n{\"type\":\"throttle\",\"pause_ms\":300}\n").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(20)).await;
+
+    let sent_at = Instant::now();
+
+    sender.send(record(1)).await.unwrap();
+    assert_eq!(read_records(&mut stream, Some(1)).await, vec![record(1)]);
+    assert!(sent_at.elapsed() >= Duration::from_millis(250));
+
+    // the pause was used up
+    let sent_at = Instant::now();
+
+    sender.send(record(2)).await.unwrap();
+    assert_eq!(read_records(&mut stream, Some(1)).await, vec![record(2)]);
+    assert!(sent_at.elapsed() < Duration::from_millis(250));
+
+    drop(sender);
+    writer.await.unwrap();
+}
+
+#[tokio::test]
+async fn pre_serialized_records_are_written_as_they_are() {
+    let (listener, address) = fake_log_store().await;
+    let (sender, recver) = channel(16);
+    let config = config(address.as_str(), &[("LOG_STORE_PRESERIALIZE", "1"), ("LOG_STORE_FLUSH_MODE", "buffered")]);
+
+    let writer = tokio::spawn(write_tcp(address.clone(), config, Arc::new(Stats::new(None)), recver, shutdown_channel().1));
+    let (stream, _) = listener.accept().await.unwrap();
+
+    sender.send(JsonValue::String(format!("{}\n", record(0)))).await.unwrap();
+    sender.send(record(1)).await.unwrap();
+    drop(sender);
+
+    let records = read_records(&mut BufReader::new(stream), None).await;
+
+    writer.await.unwrap();
+    assert_eq!(records, vec![record(0), record(1)]);
+}
+
+#[tokio::test]
+async fn tunnels_through_proxy() {
+    let (listener, proxy) = fake_log_store().await;
+    let (sender, recver) = channel(16);
+    let address = "log-store.internal:7777";
+    let config = config(address, &[("LOG_STORE_PROXY", format!("http://{}", proxy).as_str()), ("LOG_STORE_PROXY_AUTH", "user:pass")]);
+
+    let writer = tokio::spawn(write_tcp(address.to_string(), config, Arc::new(Stats::new(None)), recver, shutdown_channel().1));
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut stream = BufReader::new(stream);
+    let mut request = Vec::new();
+    let mut line = String::new();
+
+    // the fake proxy reads the CONNECT, then acts as the log-store at the end of the tunnel
+    while line != "\r\n" {
+        line.clear();
+        stream.read_line(&mut line).await.unwrap();
+        request.push(line.clone());
+    }
+
+    stream.get_mut().write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").await.unwrap();
+    sender.send(record(0)).await.unwrap();
+    drop(sender);
+
+    let records = read_records(&mut stream, None).await;
+
+    writer.await.unwrap();
+    assert_eq!(request[0], "CONNECT log-store.internal:7777 HTTP/1.1\r\n");
+    assert!(request.contains(&format!("Proxy-Authorization: Basic {}\r\n", BASE64.encode("user:pass"))));
+    assert_eq!(records, vec![record(0)]);
+}
+
+#[tokio::test]
+async fn buffered_batches_keep_records_whole() {
+    let (listener, address) = fake_log_store().await;
+    let (sender, recver) = channel(1024);
+    let config = config(address.as_str(), &[("LOG_STORE_FLUSH_MODE", "buffered"), ("LOG_STORE_BATCH_DEADLINE_MS", "50")]);
+
+    let writer = tokio::spawn(write_tcp(address.clone(), config, Arc::new(Stats::new(None)), recver, shutdown_channel().1));
+    let (stream, _) = listener.accept().await.unwrap();
+
+    // enough to fill several batches, then a partial one left for the last flush
+    for n in 0..500 {
+        sender.send(record(n)).await.unwrap();
+    }
+
+    drop(sender);
+
+    let records = read_records(&mut BufReader::new(stream), None).await;
+
+    writer.await.unwrap();
+    assert_eq!(records, (0..500).map(record).collect::<Vec<_>>());
+}
+
+#[tokio::test]
+async fn each_connection_is_a_new_session() {
+    let (listener, address) = fake_log_store().await;
+    let (sender, recver) = channel(16);
+    let (closed_tx, closed_rx) = oneshot::channel();
+    let config = config(address.as_str(), &[("LOG_STORE_INCLUDE_SESSION", "1")]);
+
+    let writer = tokio::spawn(write_tcp(address.clone(), config, Arc::new(Stats::new(None)), recver, shutdown_channel().1));
+
+    let server = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let first = read_records(&mut BufReader::new(stream), Some(2)).await;
+
+        closed_tx.send(()).unwrap();
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let second = read_records(&mut BufReader::new(stream), None).await;
+
+        (first, second)
+    });
+
+    sender.send(record(0)).await.unwrap();
+    closed_rx.await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    sender.send(record(1)).await.unwrap();
+    drop(sender);
+
+    let (first, second) = server.await.unwrap();
+
+    writer.await.unwrap();
+
+    // each connection starts with a hello carrying its session id, which every record then has
+    for (session, n) in [(&first, 0), (&second, 1)] {
+        assert_eq!(session.len(), 2);
+        assert_eq!(session[0]["type"], "session_start");
+        assert_eq!(session[0]["sid"].as_str().map(str::len), Some(36));
+        assert_eq!(session[1]["n"], n);
+        assert_eq!(session[1]["sid"], session[0]["sid"]);
+    }
+
+    assert_ne!(first[0]["sid"], second[0]["sid"]);
+}
+
+#[tokio::test]
+async fn records_are_numbered_per_connection() {
+    let (listener, address) = fake_log_store().await;
+    let (sender, recver) = channel(16);
+    let (closed_tx, closed_rx) = oneshot::channel();
+    let config = config(address.as_str(), &[("LOG_STORE_INCLUDE_SEQ", "1")]);
+
+    let writer = tokio::spawn(write_tcp(address.clone(), config, Arc::new(Stats::new(None)), recver, shutdown_channel().1));
+
+    let server = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let first = read_records(&mut BufReader::new(stream), Some(3)).await;
+
+        closed_tx.send(()).unwrap();
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let second = read_records(&mut BufReader::new(stream), None).await;
+
+        (first, second)
+    });
+
+    for i in 0..3 {
+        sender.send(object! { "t": 1_712_345_678_000i64, "type": "function", "i": i }).await.unwrap();
+    }
+
+    closed_rx.await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    sender.send(object! { "t": 1_712_345_678_000i64, "type": "function", "i": 3 }).await.unwrap();
+    drop(sender);
+
+    let (first, second) = server.await.unwrap();
+
+    writer.await.unwrap();
+
+    // the count starts again on the new connection
+    assert_eq!(first.iter().map(|record| record["n"].as_u64().unwrap()).collect::<Vec<_>>(), vec![0, 1, 2]);
+    assert_eq!(second.iter().map(|record| record["n"].as_u64().unwrap()).collect::<Vec<_>>(), vec![0]);
+}
+
+/// `json` as the log-store gets it with `include_session` and `include_seq`, after the session's hello.
+async fn stamped(vars: &[(&str, &str)], json: JsonValue) -> JsonValue {
+    let (listener, address) = fake_log_store().await;
+    let (sender, recver) = channel(16);
+    let mut all = vec![("LOG_STORE_INCLUDE_SESSION", "1"), ("LOG_STORE_INCLUDE_SEQ", "1")];
+    all.extend_from_slice(vars);
+
+    let writer = tokio::spawn(write_tcp(address.clone(), config(address.as_str(), &all), Arc::new(Stats::new(None)), recver, shutdown_channel().1));
+    let (stream, _) = listener.accept().await.unwrap();
+
+    sender.send(json).await.unwrap();
+    drop(sender);
+
+    let records = read_records(&mut BufReader::new(stream), None).await;
+
+    writer.await.unwrap();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0]["type"], "session_start");
+    records[1].clone()
+}
+
+#[tokio::test]
+async fn session_and_number_are_under_meta_in_the_envelope() {
+    let json = stamped(&[("LOG_STORE_LAYOUT", "envelope")], object! {
+        "meta": { "t": 1_712_345_678_000i64, "type": "function", "severity": "info" },
+        "body": { "msg": "hi" },
+    }).await;
+
+    assert_eq!(json["meta"]["sid"].as_str().map(str::len), Some(36));
+    assert_eq!(json["meta"]["n"], 0);
+    assert_eq!(json["body"], object! { "msg": "hi" });
+    assert_eq!(json.len(), 2);
+}
+
+#[tokio::test]
+async fn session_and_number_are_otel_attributes() {
+    let json = stamped(&[("LOG_STORE_FORMAT", "otel")], object! {
+        "timeUnixNano": "1712345678000000000",
+        "severityNumber": 9,
+        "severityText": "INFO",
+        "body": { "stringValue": "hi" },
+        "attributes": [{ "key": "type", "value": { "stringValue": "function" } }],
+    }).await;
+    let attributes = &json["attributes"];
+
+    assert!(!json.has_key("sid") && !json.has_key("n"));
+    assert_eq!(attributes.len(), 3);
+    assert_eq!(attributes[1]["key"], "sid");
+    assert_eq!(attributes[1]["value"]["stringValue"].as_str().map(str::len), Some(36));
+    assert_eq!(attributes[2], object! { "key": "n", "value": { "intValue": "0" } });
+}
+
+#[tokio::test]
+async fn sorted_keys() {
+    let (listener, address) = fake_log_store().await;
+    let (sender, recver) = channel(16);
+
+    let writer = tokio::spawn(write_tcp(address.clone(), config(address.as_str(), &[("LOG_STORE_SORT_KEYS", "1")]), Arc::new(Stats::new(None)), recver, shutdown_channel().1));
+    let (stream, _) = listener.accept().await.unwrap();
+
+    sender.send(object! { "t": 1, "type": "function", "b": { "z": 1, "a": [{ "y": 2, "x": 3 }] }, "a": true }).await.unwrap();
+    drop(sender);
+
+    let mut line = String::new();
+
+    BufReader::new(stream).read_line(&mut line).await.unwrap();
+    writer.await.unwrap();
+    assert_eq!(line, "{\"a\":true,\"b\":{\"a\":[{\"x\":3,\"y\":2}],\"z\":1},\"t\":1,\"type\":\"function\"}\n");
+}
+
+#[tokio::test]
+async fn invocations_are_written_whole() {
+    let (listener, address) = fake_log_store().await;
+    let (sender, recver) = channel(16);
+    let config = config(address.as_str(), &[("LOG_STORE_BATCH_BY", "invocation")]);
+
+    let writer = tokio::spawn(write_tcp(address.clone(), config, Arc::new(Stats::new(None)), recver, shutdown_channel().1));
+    let (stream, _) = listener.accept().await.unwrap();
+    let start = object! { "t": 1, "type": "platform_start", "request_id": "abc" };
+    let report = object! { "t": 4, "type": "platform_report", "request_id": "abc" };
+
+    for json in [record(0), start.clone(), record(2), record(3), report.clone(), record(5)] {
+        sender.send(json).await.unwrap();
+    }
+
+    drop(sender);
+
+    let records = read_records(&mut BufReader::new(stream), None).await;
+
+    writer.await.unwrap();
+
+    // records outside an invocation go as they come
+    assert_eq!(records, vec![
+        record(0),
+        JsonValue::Array(vec![start, record(2), record(3), report]),
+        record(5),
+    ]);
+}
+
+#[tokio::test]
+async fn idle_connections_are_closed() {
+    let (listener, address) = fake_log_store().await;
+    let (sender, recver) = channel(16);
+    let stats = Arc::new(Stats::new(None));
+    let config = config(address.as_str(), &[("LOG_STORE_IDLE_DISCONNECT_SECS", "1")]);
+
+    let writer = tokio::spawn(write_tcp(address.clone(), config, stats.clone(), recver, shutdown_channel().1));
+
+    let server = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let started = Instant::now();
+        let first = read_records(&mut BufReader::new(stream), None).await;
+        let idle_for = started.elapsed();
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let second = read_records(&mut BufReader::new(stream), None).await;
+
+        (first, idle_for, second)
+    });
+
+    sender.send(record(0)).await.unwrap();
+
+    // the writer closes the first connection on its own, then opens another for the next record
+    let (first, idle_for, second) = tokio::join!(async {
+        tokio::time::sleep(Duration::from_millis(1_500)).await;
+        sender.send(record(1)).await.unwrap();
+        drop(sender);
+        server.await.unwrap()
+    }, writer).0;
+
+    assert_eq!(first, vec![record(0)]);
+    assert!(idle_for >= Duration::from_millis(900));
+    assert_eq!(second, vec![record(1)]);
+    assert_eq!(stats.reconnects.load(std::sync::atomic::Ordering::Relaxed), 0);
+}
+
+#[tokio::test]
+async fn spilled_records_are_replayed_first() {
+    let dir = std::env::temp_dir().join(format!("log-store-spill-test-{}", std::process::id()));
+    let line = |n| format!("{}\n", record(n));
+
+    std::fs::create_dir_all(&dir).unwrap();
+    // no process has that pid, while pid 1 is still running
+    std::fs::write(dir.join("spill-4294967295.ndjson"), line(0) + line(1).as_str()).unwrap();
+    std::fs::write(dir.join("spill-1.ndjson"), line(9)).unwrap();
+
+    let (listener, address) = fake_log_store().await;
+    let (sender, recver) = channel(16);
+    let config = config(address.as_str(), &[
+        ("LOG_STORE_SHUTDOWN_DUMP", "spill"),
+        ("LOG_STORE_SPILL_DIR", dir.to_str().unwrap()),
+    ]);
+
+    let writer = tokio::spawn(write_tcp(address.clone(), config, Arc::new(Stats::new(None)), recver, shutdown_channel().1));
+    let (stream, _) = listener.accept().await.unwrap();
+
+    sender.send(record(2)).await.unwrap();
+    drop(sender);
+
+    let records = read_records(&mut BufReader::new(stream), None).await;
+
+    writer.await.unwrap();
+
+    let left: Vec<_> = std::fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(records, vec![record(0), record(1), record(2)]);
+    assert_eq!(left, vec!["spill-1.ndjson"]);
+}
+
+#[tokio::test]
+async fn preconnects_before_starting() {
+    let (listener, address) = fake_log_store().await;
+    let (sender, recver) = channel(16);
+    let config = config(address.as_str(), &[("LOG_STORE_INCLUDE_SESSION", "1")]);
+    let mut writer = TcpWriter::new(address.clone(), config, Arc::new(Stats::new(None)));
+
+    writer.preconnect().await;
+
+    // the hello is out before the writer has started, let alone any records
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut stream = BufReader::new(stream);
+
+    assert_eq!(read_records(&mut stream, Some(1)).await[0]["type"], "session_start");
+
+    let writer = tokio::spawn(writer.start(recver, shutdown_channel().1));
+
+    sender.send(record(0)).await.unwrap();
+    drop(sender);
+
+    let records = read_records(&mut stream, None).await;
+
+    writer.await.unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["n"], 0);
+}
+
+#[tokio::test]
+async fn oldest_records_are_dropped_over_the_memory_budget() {
+    let (listener, address) = fake_log_store().await;
+    let (sender, recver) = channel(32);
+    let stats = Arc::new(Stats::new(None).with_memory_budget(Some(300)));
+
+    // all queued up before the writer takes any, as when it's been stuck
+    for n in 0..20 {
+        assert!(stats.try_acquire(stats.inflight_size(&record(n))));
+        sender.send(record(n)).await.unwrap();
+    }
+
+    drop(sender);
+
+    let config = config(address.as_str(), &[("LOG_STORE_MEMORY_BUDGET_BYTES", "300")]);
+    let writer = tokio::spawn(write_tcp(address.clone(), config, stats.clone(), recver, shutdown_channel().1));
+    let (stream, _) = listener.accept().await.unwrap();
+    let records = read_records(&mut BufReader::new(stream), None).await;
+
+    writer.await.unwrap();
+
+    let dropped = stats.dropped.load(std::sync::atomic::Ordering::Relaxed);
+
+    assert!(dropped > 0);
+    assert_eq!(records.len() as u64, 20 - dropped);
+    assert_eq!(records.last(), Some(&record(19)));
+    assert_eq!(records[0], record(dropped as usize));
+}
+
+#[test]
+fn small_memory_budgets_flush_past_three_quarters() {
+    let stats = Stats::new(None).with_memory_budget(Some(90));
+
+    assert!(stats.try_acquire(60));
+    assert_eq!(stats.pressure(), Pressure::Normal);
+    assert!(stats.try_acquire(10));
+    assert_eq!(stats.pressure(), Pressure::Flush);
+    assert!(stats.try_acquire(20));
+    assert_eq!(stats.pressure(), Pressure::Shed);
+}
+
+#[tokio::test]
+async fn records_are_coalesced_for_the_wait() {
+    let (listener, address) = fake_log_store().await;
+    let (sender, recver) = channel(16);
+    let config = config(address.as_str(), &[("LOG_STORE_COALESCE_WAIT_MS", "50")]);
+
+    let writer = tokio::spawn(write_tcp(address.clone(), config, Arc::new(Stats::new(None)), recver, shutdown_channel().1));
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut stream = BufReader::new(stream);
+
+    sender.send(record(0)).await.unwrap();
+
+    // the first record waits for company, rather than being written straight away
+    let early = tokio::time::timeout(Duration::from_millis(20), read_records(&mut stream, Some(1))).await;
+    assert!(early.is_err());
+
+    sender.send(record(1)).await.unwrap();
+
+    let started = Instant::now();
+    let records = read_records(&mut stream, Some(2)).await;
+
+    assert_eq!(records, vec![record(0), record(1)]);
+    assert!(started.elapsed() < Duration::from_millis(50));
+
+    drop(sender);
+    writer.await.unwrap();
+}
+
+#[tokio::test]
+async fn flush_types_are_written_straight_away() {
+    let (listener, address) = fake_log_store().await;
+    let (sender, recver) = channel(16);
+    let config = config(address.as_str(), &[
+        ("LOG_STORE_FLUSH_MODE", "buffered"),
+        ("LOG_STORE_BATCH_DEADLINE_MS", "10000"),
+        ("LOG_STORE_FLUSH_TYPES", "platform_report,level:error"),
+    ]);
+
+    let writer = tokio::spawn(write_tcp(address.clone(), config, Arc::new(Stats::new(None)), recver, shutdown_channel().1));
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut stream = BufReader::new(stream);
+
+    sender.send(record(0)).await.unwrap();
+
+    // a routine record waits for the batch
+    let early = tokio::time::timeout(Duration::from_millis(50), read_records(&mut stream, Some(1))).await;
+    assert!(early.is_err());
+
+    // a report takes what's batched along with it
+    let report = object! { "t": 1_712_345_678_001i64, "type": "platform_report" };
+    sender.send(report.clone()).await.unwrap();
+
+    let records = tokio::time::timeout(Duration::from_secs(1), read_records(&mut stream, Some(2))).await.unwrap();
+    assert_eq!(records, vec![record(0), report]);
+
+    // as does anything at (or above) the level
+    let fatal = object! { "t": 1_712_345_678_002i64, "type": "function", "severity": "fatal" };
+    sender.send(fatal.clone()).await.unwrap();
+
+    let records = tokio::time::timeout(Duration::from_secs(1), read_records(&mut stream, Some(1))).await.unwrap();
+    assert_eq!(records, vec![fatal]);
+
+    drop(sender);
+    writer.await.unwrap();
+}
+
+#[tokio::test]
+async fn resolved_addresses_are_cached_for_the_ttl() {
+    let mut dns = DnsCache::new(Duration::from_secs(60));
+
+    let (addrs, cached) = dns.resolve("127.0.0.1:5000").await.unwrap();
+    assert_eq!((addrs, cached), (vec!["127.0.0.1:5000".parse().unwrap()], false));
+    assert!(dns.resolve("127.0.0.1:5000").await.unwrap().1);
+
+    dns.invalidate();
+    assert!(!dns.resolve("127.0.0.1:5000").await.unwrap().1);
+
+    // with no TTL, nothing is kept
+    let mut dns = DnsCache::new(Duration::ZERO);
+
+    dns.resolve("127.0.0.1:5000").await.unwrap();
+    assert!(!dns.resolve("127.0.0.1:5000").await.unwrap().1);
+}
+
+#[tokio::test]
+async fn records_past_the_max_age_are_dropped() {
+    let (listener, address) = fake_log_store().await;
+    let (sender, recver) = channel(16);
+    let config = config(address.as_str(), &[
+        ("LOG_STORE_MAX_RECORD_AGE_MS", "1000"),
+        ("LOG_STORE_MAX_RECORD_AGE_EXEMPT_PLATFORM", "1"),
+    ]);
+    let stats = Arc::new(Stats::new(None).with_clock(Arc::new(MockClock::new(1_712_345_680_000))));
+
+    let writer = tokio::spawn(write_tcp(address.clone(), config, stats.clone(), recver, shutdown_channel().1));
+    let (stream, _) = listener.accept().await.unwrap();
+
+    let report = object! { "t": 1_712_345_678_000i64, "type": "platform_report" };
+
+    // two seconds old, then one second old
+    sender.send(record(0)).await.unwrap();
+    sender.send(report.clone()).await.unwrap();
+    sender.send(record(1000)).await.unwrap();
+    drop(sender);
+
+    let records = read_records(&mut BufReader::new(stream), None).await;
+
+    writer.await.unwrap();
+    assert_eq!(records, vec![report, record(1000)]);
+    assert_eq!(stats.stale_dropped.load(std::sync::atomic::Ordering::Relaxed), 1);
+}
+
+#[tokio::test]
+async fn connections_are_probed_on_thaw() {
+    let (listener, address) = fake_log_store().await;
+    let (sender, recver) = channel(16);
+    let config = config(address.as_str(), &[("LOG_STORE_PROBE_ON_THAW", "1")]);
+    let stats = Arc::new(Stats::new(None));
+
+    let writer = tokio::spawn(write_tcp(address.clone(), config, stats.clone(), recver, shutdown_channel().1));
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut stream = BufReader::new(stream);
+
+    // a live connection gets a heartbeat, and is kept
+    stats.thawed();
+
+    let records = read_records(&mut stream, Some(1)).await;
+    assert_eq!(records[0]["type"], "heartbeat");
+
+    // which it gives a moment to be reset in reply
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // one the log-store dropped is replaced before any records need it
+    drop(stream);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    stats.thawed();
+
+    let (stream, _) = tokio::time::timeout(Duration::from_secs(1), listener.accept()).await.unwrap().unwrap();
+
+    sender.send(record(0)).await.unwrap();
+    drop(sender);
+
+    let records = read_records(&mut BufReader::new(stream), None).await;
+
+    writer.await.unwrap();
+    assert_eq!(records, vec![record(0)]);
+}
+
+#[tokio::test]
+async fn crc_frames_catch_corruption() {
+    let (listener, address) = fake_log_store().await;
+    let (sender, recver) = channel(16);
+    let config = config(address.as_str(), &[("LOG_STORE_FRAMING", "crc")]);
+
+    let writer = tokio::spawn(write_tcp(address.clone(), config, Arc::new(Stats::new(None)), recver, shutdown_channel().1));
+    let (mut stream, _) = listener.accept().await.unwrap();
+
+    sender.send(record(0)).await.unwrap();
+    sender.send(record(1)).await.unwrap();
+    drop(sender);
+
+    let mut bytes = Vec::new();
+
+    stream.read_to_end(&mut bytes).await.unwrap();
+    writer.await.unwrap();
+
+    let (first, used) = framing::decode(&bytes).unwrap().unwrap();
+    let (second, rest) = framing::decode(&bytes[used..]).unwrap().unwrap();
+
+    assert_eq!(json::parse(std::str::from_utf8(first).unwrap()).unwrap(), record(0));
+    assert_eq!(json::parse(std::str::from_utf8(second).unwrap()).unwrap(), record(1));
+    assert_eq!(used + rest, bytes.len());
+
+    // a flipped bit in the payload fails the check
+    bytes[10] ^= 0x01;
+    assert!(matches!(framing::decode(&bytes), Err(FrameError::Crc { .. })));
+
+    // and half a frame is just not all there yet
+    assert_eq!(framing::decode(&bytes[used + 2..used + 8]), Ok(None));
+}
+
+/// A sink that notes what it's asked to do, failing batches with a record marked `fail`.
+struct Recorder(Arc<Mutex<Vec<String>>>);
+
+impl Sink for Recorder {
+    fn write_batch<'a>(&'a mut self, records: &'a [JsonValue]) -> SinkFuture<'a> {
+        Box::pin(async move {
+            let types = records.iter().map(|json| json["type"].to_string()).collect::<Vec<_>>();
+
+            self.0.lock().unwrap().push(types.join(","));
+
+            match records.iter().any(|json| json.has_key("fail")) {
+                true => Err("the queue is full".into()),
+                false => Ok(()),
+            }
+        })
+    }
+
+    fn flush(&mut self) -> SinkFuture<'_> {
+        Box::pin(async move {
+            self.0.lock().unwrap().push("flush".to_string());
+            Ok(())
+        })
+    }
+
+    fn shutdown(&mut self) -> SinkFuture<'_> {
+        Box::pin(async move {
+            self.0.lock().unwrap().push("shutdown".to_string());
+            Ok(())
+        })
+    }
+}
+
+#[tokio::test]
+async fn custom_sinks_get_what_was_waiting_in_batches() {
+    let (sender, recver) = channel(16);
+    let (shutdown, shutdown_listener) = shutdown_channel();
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    let stats = Arc::new(Stats::new(None));
+
+    // all waiting before the writer starts
+    for n in 0..3 {
+        sender.send(record(n)).await.unwrap();
+    }
+
+    let writer = tokio::spawn(write_sink(Box::new(Recorder(calls.clone())), config("stdout", &[]), stats.clone(), recver, shutdown_listener));
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    sender.send(object! { "t": 1_712_345_678_000i64, "type": "extension", "fail": true }).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    assert!(shutdown.shutdown(ShutdownReason::Signal, Instant::now() + Duration::from_secs(1)).await);
+    writer.await.unwrap();
+
+    assert_eq!(*calls.lock().unwrap(), vec![
+        "function,function,function", "flush",
+        "extension", "flush",
+        "shutdown_summary", "flush",
+        "flush", "shutdown",
+    ]);
+    assert_eq!(stats.records_written.load(std::sync::atomic::Ordering::Relaxed), 4);
+    assert_eq!(stats.dropped.load(std::sync::atomic::Ordering::Relaxed), 1);
+}
+
+#[tokio::test]
+async fn a_writer_that_panics_is_replaced() {
+    let (listener, address) = fake_log_store().await;
+    let (sender, recver) = channel(16);
+    let config = config(address.as_str(), &[("LOG_STORE_WRITER_RESTARTS", "1")]);
+    let stats = Arc::new(Stats::new(None));
+    let mut started = 0;
+
+    let supervisor = tokio::spawn(supervise(recver, shutdown_channel().1, config.clone(), stats.clone(), move |recver, shutdown| {
+        let writer = write_tcp(address.clone(), config.clone(), stats.clone(), recver, shutdown);
+        started += 1;
+        let first = started == 1;
+
+        async move {
+            tokio::pin!(writer);
+
+            // the first writer gets going, then hits a bug
+            if first {
+                let _ = tokio::time::timeout(Duration::from_millis(50), &mut writer).await;
+                panic!("a bug in the writer");
+            }
+
+            writer.await
+        }
+    }));
+
+    let (stream, _) = listener.accept().await.unwrap();
+
+    sender.send(record(0)).await.unwrap();
+    assert_eq!(read_records(&mut BufReader::new(stream), Some(1)).await, vec![record(0)]);
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    sender.send(record(1)).await.unwrap();
+
+    // the new writer carries on with the same channel, on a new connection
+    let (stream, _) = listener.accept().await.unwrap();
+
+    drop(sender);
+
+    let records = read_records(&mut BufReader::new(stream), None).await;
+
+    supervisor.await.unwrap();
+    assert_eq!(records, vec![record(1)]);
+}
+
+#[tokio::test]
+async fn latency_is_stamped_as_written() {
+    let (listener, address) = fake_log_store().await;
+    let (sender, recver) = channel(16);
+    let config = config(address.as_str(), &[("LOG_STORE_INCLUDE_LATENCY", "1")]);
+    let stats = Stats::new(None).with_clock(Arc::new(MockClock::new(1_712_345_678_250)));
+
+    let writer = tokio::spawn(write_tcp(address.clone(), config, Arc::new(stats), recver, shutdown_channel().1));
+    let (stream, _) = listener.accept().await.unwrap();
+
+    for n in 0..2 {
+        sender.send(record(n)).await.unwrap();
+    }
+
+    drop(sender);
+
+    let records = read_records(&mut BufReader::new(stream), None).await;
+    let lags: Vec<_> = records.iter().map(|record| record["ship_lag_ms"].as_i64()).collect();
+
+    writer.await.unwrap();
+    assert_eq!(lags, vec![Some(250), Some(249)]);
+}
+
+#[tokio::test]
+async fn logfmt_quotes_what_it_has_to() {
+    let (listener, address) = fake_log_store().await;
+    let (sender, recver) = channel(16);
+    let config = config(address.as_str(), &[("LOG_STORE_FORMAT", "logfmt")]);
+
+    let writer = tokio::spawn(write_tcp(address.clone(), config, Arc::new(Stats::new(None)), recver, shutdown_channel().1));
+    let (stream, _) = listener.accept().await.unwrap();
+
+    sender.send(object! {
+        "t": 1_712_345_678_000i64,
+        "type": "function",
+        "msg": "said \"hi\" to a=b",
+        "path": "C:\\tmp",
+        "empty": "",
+        "plain": "ok",
+        "ctx": { "id": 1, "tags": ["a b"] },
+        "none": null,
+        "odd key": true,
+    }).await.unwrap();
+    drop(sender);
+
+    let mut lines = Vec::new();
+    let mut stream = BufReader::new(stream).lines();
+
+    while let Some(line) = stream.next_line().await.unwrap() {
+        lines.push(line);
+    }
+
+    writer.await.unwrap();
+    assert_eq!(lines, vec![
+        r#"t=1712345678000 type=function msg="said \"hi\" to a=b" path="C:\\tmp" empty="" plain=ok ctx="{\"id\":1,\"tags\":[\"a b\"]}" none= odd_key=true"#,
+    ]);
+}
+
+#[test]
+fn long_stdout_lines_are_truncated_to_parse() {
+    let record = object! { "t": 1_712_345_678_000i64, "type": "function", "msg": "\"quoted\" ".repeat(100) };
+    let line = record.dump();
+    let fitted = encoder::fit_line(line.as_str(), 200);
+    let parsed = json::parse(&fitted).unwrap();
+
+    assert!(fitted.len() <= 200);
+    assert_eq!(parsed["t"], 1_712_345_678_000i64);
+    assert_eq!(parsed["type"], "function");
+    assert_eq!(parsed["truncated"], true);
+    assert_eq!(parsed["bytes"], line.len());
+    assert!(line.starts_with(parsed["record"].as_str().unwrap()));
+
+    assert_eq!(encoder::fit_line(line.as_str(), 0), line);
+    assert_eq!(encoder::fit_line(line.as_str(), line.len()), line);
+}
+
+#[tokio::test]
+async fn local_log_stores_are_waited_for() {
+    // the port is free again once the listener is dropped, as if the log-store hadn't started yet
+    let (listener, address) = fake_log_store().await;
+    drop(listener);
+
+    let (sender, recver) = channel(16);
+    let config = config(address.as_str(), &[("LOG_STORE_INITIAL_CONNECT_RETRIES", "0"), ("LOG_STORE_WAIT_FOR_SINK_SECS", "5")]);
+    let writer = tokio::spawn(write_tcp(address.clone(), config, Arc::new(Stats::new(None)), recver, shutdown_channel().1));
+
+    sender.send(record(0)).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(600)).await;
+
+    let listener = TcpListener::bind(address.as_str()).await.unwrap();
+    let (stream, _) = listener.accept().await.unwrap();
+
+    drop(sender);
+
+    let records = read_records(&mut BufReader::new(stream), None).await;
+
+    writer.await.unwrap();
+    assert_eq!(records, vec![record(0)]);
+}
+
+#[tokio::test]
+async fn records_are_held_while_the_first_connection_is_made() {
+    let (listener, address) = fake_log_store().await;
+    drop(listener);
+
+    // room for two on the channel: the rest are only taken if the writer holds them while it connects
+    let (sender, recver) = channel(2);
+    let config = config(address.as_str(), &[
+        ("LOG_STORE_INITIAL_CONNECT_RETRIES", "0"),
+        ("LOG_STORE_WAIT_FOR_SINK_SECS", "5"),
+        ("LOG_STORE_INIT_BUFFER_MAX", "8"),
+    ]);
+    let writer = tokio::spawn(write_tcp(address.clone(), config, Arc::new(Stats::new(None)), recver, shutdown_channel().1));
+
+    for n in 0..6 {
+        tokio::time::timeout(Duration::from_millis(500), sender.send(record(n))).await.unwrap().unwrap();
+    }
+
+    let listener = TcpListener::bind(address.as_str()).await.unwrap();
+    let (stream, _) = listener.accept().await.unwrap();
+
+    sender.send(record(6)).await.unwrap();
+    drop(sender);
+
+    let records = read_records(&mut BufReader::new(stream), None).await;
+
+    writer.await.unwrap();
+    assert_eq!(records, (0..7).map(record).collect::<Vec<_>>());
+}
+
+/// A TCP writer sending health records per `config`, made by handlers' state queueing to it with `sender`.
+fn health_writer(address: &str, config: Arc<Config>, stats: Arc<Stats>, sender: &Sender<JsonValue>) -> (TcpWriter, Arc<HandlerState>) {
+    let state = Arc::new(HandlerState::new(&config, sender.clone(), stats.clone()));
+    let writer = TcpWriter::new(address.to_string(), config.clone(), stats).with_health(Health::new(&config, &state));
+
+    (writer, state)
+}
+
+#[tokio::test]
+async fn health_records_are_sent_on_an_interval() {
+    let (listener, address) = fake_log_store().await;
+    let (sender, recver) = channel(16);
+    let stats = Arc::new(Stats::new(None));
+
+    stats.watch_queue(&sender);
+
+    let config = config(address.as_str(), &[("LOG_STORE_HEALTH_INTERVAL_SECS", "1"), ("LOG_STORE_SEQ_SCOPE", "global")]);
+    let (writer, state) = health_writer(address.as_str(), config, stats, &sender);
+    let writer = tokio::spawn(writer.start(recver, shutdown_channel().1));
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut stream = BufReader::new(stream);
+
+    sender.send(record(0)).await.unwrap();
+
+    let records = read_records(&mut stream, Some(2)).await;
+
+    assert_eq!(records[0], record(0));
+    assert_eq!(records[1]["type"], "health");
+    assert_eq!(records[1]["connected"], true);
+    assert_eq!(records[1]["channel_depth"], 0);
+    assert_eq!(records[1]["dropped_total"], 0);
+    assert_eq!(records[1]["reconnects"], 0);
+    assert!(records[1]["last_write_ago_ms"].as_u64().is_some_and(|ago| ago < 2_000));
+    // stamped as the handlers' records are
+    assert!(records[1]["t"].as_u64().is_some());
+    assert!(records[1]["seq"].as_u64().is_some());
+
+    // the state is only held weakly, so the channel still closes when the handlers are done
+    drop((sender, state));
+    read_records(&mut stream, None).await;
+    writer.await.unwrap();
+}
+
+#[tokio::test]
+async fn health_records_are_formatted_as_configured() {
+    let (listener, address) = fake_log_store().await;
+    let (sender, recver) = channel(16);
+    let config = config(address.as_str(), &[("LOG_STORE_HEALTH_INTERVAL_SECS", "1"), ("LOG_STORE_FORMAT", "otel")]);
+    let (writer, state) = health_writer(address.as_str(), config, Arc::new(Stats::new(None)), &sender);
+    let writer = tokio::spawn(writer.start(recver, shutdown_channel().1));
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut stream = BufReader::new(stream);
+    let health = &read_records(&mut stream, Some(1)).await[0];
+
+    assert_eq!(health["body"]["stringValue"], "health");
+    assert!(health["timeUnixNano"].is_string());
+    assert!(health["attributes"].members().any(|attribute| attribute["key"] == "connected"));
+
+    drop((sender, state));
+    read_records(&mut stream, None).await;
+    writer.await.unwrap();
+}
+
+#[tokio::test]
+async fn writes_blocked_on_a_slow_log_store_are_flagged() {
+    let (listener, address) = fake_log_store().await;
+    let (sender, recver) = channel(16);
+    let stats = Arc::new(Stats::new(None));
+    let config = config(address.as_str(), &[("LOG_STORE_SLOW_SINK_MS", "50")]);
+    let writer = tokio::spawn(write_tcp(address.clone(), config, stats.clone(), recver, shutdown_channel().1));
+    let (stream, _) = listener.accept().await.unwrap();
+
+    // far more than the socket buffers hold, with nothing reading them
+    let big = "x".repeat(64 * 1024);
+    let feeder = tokio::spawn(async move {
+        for n in 0..512 {
+            sender.send(object! { "t": 1_712_345_678_000i64, "type": "function", "n": n, "record": big.as_str() }).await.unwrap();
+        }
+    });
+    let deadline = Instant::now() + Duration::from_secs(5);
+
+    while !stats.sink_slow.load(std::sync::atomic::Ordering::Relaxed) {
+        assert!(Instant::now() < deadline, "the writer never blocked");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    // once the log-store reads again, the write completes
+    let records = read_records(&mut BufReader::new(stream), None).await;
+
+    feeder.await.unwrap();
+    writer.await.unwrap();
+    assert_eq!(records.len(), 512);
+    assert!(!stats.sink_slow.load(std::sync::atomic::Ordering::Relaxed));
+    assert!(stats.slow_writes.load(std::sync::atomic::Ordering::Relaxed) >= 1);
+}
+
+#[tokio::test]
+async fn time_fields_can_be_renamed_per_type() {
+    let (listener, address) = fake_log_store().await;
+    let (sender, recver) = channel(16);
+    let config = config(address.as_str(), &[
+        ("LOG_STORE_TIME_FIELD", "ts"),
+        ("LOG_STORE_TIME_FIELD_BY_TYPE", "platform_report:metric_t, platform_start:t"),
+    ]);
+    let writer = tokio::spawn(write_tcp(address.clone(), config, Arc::new(Stats::new(None)), recver, shutdown_channel().1));
+    let (stream, _) = listener.accept().await.unwrap();
+
+    sender.send(record(0)).await.unwrap();
+    sender.send(object! { "t": 1_712_345_678_000i64, "type": "platform_report", "duration_ms": 1.5 }).await.unwrap();
+    sender.send(object! { "t": 1_712_345_678_000i64, "type": "platform_start" }).await.unwrap();
+    drop(sender);
+
+    let records = read_records(&mut BufReader::new(stream), None).await;
+
+    writer.await.unwrap();
+    assert_eq!(records[0].dump(), r#"{"ts":1712345678000,"type":"function","n":0}"#);
+    assert_eq!(records[1].dump(), r#"{"metric_t":1712345678000,"type":"platform_report","duration_ms":1.5}"#);
+    assert_eq!(records[2].dump(), r#"{"t":1712345678000,"type":"platform_start"}"#);
+}
+
+#[tokio::test]
+async fn fields_can_be_renamed() {
+    let (listener, address) = fake_log_store().await;
+    let (sender, recver) = channel(16);
+    let config = config(address.as_str(), &[("LOG_STORE_RENAME", "duration_ms:dur,billed_duration_ms:billed")]);
+    let writer = tokio::spawn(write_tcp(address.clone(), config, Arc::new(Stats::new(None)), recver, shutdown_channel().1));
+    let (stream, _) = listener.accept().await.unwrap();
+
+    sender.send(object! { "t": 1, "type": "platform_report", "duration_ms": 1.5, "billed_duration_ms": 2 }).await.unwrap();
+    // a field of that name is there already, so it's left as it is
+    sender.send(object! { "t": 2, "type": "function", "duration_ms": 3, "dur": "slow" }).await.unwrap();
+    drop(sender);
+
+    let records = read_records(&mut BufReader::new(stream), None).await;
+
+    writer.await.unwrap();
+    assert_eq!(records[0].dump(), r#"{"t":1,"type":"platform_report","dur":1.5,"billed":2}"#);
+    assert_eq!(records[1].dump(), r#"{"t":2,"type":"function","duration_ms":3,"dur":"slow"}"#);
+}
+
+#[tokio::test]
+async fn records_can_go_to_syslog() {
+    let (listener, address) = fake_log_store().await;
+    let (sender, recver) = channel(16);
+    let (shutdown, shutdown_listener) = shutdown_channel();
+    let syslog_address = format!("syslog+tcp://{}", address);
+    let config = config(syslog_address.as_str(), &[("AWS_LAMBDA_FUNCTION_NAME", "my fn"), ("LOG_STORE_FORMAT", "otel")]);
+    let syslog = Syslog::new(SyslogTransport::Tcp, address.clone(), &config);
+
+    // records are what the messages are made from, whatever the format
+    assert_eq!(config.format, Format::Json);
+    assert_eq!(config.warnings[0].field, "LOG_STORE_FORMAT");
+
+    let writer = tokio::spawn(write_sink(Box::new(syslog), config.clone(), Arc::new(Stats::new(None)), recver, shutdown_listener));
+
+    sender.send(object! {
+        "t": 1_712_345_678_123i64, "type": "function", "severity": "error", "record": "upstream timed out",
+        "http": { "status": 500 }, "note": "a \"b\"]", "tags": [1, 2],
+    }).await.unwrap();
+    sender.send(object! { "t": 1_712_345_678_000i64, "type": "platform_start", "severity": "info" }).await.unwrap();
+
+    // connected for the first batch
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let mut bytes = String::new();
+
+    assert!(shutdown.shutdown(ShutdownReason::Signal, Instant::now() + Duration::from_secs(1)).await);
+    stream.read_to_string(&mut bytes).await.unwrap();
+    writer.await.unwrap();
+
+    // octet counted: each message's length, a space, then the message
+    let mut messages = Vec::new();
+    let mut rest = bytes.as_str();
+
+    while let Some((len, after)) = rest.split_once(' ') {
+        let len: usize = len.parse().unwrap();
+
+        messages.push(&after[..len]);
+        rest = &after[len..];
+    }
+
+    assert_eq!(messages[..2], [
+        "<11>1 2024-04-05T19:34:38.123Z my_fn my_fn - function [fields@32473 http.status=\"500\" note=\"a \\\"b\\\"\\]\" tags=\"[1,2\\]\"] \u{feff}upstream timed out",
+        "<14>1 2024-04-05T19:34:38.000Z my_fn my_fn - platform_start -",
+    ]);
+    assert!(messages[2].contains(" shutdown_summary [fields@32473 "));
+
+    // over UDP, a datagram each
+    let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut syslog = Syslog::new(SyslogTransport::Udp, server.local_addr().unwrap().to_string(), &config);
+    let mut datagram = [0; 1024];
+
+    syslog.write_batch(&[object! { "t": 0, "type": "extension", "severity": "debug", "msg": "hi" }]).await.unwrap();
+
+    let len = server.recv(&mut datagram).await.unwrap();
+
+    assert_eq!(std::str::from_utf8(&datagram[..len]).unwrap(), "<15>1 1970-01-01T00:00:00.000Z my_fn my_fn - extension - \u{feff}hi");
+}